@@ -1,8 +1,8 @@
-use std::path::Path;
-
 fn main() {
     #[cfg(feature = "sgx")]
     {
+        use std::path::Path;
+
         let sgxsdk = std::env::var("SGX_SDK").unwrap_or_else(|_| "/opt/intel/sgxsdk".into());
         let libsgxstep =
             std::env::var("LIBSGXSTEP").unwrap_or_else(|_| "../../../libsgxstep".into());
@@ -1,36 +1,186 @@
 use bmp::{Image, Pixel};
-use clap::{Parser, Subcommand};
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand, ValueEnum};
 use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, info, trace, warn};
 use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use sgx_profiler_core::{
+    check,
+    state_machine::{PageState, PageStateMachine, Transition},
+};
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{BTreeMap, BTreeSet, HashMap},
     error::Error,
-    ffi::{c_char, c_int, CString},
+    ffi::c_int,
     fmt::{Display, Formatter},
     fs::File,
     io::BufReader,
     ops::Range,
-    ptr::null_mut,
-    sync::{
-        atomic::{AtomicBool, AtomicUsize, Ordering},
-        Mutex,
-    },
+    path::Path,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
 };
 
-static PROGRESS_BAR: OnceCell<ProgressBar> = OnceCell::new();
+static PROGRESS_BAR: OnceCell<BatchedProgress> = OnceCell::new();
+
+/// Progress bar that is only redrawn every `batch` increments,
+/// as redrawing on every reconstructed block slows down the attack
+#[derive(Debug)]
+struct BatchedProgress {
+    bar: ProgressBar,
+    batch: u64,
+    pending: AtomicU64,
+}
+
+impl BatchedProgress {
+    fn new(bar: ProgressBar, batch: u64) -> Self {
+        Self {
+            bar,
+            batch: batch.max(1),
+            pending: AtomicU64::new(0),
+        }
+    }
+
+    fn inc(&self) {
+        if self.pending.fetch_add(1, Ordering::Relaxed) + 1 >= self.batch {
+            self.flush();
+        }
+    }
+
+    /// Add the pending increments to the progress bar
+    fn flush(&self) {
+        self.bar.inc(self.pending.swap(0, Ordering::Relaxed));
+    }
+
+    /// Restart the progress bar for the next image, with `len` blocks
+    fn reset(&self, len: u64) {
+        self.flush();
+        self.bar.set_length(len);
+        self.bar.reset();
+    }
+}
+
+/// An input image to attack, with the outputs derived for it
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub image: String,
+    pub output: Option<String>,
+    pub raw_output: Option<String>,
+    /// True per-block data counts to score the reconstruction against, see `--golden`
+    pub golden: Option<String>,
+    /// Output of the true per-block data counts, see `--golden-output`
+    #[cfg(feature = "sgx")]
+    pub golden_output: Option<String>,
+    /// Size of the image file, for the input buffer of the enclave
+    pub input_size: u64,
+    /// Size of the decompressed image, for the output buffer of the enclave
+    pub output_size: u64,
+    /// Number of blocks that will be reconstructed
+    pub num_blocks: u64,
+}
+
+impl Target {
+    /// Read the dimensions of `image`, which are needed to size the enclave buffers.
+    ///
+    /// This information is NOT used by the attack.
+    fn new(
+        image: String,
+        output: Option<String>,
+        raw_output: Option<String>,
+        color: bool,
+    ) -> Result<Self, Box<dyn Error>> {
+        let (width, height) = image::image_dimensions(&image)?;
+        let input_size = std::fs::metadata(&image)?.len();
+        let output_size = ((width * height * 3) + 100) as u64;
+        let mut num_blocks = ((width / 8) + 1) * ((height / 8) + 1);
+        if color {
+            num_blocks *= 3;
+        }
+        Ok(Self {
+            image,
+            output,
+            raw_output,
+            golden: None,
+            #[cfg(feature = "sgx")]
+            golden_output: None,
+            input_size,
+            output_size,
+            num_blocks: num_blocks as u64,
+        })
+    }
+
+    /// Autosave configuration of `--autosave-interval`
+    fn autosave(&self, interval: Option<usize>) -> Option<Autosave> {
+        interval.map(|interval| Autosave {
+            interval: interval.max(1),
+            output: self.output.clone(),
+            raw_output: self.raw_output.clone(),
+        })
+    }
+
+    /// Score `reconstruct` against the true data counts of `--golden`, if given
+    fn score(&self, reconstruct: &JpegReconstruct) -> Result<(), Box<dyn Error>> {
+        let Some(golden) = &self.golden else {
+            return Ok(());
+        };
+        let file = File::open(golden).map_err(|e| format!("failed to open {golden}: {e}"))?;
+        let truth: Vec<Vec<Vec<usize>>> = serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| format!("invalid golden file {golden}: {e}"))?;
+        ReconstructionError::from_raw(reconstruct.raw_reconstruction(), &truth)
+            .print(&self.image, &format!("the golden counts in {golden}"));
+        Ok(())
+    }
+}
+
+/// Name of the output for `image` in batch mode: `output` with the file stem of `image`
+/// appended to its own, e.g. `out/rec.bmp` becomes `out/rec_birds.bmp` for `birds.jpg`.
+fn batch_output(output: &str, image: &str) -> String {
+    let output = Path::new(output);
+    let stem = |path: &Path| {
+        path.file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    };
+    let mut name = format!("{}_{}", stem(output), stem(Path::new(image)));
+    if let Some(extension) = output.extension() {
+        name = format!("{name}.{}", extension.to_string_lossy());
+    }
+    output.with_file_name(name).to_string_lossy().into_owned()
+}
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum AttackError {
-    // TODO: add different errors
-    Unknown,
-    Mprotect,
+    /// Changing the protection of `len` pages starting at `page` failed with `errno`
+    Mprotect { page: usize, len: usize, errno: i32 },
+    /// The enclave could not be created, with the given `sgx_status_t`
+    EnclaveCreate(u32),
+    /// The image could not be loaded into the enclave
+    ImageLoad,
+    /// The ecall wrapper with the given name returned a nonzero status
+    Ecall(&'static str, c_int),
+    /// A page fault on `page` could not be explained by the state machine in `state`
+    StateDesync { state: JpegState, page: usize },
+    /// The enclave does not contain a required symbol
+    SymbolMissing(String),
 }
 
 impl Display for AttackError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            AttackError::Unknown => f.write_str("unknown error"),
-            AttackError::Mprotect => f.write_str("failed to protect memory"),
+            AttackError::Mprotect { page, len, errno } => write!(
+                f,
+                "failed to protect {len} page(s) starting at page {page}: {}",
+                std::io::Error::from_raw_os_error(*errno)
+            ),
+            AttackError::EnclaveCreate(status) => {
+                write!(f, "failed to create enclave, error code: {status:x}")
+            }
+            AttackError::ImageLoad => f.write_str("failed to load image into the enclave"),
+            AttackError::Ecall(name, status) => write!(f, "ecall {name} failed with {status}"),
+            AttackError::StateDesync { state, page } => {
+                write!(f, "unexpected page fault on page {page} in state {state:?}")
+            }
+            AttackError::SymbolMissing(name) => write!(f, "missing enclave symbol: {name}"),
         }
     }
 }
@@ -57,6 +207,50 @@ pub struct JpegReconstruct {
     current_row: usize,
     pub scale: f64,
     pub offset: f64,
+    /// Number of blocks reconstructed so far
+    blocks: usize,
+    autosave: Option<Autosave>,
+    /// Whether `autosave.interval` blocks were reconstructed since the last autosave
+    autosave_due: bool,
+    /// Whether reconstructed blocks advance the progress bar
+    progress: bool,
+    bit_depth: BitDepth,
+}
+
+/// Bits per channel of the reconstructed image
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum BitDepth {
+    /// 8-bit bitmap
+    #[default]
+    #[value(name = "8")]
+    #[serde(rename = "8")]
+    Eight,
+    /// 16-bit PNG, which keeps more of the range of the data counts
+    #[value(name = "16")]
+    #[serde(rename = "16")]
+    Sixteen,
+}
+
+/// Periodically save a partial reconstruction, so it survives a crash
+#[derive(Debug, Clone)]
+pub struct Autosave {
+    /// Number of reconstructed blocks between saves
+    pub interval: usize,
+    pub output: Option<String>,
+    pub raw_output: Option<String>,
+}
+
+/// Write the file at `path` using `write` on a temporary file, which then replaces `path`.
+///
+/// The rename is atomic, so `path` always holds a complete file,
+/// even if writing is interrupted.
+fn write_atomic(
+    path: &str,
+    write: impl FnOnce(&str) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let tmp = format!("{path}.tmp");
+    write(&tmp)?;
+    std::fs::rename(tmp, path)
 }
 
 impl JpegReconstruct {
@@ -75,12 +269,67 @@ impl JpegReconstruct {
             reconstructed_buffer: buffer,
             scale: -0.08,
             offset: 20.,
+            blocks: 0,
+            autosave: None,
+            autosave_due: false,
+            progress: true,
+            bit_depth: BitDepth::Eight,
+        }
+    }
+
+    /// Save the reconstructed image with `bit_depth` bits per channel
+    pub fn with_bit_depth(mut self, bit_depth: BitDepth) -> Self {
+        self.bit_depth = bit_depth;
+        self
+    }
+
+    /// Don't advance the progress bar, for a reconstruction that runs alongside another one
+    pub fn without_progress(mut self) -> Self {
+        self.progress = false;
+        self
+    }
+
+    /// Save the reconstruction every `autosave.interval` blocks
+    pub fn with_autosave(mut self, autosave: Option<Autosave>) -> Self {
+        self.autosave = autosave;
+        self
+    }
+
+    /// Save the image to `output` and the raw reconstruction as JSON to `raw_output`.
+    ///
+    /// The image is a bitmap, or a PNG with [`BitDepth::Sixteen`].
+    pub fn save(&self, output: Option<&str>, raw_output: Option<&str>) -> std::io::Result<()> {
+        if let Some(o) = raw_output {
+            write_atomic(o, |tmp| {
+                std::fs::write(
+                    tmp,
+                    serde_json::to_string_pretty(self.raw_reconstruction())?,
+                )
+            })?;
+        }
+        if let Some(o) = output {
+            match self.bit_depth {
+                BitDepth::Eight => {
+                    let image = self.reconstructed_bitmap();
+                    write_atomic(o, |tmp| image.save(tmp))?;
+                }
+                BitDepth::Sixteen => {
+                    let image = self.reconstructed_image16();
+                    write_atomic(o, |tmp| {
+                        image
+                            .save_with_format(tmp, image::ImageFormat::Png)
+                            .map_err(std::io::Error::other)
+                    })?;
+                }
+            }
         }
+        Ok(())
     }
 
     pub fn reconstructed_pixel(&self, color: usize, x: usize, y: usize) -> isize {
-        *self.reconstructed_buffer[color % self.num_colors][y]
-            .get(x)
+        *self.reconstructed_buffer[color % self.num_colors]
+            .get(y)
+            .and_then(|row| row.get(x))
             .unwrap_or(&0) as isize
     }
 
@@ -105,7 +354,7 @@ impl JpegReconstruct {
         buffer.sort();
         let median = buffer[buffer.len() / 2];
         let min = self.min_data as isize;
-        println!("min: {}, median: {}, max: {}", min, median, self.max_data);
+        debug!("min: {}, median: {}, max: {}", min, median, self.max_data);
         let scale = 255. / ((self.max_data as isize - min) as f64);
 
         for x in 0..width {
@@ -127,12 +376,47 @@ impl JpegReconstruct {
         image
     }
 
+    /// Creates a 16-bit image with the reconstruction, grayscale unless it has colors.
+    ///
+    /// Like [`Self::reconstructed_bitmap`], the data counts are scaled from
+    /// `min_data..max_data`, but to `u16::MAX` instead of 255, so blocks whose
+    /// counts differ slightly keep different values.
+    pub fn reconstructed_image16(&self) -> image::DynamicImage {
+        let [width, height] = self.reconstructed_size();
+        let min = self.min_data as isize;
+        let scale = u16::MAX as f64 / ((self.max_data as isize - min) as f64);
+        let pixel = |color: usize, x: u32, y: u32| {
+            (((self.reconstructed_pixel(color, x as usize, y as usize) - min) as f64).max(0.)
+                * scale) as u16
+        };
+
+        if self.num_colors == 1 {
+            image::DynamicImage::ImageLuma16(image::ImageBuffer::from_fn(
+                width as u32,
+                height as u32,
+                |x, y| image::Luma([pixel(JPEG_GRAY.0, x, y)]),
+            ))
+        } else {
+            image::DynamicImage::ImageRgb16(image::ImageBuffer::from_fn(
+                width as u32,
+                height as u32,
+                |x, y| {
+                    image::Rgb([
+                        pixel(JPEG_RED.0, x, y),
+                        pixel(JPEG_GREEN.0, x, y),
+                        pixel(JPEG_BLUE.0, x, y),
+                    ])
+                },
+            ))
+        }
+    }
+
     /// Returns the reconstruction buffer
     pub fn reconstruction(&self, color: JpegColor) -> Vec<usize> {
         self.reconstructed_buffer[color.0]
             .iter()
-            .cloned()
             .flatten()
+            .copied()
             .collect()
     }
 
@@ -142,32 +426,87 @@ impl JpegReconstruct {
 
     /// Advance to the next row in the reconstruction.
     pub fn next_row(&mut self) {
-        for i in 0..self.num_colors {
-            self.reconstructed_buffer[i].push(Vec::with_capacity(300));
-        }
         self.current_row += 1;
+        for rows in &mut self.reconstructed_buffer {
+            // Colors may already have the row, see `reconstruct_block`
+            if rows.len() <= self.current_row {
+                rows.resize_with(self.current_row + 1, || Vec::with_capacity(300));
+            }
+        }
     }
 
     /// Reconstruct a JPEG block based on the given number of data accesses that were counted
     /// during reconstruction of this block.
+    ///
+    /// If the current row is missing from the buffer of the current color, e.g. after
+    /// transitions in an unexpected order, empty rows are added up to it instead of
+    /// panicking, so a desync only garbles the reconstruction.
     pub fn reconstruct_block(&mut self, num_data: usize) {
         // Also update the min and max data count values encountered,
         // which will be used to normalize the reconstructed image.
         self.max_data = self.max_data.max(num_data);
         self.min_data = self.min_data.min(num_data);
-        self.reconstructed_buffer[self.current_color][self.current_row as usize].push(num_data);
+        let rows = &mut self.reconstructed_buffer[self.current_color];
+        if rows.len() <= self.current_row {
+            warn!(
+                "block in row {} of color {}, which only has {} rows, adding empty rows",
+                self.current_row,
+                self.current_color,
+                rows.len()
+            );
+            rows.resize_with(self.current_row + 1, Vec::new);
+        }
+        rows[self.current_row].push(num_data);
         self.current_color = (self.current_color + 1) % self.num_colors;
-        PROGRESS_BAR.get().unwrap().inc(1);
+        if self.progress {
+            PROGRESS_BAR.get().unwrap().inc();
+        }
+
+        self.blocks += 1;
+        if let Some(autosave) = &self.autosave {
+            // Blocks are reconstructed in the fault handler, which must not write files
+            self.autosave_due |= self.blocks.is_multiple_of(autosave.interval);
+        }
+    }
+
+    /// Save the reconstruction if `autosave.interval` blocks were reconstructed
+    /// since the last autosave.
+    ///
+    /// Called after reconstructing, outside of the fault handler.
+    pub fn autosave(&mut self) {
+        let Some(autosave) = self.autosave.as_ref().filter(|_| self.autosave_due) else {
+            return;
+        };
+        self.autosave_due = false;
+        // A failed autosave should not abort the attack, the final save reports errors
+        if let Err(e) = self.save(autosave.output.as_deref(), autosave.raw_output.as_deref()) {
+            warn!("autosave failed: {e}");
+        }
     }
 
     /// Called to notify `JpegReconstruct` of a state transition
     pub fn reconstruct(&mut self, prev_state: JpegState, new_state: JpegState) {
+        let data_count = match prev_state {
+            JpegState::DataCount(data_count) => data_count,
+            _ => 0,
+        };
+        self.reconstruct_counted(prev_state, new_state, data_count);
+    }
+
+    /// Like `reconstruct`, but a block is reconstructed from the given number of
+    /// data accesses instead of the count of the `JpegState::DataCount` state.
+    pub fn reconstruct_counted(
+        &mut self,
+        prev_state: JpegState,
+        new_state: JpegState,
+        data_count: usize,
+    ) {
         // If we were previously in a data counting state, but we no longer are,
         // reconstruct another block based on the number of data accesses counted.
-        if let JpegState::DataCount(data_count) = prev_state {
-            if !matches!(new_state, JpegState::DataCount(_)) {
-                self.reconstruct_block(data_count);
-            }
+        if matches!(prev_state, JpegState::DataCount(_))
+            && !matches!(new_state, JpegState::DataCount(_))
+        {
+            self.reconstruct_block(data_count);
         }
 
         // If we transition from `JpegState::NextRow` to `JpegState::StartRow`,
@@ -178,11 +517,123 @@ impl JpegReconstruct {
     }
 }
 
+/// Per-block difference between a reconstruction and the ground truth, see `--verify`
+#[derive(Debug, Clone)]
+pub struct ReconstructionError {
+    /// Number of blocks in either reconstruction
+    pub blocks: usize,
+    /// Number of blocks whose data count differs, including blocks that are
+    /// missing from one of the reconstructions
+    pub differing: usize,
+    /// Mean absolute difference of the data counts over all blocks
+    pub mean_abs_error: f64,
+    pub max_abs_error: usize,
+    /// Absolute difference of every block, by color, row and block.
+    /// Missing blocks count as zero.
+    errors: Vec<Vec<Vec<usize>>>,
+}
+
+impl ReconstructionError {
+    /// Compare the blocks of `reconstruction` to those of `truth`
+    pub fn new(reconstruction: &JpegReconstruct, truth: &JpegReconstruct) -> Self {
+        Self::from_raw(
+            reconstruction.raw_reconstruction(),
+            truth.raw_reconstruction(),
+        )
+    }
+
+    /// Compare raw reconstructions, by color, row and block, as written to `--raw-output`
+    pub fn from_raw(a: &[Vec<Vec<usize>>], b: &[Vec<Vec<usize>>]) -> Self {
+        let mut error = Self {
+            blocks: 0,
+            differing: 0,
+            mean_abs_error: 0.,
+            max_abs_error: 0,
+            errors: Vec::new(),
+        };
+        let mut total = 0;
+        for color in 0..a.len().max(b.len()) {
+            let (rows_a, rows_b) = (a.get(color), b.get(color));
+            let num_rows = rows_a.map_or(0, Vec::len).max(rows_b.map_or(0, Vec::len));
+            let mut rows = Vec::with_capacity(num_rows);
+            for row in 0..num_rows {
+                let row_a = rows_a
+                    .and_then(|r| r.get(row))
+                    .map_or(&[][..], Vec::as_slice);
+                let row_b = rows_b
+                    .and_then(|r| r.get(row))
+                    .map_or(&[][..], Vec::as_slice);
+                let row = (0..row_a.len().max(row_b.len()))
+                    .map(|block| {
+                        let (x, y) = (row_a.get(block), row_b.get(block));
+                        let diff = x.unwrap_or(&0).abs_diff(*y.unwrap_or(&0));
+                        error.blocks += 1;
+                        error.differing += (x != y) as usize;
+                        error.max_abs_error = error.max_abs_error.max(diff);
+                        total += diff;
+                        diff
+                    })
+                    .collect();
+                rows.push(row);
+            }
+            error.errors.push(rows);
+        }
+        if error.blocks > 0 {
+            error.mean_abs_error = total as f64 / error.blocks as f64;
+        }
+        error
+    }
+
+    /// Print the errors of the reconstruction of `image`, compared to `truth`
+    fn print(&self, image: &str, truth: &str) {
+        println!(
+            "{image}: {} of {} blocks differ from {truth}, mean absolute error {:.3}, max {}",
+            self.differing, self.blocks, self.mean_abs_error, self.max_abs_error
+        );
+    }
+
+    /// Bitmap of the per-block errors, brighter for larger errors, with one
+    /// channel per color component like the reconstructed bitmap
+    pub fn bitmap(&self) -> Image {
+        let rows = self.errors.first().map_or(&[][..], Vec::as_slice);
+        let height = rows
+            .iter()
+            .rposition(|row| !row.is_empty())
+            .map_or(0, |r| r + 1);
+        let width = rows.iter().map(Vec::len).max().unwrap_or(0);
+        let mut image = Image::new(width as u32, height as u32);
+
+        let scale = 255. / self.max_abs_error.max(1) as f64;
+        let pixel = |color: usize, x: usize, y: usize| {
+            let errors = &self.errors[color % self.errors.len()];
+            let error = errors.get(y).and_then(|row| row.get(x)).unwrap_or(&0);
+            (*error as f64 * scale) as u8
+        };
+        for x in 0..width {
+            for y in 0..height {
+                image.set_pixel(
+                    x as u32,
+                    y as u32,
+                    Pixel::new(
+                        pixel(JPEG_RED.0, x, y),
+                        pixel(JPEG_GREEN.0, x, y),
+                        pixel(JPEG_BLUE.0, x, y),
+                    ),
+                );
+            }
+        }
+        image
+    }
+}
+
 /// State machine used for the libjpeg attack.
 ///
 /// Every state corresponds to a range of pages that when encountered
 /// can trigger a state transition to this state, if the transition is allowed.
-#[derive(Debug, Clone, Default, Copy, PartialEq, Eq)]
+///
+/// States are serialized by their [`JpegState::name`], and `DataCount` with its
+/// count, e.g. `"NextRow"` and `{"DataCount":3}` in JSON.
+#[derive(Debug, Clone, Default, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum JpegState {
     #[default]
     PreStart,
@@ -195,9 +646,14 @@ pub enum JpegState {
     DataCount(usize),
 }
 
-impl JpegState {
+/// The states of the attack, fed to a [`PageStateMachine`].
+///
+/// The page ranges depend on whether the enclave is built with AEX-Notify.
+impl PageState for JpegState {
+    type Config = bool;
+
     /// Defines the ranges of pages for each state
-    pub fn pages(self, has_aexnotify: bool) -> Range<usize> {
+    fn pages(self, has_aexnotify: &bool) -> Range<usize> {
         match self {
             Self::PreStart => 0..0,
             Self::Start => 54..55,
@@ -210,7 +666,7 @@ impl JpegState {
             // Self::DataCount(_) => 156..167,
             // Self::DataCount(_) => 150..190,
             Self::DataCount(_) => {
-                if has_aexnotify {
+                if *has_aexnotify {
                     150..4335
                 } else {
                     150..4340
@@ -241,7 +697,7 @@ impl JpegState {
     }
 
     /// Defines the transitions between states
-    pub fn next_states(self) -> Vec<Self> {
+    fn next_states(self) -> Vec<Self> {
         match self {
             Self::PreStart => vec![Self::Start],
             Self::Start => vec![Self::StartRow],
@@ -253,117 +709,399 @@ impl JpegState {
             _ => vec![],
         }
     }
+}
 
-    /// Advance to the next state if we fault on the given page
-    pub fn next(self, page: usize, has_aexnotify: bool) -> Self {
-        self.next_states()
+impl JpegState {
+    /// All states that are entered on a page fault
+    pub const TRIGGERED: [Self; 7] = [
+        Self::Start,
+        Self::NextRow,
+        Self::StartRow,
+        Self::PreIdctSlow,
+        Self::StartIdctSlow,
+        Self::IdctSlow,
+        Self::DataCount(0),
+    ];
+
+    /// Check that the page ranges of all states lie within the first `num_pages` pages.
+    ///
+    /// Returns a description of every range that is out of bounds.
+    pub fn check_pages(num_pages: usize, has_aexnotify: bool) -> Vec<String> {
+        PageStateMachine::new(Self::PreStart, has_aexnotify)
+            .out_of_bounds(Self::TRIGGERED, num_pages)
             .into_iter()
-            .find(|state| state.pages(has_aexnotify).contains(&page))
-            .unwrap_or(self)
+            .map(|(state, pages)| {
+                format!(
+                    "pages {pages:?} of state {} exceed the {num_pages} pages of the enclave",
+                    state.name()
+                )
+            })
+            .collect()
+    }
+
+    /// Name of the state, ignoring the data count
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::PreStart => "PreStart",
+            Self::Start => "Start",
+            Self::NextRow => "NextRow",
+            Self::StartRow => "StartRow",
+            Self::PreIdctSlow => "PreIdctSlow",
+            Self::StartIdctSlow => "StartIdctSlow",
+            Self::IdctSlow => "IdctSlow",
+            Self::DataCount(_) => "DataCount",
+        }
     }
 
-    /// Returns the list of pages that may trigger a state transition.
+    /// Log which of the candidate states a fault chose, if the pages of more than one
+    /// contain the faulting page.
     ///
-    /// In other words, the union of all ranges of pages for all potential
-    /// next states reachable from the current state.
+    /// The first candidate is chosen silently otherwise, so a warning is emitted the
+    /// first time states overlap.
+    pub fn log_candidates(transition: &Transition<Self>) {
+        static WARNED: AtomicBool = AtomicBool::new(false);
+
+        if !transition.is_ambiguous() {
+            return;
+        }
+        let Transition {
+            page,
+            from,
+            to,
+            candidates,
+        } = transition;
+        debug!("fault@{page}: {from:?} -> {to:?}, chosen from {candidates:?}");
+        if !WARNED.swap(true, Ordering::Relaxed) {
+            warn!(
+                "pages of the next states {candidates:?} of {from:?} overlap on page {page}, \
+                 choosing {to:?}"
+            );
+        }
+    }
+
+    /// Log a transition on a fault on `page`.
     ///
-    /// This is used during the attack to determine which pages to revoke access to.
-    pub fn next_pages(self, has_aexnotify: bool) -> Vec<Range<usize>> {
-        self.next_states()
-            .into_iter()
-            .map(|state| state.pages(has_aexnotify))
-            .collect()
+    /// Transitions between data counts happen for every coefficient,
+    /// so they are only logged at the trace level.
+    pub fn log_transition(page: usize, prev_state: Self, new_state: Self) {
+        if new_state == prev_state {
+            return;
+        }
+        if new_state.name() != prev_state.name() {
+            debug!("fault@{page}: {prev_state:?} -> {new_state:?}");
+        } else {
+            trace!("data on page {page}: {prev_state:?} -> {new_state:?}");
+        }
     }
 }
 
 #[cfg(feature = "sgx")]
 mod sgx {
     use super::*;
-    use sgx_step::sgx_step_sys::{
-        get_enclave_ssa_gprsgx_adrs, print_enclave_info, register_enclave_info,
-        register_fault_handler, restore_pages, revoke_pages,
+    use log::{error, log_enabled, Level};
+    use nix::sys::signal::{signal, SigHandler, Signal};
+    use sgx_profiler::{
+        dump::{DumperConfig, RSet, VCDDumper},
+        PageAccess, PageProtection, Perms, ProfilerError,
+    };
+    use sgx_step::{
+        page::RelativePage,
+        sgx_step_sys::{
+            edbgrd_erip, get_enclave_base, print_enclave_info, register_fault_handler,
+            PAGE_SIZE_4KiB,
+        },
+        EnclaveId, EnclaveRef,
     };
     use sgx_urts_sys::{
-        sgx_create_enclave, sgx_destroy_enclave, sgx_enclave_id_t, sgx_launch_token_t,
+        _status_t_SGX_SUCCESS, sgx_create_enclave, sgx_destroy_enclave, sgx_enclave_id_t,
+        sgx_launch_token_t, sgx_status_t,
+    };
+    use std::{
+        collections::VecDeque,
+        ffi::{c_char, CString},
+        ptr::null_mut,
+        sync::{atomic::AtomicUsize, Mutex, PoisonError},
     };
 
     static GLOBAL_STATE: OnceCell<Mutex<GlobalState>> = OnceCell::new();
 
+    /// Trace of `--marker-trace`, with the faults as steps and the ocalls as markers
+    static MARKER_TRACE: Mutex<Option<VCDDumper<RSet>>> = Mutex::new(None);
+
+    /// Faults of the `--marker-trace` that are not written to it yet
+    static MARKER_FAULTS: FaultLog<{ 1 << 16 }> = FaultLog::new();
+
+    /// Pages of faults, recorded by the fault handler without locking, up to `N`
+    /// faults until they are drained outside of the handler.
+    ///
+    /// The fault handler and the ocalls run on the thread that enters the enclave,
+    /// so recording and draining never overlap, and the atomics only make sharing
+    /// the log sound.
+    struct FaultLog<const N: usize> {
+        pages: [AtomicUsize; N],
+        /// Number of recorded faults, which exceeds `N` if faults were dropped
+        len: AtomicUsize,
+        enabled: AtomicBool,
+    }
+
+    impl<const N: usize> FaultLog<N> {
+        const fn new() -> Self {
+            Self {
+                pages: [const { AtomicUsize::new(0) }; N],
+                len: AtomicUsize::new(0),
+                enabled: AtomicBool::new(false),
+            }
+        }
+
+        /// Record faults from now on, or stop recording them
+        fn enable(&self, enabled: bool) {
+            self.enabled.store(enabled, Ordering::Release);
+        }
+
+        /// Record a fault on `page`, if enabled. Safe to call in a signal handler.
+        fn record(&self, page: usize) {
+            if !self.enabled.load(Ordering::Acquire) {
+                return;
+            }
+            let index = self.len.fetch_add(1, Ordering::AcqRel);
+            if let Some(slot) = self.pages.get(index) {
+                slot.store(page, Ordering::Release);
+            }
+        }
+
+        /// Pass the recorded faults to `f` in order and clear them, returning the
+        /// number of faults that were dropped as the log was full
+        fn drain(&self, mut f: impl FnMut(usize)) -> usize {
+            let len = self.len.swap(0, Ordering::AcqRel);
+            for slot in &self.pages[..len.min(N)] {
+                f(slot.load(Ordering::Acquire));
+            }
+            len.saturating_sub(N)
+        }
+    }
+
+    /// Write the faults recorded by the fault handler to the `--marker-trace`,
+    /// one step per fault
+    fn write_marker_faults(trace: &mut VCDDumper<RSet>) {
+        let dropped = MARKER_FAULTS.drain(|page| {
+            let access = PageAccess::new(RelativePage::new(page), Perms::READ);
+            trace.next_step(|entry| entry.write_page_accesses([access].iter()));
+        });
+        if dropped > 0 {
+            warn!("{dropped} page faults are missing from the marker trace");
+        }
+    }
+
+    /// Label the next step of the `--marker-trace`, if any
+    fn mark(label: impl FnOnce() -> String) {
+        if let Some(trace) = MARKER_TRACE.lock().unwrap().as_mut() {
+            write_marker_faults(trace);
+            trace.mark(&label());
+        }
+    }
+
+    /// Per-state histogram of the erip at the faults that caused a transition into the state.
+    ///
+    /// Erips are relative to the enclave base, so they can be matched against the
+    /// page ranges of `JpegState::pages`.
+    #[derive(Debug, Default)]
+    pub struct EripHistogram(BTreeMap<&'static str, BTreeMap<usize, usize>>);
+
+    impl EripHistogram {
+        pub fn record(&mut self, state: JpegState, erip: usize) {
+            *self
+                .0
+                .entry(state.name())
+                .or_default()
+                .entry(erip)
+                .or_default() += 1;
+        }
+
+        /// Choose between states that share a page range, based on the code page of `erip`.
+        ///
+        /// Prefers the candidate that was most often entered from the same code page.
+        /// Ties, e.g. when no candidate was entered from there yet, go to the first candidate.
+        pub fn disambiguate(&self, candidates: &[JpegState], erip: usize) -> Option<JpegState> {
+            let code_page = erip / PAGE_SIZE_4KiB as usize;
+            let count = |state: &JpegState| {
+                self.0.get(state.name()).map_or(0, |h| {
+                    h.iter()
+                        .filter(|(e, _)| *e / PAGE_SIZE_4KiB as usize == code_page)
+                        .map(|(_, n)| n)
+                        .sum::<usize>()
+                })
+            };
+            candidates.iter().copied().rev().max_by_key(count)
+        }
+
+        pub fn to_json(&self) -> serde_json::Value {
+            self.0
+                .iter()
+                .map(|(state, histogram)| {
+                    let histogram = histogram
+                        .iter()
+                        .map(|(erip, n)| (format!("{erip:#x}"), (*n).into()))
+                        .collect::<serde_json::Map<_, _>>();
+                    (state.to_string(), histogram.into())
+                })
+                .collect::<serde_json::Map<_, _>>()
+                .into()
+        }
+    }
+
     /// Global state used when attacking an enclave.
     ///
     /// We use global state, since page faults are handled asynchronously.
     #[derive(Debug)]
     pub struct GlobalState {
-        state: JpegState,
+        enclave: EnclaveRef,
+        machine: PageStateMachine<JpegState>,
         reconstruct: JpegReconstruct,
         working_set: VecDeque<usize>,
         prev_page: usize,
         use_ocalls: bool,
-        has_aexnotify: bool,
+        /// Set if erip is used to validate state transitions
+        erip_histogram: Option<EripHistogram>,
+        /// Reconstruction from the ocalls, set with `--verify` to check the page
+        /// fault reconstruction against
+        ground_truth: Option<JpegReconstruct>,
+        /// Error of the fault handler, which stopped the attack
+        fault_error: Option<AttackError>,
+        /// First fault of the image on a page of none of the next states, which
+        /// is reported after the image
+        desync: Option<AttackError>,
+        /// Number of faults of the image on a page of none of the next states
+        desyncs: usize,
+        /// First transition of the image that chose from overlapping states, which
+        /// is logged after the image
+        ambiguous: Option<Transition<JpegState>>,
     }
 
     unsafe impl Sync for GlobalState {}
     unsafe impl Send for GlobalState {}
 
     impl GlobalState {
-        pub fn new(color: bool) -> Self {
+        pub fn new(
+            enclave: EnclaveRef,
+            color: bool,
+            has_aexnotify: bool,
+            autosave: Option<Autosave>,
+        ) -> Self {
             Self {
-                state: JpegState::PreStart,
-                reconstruct: JpegReconstruct::new(if color { 3 } else { 1 }),
+                enclave,
+                machine: PageStateMachine::new(JpegState::PreStart, has_aexnotify),
+                reconstruct: JpegReconstruct::new(if color { 3 } else { 1 })
+                    .with_autosave(autosave),
                 working_set: VecDeque::new(),
                 prev_page: 0,
                 use_ocalls: false,
-                has_aexnotify: false,
+                erip_histogram: None,
+                ground_truth: None,
+                fault_error: None,
+                desync: None,
+                desyncs: 0,
+                ambiguous: None,
             }
         }
 
+        fn has_aexnotify(&self) -> bool {
+            *self.machine.config()
+        }
+
         /// Revoke access to pages from valid next states
-        pub fn protect_next_pages(&mut self) -> Result<(), AttackError> {
-            // For each state
-            self.state
-                .next_pages(self.has_aexnotify)
+        pub fn protect_next_pages(&mut self) -> Result<(), ProfilerError> {
+            // Pages is the range of pages of one of the possible next states,
+            // which are revoked using a single mprotect call
+            self.machine
+                .protect_next_pages(|pages| self.enclave.revoke(pages))
+        }
+
+        /// Restore access to the pages of all states, which may have been revoked
+        /// by any of the previous states
+        pub fn restore_all_pages(&self) -> Result<(), ProfilerError> {
+            JpegState::TRIGGERED
                 .into_iter()
-                .map(|pages| {
-                    // Pages is the range of pages of one of the possible next states.
-                    //
-                    // We can revoke them using a single mprotect call,
-                    // but the implementation is abstracted away in libsgxstep,
-                    // and could be replaced with more clever PTE hacking.
-                    let res = unsafe { revoke_pages(pages.start, pages.len()) };
-                    if res != 0 {
-                        Err(AttackError::Mprotect)
-                    } else {
-                        Ok(())
-                    }
-                })
-                .collect::<Result<Vec<()>, AttackError>>()?;
-            Ok(())
+                .map(|state| self.machine.pages(state))
+                .try_for_each(|pages| self.enclave.restore(pages))
+        }
+
+        /// Start the attack on the next image, keeping the erip histogram
+        pub fn reset(&mut self, color: bool, autosave: Option<Autosave>, bit_depth: BitDepth) {
+            self.machine.set_state(JpegState::PreStart);
+            self.reconstruct = JpegReconstruct::new(if color { 3 } else { 1 })
+                .with_autosave(autosave)
+                .with_bit_depth(bit_depth);
+            self.working_set.clear();
+            self.prev_page = 0;
+            self.desync = None;
+            self.desyncs = 0;
+            self.ambiguous = None;
+            if let Some(truth) = &mut self.ground_truth {
+                *truth = JpegReconstruct::new(if color { 3 } else { 1 }).without_progress();
+            }
         }
     }
 
-    /// Page fault handler
+    /// Page fault handler, `page` is relative to the enclave base
     extern "C" fn fault_handler(page: usize) {
         let mut global = GLOBAL_STATE.get().unwrap().lock().unwrap();
+        let Some(page) = RelativePage::checked(page, &global.enclave) else {
+            error!(
+                "page fault on page {page}, outside of the {} enclave pages",
+                global.enclave.page_count()
+            );
+            // Returning re-executes the faulting instruction, which now faults
+            // with the default action
+            let _ = unsafe { signal(Signal::SIGSEGV, SigHandler::SigDfl) };
+            return;
+        };
+        // Written to the trace outside of the handler, which must not lock it
+        MARKER_FAULTS.record(page.get());
+        let page = page.get();
 
         // Transition to the next state
-        let prev_state = global.state;
-        let new_state = global.state.next(page, global.has_aexnotify);
-        // if new_state != prev_state {
-        //     if !matches!(new_state, JpegState::DataCount(_)) {
-        //         println!("fault@{page}: {prev_state:?} -> {new_state:?}");
-        //     } else {
-        //         // println!("Data on page {page}");
-        //     }
-        // }
+        let prev_state = global.machine.state();
+        if global.machine.candidates(page).is_empty() {
+            // The state is kept, but the reconstruction may have lost track of it
+            global.desyncs += 1;
+            global.desync.get_or_insert(AttackError::StateDesync {
+                state: prev_state,
+                page,
+            });
+        }
+        let new_state = if let Some(histogram) = &global.erip_histogram {
+            let erip = unsafe { edbgrd_erip() as usize - get_enclave_base() as usize };
+            let candidates = global.machine.candidates(page);
+            let new_state = histogram
+                .disambiguate(&candidates, erip)
+                .unwrap_or(prev_state);
+            if new_state != prev_state {
+                if new_state.name() != prev_state.name() {
+                    debug!("fault@{page} erip={erip:#x}: {prev_state:?} -> {new_state:?}");
+                }
+                global
+                    .erip_histogram
+                    .as_mut()
+                    .unwrap()
+                    .record(new_state, erip);
+            }
+            new_state
+        } else {
+            let transition = global.machine.transition(page);
+            JpegState::log_transition(page, prev_state, transition.to);
+            let new_state = transition.to;
+            if transition.is_ambiguous() {
+                global.ambiguous.get_or_insert(transition);
+            }
+            new_state
+        };
 
         // Notify the reconstruction of the state transition
         global.reconstruct.reconstruct(prev_state, new_state);
-        global.state = new_state;
+        global.machine.set_state(new_state);
 
-        // Revoke access to next pages to set up state transition triggers
-        global.protect_next_pages().unwrap();
-
-        if global.has_aexnotify {
+        let aexnotify = global.has_aexnotify();
+        if aexnotify {
             global.working_set.push_back(page);
 
             // Working set of size 2
@@ -371,23 +1109,77 @@ mod sgx {
                 global.working_set.pop_front();
             }
 
-            // println!("{:?}", global.working_set);
-
-            for page in global.working_set.iter() {
-                if unsafe { restore_pages(*page, 1) } != 0 {
-                    panic!("Unable to mprotect");
-                }
-            }
+            trace!("working set: {:?}", global.working_set);
+        }
+        let GlobalState {
+            enclave,
+            machine,
+            working_set,
+            ..
+        } = &*global;
+        let result = if aexnotify {
+            update_triggers(machine, working_set.iter().copied(), enclave)
         } else {
             // Restore access to the current page
-            if unsafe { restore_pages(page, 1) } != 0 {
-                panic!("Unable to mprotect");
+            update_triggers(machine, [page], enclave)
+        };
+        if let Err(error) = result {
+            error!("{error}, stopping the attack");
+            // Let the enclave run to completion without faulting
+            if let Err(e) = global
+                .restore_all_pages()
+                .and_then(|()| global.enclave.restore(page..page + 1))
+            {
+                error!("{e}, terminating at the faulting enclave instruction");
+                let _ = unsafe { signal(Signal::SIGSEGV, SigHandler::SigDfl) };
             }
+            global.fault_error.get_or_insert(error);
         }
 
         global.prev_page = page;
     }
 
+    /// Set up the state transition triggers after a fault: revoke access to the pages
+    /// of the next states of `machine` with `protection`, and restore access to the
+    /// pages in `restored`, which the enclave needs to make progress.
+    ///
+    /// Errors name the pages whose protection could not be changed.
+    fn update_triggers(
+        machine: &PageStateMachine<JpegState>,
+        restored: impl IntoIterator<Item = usize>,
+        protection: &impl PageProtection,
+    ) -> Result<(), AttackError> {
+        machine
+            .protect_next_pages(|pages| {
+                protection
+                    .revoke(pages.clone())
+                    .map_err(|e| mprotect_error(pages, &e))
+            })
+            .and_then(|()| {
+                restored.into_iter().try_for_each(|page| {
+                    protection
+                        .restore(page..page + 1)
+                        .map_err(|e| mprotect_error(page..page + 1, &e))
+                })
+            })
+    }
+
+    /// The error of changing the protection of `pages`.
+    ///
+    /// Pages outside of the enclave get `ENOMEM`, like `mprotect` on unmapped memory.
+    fn mprotect_error(pages: Range<usize>, error: &ProfilerError) -> AttackError {
+        let errno = match error {
+            ProfilerError::Mprotect { error, .. } => error.raw_os_error().unwrap_or(0),
+            ProfilerError::PagesOutOfBounds { .. } => nix::libc::ENOMEM,
+            _ => 0,
+        };
+        AttackError::Mprotect {
+            page: pages.start,
+            len: pages.len(),
+            errno,
+        }
+    }
+
     // See `libjpeg.c` for implementation of these function.
     //
     // They are wrappers around ecalls to the libjpeg enclave.
@@ -402,14 +1194,29 @@ mod sgx {
         fn free_image(eid: sgx_enclave_id_t) -> c_int;
     }
 
+    /// The ecalls of the attack and the destruction of the enclave, which tests replace
+    struct Ecalls {
+        load_image: unsafe extern "C" fn(sgx_enclave_id_t, *const c_char, usize, usize) -> c_int,
+        decompress_image: unsafe extern "C" fn(sgx_enclave_id_t) -> c_int,
+        free_image: unsafe extern "C" fn(sgx_enclave_id_t) -> c_int,
+        destroy_enclave: unsafe extern "C" fn(sgx_enclave_id_t) -> sgx_status_t,
+    }
+
+    static ECALLS: Ecalls = Ecalls {
+        load_image,
+        decompress_image,
+        free_image,
+        destroy_enclave: sgx_destroy_enclave,
+    };
+
     #[no_mangle]
     pub extern "C" fn ocall_print_string(s: *mut c_char) {
-        println!("{}", unsafe { CString::from_raw(s) }.into_string().unwrap());
+        info!("{}", unsafe { CString::from_raw(s) }.into_string().unwrap());
     }
 
     #[no_mangle]
     pub extern "C" fn ocall_print_int(s: *mut c_char, i: c_int) {
-        println!(
+        info!(
             "{}: {}",
             unsafe { CString::from_raw(s) }.into_string().unwrap(),
             i
@@ -418,6 +1225,9 @@ mod sgx {
 
     static ZERO_COUNT: AtomicUsize = AtomicUsize::new(0);
     static SKIP_FIRST: AtomicBool = AtomicBool::new(false);
+    /// Rows and blocks of the current image so far, for the labels of the markers
+    static ROWS: AtomicUsize = AtomicUsize::new(0);
+    static BLOCKS: AtomicUsize = AtomicUsize::new(0);
 
     #[no_mangle]
     pub extern "C" fn ocall_next_row() {
@@ -425,20 +1235,28 @@ mod sgx {
             SKIP_FIRST.store(true, Ordering::Relaxed);
             return;
         }
+        mark(|| format!("row {}", ROWS.fetch_add(1, Ordering::Relaxed)));
         let mut global = GLOBAL_STATE.get().unwrap().lock().unwrap();
         if global.use_ocalls {
             global.reconstruct.next_row();
         }
+        if let Some(truth) = &mut global.ground_truth {
+            truth.next_row();
+        }
+        // The ocalls run outside of the fault handler, which only flags the autosave
+        global.reconstruct.autosave();
     }
 
     #[no_mangle]
     pub extern "C" fn ocall_idct_islow() {
+        mark(|| format!("block {}", BLOCKS.fetch_add(1, Ordering::Relaxed)));
         let mut global = GLOBAL_STATE.get().unwrap().lock().unwrap();
+        let zero_count = ZERO_COUNT.swap(0, Ordering::Relaxed);
         if global.use_ocalls {
-            global
-                .reconstruct
-                .reconstruct_block(ZERO_COUNT.load(Ordering::Relaxed));
-            ZERO_COUNT.store(0, Ordering::Relaxed);
+            global.reconstruct.reconstruct_block(zero_count);
+        }
+        if let Some(truth) = &mut global.ground_truth {
+            truth.reconstruct_block(zero_count);
         }
     }
 
@@ -447,183 +1265,1246 @@ mod sgx {
         ZERO_COUNT.fetch_add(1, Ordering::Relaxed);
     }
 
-    pub fn attack_enclave(
-        enclave: &str,
-        args: &Args,
-        input_size: u64,
-        output_size: u64,
-        use_fault_handler: bool,
-    ) -> Result<(), Box<dyn Error>> {
+    /// Create the enclave, in debug mode unless `production` is set
+    fn create_enclave(enclave: &str, production: bool) -> Result<sgx_enclave_id_t, Box<dyn Error>> {
         let mut token: sgx_launch_token_t = [0; 1024];
         let mut updated = 0;
         let mut eid: sgx_enclave_id_t = 0;
-        Ok(unsafe {
-            // Create the enclave
-            let enclave_so = CString::new(enclave)?;
-            println!(
-                "Creating enclave... result: {:x}",
-                sgx_create_enclave(
-                    enclave_so.as_ptr(),
-                    1,
-                    &mut token,
-                    &mut updated,
-                    &mut eid,
-                    null_mut(),
-                )
-            );
-
-            println!("Created enclave with eid {eid}");
-
-            register_enclave_info();
-            print_enclave_info();
+        let enclave_so = CString::new(enclave)?;
+        let result = unsafe {
+            sgx_create_enclave(
+                enclave_so.as_ptr(),
+                if production { 0 } else { 1 },
+                &mut token,
+                &mut updated,
+                &mut eid,
+                null_mut(),
+            )
+        };
+        debug!("Creating enclave... result: {:x}", result);
+        if result != _status_t_SGX_SUCCESS {
+            return Err(AttackError::EnclaveCreate(result).into());
+        }
 
-            // Initialize global state
-            let mut data = GlobalState::new(args.color);
-            dbg!(get_enclave_ssa_gprsgx_adrs());
+        debug!("Created enclave with eid {eid}");
+        Ok(eid)
+    }
 
-            // Load the libjpeg image into the enclave
-            let input = CString::new(args.image.as_str())?;
-            assert!(
-                load_image(
-                    eid,
-                    input.as_ptr(),
-                    input_size as usize,
-                    output_size as usize
-                ) == 0
-            );
+    /// Tears down the attack on an enclave when dropped, also on early returns with
+    /// an error.
+    ///
+    /// Unregisters the fault handler, restores the pages revoked by the state machine,
+    /// destroys the enclave and writes the last step of the `--marker-trace`, so a
+    /// failed attack leaves nothing behind.
+    struct EnclaveGuard {
+        eid: sgx_enclave_id_t,
+        ecalls: &'static Ecalls,
+        /// Whether the fault handler was registered
+        fault_handler: bool,
+    }
 
-            if use_fault_handler {
-                // Register a page fault handler
-                register_fault_handler(Some(fault_handler));
-                data.protect_next_pages().unwrap();
-            } else {
-                data.use_ocalls = true;
+    impl EnclaveGuard {
+        fn new(eid: sgx_enclave_id_t) -> Self {
+            Self {
+                eid,
+                ecalls: &ECALLS,
+                fault_handler: false,
             }
-            data.has_aexnotify = args.aexnotify;
+        }
+    }
 
-            GLOBAL_STATE.set(Mutex::new(data)).unwrap();
+    impl Drop for EnclaveGuard {
+        fn drop(&mut self) {
+            if self.fault_handler {
+                unsafe { register_fault_handler(None) };
+            }
+            if let Some(global) = GLOBAL_STATE.get() {
+                // A panic in the fault handler poisons the state, which is still intact
+                let global = global.lock().unwrap_or_else(PoisonError::into_inner);
+                if let Err(e) = global.restore_all_pages() {
+                    warn!("failed to restore the enclave pages: {e}");
+                }
+            }
+            debug!("Destroying enclave with eid {}", self.eid);
+            unsafe { (self.ecalls.destroy_enclave)(self.eid) };
+            MARKER_FAULTS.enable(false);
+            if let Some(mut trace) = MARKER_TRACE
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take()
+            {
+                write_marker_faults(&mut trace);
+            }
+        }
+    }
 
-            // Call vulnerable decompression code
-            assert!(decompress_image(eid) == 0);
+    /// Fail if the ecall wrapper `name` returned a nonzero `status`
+    fn check_ecall(name: &'static str, status: c_int) -> Result<(), AttackError> {
+        match status {
+            0 => Ok(()),
+            status => Err(AttackError::Ecall(name, status)),
+        }
+    }
 
-            // Free the image
-            assert!(free_image(eid) == 0);
+    /// Create the enclave and check that the page ranges of the state machine fit in it
+    pub fn check_enclave(enclave: &str, args: &Args) -> Result<(), Box<dyn Error>> {
+        let problems = match create_enclave(enclave, args.production) {
+            Ok(eid) => {
+                let guard = EnclaveGuard::new(eid);
+                let enclave = unsafe { EnclaveRef::from_raw(EnclaveId::SGX(eid)) };
+                let num_pages = enclave.size() as usize / PAGE_SIZE_4KiB as usize;
+                let mut problems = JpegState::check_pages(num_pages, args.aexnotify);
+                problems.extend(check_idct(&enclave, args.aexnotify));
+                drop(guard);
+                problems
+            }
+            Err(e) => vec![e.to_string()],
+        };
+        Ok(check::report(&problems)?)
+    }
 
-            // Destroy the enclave
-            sgx_destroy_enclave(eid);
+    /// Function whose code is on the pages of `JpegState::IdctSlow`
+    const IDCT_SYMBOL: &str = "jpeg_idct_islow";
+
+    /// Check that the enclave has the IDCT function on the pages of `JpegState::IdctSlow`
+    fn check_idct(enclave: &EnclaveRef, aexnotify: bool) -> Option<String> {
+        let Some(address) = enclave
+            .symbol_addresses(&[IDCT_SYMBOL])
+            .ok()
+            .and_then(|addresses| addresses[0])
+        else {
+            return Some(AttackError::SymbolMissing(IDCT_SYMBOL.to_string()).to_string());
+        };
+        let page = (address as usize - enclave.base() as usize) / PAGE_SIZE_4KiB as usize;
+        let pages = JpegState::IdctSlow.pages(&aexnotify);
+        (!pages.contains(&page)).then(|| {
+            format!("{IDCT_SYMBOL} is on page {page}, outside of the pages {pages:?} of IdctSlow")
+        })
+    }
 
-            // Save the reconstructed image
-            let data = GLOBAL_STATE.get().unwrap().lock().unwrap();
-            args.raw_output.as_ref().map(|o| {
-                std::fs::write(
-                    o,
-                    serde_json::to_string_pretty(data.reconstruct.raw_reconstruction()).unwrap(),
-                )
-            });
-            let image = data.reconstruct.reconstructed_bitmap();
-            args.output.as_ref().map(|o| image.save(o).unwrap());
+    /// Attack every target in turn, within a single enclave.
+    ///
+    /// The enclave is destroyed when the attack fails, see [`EnclaveGuard`].
+    pub fn attack_enclave(
+        enclave: &str,
+        args: &Args,
+        targets: &[Target],
+        use_fault_handler: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let eid = create_enclave(enclave, args.production)?;
+        let mut guard = EnclaveGuard::new(eid);
+        unsafe {
+            let enclave = EnclaveRef::from_raw(EnclaveId::SGX(eid));
+            if log_enabled!(Level::Debug) {
+                print_enclave_info();
+            }
+            match enclave.ssa_gprsgx_address() {
+                Ok(address) => debug!("SSA GPRSGX at {address:#x}"),
+                Err(e) => debug!("{e}"),
+            }
 
-            // print_enclave_info();
-        })
+            if let Some(path) = &args.marker_trace {
+                let config = DumperConfig {
+                    markers: true,
+                    ..Default::default()
+                };
+                *MARKER_TRACE.lock().unwrap() =
+                    Some(VCDDumper::with_config(path, enclave.page_count(), config)?);
+                MARKER_FAULTS.enable(true);
+            }
+
+            // Initialize global state
+            let mut data = GlobalState::new(enclave, args.color, args.aexnotify, None);
+
+            if use_fault_handler {
+                // Register a page fault handler
+                register_fault_handler(Some(fault_handler));
+                guard.fault_handler = true;
+            } else {
+                data.use_ocalls = true;
+            }
+            if args.erip_assist {
+                data.erip_histogram = Some(EripHistogram::default());
+            }
+            if use_fault_handler && (args.verify || args.golden_output.is_some()) {
+                // The ocalls are made in both modes, so the ground truth is
+                // reconstructed in the same run as the page fault attack
+                data.ground_truth =
+                    Some(JpegReconstruct::new(if args.color { 3 } else { 1 }).without_progress());
+            }
+
+            GLOBAL_STATE.set(Mutex::new(data)).unwrap();
+        }
+
+        // Destroys the enclave and writes the last step of the marker trace when done
+        attack_targets(guard, args, targets, use_fault_handler)?;
+
+        let data = GLOBAL_STATE.get().unwrap().lock().unwrap();
+        if let Some(histogram) = &data.erip_histogram {
+            let json = serde_json::to_string_pretty(&histogram.to_json())?;
+            match &args.erip_histogram {
+                Some(o) => std::fs::write(o, json)?,
+                None => println!("{json}"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Attack every target in the enclave of `guard`, which is destroyed on return
+    fn attack_targets(
+        guard: EnclaveGuard,
+        args: &Args,
+        targets: &[Target],
+        use_fault_handler: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let eid = guard.eid;
+        let ecalls = guard.ecalls;
+        unsafe {
+            for target in targets {
+                if targets.len() > 1 {
+                    info!("Attacking {}", target.image);
+                }
+                PROGRESS_BAR.get().unwrap().reset(target.num_blocks);
+                SKIP_FIRST.store(false, Ordering::Relaxed);
+                ZERO_COUNT.store(0, Ordering::Relaxed);
+                ROWS.store(0, Ordering::Relaxed);
+                BLOCKS.store(0, Ordering::Relaxed);
+                mark(|| format!("image {}", target.image));
+
+                // Load the libjpeg image into the enclave, with buffers sized for this image
+                let input = CString::new(target.image.as_str())?;
+                if (ecalls.load_image)(
+                    eid,
+                    input.as_ptr(),
+                    target.input_size as usize,
+                    target.output_size as usize,
+                ) != 0
+                {
+                    return Err(AttackError::ImageLoad.into());
+                }
+
+                {
+                    let mut data = GLOBAL_STATE.get().unwrap().lock().unwrap();
+                    data.reset(
+                        args.color,
+                        target.autosave(args.autosave_interval),
+                        args.bit_depth,
+                    );
+                    if use_fault_handler {
+                        data.protect_next_pages()?;
+                    }
+                }
+
+                // Call vulnerable decompression code
+                check_ecall("decompress_image", (ecalls.decompress_image)(eid))?;
+
+                let mut data = GLOBAL_STATE.get().unwrap().lock().unwrap();
+                if let Some(error) = data.fault_error.take() {
+                    return Err(error.into());
+                }
+                if let Some(transition) = data.ambiguous.take() {
+                    JpegState::log_candidates(&transition);
+                }
+                if let Some(desync) = data.desync.take() {
+                    warn!("{} unexpected page fault(s), first: {desync}", data.desyncs);
+                }
+                if use_fault_handler {
+                    // Don't interpret the faults of the next ecalls as state transitions
+                    data.restore_all_pages()?;
+                }
+
+                // Free the image
+                check_ecall("free_image", (ecalls.free_image)(eid))?;
+
+                // Save the reconstructed image
+                PROGRESS_BAR.get().unwrap().flush();
+                data.reconstruct
+                    .save(target.output.as_deref(), target.raw_output.as_deref())?;
+                info!(
+                    "reconstructed {} blocks of {}",
+                    data.reconstruct.blocks, target.image
+                );
+
+                if let Some(path) = &target.golden_output {
+                    // The ocalls give the true counts, reconstructed alongside the page
+                    // fault attack in `enclave` mode
+                    let truth = data.ground_truth.as_ref().unwrap_or(&data.reconstruct);
+                    truth.save(None, Some(path))?;
+                }
+                target.score(&data.reconstruct)?;
+
+                if let Some(truth) = data.ground_truth.as_ref().filter(|_| args.verify) {
+                    let error = ReconstructionError::new(&data.reconstruct, truth);
+                    error.print(&target.image, "the ocall reconstruction");
+                    if let Some(output) = &args.verify_output {
+                        let output = match targets.len() {
+                            1 => output.clone(),
+                            _ => batch_output(output, &target.image),
+                        };
+                        error.bitmap().save(output)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::io;
+
+        /// Page protection that fails to change the protection of `failing`
+        struct FailingProtection {
+            failing: usize,
+        }
+
+        impl FailingProtection {
+            fn change(&self, pages: Range<usize>) -> Result<(), ProfilerError> {
+                if pages.contains(&self.failing) {
+                    return Err(ProfilerError::Mprotect {
+                        pages,
+                        error: io::Error::from_raw_os_error(nix::libc::EACCES),
+                    });
+                }
+                Ok(())
+            }
+        }
+
+        impl PageProtection for FailingProtection {
+            fn revoke(&self, pages: Range<usize>) -> Result<(), ProfilerError> {
+                self.change(pages)
+            }
+
+            fn restore(&self, pages: Range<usize>) -> Result<(), ProfilerError> {
+                self.change(pages)
+            }
+        }
+
+        #[test]
+        fn failing_mprotect_reports_the_faulting_page() {
+            let mut machine = PageStateMachine::new(JpegState::PreStart, false);
+            let page = machine.pages(JpegState::Start).start;
+            machine.set_state(machine.next(page));
+            let next = machine.next_pages()[0].start;
+
+            // Restoring the faulting page fails
+            let error = update_triggers(&machine, [page], &FailingProtection { failing: page })
+                .unwrap_err();
+            assert!(matches!(
+                error,
+                AttackError::Mprotect { page: p, len: 1, errno: nix::libc::EACCES } if p == page
+            ));
+            assert!(error
+                .to_string()
+                .contains(&format!("1 page(s) starting at page {page}: ")));
+
+            // Revoking the pages of a next state fails, which is not the faulting page
+            let error = update_triggers(&machine, [page], &FailingProtection { failing: next })
+                .unwrap_err();
+            let pages = machine.next_pages()[0].clone();
+            assert!(matches!(
+                error,
+                AttackError::Mprotect { page, len, errno: nix::libc::EACCES }
+                    if page == pages.start && len == pages.len()
+            ));
+
+            assert!(update_triggers(
+                &machine,
+                [page],
+                &FailingProtection {
+                    failing: usize::MAX
+                }
+            )
+            .is_ok());
+        }
+
+        #[test]
+        fn reset_starts_the_next_image_from_scratch() {
+            let enclave = EnclaveRef::from_external(1, 0x7000_0000, 8192 * 4096);
+            let mut global = GlobalState::new(enclave, false, false, None);
+            global.erip_histogram = Some(EripHistogram::default());
+            global.reconstruct = global.reconstruct.clone().without_progress();
+
+            // The first image ends in the middle of a block
+            global.machine.set_state(JpegState::DataCount(3));
+            global
+                .reconstruct
+                .reconstruct(JpegState::DataCount(3), JpegState::PreIdctSlow);
+            global.working_set.push_back(150);
+            global
+                .erip_histogram
+                .as_mut()
+                .unwrap()
+                .record(JpegState::Start, 0x1000);
+
+            global.reset(true, None, BitDepth::Sixteen);
+            assert_eq!(global.machine.state(), JpegState::PreStart);
+            assert_eq!(global.reconstruct.blocks, 0);
+            assert_eq!(global.reconstruct.raw_reconstruction().len(), 3);
+            assert_eq!(global.reconstruct.bit_depth, BitDepth::Sixteen);
+            assert!(global.working_set.is_empty());
+            // The histogram learned from the first image still helps with the next one
+            assert_eq!(
+                global
+                    .erip_histogram
+                    .as_ref()
+                    .unwrap()
+                    .disambiguate(&[JpegState::NextRow, JpegState::Start], 0x1008),
+                Some(JpegState::Start)
+            );
+        }
+
+        #[test]
+        fn fault_log_keeps_the_order_and_counts_dropped_faults() {
+            let log = FaultLog::<3>::new();
+            log.record(1);
+            log.enable(true);
+            for page in [5, 3, 4, 6, 7] {
+                log.record(page);
+            }
+            let mut pages = vec![];
+            assert_eq!(log.drain(|page| pages.push(page)), 2);
+            assert_eq!(pages, [5, 3, 4]);
+
+            // Draining frees the log for the next faults
+            log.record(8);
+            pages.clear();
+            assert_eq!(log.drain(|page| pages.push(page)), 0);
+            assert_eq!(pages, [8]);
+        }
+
+        /// Enclave destroyed by `record_destroy`
+        static DESTROYED: AtomicU64 = AtomicU64::new(0);
+
+        unsafe extern "C" fn failing_load(
+            _eid: sgx_enclave_id_t,
+            _input: *const c_char,
+            _input_size: usize,
+            _output_size: usize,
+        ) -> c_int {
+            1
+        }
+
+        unsafe extern "C" fn succeeding_ecall(_eid: sgx_enclave_id_t) -> c_int {
+            0
+        }
+
+        unsafe extern "C" fn record_destroy(eid: sgx_enclave_id_t) -> sgx_status_t {
+            DESTROYED.store(eid, Ordering::SeqCst);
+            _status_t_SGX_SUCCESS
+        }
+
+        /// Ecalls of an enclave that fails to load the image
+        static FAILING_LOAD: Ecalls = Ecalls {
+            load_image: failing_load,
+            decompress_image: succeeding_ecall,
+            free_image: succeeding_ecall,
+            destroy_enclave: record_destroy,
+        };
+
+        #[test]
+        fn failed_attack_destroys_the_enclave() {
+            PROGRESS_BAR.get_or_init(|| BatchedProgress::new(ProgressBar::hidden(), 1));
+            let args = Args::try_parse_from([
+                "libjpeg_attack",
+                "--image",
+                "a.jpg",
+                "enclave",
+                "--enclave",
+                "enclave.so",
+            ])
+            .unwrap();
+            let target = Target {
+                image: "a.jpg".into(),
+                output: None,
+                raw_output: None,
+                golden: None,
+                golden_output: None,
+                input_size: 100,
+                output_size: 8 * 8 * 3 + 100,
+                num_blocks: 4,
+            };
+            let guard = EnclaveGuard {
+                eid: 42,
+                ecalls: &FAILING_LOAD,
+                fault_handler: false,
+            };
+
+            let error = attack_targets(guard, &args, &[target], true).unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<AttackError>(),
+                Some(AttackError::ImageLoad)
+            ));
+            assert_eq!(DESTROYED.load(Ordering::SeqCst), 42);
+        }
     }
 }
 
 mod trace {
     use super::*;
-    use vcd::{Command, IdCode};
+    use sgx_profiler_core::trace::{is_trace, TraceReader};
+    use std::{
+        fmt::Write,
+        io::BufRead,
+        thread::sleep,
+        time::{Duration, Instant},
+    };
+    use vcd::{Command, Header, IdCode};
 
-    pub fn attack_vcd(vcd: &str, args: &Args) -> Result<(), Box<dyn Error>> {
-        let mut reader = vcd::Parser::new(BufReader::new(File::open(vcd)?));
-        let header = reader.parse_header()?;
+    /// Limits the number of trace steps replayed per second, see `--replay-sps`
+    struct Throttle {
+        /// Minimum time between steps, steps are not delayed if `None`
+        interval: Option<Duration>,
+        next: Instant,
+    }
 
-        // Create a mapping between VCD id codes and page numbers
-        let vars: HashMap<IdCode, u64> = (0..9999)
+    impl Throttle {
+        fn new(steps_per_second: Option<f64>) -> Result<Self, Box<dyn Error>> {
+            let interval = steps_per_second
+                .map(|sps| {
+                    if sps > 0.0 {
+                        Duration::try_from_secs_f64(sps.recip()).map_err(|e| e.to_string())
+                    } else {
+                        Err(format!("--replay-sps must be positive, got {sps}"))
+                    }
+                })
+                .transpose()?;
+            Ok(Self {
+                interval,
+                next: Instant::now(),
+            })
+        }
+
+        /// Wait until the next step may be replayed.
+        ///
+        /// A replay that falls behind does not catch up with a burst of steps, the
+        /// rate is measured from the last step instead.
+        fn step(&mut self) {
+            let Some(interval) = self.interval else {
+                return;
+            };
+            let now = Instant::now();
+            if now < self.next {
+                sleep(self.next - now);
+                self.next += interval;
+            } else {
+                self.next = now + interval;
+            }
+        }
+    }
+
+    /// Create a mapping between VCD id codes and page numbers
+    fn page_vars(header: &Header) -> HashMap<IdCode, u64> {
+        (0..9999)
             .filter_map(|page| {
                 header
-                    .find_var(&["trace", &format!("_{}", page.to_string())])
+                    .find_var(&["trace", &format!("_{page}")])
                     .map(|p| (p.code, page))
             })
-            .collect();
+            .collect()
+    }
 
-        // Initialize state and reconstruction
-        let mut state = JpegState::PreStart;
-        let mut reconstruct = JpegReconstruct::new(if args.color { 3 } else { 1 });
+    /// Width of the counter wires of a trace recorded with the `CountSet` of the profiler
+    const COUNT_WIDTH: u32 = 16;
+
+    /// Whether the page wires of the trace hold access counts rather than booleans
+    fn has_page_counts(header: &Header) -> bool {
+        (0..9999)
+            .find_map(|page| header.find_var(&["trace", &format!("_{page}")]))
+            .is_some_and(|var| var.size == COUNT_WIDTH)
+    }
+
+    /// Replay the accesses of a step of a trace with page access counts.
+    ///
+    /// The state machine advances once for every accessed page, but the data count
+    /// of a block is the sum of the counts of its data page accesses, instead of the
+    /// number of accesses the state machine observed.
+    fn replay_counted_step(
+        counts: &BTreeMap<u64, u64>,
+        machine: &mut PageStateMachine<JpegState>,
+        reconstruct: &mut JpegReconstruct,
+        data_count: &mut usize,
+    ) {
+        for (&page, &count) in counts {
+            let page = page as usize;
+            let transition = machine.transition(page);
+            JpegState::log_candidates(&transition);
+            let (prev_state, new_state) = (transition.from, transition.to);
+            JpegState::log_transition(page, prev_state, new_state);
+            if matches!(new_state, JpegState::DataCount(_)) {
+                if !matches!(prev_state, JpegState::DataCount(_)) {
+                    *data_count = 0;
+                }
+                *data_count += count as usize;
+            }
+            reconstruct.reconstruct_counted(prev_state, new_state, *data_count);
+            reconstruct.autosave();
+        }
+    }
+
+    /// Replay a trace with page access counts.
+    ///
+    /// A count keeps its value until it changes, so a page that is accessed in
+    /// consecutive steps is replayed at every timestamp, not only when it changes.
+    fn replay_counts(
+        reader: &mut vcd::Parser<BufReader<File>>,
+        vars: &HashMap<IdCode, u64>,
+        machine: &mut PageStateMachine<JpegState>,
+        reconstruct: &mut JpegReconstruct,
+        throttle: &mut Throttle,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut counts = BTreeMap::new();
+        let mut data_count = 0;
+        let mut changed = false;
+        while let Some(command) = reader.next().transpose()? {
+            match command {
+                Command::Timestamp(_) => {
+                    throttle.step();
+                    replay_counted_step(&counts, machine, reconstruct, &mut data_count);
+                    changed = false;
+                }
+                Command::ChangeVector(i, v) => {
+                    if let Some(&page) = vars.get(&i) {
+                        let count = v
+                            .iter()
+                            .fold(0, |n, b| n << 1 | (b == vcd::Value::V1) as u64);
+                        if count == 0 {
+                            counts.remove(&page);
+                        } else {
+                            counts.insert(page, count);
+                        }
+                        changed = true;
+                    }
+                }
+                _ => {}
+            }
+        }
+        // The last step is only followed by a timestamp if it was complete
+        if changed {
+            throttle.step();
+            replay_counted_step(&counts, machine, reconstruct, &mut data_count);
+        }
+        Ok(())
+    }
 
-        // Iterate over all VCD commands and simulate the attack
+    /// Replay a record trace, in which every step holds the accessed pages and,
+    /// if the trace has counts, the number of accesses to each of them.
+    fn replay_records(
+        reader: TraceReader<BufReader<File>>,
+        machine: &mut PageStateMachine<JpegState>,
+        reconstruct: &mut JpegReconstruct,
+        throttle: &mut Throttle,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut data_count = 0;
+        for record in reader {
+            throttle.step();
+            let counts = record?
+                .pages
+                .iter()
+                .map(|p| (p.page as u64, p.count as u64))
+                .collect::<BTreeMap<_, _>>();
+            replay_counted_step(&counts, machine, reconstruct, &mut data_count);
+        }
+        Ok(())
+    }
+
+    /// Open a trace, returning whether it is a record trace rather than a VCD trace
+    fn open_trace(path: &str) -> Result<(BufReader<File>, bool), Box<dyn Error>> {
+        let mut file = BufReader::new(File::open(path)?);
+        let records = is_trace(file.fill_buf()?);
+        Ok((file, records))
+    }
+
+    /// Read the set of accessed pages at every timestamp of a VCD trace
+    fn read_steps(vcd: impl BufRead) -> Result<BTreeMap<u64, BTreeSet<u64>>, Box<dyn Error>> {
+        let mut reader = vcd::Parser::new(vcd);
+        let vars = page_vars(&reader.parse_header()?);
+
+        let mut steps = BTreeMap::new();
+        let mut timestamp = 0;
+        let mut accessed = BTreeSet::new();
         while let Some(command) = reader.next().transpose()? {
             match command {
+                Command::Timestamp(t) => {
+                    steps.insert(timestamp, accessed.clone());
+                    timestamp = t;
+                }
                 Command::ChangeScalar(i, v) => {
-                    if v == vcd::Value::V1 {
+                    if let Some(&page) = vars.get(&i) {
+                        if v == vcd::Value::V1 {
+                            accessed.insert(page);
+                        } else {
+                            accessed.remove(&page);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        steps.insert(timestamp, accessed);
+        Ok(steps)
+    }
+
+    /// The accessed pages at timestamp `t`, or `None` if the trace ended before `t`
+    fn state_at(steps: &BTreeMap<u64, BTreeSet<u64>>, t: u64) -> Option<&BTreeSet<u64>> {
+        let (&last, _) = steps.last_key_value()?;
+        if t > last {
+            return None;
+        }
+        steps.range(..=t).next_back().map(|(_, s)| s)
+    }
+
+    /// Difference of two traces, see [`diff_steps`]
+    #[derive(Debug, PartialEq)]
+    struct StepDiff {
+        /// Number of timestamps of either trace
+        steps: usize,
+        /// Number of timestamps at which the accessed pages differ
+        divergent: usize,
+        /// Timestamp of the first difference, the pages only accessed in the first and
+        /// in the second trace, and which trace ended, if any
+        first: Option<(u64, Vec<u64>, Vec<u64>, &'static str)>,
+        /// One line per divergent timestamp
+        lines: String,
+    }
+
+    /// Compare the accessed pages of two traces read with `read_steps` at every
+    /// timestamp of either trace.
+    ///
+    /// Pages keep their value until the next timestamp of their trace, and a trace
+    /// that ended before a timestamp differs from any trace that did not.
+    fn diff_steps(
+        steps_a: &BTreeMap<u64, BTreeSet<u64>>,
+        steps_b: &BTreeMap<u64, BTreeSet<u64>>,
+    ) -> StepDiff {
+        let empty = BTreeSet::new();
+
+        let timestamps = steps_a
+            .keys()
+            .chain(steps_b.keys())
+            .copied()
+            .collect::<BTreeSet<_>>();
+        let mut divergent = 0;
+        let mut first = None;
+        let mut lines = String::new();
+        for &t in &timestamps {
+            let (state_a, state_b) = (state_at(steps_a, t), state_at(steps_b, t));
+            if state_a == state_b {
+                continue;
+            }
+            let only_a = state_a
+                .unwrap_or(&empty)
+                .difference(state_b.unwrap_or(&empty));
+            let only_b = state_b
+                .unwrap_or(&empty)
+                .difference(state_a.unwrap_or(&empty));
+            let (only_a, only_b) = (only_a.collect::<Vec<_>>(), only_b.collect::<Vec<_>>());
+
+            divergent += 1;
+            let ended = match (state_a, state_b) {
+                (None, _) => " (first trace ended)",
+                (_, None) => " (second trace ended)",
+                _ => "",
+            };
+            // Writing to a `String` can't fail
+            let _ = writeln!(lines, "#{t}: -{only_a:?} +{only_b:?}{ended}");
+            first.get_or_insert((
+                t,
+                only_a.into_iter().copied().collect(),
+                only_b.into_iter().copied().collect(),
+                ended,
+            ));
+        }
+        StepDiff {
+            steps: timestamps.len(),
+            divergent,
+            first,
+            lines,
+        }
+    }
+
+    /// Compare two VCD traces at every timestamp of either trace, see [`diff_steps`]
+    pub fn diff_vcd(a: &str, b: &str, output: Option<&str>) -> Result<(), Box<dyn Error>> {
+        let read = |vcd| read_steps(BufReader::new(File::open(vcd)?));
+        let (steps_a, steps_b) = (read(a)?, read(b)?);
+        let diff = diff_steps(&steps_a, &steps_b);
+
+        println!(
+            "{} of {} steps differ ({a}: {} steps, {b}: {} steps)",
+            diff.divergent,
+            diff.steps,
+            steps_a.len(),
+            steps_b.len()
+        );
+        if let Some((t, only_a, only_b, ended)) = &diff.first {
+            println!("first difference at #{t}{ended}");
+            println!("  only in {a}: {only_a:?}");
+            println!("  only in {b}: {only_b:?}");
+        }
+        if let Some(output) = output {
+            std::fs::write(output, diff.lines)?;
+        }
+        Ok(())
+    }
+
+    /// Check that the trace covers every page of the state machine
+    pub fn check_vcd(vcd: &str, args: &Args) -> Result<(), Box<dyn Error>> {
+        let num_pages = open_trace(vcd).and_then(|(file, records)| {
+            if records {
+                return Ok(TraceReader::new(file)?.header().num_pages);
+            }
+            let header = vcd::Parser::new(file).parse_header()?;
+            Ok((0..9999)
+                .take_while(|page| header.find_var(&["trace", &format!("_{page}")]).is_some())
+                .count())
+        });
+        let problems = match num_pages {
+            Ok(num_pages) => JpegState::check_pages(num_pages, args.aexnotify),
+            Err(e) => vec![format!("failed to read {vcd}: {e}")],
+        };
+        Ok(check::report(&problems)?)
+    }
+
+    /// Simulate the attack on a VCD trace or a record trace written with `--records`,
+    /// replaying at most `replay_sps` steps per second if given
+    pub fn attack_vcd(
+        vcd: &str,
+        replay_sps: Option<f64>,
+        args: &Args,
+        target: &Target,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut throttle = Throttle::new(replay_sps)?;
+        let (file, records) = open_trace(vcd)?;
+
+        // Initialize state and reconstruction
+        PROGRESS_BAR.get().unwrap().reset(target.num_blocks);
+        let mut machine = PageStateMachine::new(JpegState::PreStart, args.aexnotify);
+        let mut reconstruct = JpegReconstruct::new(if args.color { 3 } else { 1 })
+            .with_autosave(target.autosave(args.autosave_interval))
+            .with_bit_depth(args.bit_depth);
+
+        if records {
+            let reader = TraceReader::new(file)?;
+            if reader.header().has_counts {
+                info!("trace has page access counts, counting data accesses directly");
+            }
+            replay_records(reader, &mut machine, &mut reconstruct, &mut throttle)?;
+        } else {
+            attack_vcd_trace(
+                vcd::Parser::new(file),
+                &mut machine,
+                &mut reconstruct,
+                &mut throttle,
+            )?;
+        }
+
+        // Save the reconstructed image
+        reconstruct.save(target.output.as_deref(), target.raw_output.as_deref())?;
+        info!(
+            "reconstructed {} blocks of {}",
+            reconstruct.blocks, target.image
+        );
+        target.score(&reconstruct)?;
+        Ok(())
+    }
+
+    /// Replay a VCD trace, with page access counts if it has them
+    fn attack_vcd_trace(
+        mut reader: vcd::Parser<BufReader<File>>,
+        machine: &mut PageStateMachine<JpegState>,
+        reconstruct: &mut JpegReconstruct,
+        throttle: &mut Throttle,
+    ) -> Result<(), Box<dyn Error>> {
+        let header = reader.parse_header()?;
+
+        // Create a mapping between VCD id codes and page numbers
+        let vars = page_vars(&header);
+
+        if has_page_counts(&header) {
+            info!("trace has page access counts, counting data accesses directly");
+            replay_counts(&mut reader, &vars, machine, reconstruct, throttle)?;
+        } else {
+            // Iterate over all VCD commands and simulate the attack
+            while let Some(command) = reader.next().transpose()? {
+                match command {
+                    Command::Timestamp(_) => throttle.step(),
+                    Command::ChangeScalar(i, vcd::Value::V1) => {
                         if let Some(page) = vars.get(&i) {
                             let page = *page as usize;
-                            // println!("access to page {page}");
-                            let prev_state = state;
-                            let new_state = state.next(page, args.aexnotify);
-                            // if new_state != prev_state {
-                            //     if !matches!(new_state, JpegState::DataCount(_)) {
-                            //         println!("{prev_state:?} -> {new_state:?}");
-                            //     } else {
-                            //         println!("Data on page {page}");
-                            //     }
-                            // }
+                            trace!("access to page {page}");
+                            let transition = machine.transition(page);
+                            JpegState::log_candidates(&transition);
+                            let (prev_state, new_state) = (transition.from, transition.to);
+                            JpegState::log_transition(page, prev_state, new_state);
                             reconstruct.reconstruct(prev_state, new_state);
-                            // if new_state != state {
-                            //     println!("{state:?} -> {new_state:?}");
-                            // }
-                            state = new_state;
+                            reconstruct.autosave();
                         }
                     }
+                    _ => {}
                 }
-                _ => {}
             }
         }
+        Ok(())
+    }
 
-        // Save the reconstructed image
-        args.raw_output.as_ref().map(|o| {
-            std::fs::write(
-                o,
-                serde_json::to_string_pretty(reconstruct.raw_reconstruction()).unwrap(),
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        /// Steps of a run over two rows with the accessed pages and their access counts.
+        /// The data page of the first block is accessed 3 times in a single step.
+        const STEPS: [&[(u64, u64)]; 12] = [
+            &[(54, 1)],
+            &[(58, 1)],
+            &[(63, 1)],
+            &[(150, 3)],
+            &[(59, 1)],
+            &[(63, 1)],
+            &[(160, 1)],
+            &[(44, 1)],
+            &[(58, 1)],
+            &[(63, 1)],
+            &[(170, 2)],
+            &[(44, 1)],
+        ];
+
+        /// Write a VCD trace of `steps` to `path`, with a wire per page like the profiler.
+        /// The wires hold the access counts if `counts` is set, and booleans otherwise.
+        fn write_trace(path: &Path, steps: &[&[(u64, u64)]], counts: bool) {
+            let mut writer = vcd::Writer::new(File::create(path).unwrap());
+            writer.add_module("trace").unwrap();
+            let width = if counts { COUNT_WIDTH } else { 1 };
+            let wires = (0..200)
+                .map(|page| writer.add_wire(width, &format!("_{page}")).unwrap())
+                .collect::<Vec<_>>();
+            writer.upscope().unwrap();
+            writer.enddefinitions().unwrap();
+
+            let mut previous: &[(u64, u64)] = &[];
+            for (t, step) in steps.iter().enumerate() {
+                writer.timestamp(t as u64).unwrap();
+                let changes = previous
+                    .iter()
+                    .map(|&(page, _)| (page, 0))
+                    .chain(step.iter().copied());
+                for (page, count) in changes {
+                    let wire = wires[page as usize];
+                    if counts {
+                        let bits = (0..COUNT_WIDTH).rev().map(|n| (count >> n & 1 == 1).into());
+                        writer.change_vector(wire, bits).unwrap();
+                    } else {
+                        writer.change_scalar(wire, count > 0).unwrap();
+                    }
+                }
+                previous = step;
+            }
+        }
+
+        /// Reconstruct the image from the VCD trace at `path`
+        fn reconstruct(path: &Path) -> Vec<Vec<Vec<usize>>> {
+            let mut machine = PageStateMachine::new(JpegState::PreStart, false);
+            let mut reconstruct = JpegReconstruct::new(1).without_progress();
+            attack_vcd_trace(
+                vcd::Parser::new(BufReader::new(File::open(path).unwrap())),
+                &mut machine,
+                &mut reconstruct,
+                &mut Throttle::new(None).unwrap(),
             )
-        });
-        let image = reconstruct.reconstructed_bitmap();
-        args.output.as_ref().map(|o| image.save(o).unwrap());
-        Ok(())
+            .unwrap();
+            reconstruct.raw_reconstruction().clone()
+        }
+
+        #[test]
+        fn counted_and_boolean_traces_agree_on_single_accesses() {
+            let dir = tempfile::tempdir().unwrap();
+            // Every page accessed once per step
+            let single = STEPS.map(|step| [(step[0].0, 1)]);
+            let single = single.iter().map(|step| &step[..]).collect::<Vec<_>>();
+            let (boolean, counted) = (dir.path().join("bool.vcd"), dir.path().join("counts.vcd"));
+            write_trace(&boolean, &single, false);
+            write_trace(&counted, &single, true);
+
+            assert_eq!(reconstruct(&boolean), [vec![vec![1, 1], vec![1]]]);
+            assert_eq!(reconstruct(&counted), reconstruct(&boolean));
+        }
+
+        #[test]
+        fn counted_trace_includes_repeated_accesses_in_a_step() {
+            let dir = tempfile::tempdir().unwrap();
+            let (boolean, counted) = (dir.path().join("bool.vcd"), dir.path().join("counts.vcd"));
+            write_trace(&boolean, &STEPS, false);
+            write_trace(&counted, &STEPS, true);
+
+            // A boolean wire only tells that the data page was accessed in a step
+            assert_eq!(reconstruct(&boolean), [vec![vec![1, 1], vec![1]]]);
+            assert_eq!(reconstruct(&counted), [vec![vec![3, 1], vec![2]]]);
+        }
+
+        #[test]
+        fn throttle_limits_the_step_rate() {
+            let interval = Duration::from_millis(10);
+            let mut throttle = Throttle::new(Some(100.0)).unwrap();
+            let start = Instant::now();
+            for _ in 0..11 {
+                throttle.step();
+            }
+            // Only the first step is not delayed
+            assert!(start.elapsed() >= 10 * interval);
+
+            // A replay that fell behind is not allowed a burst of steps
+            sleep(5 * interval);
+            throttle.step();
+            let caught_up = Instant::now();
+            throttle.step();
+            assert!(caught_up.elapsed() >= interval);
+        }
+
+        #[test]
+        fn unthrottled_steps_are_not_delayed() {
+            let mut throttle = Throttle::new(None).unwrap();
+            let start = Instant::now();
+            for _ in 0..1000 {
+                throttle.step();
+            }
+            assert!(start.elapsed() < Duration::from_millis(100));
+            assert!(Throttle::new(Some(0.0)).is_err());
+            assert!(Throttle::new(Some(-1.0)).is_err());
+        }
+
+        /// Header of a VCD trace of pages 0 to 3
+        const HEADER: &str = "$timescale 1 ms $end
+$scope module trace $end
+$var wire 1 ! _0 $end
+$var wire 1 \" _1 $end
+$var wire 1 # _2 $end
+$var wire 1 $ _3 $end
+$upscope $end
+$enddefinitions $end
+";
+
+        fn steps(changes: &str) -> BTreeMap<u64, BTreeSet<u64>> {
+            read_steps(format!("{HEADER}{changes}").as_bytes()).unwrap()
+        }
+
+        #[test]
+        fn diff_reports_the_first_difference_and_a_trace_that_ended() {
+            // Pages 1, 2 and 1 again, and pages 1 and 3 in a trace that ends earlier
+            let a = steps("#0\n1\"\n#1\n0\"\n1#\n#2\n0#\n1\"\n");
+            let b = steps("#0\n1\"\n#1\n0\"\n1$\n");
+            assert_eq!(a[&1], BTreeSet::from([2]));
+            assert_eq!(b.len(), 2);
+
+            assert_eq!(
+                diff_steps(&a, &b),
+                StepDiff {
+                    steps: 3,
+                    divergent: 2,
+                    first: Some((1, vec![2], vec![3], "")),
+                    lines: "#1: -[2] +[3]\n#2: -[1] +[] (second trace ended)\n".into(),
+                }
+            );
+            assert_eq!(diff_steps(&b, &a).first, Some((1, vec![3], vec![2], "")));
+        }
+
+        #[test]
+        fn identical_traces_do_not_differ() {
+            let a = steps("#0\n1!\n#5\n0!\n1$\n");
+            let diff = diff_steps(&a, &a.clone());
+            assert_eq!((diff.steps, diff.divergent, diff.first), (2, 0, None));
+            assert!(diff.lines.is_empty());
+        }
     }
 }
 
 /// Page fault attack on libjpeg
-#[derive(Parser, Debug)]
+///
+/// Serialized with the fields named after the long arguments.
+#[derive(Parser, Debug, Serialize, Deserialize)]
 #[command(author, version, about, long_about = None)]
+#[serde(rename_all = "kebab-case")]
 pub struct Args {
     /// Attack mode
     #[command(subcommand)]
     mode: Mode,
 
-    /// Output bitmap file
+    /// Output bitmap file, or PNG file with `--bit-depth 16`
     #[arg(short, long)]
     output: Option<String>,
 
+    /// Bits per channel of the output image. With 16 bits, the image is written as PNG
+    /// and keeps more of the range of the data counts than the 8-bit bitmap.
+    #[arg(long, default_value = "8")]
+    bit_depth: BitDepth,
+
     /// Output JSON file
     #[arg(short, long)]
     raw_output: Option<String>,
 
-    /// Input image file
+    /// Input image file, required by all modes except `diff`.
+    /// A directory or a glob pattern attacks every matching JPEG image in turn
+    /// within a single enclave, and appends the image name to the output names.
     #[arg(short, long)]
-    image: String,
+    image: Option<String>,
 
     #[arg(short, long)]
     color: bool,
 
     #[arg(short, long, default_value_t = false)]
     aexnotify: bool,
+
+    /// Score the reconstruction against the true per-block data counts in this JSON file,
+    /// e.g. written by `--golden-output`, and report how many blocks differ
+    #[arg(long)]
+    golden: Option<String>,
+
+    /// Write the true per-block data counts from the ocalls of the enclave to this JSON
+    /// file, in the format of `--raw-output`, to score other reconstructions with `--golden`.
+    /// Only applies to `enclave` and `ocalls` mode.
+    #[cfg(feature = "sgx")]
+    #[arg(long)]
+    golden_output: Option<String>,
+
+    /// Only check that the page ranges of the attack fit the target, without attacking
+    #[arg(long)]
+    check: bool,
+
+    /// Save the partial reconstruction to `--output` and `--raw-output`
+    /// every N reconstructed blocks
+    #[arg(long)]
+    autosave_interval: Option<usize>,
+
+    /// Number of reconstructed blocks between progress bar updates
+    #[arg(long, default_value_t = 64)]
+    progress_batch: u64,
+
+    /// Only log warnings and errors, and hide the progress bar
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Read erip on every page fault to log and disambiguate state transitions.
+    /// Only works on debug enclaves.
+    #[cfg(feature = "sgx")]
+    #[arg(long)]
+    erip_assist: bool,
+
+    /// Output JSON file for the per-state erip histogram of `--erip-assist`,
+    /// printed to stdout if not given
+    #[cfg(feature = "sgx")]
+    #[arg(long, requires = "erip_assist")]
+    erip_histogram: Option<String>,
+
+    /// Create the enclave in production (non-debug) mode.
+    /// The page fault attack does not need to read enclave memory.
+    #[cfg(feature = "sgx")]
+    #[arg(long, conflicts_with = "erip_assist")]
+    production: bool,
+
+    /// Also reconstruct the image from the ocalls of the enclave, which give the true
+    /// data counts, and report how many blocks of the page fault attack differ.
+    /// Only applies to `enclave` mode.
+    #[cfg(feature = "sgx")]
+    #[arg(long)]
+    verify: bool,
+
+    /// Output bitmap file of the per-block errors found by `--verify`
+    #[cfg(feature = "sgx")]
+    #[arg(long, requires = "verify")]
+    verify_output: Option<String>,
+
+    /// Write a VCD trace with a step per page fault, labeled with the row and block
+    /// boundaries reported by the ocalls of the enclave in its `marker` wire, e.g. to
+    /// check the alignment of the reconstruction in GTKWave. Only applies to `enclave` mode.
+    #[cfg(feature = "sgx")]
+    #[arg(long)]
+    marker_trace: Option<String>,
+}
+
+impl Args {
+    /// The input image, which `main` checks is given
+    fn image(&self) -> &str {
+        self.image.as_deref().unwrap()
+    }
+
+    /// The images to attack, given by `--image`.
+    ///
+    /// If `--image` is a directory or a glob pattern, the outputs of every image
+    /// are derived from `--output` and `--raw-output` with [`batch_output`].
+    fn targets(&self) -> Result<Vec<Target>, Box<dyn Error>> {
+        let image = self.image();
+        let images = if Path::new(image).is_dir() {
+            let mut images = std::fs::read_dir(image)?
+                .map(|entry| Ok(entry?.path()))
+                .collect::<Result<Vec<_>, std::io::Error>>()?;
+            images.retain(|path| {
+                path.extension().is_some_and(|e| {
+                    e.eq_ignore_ascii_case("jpg") || e.eq_ignore_ascii_case("jpeg")
+                })
+            });
+            images.sort();
+            images
+        } else if image.contains(['*', '?', '[']) {
+            glob::glob(image)?.collect::<Result<Vec<_>, _>>()?
+        } else {
+            let mut target = Target::new(
+                image.to_owned(),
+                self.output.clone(),
+                self.raw_output.clone(),
+                self.color,
+            )?;
+            target.golden = self.golden.clone();
+            #[cfg(feature = "sgx")]
+            {
+                target.golden_output = self.golden_output.clone();
+            }
+            return Ok(vec![target]);
+        };
+        if images.is_empty() {
+            return Err(format!("no images found for `{image}`").into());
+        }
+
+        images
+            .into_iter()
+            .map(|path| {
+                let path = path.to_string_lossy().into_owned();
+                let output = self.output.as_deref().map(|o| batch_output(o, &path));
+                let raw_output = self.raw_output.as_deref().map(|o| batch_output(o, &path));
+                let golden = self.golden.as_deref().map(|o| batch_output(o, &path));
+                #[cfg(feature = "sgx")]
+                let golden_output = self
+                    .golden_output
+                    .as_deref()
+                    .map(|o| batch_output(o, &path));
+                Target::new(path, output, raw_output, self.color).map(|target| Target {
+                    golden,
+                    #[cfg(feature = "sgx")]
+                    golden_output,
+                    ..target
+                })
+            })
+            .collect()
+    }
 }
 
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", rename_all_fields = "kebab-case")]
 pub enum Mode {
-    /// Simulate attack with a VCD page access trace
+    /// Simulate attack with a VCD page access trace, or a record trace
     Trace {
         #[arg(short, long)]
         vcd: String,
+        /// Replay at most this many trace steps per second, to follow the
+        /// reconstruction as it happens. Steps are replayed as fast as possible if not given.
+        #[arg(long)]
+        replay_sps: Option<f64>,
+    },
+    /// Compare the pages accessed at every timestamp of two VCD traces
+    Diff {
+        a: String,
+        b: String,
+        /// Write every differing step to this file
+        #[arg(short, long)]
+        diff_output: Option<String>,
     },
     #[cfg(feature = "sgx")]
     /// Attack on an enclave using page faults
@@ -639,23 +2520,75 @@ pub enum Mode {
     },
 }
 
+/// Initialize logging to stderr, at the `info` level unless `RUST_LOG` is set.
+/// With `quiet`, only warnings and errors are logged.
+fn init_logger(quiet: bool) {
+    let mut builder = if quiet {
+        let mut builder = env_logger::Builder::new();
+        builder.filter_level(log::LevelFilter::Warn);
+        builder
+    } else {
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+    };
+    builder.init();
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
+    init_logger(args.quiet);
+
+    if let Mode::Diff { a, b, diff_output } = &args.mode {
+        return trace::diff_vcd(a, b, diff_output.as_deref());
+    }
+    if args.image.is_none() {
+        Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "the following required arguments were not provided:\n  --image <IMAGE>",
+            )
+            .exit();
+    }
+
+    #[cfg(feature = "sgx")]
+    if args.verify && !matches!(args.mode, Mode::Enclave { .. }) {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--verify requires `enclave` mode, which it checks against the ocalls",
+            )
+            .exit();
+    }
+    #[cfg(feature = "sgx")]
+    if args.marker_trace.is_some() && !matches!(args.mode, Mode::Enclave { .. }) {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--marker-trace requires `enclave` mode, which has a step per page fault",
+            )
+            .exit();
+    }
 
-    // We need to know the dimensions of the image in order to make sure
+    // We need to know the dimensions of the images in order to make sure
     // the enclave has a sufficiently large buffer for the image.
-    //
-    // This information is NOT used by the attack.
-    let (width, height) = image::image_dimensions(&args.image)?;
-    let input_size = std::fs::metadata(&args.image)?.len();
-    let output_size = ((width * height * 3) + 100) as u64;
+    let targets = args.targets()?;
+
+    if args.check {
+        return match &args.mode {
+            Mode::Trace { vcd, .. } => trace::check_vcd(vcd, &args),
+            Mode::Diff { .. } => unreachable!("diff is handled above"),
+            #[cfg(feature = "sgx")]
+            Mode::Enclave { enclave } | Mode::Ocalls { enclave } => {
+                sgx::check_enclave(enclave, &args)
+            }
+        };
+    }
 
     // Initialize the progress bar
-    let mut num_blocks = ((width / 8) + 1) * ((height / 8) + 1);
-    if args.color {
-        num_blocks *= 3;
-    }
-    let progress_bar = ProgressBar::new(num_blocks as u64);
+    let progress_bar = if args.quiet {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(targets[0].num_blocks)
+    };
     progress_bar.set_style(
         ProgressStyle::with_template(
             "[{elapsed_precise}] {wide_bar} {pos:>7}/{len:7} ETA: [{eta_precise}] ",
@@ -663,19 +2596,362 @@ fn main() -> Result<(), Box<dyn Error>> {
         .unwrap()
         .progress_chars("##-"),
     );
-    PROGRESS_BAR.set(progress_bar).unwrap();
+    PROGRESS_BAR
+        .set(BatchedProgress::new(progress_bar, args.progress_batch))
+        .unwrap();
 
     match &args.mode {
-        Mode::Trace { vcd } => trace::attack_vcd(vcd, &args)?,
+        Mode::Trace { vcd, replay_sps } => match targets.as_slice() {
+            [target] => trace::attack_vcd(vcd, *replay_sps, &args, target)?,
+            _ => return Err("a VCD trace belongs to a single image, not a batch".into()),
+        },
+        Mode::Diff { .. } => unreachable!("diff is handled above"),
         #[cfg(feature = "sgx")]
         Mode::Enclave { enclave } | Mode::Ocalls { enclave } => sgx::attack_enclave(
             enclave,
             &args,
-            input_size,
-            output_size,
+            &targets,
             matches!(&args.mode, &Mode::Enclave { .. }),
         )?,
     };
+    PROGRESS_BAR.get().unwrap().flush();
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse the arguments of a run, after the program name
+    fn args(args: &[&str]) -> Args {
+        Args::try_parse_from(["libjpeg_attack"].iter().chain(args)).unwrap()
+    }
+
+    #[test]
+    fn progress_is_drawn_in_batches() {
+        let progress = BatchedProgress::new(ProgressBar::hidden(), 4);
+        progress.reset(10);
+        for _ in 0..3 {
+            progress.inc();
+        }
+        assert_eq!(progress.bar.position(), 0);
+        // The fourth increment completes a batch
+        progress.inc();
+        assert_eq!(progress.bar.position(), 4);
+        for _ in 0..5 {
+            progress.inc();
+        }
+        assert_eq!(progress.bar.position(), 8);
+        progress.flush();
+        assert_eq!(progress.bar.position(), 9);
+
+        // Pending increments are added before the bar restarts for the next image
+        progress.inc();
+        progress.reset(20);
+        assert_eq!(
+            (progress.bar.position(), progress.bar.length()),
+            (0, Some(20))
+        );
+        assert_eq!(progress.pending.load(Ordering::Relaxed), 0);
+    }
+
+    /// Pages of a run over two rows, with blocks of 3 and 1 data accesses in the
+    /// first row and a block of 1 in the second
+    const TWO_ROWS: [usize; 14] = [54, 58, 63, 150, 151, 152, 59, 63, 160, 44, 58, 63, 170, 44];
+
+    /// Feed the faults on `pages` to the libjpeg state machine, returning the state
+    /// after every fault and the reconstruction
+    fn replay(pages: &[usize]) -> (Vec<JpegState>, JpegReconstruct) {
+        let mut machine = PageStateMachine::new(JpegState::PreStart, false);
+        let mut reconstruct = JpegReconstruct::new(1).without_progress();
+        let states = pages
+            .iter()
+            .map(|&page| {
+                let transition = machine.transition(page);
+                reconstruct.reconstruct(transition.from, transition.to);
+                transition.to
+            })
+            .collect();
+        (states, reconstruct)
+    }
+
+    /// Write a gray JPEG image of `width` by `height` pixels to `path`
+    fn write_jpeg(path: &Path, width: u32, height: u32) {
+        image::GrayImage::from_pixel(width, height, image::Luma([128]))
+            .save(path)
+            .unwrap();
+    }
+
+    #[test]
+    fn batch_targets_are_sized_per_image() {
+        let dir = tempfile::tempdir().unwrap();
+        write_jpeg(&dir.path().join("b.jpg"), 16, 8);
+        write_jpeg(&dir.path().join("a.jpg"), 8, 8);
+        std::fs::write(dir.path().join("notes.txt"), "not an image").unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+
+        let targets = args(&[
+            "--image",
+            &path(""),
+            "--output",
+            &path("rec.bmp"),
+            "--raw-output",
+            &path("rec.json"),
+            "trace",
+            "--vcd",
+            "unused.vcd",
+        ])
+        .targets()
+        .unwrap();
+
+        let images = targets.iter().map(|t| t.image.as_str()).collect::<Vec<_>>();
+        assert_eq!(images, [path("a.jpg"), path("b.jpg")]);
+        assert_eq!(
+            targets[0].output.as_deref(),
+            Some(path("rec_a.bmp").as_str())
+        );
+        assert_eq!(
+            targets[1].raw_output.as_deref(),
+            Some(path("rec_b.json").as_str())
+        );
+        assert_eq!(
+            targets.iter().map(|t| t.output_size).collect::<Vec<_>>(),
+            [8 * 8 * 3 + 100, 16 * 8 * 3 + 100]
+        );
+        assert_eq!(
+            targets.iter().map(|t| t.num_blocks).collect::<Vec<_>>(),
+            [2 * 2, 3 * 2]
+        );
+    }
+
+    #[test]
+    fn single_image_keeps_its_output_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("a.jpg");
+        write_jpeg(&image, 8, 8);
+
+        let targets = args(&[
+            "--image",
+            &image.to_string_lossy(),
+            "--output",
+            "rec.bmp",
+            "trace",
+            "--vcd",
+            "unused.vcd",
+        ])
+        .targets()
+        .unwrap();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].output.as_deref(), Some("rec.bmp"));
+    }
+
+    #[test]
+    fn state_machine_follows_a_scripted_run() {
+        use JpegState::*;
+
+        let (states, reconstruct) = replay(&TWO_ROWS);
+        assert_eq!(
+            states,
+            [
+                Start,
+                StartRow,
+                IdctSlow,
+                DataCount(1),
+                DataCount(2),
+                DataCount(3),
+                PreIdctSlow,
+                IdctSlow,
+                DataCount(1),
+                NextRow,
+                StartRow,
+                IdctSlow,
+                DataCount(1),
+                NextRow
+            ]
+        );
+        assert_eq!(
+            reconstruct.raw_reconstruction(),
+            &[vec![vec![3, 1], vec![1]]]
+        );
+        assert_eq!(reconstruct.reconstructed_size(), [2, 1]);
+    }
+
+    #[test]
+    fn faults_outside_the_next_states_are_ignored() {
+        let mut pages = TWO_ROWS.to_vec();
+        // A fault on the code of IdctSlow before the row started, and on a page
+        // that belongs to no state
+        pages.insert(1, 63);
+        pages.insert(5, 5000);
+        let (states, reconstruct) = replay(&pages);
+        assert_eq!(states[1], JpegState::Start);
+        assert_eq!(states[5], states[4]);
+        assert_eq!(
+            reconstruct.raw_reconstruction(),
+            replay(&TWO_ROWS).1.raw_reconstruction()
+        );
+    }
+
+    #[test]
+    fn write_atomic_replaces_the_whole_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rec.json");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "old").unwrap();
+
+        write_atomic(path, |tmp| std::fs::write(tmp, "new")).unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "new");
+        assert!(!Path::new(&format!("{path}.tmp")).exists());
+
+        // A write that fails halfway leaves the previous file
+        let error = write_atomic(path, |tmp| {
+            std::fs::write(tmp, "ne")?;
+            Err(std::io::Error::other("interrupted"))
+        })
+        .unwrap_err();
+        assert_eq!(error.to_string(), "interrupted");
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "new");
+    }
+
+    #[test]
+    fn autosave_every_interval_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let raw_output = dir.path().join("rec.json");
+        let read = || {
+            serde_json::from_str::<Vec<Vec<Vec<usize>>>>(
+                &std::fs::read_to_string(&raw_output).unwrap(),
+            )
+            .unwrap()
+        };
+        let mut reconstruct = JpegReconstruct::new(1)
+            .without_progress()
+            .with_autosave(Some(Autosave {
+                interval: 2,
+                output: None,
+                raw_output: Some(raw_output.to_string_lossy().into_owned()),
+            }));
+
+        reconstruct.reconstruct_block(5);
+        reconstruct.autosave();
+        assert!(!raw_output.exists());
+        // Reconstructing only flags the autosave, which is written by `autosave`
+        reconstruct.reconstruct_block(7);
+        assert!(!raw_output.exists());
+        reconstruct.autosave();
+        assert_eq!(read(), [[[5, 7]]]);
+
+        reconstruct.reconstruct_block(9);
+        reconstruct.autosave();
+        assert_eq!(read(), [[[5, 7]]]);
+        reconstruct.reconstruct_block(2);
+        reconstruct.autosave();
+        assert_eq!(read(), [[[5, 7, 9, 2]]]);
+    }
+
+    #[test]
+    fn jpeg_states_round_trip() {
+        use JpegState::*;
+
+        let states = [
+            PreStart,
+            Start,
+            NextRow,
+            StartRow,
+            PreIdctSlow,
+            StartIdctSlow,
+            IdctSlow,
+            DataCount(0),
+            DataCount(3),
+        ];
+        let json = serde_json::to_string(&states).unwrap();
+        assert_eq!(
+            json,
+            r#"["PreStart","Start","NextRow","StartRow","PreIdctSlow","StartIdctSlow","IdctSlow",{"DataCount":0},{"DataCount":3}]"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Vec<JpegState>>(&json).unwrap(),
+            states
+        );
+    }
+
+    #[test]
+    fn args_round_trip() {
+        let args = args(&[
+            "--image",
+            "a.jpg",
+            "--bit-depth",
+            "16",
+            "trace",
+            "--vcd",
+            "a.vcd",
+        ]);
+        let json = serde_json::to_string(&args).unwrap();
+        let parsed = serde_json::from_str::<Args>(&json).unwrap();
+        assert_eq!(format!("{parsed:?}"), format!("{args:?}"));
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+    }
+
+    #[test]
+    fn image16_preserves_the_order_of_the_counts() {
+        // 5000 and 5001 fall on the same level of an 8-bit image
+        let counts = [10000, 5000, 0, 5001, 2, 1];
+        let mut reconstruct = JpegReconstruct::new(1)
+            .without_progress()
+            .with_bit_depth(BitDepth::Sixteen);
+        for count in counts {
+            reconstruct.reconstruct_block(count);
+        }
+        reconstruct.next_row();
+
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("rec.png");
+        reconstruct
+            .save(Some(&output.to_string_lossy()), None)
+            .unwrap();
+        let image = image::open(output).unwrap();
+        assert_eq!(image.color(), image::ColorType::L16);
+        let image = image.into_luma16();
+        assert_eq!(image.dimensions(), (6, 1));
+        let pixels = image.pixels().map(|p| p.0[0]).collect::<Vec<_>>();
+        assert_eq!((pixels[0], pixels[2]), (u16::MAX, 0));
+        for i in 0..counts.len() {
+            for j in 0..counts.len() {
+                assert_eq!(counts[i].cmp(&counts[j]), pixels[i].cmp(&pixels[j]));
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_order_transitions_do_not_panic() {
+        use JpegState::*;
+
+        let mut reconstruct = JpegReconstruct::new(3).without_progress();
+        reconstruct.reconstruct(DataCount(2), NextRow);
+        // Two rows start without the blocks of the other colors in between
+        reconstruct.reconstruct(NextRow, StartRow);
+        reconstruct.reconstruct(NextRow, StartRow);
+        reconstruct.reconstruct(DataCount(1), PreIdctSlow);
+        reconstruct.reconstruct(StartRow, DataCount(4));
+
+        assert_eq!(
+            reconstruct.raw_reconstruction(),
+            &[
+                vec![vec![2], vec![], vec![]],
+                vec![vec![], vec![], vec![1]],
+                vec![vec![], vec![], vec![]]
+            ]
+        );
+        assert_eq!(reconstruct.reconstructed_size(), [1, 2]);
+        reconstruct.reconstructed_bitmap();
+    }
+
+    #[test]
+    fn out_of_order_pages_do_not_panic() {
+        // The pages of the run backwards, and its second row before its first
+        let mut pages = TWO_ROWS.to_vec();
+        pages.reverse();
+        replay(&pages);
+        replay(&[&TWO_ROWS[9..], &TWO_ROWS[..9]].concat());
+    }
+}
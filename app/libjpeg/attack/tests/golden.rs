@@ -0,0 +1,76 @@
+//! Reconstruction of a tiny image from a trace, scored against its golden data counts
+//!
+//! `tests/golden/tiny.vcd` is a single-stepped trace of the decompression of an image
+//! with two rows of blocks, and `tests/golden/tiny.json` holds the true data counts
+//! of its blocks, in the format of `--golden-output`.
+
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+fn golden(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(name)
+}
+
+/// Attack the trace `tiny.vcd`, scoring it against the `golden` counts, and return
+/// the output of the attack with the raw reconstruction
+fn attack(dir: &Path, golden: &Path) -> (Output, String) {
+    // Only the dimensions of the image are read, for the enclave buffers
+    let image = dir.join("tiny.jpg");
+    image::GrayImage::from_pixel(16, 8, image::Luma([128]))
+        .save(&image)
+        .unwrap();
+    let raw_output = dir.join("tiny.json");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_libjpeg_attack"))
+        .arg("--quiet")
+        .arg("--image")
+        .arg(&image)
+        .arg("--raw-output")
+        .arg(&raw_output)
+        .arg("--golden")
+        .arg(golden)
+        .arg("trace")
+        .arg("--vcd")
+        .arg(self::golden("tiny.vcd"))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "attack failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    (output, std::fs::read_to_string(raw_output).unwrap())
+}
+
+#[test]
+fn reconstruction_matches_golden_counts() {
+    let dir = tempfile::tempdir().unwrap();
+    let (output, raw) = attack(dir.path(), &golden("tiny.json"));
+
+    assert_eq!(raw, std::fs::read_to_string(golden("tiny.json")).unwrap());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("0 of 3 blocks differ"),
+        "unexpected score: {stdout}"
+    );
+}
+
+#[test]
+fn differing_blocks_are_counted() {
+    let dir = tempfile::tempdir().unwrap();
+    // The second block of the first row had 2 data accesses instead of 1
+    let truth = dir.path().join("truth.json");
+    std::fs::write(&truth, "[[[3, 2], [2]]]").unwrap();
+    let (output, _) = attack(dir.path(), &truth);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("1 of 3 blocks differ")
+            && stdout.contains("mean absolute error 0.333, max 1"),
+        "unexpected score: {stdout}"
+    );
+}
@@ -0,0 +1,47 @@
+//! Cost of a union of two [`PageBitmap`]s against that of two hash sets, as quoted
+//! in the documentation of the `bitmap` module
+
+use std::collections::HashSet;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use sgx_profiler::bitmap::PageBitmap;
+
+/// Pages of the enclave the sets are drawn from
+const NUM_PAGES: usize = 64 * 1024;
+
+fn random_pages(rng: &mut StdRng, len: usize) -> Vec<usize> {
+    (0..len).map(|_| rng.gen_range(0..NUM_PAGES)).collect()
+}
+
+fn union(c: &mut Criterion) {
+    let mut group = c.benchmark_group("union");
+    let mut rng = StdRng::seed_from_u64(1394);
+    for len in [10, 1000] {
+        let (a, b) = (random_pages(&mut rng, len), random_pages(&mut rng, len));
+
+        let bitmap = |pages: &[usize]| {
+            let mut bitmap = PageBitmap::with_capacity(NUM_PAGES);
+            bitmap.extend(pages.iter().copied());
+            bitmap
+        };
+        let (x, y) = (bitmap(&a), bitmap(&b));
+        group.bench_with_input(BenchmarkId::new("bitmap", len), &(x, y), |bench, (x, y)| {
+            bench.iter(|| black_box(x) | black_box(y))
+        });
+
+        let (x, y) = (
+            a.into_iter().collect::<HashSet<_>>(),
+            b.into_iter().collect::<HashSet<_>>(),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("hash_set", len),
+            &(x, y),
+            |bench, (x, y)| bench.iter(|| black_box(x) | black_box(y)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, union);
+criterion_main!(benches);
@@ -0,0 +1,58 @@
+//! Reporting of the problems found by the `--check` option of the binaries
+//!
+//! A check validates the configuration of a run, e.g. that the enclave has the
+//! symbols and pages it refers to, without running it. All problems are collected
+//! and printed together, so they can be fixed in one go.
+
+use std::{error::Error, fmt::Display};
+
+/// A check found `problems` problems, which were printed by [`report`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CheckFailed {
+    pub problems: usize,
+}
+
+impl Display for CheckFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "check failed with {} problem(s)", self.problems)
+    }
+}
+
+impl Error for CheckFailed {}
+
+/// Print the `problems` found by a check to stderr, failing if there are any.
+///
+/// ```
+/// use sgx_profiler_core::check::{report, CheckFailed};
+///
+/// assert_eq!(report(&[]), Ok(()));
+/// let problems = ["symbol not found in enclave: foo".to_owned()];
+/// assert_eq!(report(&problems), Err(CheckFailed { problems: 1 }));
+/// ```
+pub fn report(problems: &[String]) -> Result<(), CheckFailed> {
+    for problem in problems {
+        eprintln!("error: {problem}");
+    }
+    if problems.is_empty() {
+        println!("check passed");
+        Ok(())
+    } else {
+        Err(CheckFailed {
+            problems: problems.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_every_problem() {
+        let problems =
+            ["page 3 out of bounds", "symbol not found in enclave: foo"].map(String::from);
+        let failed = report(&problems).unwrap_err();
+        assert_eq!(failed, CheckFailed { problems: 2 });
+        assert_eq!(failed.to_string(), "check failed with 2 problem(s)");
+    }
+}
@@ -0,0 +1,8 @@
+//! Parts of the profiler that do not depend on SGX
+//!
+//! These can be used on machines without SGX support, e.g. to replay recorded
+//! traces. The `sgx_profiler` library re-exports them.
+
+pub mod check;
+pub mod state_machine;
+pub mod trace;
@@ -0,0 +1,356 @@
+//! Page-fault driven state machines
+//!
+//! A page fault attack follows the execution of an enclave by revoking access to
+//! the pages that the next steps of the execution will access. Every state of a
+//! [`PageStateMachine`] has a range of pages, and a fault on one of the pages of
+//! a state that can follow the current state transitions to that state.
+
+use std::{fmt::Debug, ops::Range};
+
+/// A state of a [`PageStateMachine`]
+///
+/// Implementations are tables of the page ranges of, and transitions between, the
+/// states of an attack.
+pub trait PageState: Copy + Eq + Debug {
+    /// Parameters the page ranges depend on, e.g. the build of the target enclave
+    type Config: Debug + Clone;
+
+    /// Pages that trigger a transition into this state
+    fn pages(self, config: &Self::Config) -> Range<usize>;
+
+    /// States that can follow this state, in order of preference
+    fn next_states(self) -> Vec<Self>;
+}
+
+/// Transition of a [`PageStateMachine`] on a fault, with the states it chose from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transition<S> {
+    pub page: usize,
+    pub from: S,
+    /// The first candidate, or `from` if there are none
+    pub to: S,
+    /// Next states of `from` whose pages contain `page`, in order of preference
+    pub candidates: Vec<S>,
+}
+
+impl<S> Transition<S> {
+    /// Whether the pages of more than one next state contain the faulting page,
+    /// in which case the first one was chosen. This usually means the page ranges
+    /// of the states are misconfigured.
+    pub fn is_ambiguous(&self) -> bool {
+        self.candidates.len() > 1
+    }
+}
+
+/// Tracks the state of an attack from the faulting pages
+///
+/// ```
+/// use std::ops::Range;
+/// use sgx_profiler_core::state_machine::{PageState, PageStateMachine};
+///
+/// #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// enum Loop {
+///     Start,
+///     Body(usize),
+///     End,
+/// }
+///
+/// impl PageState for Loop {
+///     type Config = ();
+///
+///     fn pages(self, _: &()) -> Range<usize> {
+///         match self {
+///             Loop::Start => 0..1,
+///             Loop::Body(_) => 10..12,
+///             Loop::End => 20..21,
+///         }
+///     }
+///
+///     fn next_states(self) -> Vec<Self> {
+///         match self {
+///             Loop::Start => vec![Loop::Body(1)],
+///             Loop::Body(n) => vec![Loop::Body(n + 1), Loop::End],
+///             Loop::End => vec![],
+///         }
+///     }
+/// }
+///
+/// let mut machine = PageStateMachine::new(Loop::Start, ());
+/// assert_eq!(machine.next_pages(), [10..12]);
+/// // Faults on pages of states that can't follow the current state are ignored
+/// for page in [10, 11, 5, 20, 10] {
+///     machine.advance(page);
+/// }
+/// assert_eq!(machine.state(), Loop::End);
+///
+/// let mut machine = PageStateMachine::new(Loop::Start, ());
+/// for page in [10, 11, 10] {
+///     machine.advance(page);
+/// }
+/// assert_eq!(machine.state(), Loop::Body(3));
+/// ```
+#[derive(Debug, Clone)]
+pub struct PageStateMachine<S: PageState> {
+    state: S,
+    config: S::Config,
+}
+
+impl<S: PageState> PageStateMachine<S> {
+    pub fn new(initial: S, config: S::Config) -> Self {
+        Self {
+            state: initial,
+            config,
+        }
+    }
+
+    pub fn state(&self) -> S {
+        self.state
+    }
+
+    pub fn config(&self) -> &S::Config {
+        &self.config
+    }
+
+    /// Move to `state`, e.g. to a candidate chosen by other means than its order.
+    pub fn set_state(&mut self, state: S) {
+        self.state = state;
+    }
+
+    /// Pages that trigger a transition into `state`
+    pub fn pages(&self, state: S) -> Range<usize> {
+        state.pages(&self.config)
+    }
+
+    /// Returns the next states that a fault on the given page can transition to,
+    /// in order of preference.
+    ///
+    /// More than one state is returned if their page ranges overlap.
+    pub fn candidates(&self, page: usize) -> Vec<S> {
+        self.state
+            .next_states()
+            .into_iter()
+            .filter(|state| self.pages(*state).contains(&page))
+            .collect()
+    }
+
+    /// The state a fault on the given page transitions to,
+    /// which is the current state if no next state contains the page.
+    pub fn next(&self, page: usize) -> S {
+        self.candidates(page).first().copied().unwrap_or(self.state)
+    }
+
+    /// Transition on a fault on the given page, returning the new state.
+    pub fn advance(&mut self, page: usize) -> S {
+        self.state = self.next(page);
+        self.state
+    }
+
+    /// Transition on a fault on the given page like [`PageStateMachine::advance`],
+    /// returning which candidate states the transition chose from.
+    ///
+    /// ```
+    /// use std::ops::Range;
+    /// use sgx_profiler_core::state_machine::{PageState, PageStateMachine};
+    ///
+    /// #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    /// enum Parse {
+    ///     Start,
+    ///     Header,
+    ///     Body,
+    /// }
+    ///
+    /// impl PageState for Parse {
+    ///     type Config = ();
+    ///
+    ///     // The pages of the header and the body overlap on page 11
+    ///     fn pages(self, _: &()) -> Range<usize> {
+    ///         match self {
+    ///             Parse::Start => 0..1,
+    ///             Parse::Header => 10..12,
+    ///             Parse::Body => 11..13,
+    ///         }
+    ///     }
+    ///
+    ///     fn next_states(self) -> Vec<Self> {
+    ///         match self {
+    ///             Parse::Start => vec![Parse::Header, Parse::Body],
+    ///             _ => vec![],
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let mut machine = PageStateMachine::new(Parse::Start, ());
+    /// let transition = machine.transition(12);
+    /// assert_eq!((transition.from, transition.to), (Parse::Start, Parse::Body));
+    /// assert!(!transition.is_ambiguous());
+    ///
+    /// let mut machine = PageStateMachine::new(Parse::Start, ());
+    /// let transition = machine.transition(11);
+    /// assert_eq!(transition.to, Parse::Header);
+    /// assert_eq!(transition.candidates, [Parse::Header, Parse::Body]);
+    /// assert!(transition.is_ambiguous());
+    /// assert_eq!(machine.state(), Parse::Header);
+    /// ```
+    pub fn transition(&mut self, page: usize) -> Transition<S> {
+        let candidates = self.candidates(page);
+        let from = self.state;
+        self.state = candidates.first().copied().unwrap_or(from);
+        Transition {
+            page,
+            from,
+            to: self.state,
+            candidates,
+        }
+    }
+
+    /// Returns the list of pages that may trigger a state transition.
+    ///
+    /// In other words, the union of all ranges of pages for all potential
+    /// next states reachable from the current state.
+    pub fn next_pages(&self) -> Vec<Range<usize>> {
+        self.state
+            .next_states()
+            .into_iter()
+            .map(|state| self.pages(state))
+            .collect()
+    }
+
+    /// Revoke access to the pages that may trigger a state transition,
+    /// using `revoke` on the range of pages of every next state.
+    pub fn protect_next_pages<E>(
+        &self,
+        revoke: impl FnMut(Range<usize>) -> Result<(), E>,
+    ) -> Result<(), E> {
+        self.next_pages().into_iter().try_for_each(revoke)
+    }
+
+    /// Returns the states among `states` whose pages exceed the first `num_pages` pages,
+    /// with their page ranges.
+    pub fn out_of_bounds(
+        &self,
+        states: impl IntoIterator<Item = S>,
+        num_pages: usize,
+    ) -> Vec<(S, Range<usize>)> {
+        states
+            .into_iter()
+            .map(|state| (state, self.pages(state)))
+            .filter(|(_, pages)| pages.end > num_pages)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A loop over blocks of `stride` pages each, laid out from page 10 on
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Blocks {
+        Start,
+        Block(usize),
+        Done,
+    }
+
+    #[derive(Debug, Clone)]
+    struct Layout {
+        blocks: usize,
+        stride: usize,
+    }
+
+    impl PageState for Blocks {
+        type Config = Layout;
+
+        fn pages(self, layout: &Layout) -> Range<usize> {
+            match self {
+                Blocks::Start => 0..1,
+                Blocks::Block(n) => {
+                    let start = 10 + n * layout.stride;
+                    start..start + layout.stride
+                }
+                Blocks::Done => 1..2,
+            }
+        }
+
+        fn next_states(self) -> Vec<Self> {
+            match self {
+                Blocks::Start => vec![Blocks::Block(0)],
+                Blocks::Block(n) => vec![Blocks::Block(n + 1), Blocks::Done],
+                Blocks::Done => vec![],
+            }
+        }
+    }
+
+    fn machine(stride: usize) -> PageStateMachine<Blocks> {
+        PageStateMachine::new(Blocks::Start, Layout { blocks: 3, stride })
+    }
+
+    #[test]
+    fn follows_a_scripted_sequence() {
+        let mut machine = machine(2);
+        let states = [10, 12, 15, 1]
+            .into_iter()
+            .map(|page| machine.advance(page))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            states,
+            [
+                Blocks::Block(0),
+                Blocks::Block(1),
+                Blocks::Block(2),
+                Blocks::Done
+            ]
+        );
+        assert!(machine.next_pages().is_empty());
+    }
+
+    #[test]
+    fn unexpected_pages_keep_the_state() {
+        let mut machine = machine(2);
+        for page in [1, 12, 100, usize::MAX] {
+            let transition = machine.transition(page);
+            assert_eq!(
+                (transition.from, transition.to),
+                (Blocks::Start, Blocks::Start)
+            );
+            assert!(transition.candidates.is_empty());
+        }
+    }
+
+    #[test]
+    fn set_state_changes_the_next_pages() {
+        let mut machine = machine(4);
+        machine.set_state(Blocks::Block(1));
+        assert_eq!(machine.next_pages(), [18..22, 1..2]);
+        assert_eq!(machine.next(19), Blocks::Block(2));
+    }
+
+    #[test]
+    fn protect_next_pages_stops_at_the_first_error() {
+        let mut machine = machine(2);
+        machine.advance(10);
+        let mut revoked = Vec::new();
+        let result = machine.protect_next_pages(|pages| {
+            revoked.push(pages.clone());
+            if pages.start == 1 {
+                Err(pages)
+            } else {
+                Ok(())
+            }
+        });
+        assert_eq!(result, Err(1..2));
+        assert_eq!(revoked, [12..14, 1..2]);
+    }
+
+    #[test]
+    fn out_of_bounds_lists_states_past_the_enclave() {
+        let machine = machine(4);
+        let blocks = (0..machine.config().blocks).map(Blocks::Block);
+        assert_eq!(
+            machine.out_of_bounds(blocks, 20),
+            [(Blocks::Block(2), 18..22)]
+        );
+        assert!(machine
+            .out_of_bounds([Blocks::Start, Blocks::Done], 2)
+            .is_empty());
+    }
+}
@@ -0,0 +1,326 @@
+//! Binary trace format with the erip, accessed pages and access counts of every step
+//!
+//! The VCD traces of the profiler only hold one kind of page set per file, and the
+//! erip as a separate wire. A record trace instead holds a [`TraceRecord`] per step
+//! with everything that is known about the step, so tools that replay traces need
+//! only one reader.
+//!
+//! A trace starts with [`MAGIC`], the format version and a [`TraceHeader`], followed
+//! by the records of all steps. All of them are encoded with `bincode`.
+//!
+//! The simulator can also record its interrupt schedule in this format, with the
+//! state of the enclave it read at every interrupt, so that a run on hardware can be
+//! replayed exactly, see [`TraceHeader::schedule`].
+
+use std::io::{self, BufRead, Write};
+
+use serde::{Deserialize, Serialize};
+
+/// First bytes of every record trace
+pub const MAGIC: [u8; 8] = *b"SGXTRACE";
+
+/// Version of the format, incremented on incompatible changes
+///
+/// Traces of version 1, which lack the schedule fields, can still be read.
+pub const VERSION: u32 = 2;
+
+/// Description of the records of a trace
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceHeader {
+    /// Number of pages of the enclave, all pages in the records are below it
+    pub num_pages: usize,
+    /// Whether the `count` of a page is the number of accesses in the step.
+    /// Otherwise it is always 1, as only whether a page was accessed is known.
+    pub has_counts: bool,
+    /// Whether the trace is an interrupt schedule of the simulator. Its first record
+    /// is the interrupt that primed the simulator, and every record holds the stack
+    /// page and the changes of the PAM that the simulator read at the interrupt.
+    pub schedule: bool,
+}
+
+/// Access to a page in a step of a [`TraceRecord`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PageRecord {
+    /// Page number relative to the enclave base
+    pub page: usize,
+    /// Union of the permissions of the accesses, see [`PageRecord::READ`] and others
+    pub perms: u8,
+    /// Number of accesses to the page in the step
+    pub count: u32,
+}
+
+impl PageRecord {
+    pub const READ: u8 = 1 << 0;
+    pub const WRITE: u8 = 1 << 1;
+    pub const EXECUTE: u8 = 1 << 2;
+}
+
+/// PAM of a TLBlur-instrumented enclave, as read at a step of a schedule
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PamRecord {
+    /// Global PAM counter
+    pub counter: u64,
+    /// Entries of the PAM that changed since the previous record, with their new value
+    pub entries: Vec<(usize, u64)>,
+}
+
+/// Everything recorded about a single step
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceRecord {
+    /// Timestamp of the step, the same as in a VCD trace written along with it
+    pub step: u64,
+    /// Instruction pointer of the enclave at the step, if it could be read
+    pub erip: Option<u64>,
+    /// Accessed pages, sorted by page number
+    pub pages: Vec<PageRecord>,
+    /// Page holding the stack pointer at the step, only known in schedules
+    pub stack_page: Option<usize>,
+    /// PAM read at the step, only in schedules and if it was read
+    pub pam: Option<PamRecord>,
+}
+
+/// Record of version 1 of the format
+#[derive(Deserialize)]
+struct TraceRecordV1 {
+    step: u64,
+    erip: Option<u64>,
+    pages: Vec<PageRecord>,
+}
+
+impl From<TraceRecordV1> for TraceRecord {
+    fn from(record: TraceRecordV1) -> Self {
+        Self {
+            step: record.step,
+            erip: record.erip,
+            pages: record.pages,
+            ..Default::default()
+        }
+    }
+}
+
+/// Header of version 1 of the format
+#[derive(Deserialize)]
+struct TraceHeaderV1 {
+    num_pages: usize,
+    has_counts: bool,
+}
+
+/// Whether `start`, the first bytes of a file, is the start of a record trace
+pub fn is_trace(start: &[u8]) -> bool {
+    start.starts_with(&MAGIC)
+}
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Writes a record trace
+pub struct TraceWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// Start a trace described by `header`
+    pub fn new(mut writer: W, header: TraceHeader) -> io::Result<Self> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        bincode::serialize_into(&mut writer, &header).map_err(invalid_data)?;
+        Ok(Self { writer })
+    }
+
+    /// Append the record of the next step
+    pub fn write(&mut self, record: &TraceRecord) -> io::Result<()> {
+        bincode::serialize_into(&mut self.writer, record).map_err(invalid_data)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads the records of a trace written by a [`TraceWriter`]
+///
+/// ```
+/// use sgx_profiler_core::trace::{
+///     is_trace, PageRecord, TraceHeader, TraceReader, TraceRecord, TraceWriter,
+/// };
+///
+/// let header = TraceHeader {
+///     num_pages: 16,
+///     has_counts: true,
+///     schedule: false,
+/// };
+/// let records = vec![
+///     TraceRecord {
+///         step: 0,
+///         erip: Some(0x1234),
+///         pages: vec![
+///             PageRecord { page: 1, perms: PageRecord::READ | PageRecord::EXECUTE, count: 1 },
+///             PageRecord { page: 7, perms: PageRecord::WRITE, count: 3 },
+///         ],
+///         ..Default::default()
+///     },
+///     TraceRecord { step: 1, ..Default::default() },
+/// ];
+///
+/// let mut trace = Vec::new();
+/// let mut writer = TraceWriter::new(&mut trace, header).unwrap();
+/// for record in &records {
+///     writer.write(record).unwrap();
+/// }
+/// writer.flush().unwrap();
+///
+/// assert!(is_trace(&trace));
+/// let reader = TraceReader::new(trace.as_slice()).unwrap();
+/// assert_eq!(reader.header(), header);
+/// assert_eq!(reader.collect::<Result<Vec<_>, _>>().unwrap(), records);
+///
+/// // Traces of version 1 lack the fields of schedules
+/// let mut v1 = b"SGXTRACE".to_vec();
+/// v1.extend(1u32.to_le_bytes());
+/// v1.extend(bincode::serialize(&(16usize, false)).unwrap());
+/// v1.extend(bincode::serialize(&(5u64, Some(0x1234u64), vec![(3usize, 1u8, 1u32)])).unwrap());
+/// let mut reader = TraceReader::new(v1.as_slice()).unwrap();
+/// assert!(!reader.header().schedule);
+/// let record = reader.next().unwrap().unwrap();
+/// assert_eq!((record.step, record.pages[0].page, record.pam), (5, 3, None));
+///
+/// // VCD traces are rejected
+/// assert!(TraceReader::new(b"$date\n".as_slice()).is_err());
+/// ```
+pub struct TraceReader<R: BufRead> {
+    reader: R,
+    header: TraceHeader,
+    version: u32,
+}
+
+impl<R: BufRead> TraceReader<R> {
+    /// Read the header of a trace, failing with `InvalidData` if `reader` does not
+    /// hold a record trace of a supported version.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(invalid_data("not a record trace"));
+        }
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        let header = match version {
+            1 => {
+                let header: TraceHeaderV1 =
+                    bincode::deserialize_from(&mut reader).map_err(invalid_data)?;
+                TraceHeader {
+                    num_pages: header.num_pages,
+                    has_counts: header.has_counts,
+                    schedule: false,
+                }
+            }
+            VERSION => bincode::deserialize_from(&mut reader).map_err(invalid_data)?,
+            _ => {
+                return Err(invalid_data(format!(
+                    "unsupported record trace version {version}, expected at most {VERSION}"
+                )))
+            }
+        };
+        Ok(Self {
+            reader,
+            header,
+            version,
+        })
+    }
+
+    pub fn header(&self) -> TraceHeader {
+        self.header
+    }
+}
+
+impl<R: BufRead> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.reader.fill_buf() {
+            Ok([]) => None,
+            Ok(_) if self.version == 1 => Some(
+                bincode::deserialize_from::<_, TraceRecordV1>(&mut self.reader)
+                    .map(TraceRecord::from)
+                    .map_err(invalid_data),
+            ),
+            Ok(_) => Some(bincode::deserialize_from(&mut self.reader).map_err(invalid_data)),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_trace(header: TraceHeader, records: &[TraceRecord]) -> Vec<u8> {
+        let mut trace = Vec::new();
+        let mut writer = TraceWriter::new(&mut trace, header).unwrap();
+        for record in records {
+            writer.write(record).unwrap();
+        }
+        writer.flush().unwrap();
+        trace
+    }
+
+    #[test]
+    fn schedule_round_trips() {
+        let header = TraceHeader {
+            num_pages: 1 << 20,
+            has_counts: false,
+            schedule: true,
+        };
+        let records = (0..100u64)
+            .map(|step| TraceRecord {
+                step,
+                erip: (step % 3 != 0).then_some(0x7f00_0000 + step),
+                pages: (0..step as usize % 5)
+                    .map(|i| PageRecord {
+                        page: (1 << 20) - 1 - i,
+                        perms: PageRecord::READ | (i as u8 & PageRecord::WRITE),
+                        count: 1,
+                    })
+                    .collect(),
+                stack_page: Some(step as usize),
+                pam: (step % 2 == 0).then(|| PamRecord {
+                    counter: u64::MAX - step,
+                    entries: vec![(step as usize, step << 32)],
+                }),
+            })
+            .collect::<Vec<_>>();
+
+        let trace = write_trace(header, &records);
+        let reader = TraceReader::new(trace.as_slice()).unwrap();
+        assert_eq!(reader.header(), header);
+        assert_eq!(reader.collect::<io::Result<Vec<_>>>().unwrap(), records);
+    }
+
+    #[test]
+    fn empty_trace_has_no_records() {
+        let trace = write_trace(TraceHeader::default(), &[]);
+        let mut reader = TraceReader::new(trace.as_slice()).unwrap();
+        assert_eq!(reader.header(), TraceHeader::default());
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn truncated_record_and_newer_version_are_rejected() {
+        let record = TraceRecord {
+            step: 1,
+            erip: Some(0x1000),
+            ..Default::default()
+        };
+        let trace = write_trace(TraceHeader::default(), &[record]);
+        let mut reader = TraceReader::new(&trace[..trace.len() - 1]).unwrap();
+        let error = reader.next().unwrap().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let mut newer = trace.clone();
+        newer[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let error = TraceReader::new(newer.as_slice()).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
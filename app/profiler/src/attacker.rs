@@ -0,0 +1,766 @@
+//! Attacker models used by the TLBlur simulator
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    ops::Range,
+    str::FromStr,
+};
+
+use clap::ValueEnum;
+use rand::{rngs::StdRng, Rng};
+use serde::{Deserialize, Serialize};
+use sgx_step::page::RelativePage;
+
+use crate::{
+    bitmap::PageBitmap,
+    dump::{TracePageSet, VCDEntry},
+    page_ranges,
+    simulator::DeterministicState,
+    tlb::HardwareTLB,
+    PageAccess,
+};
+
+/// Page accesses observed through the PTE A/D bits since they were last cleared
+///
+/// ```
+/// use rand::{rngs::StdRng, SeedableRng};
+/// use sgx_profiler::{
+///     attacker::PageTableObservations,
+///     sgx_step::page::RelativePage,
+///     tlb::{HardwareTLB, HardwareTLBConfig},
+///     PageAccess, Perms,
+/// };
+///
+/// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+/// let mut observations = PageTableObservations::new();
+/// observations.update([read(1), read(2), read(3)].iter());
+/// assert_eq!(observations.len(), 3);
+///
+/// let mut tlb = HardwareTLB::from(HardwareTLBConfig::Perfect { capacity: None });
+/// tlb.update([read(2)].iter(), &mut StdRng::seed_from_u64(0));
+/// let mut missed = observations.subtract_tlb(&tlb).map(|p| p.page.get()).collect::<Vec<_>>();
+/// missed.sort();
+/// assert_eq!(missed, [1, 3]);
+///
+/// let live = [RelativePage::new(1), RelativePage::new(3)];
+/// let faulting = observations.subtract_pages(&live).map(|p| p.page.get()).collect::<Vec<_>>();
+/// assert_eq!(faulting, [2]);
+/// ```
+#[derive(Debug, Default)]
+pub struct PageTableObservations {
+    state: HashMap<RelativePage, PageAccess, DeterministicState>,
+}
+
+impl PageTableObservations {
+    pub fn new() -> Self {
+        Self {
+            state: HashMap::default(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.state.clear()
+    }
+
+    pub fn update<'a>(&mut self, pages: impl Iterator<Item = &'a PageAccess>) {
+        for page in pages {
+            self.state
+                .entry(page.page)
+                .and_modify(|e| *e = e.union(page))
+                .or_insert(page.to_owned());
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PageAccess> {
+        self.state.values()
+    }
+
+    /// Number of observed pages
+    pub fn len(&self) -> usize {
+        self.state.len()
+    }
+
+    /// The observed pages as ranges of consecutive pages, see [`page_ranges`]
+    ///
+    /// ```
+    /// use sgx_profiler::{
+    ///     attacker::PageTableObservations, sgx_step::page::RelativePage, PageAccess, Perms,
+    /// };
+    ///
+    /// let access = |page, perms| PageAccess::new(RelativePage::new(page), perms);
+    /// let mut observations = PageTableObservations::new();
+    /// observations.update([access(11, Perms::READ), access(10, Perms::WRITE)].iter());
+    /// observations.update([access(10, Perms::READ), access(13, Perms::READ)].iter());
+    /// assert_eq!(observations.accessed_ranges(), [10..12, 13..14]);
+    /// ```
+    pub fn accessed_ranges(&self) -> Vec<Range<usize>> {
+        page_ranges(self.state.keys().map(|page| page.get()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.is_empty()
+    }
+
+    /// The observed pages as a bitmap, e.g. to compare the observations of many steps
+    pub fn bitmap(&self) -> PageBitmap {
+        self.state.keys().map(|page| page.get()).collect()
+    }
+
+    /// Observations that are not covered by the hardware TLB
+    pub fn subtract_tlb<'a>(
+        &'a self,
+        hw_tlb: &'a HardwareTLB,
+    ) -> impl Iterator<Item = &'a PageAccess> {
+        self.iter().filter(|p| !hw_tlb.test(p))
+    }
+
+    /// Observations of pages that are not in `pages`
+    pub fn subtract_pages<'a>(
+        &'a self,
+        pages: &'a [RelativePage],
+    ) -> impl Iterator<Item = &'a PageAccess> {
+        self.iter().filter(|p| !pages.contains(&p.page))
+    }
+}
+
+/// Interrupt pattern of the simulated attacker
+///
+/// Patterns and attackers are parsed from the strings they are displayed as, and
+/// every attacker has the pattern it is created from. Patterns are serialized as
+/// these strings too.
+///
+/// ```
+/// use clap::ValueEnum;
+/// use sgx_profiler::attacker::{Attacker, InterruptPattern};
+///
+/// for &pattern in InterruptPattern::value_variants() {
+///     let name = pattern.to_string();
+///     assert_eq!(name.parse::<InterruptPattern>(), Ok(pattern));
+///     let json = serde_json::to_string(&pattern).unwrap();
+///     assert_eq!(json, format!("\"{name}\""));
+///     assert_eq!(serde_json::from_str::<InterruptPattern>(&json).unwrap(), pattern);
+///
+///     let attacker = Attacker::from(pattern);
+///     assert_eq!(attacker.to_string(), name);
+///     assert_eq!(InterruptPattern::from(&attacker), pattern);
+///     assert_eq!(name.parse::<Attacker>(), Ok(attacker));
+/// }
+/// assert!("single_step".parse::<InterruptPattern>().is_err());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InterruptPattern {
+    DebugSingleStep,
+    SingleStep,
+    PageFault,
+    CoarsePageFault,
+    Stealthy,
+}
+
+impl Display for InterruptPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::DebugSingleStep => "debug-single-step",
+            Self::SingleStep => "single-step",
+            Self::PageFault => "page-fault",
+            Self::CoarsePageFault => "coarse-page-fault",
+            Self::Stealthy => "stealthy",
+        })
+    }
+}
+
+impl FromStr for InterruptPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::value_variants()
+            .iter()
+            .copied()
+            .find(|pattern| pattern.to_string() == s)
+            .ok_or_else(|| format!("unknown interrupt pattern `{s}`"))
+    }
+}
+
+impl From<&Attacker> for InterruptPattern {
+    fn from(value: &Attacker) -> Self {
+        match value {
+            Attacker::DebugSingleStep => Self::DebugSingleStep,
+            Attacker::SingleStep => Self::SingleStep,
+            Attacker::PageFault { .. } => Self::PageFault,
+            Attacker::CoarsePageFault { .. } => Self::CoarsePageFault,
+            Attacker::Stealthy { .. } => Self::Stealthy,
+        }
+    }
+}
+
+/// Capabilities of the simulated attacker
+#[derive(Debug, Clone, PartialEq)]
+pub enum Attacker {
+    DebugSingleStep,
+    SingleStep,
+    PageFault {
+        live_pages: Vec<RelativePage>,
+        observe_ptes: bool,
+    },
+    /// Only unmaps the pages of the enclave every `period` steps, and faults on the
+    /// next page that misses the hardware TLB after that.
+    ///
+    /// Accesses made in between accumulate in the PTE A/D bits and are observed in
+    /// a single step on the fault, so this attacker sees the page accesses at a coarser
+    /// temporal granularity than a [`Attacker::PageFault`] attacker, but still
+    /// interrupts unlike a [`Attacker::Stealthy`] one. `steps` counts the steps since
+    /// the last fault.
+    ///
+    /// ```
+    /// use sgx_profiler::{
+    ///     attacker::Attacker,
+    ///     backend::{MockBackend, MockStep},
+    ///     golden::Trace,
+    ///     simulator::TlblurLayout,
+    ///     sgx_step::page::RelativePage,
+    ///     PageAccess, Perms, SimulatorBuilder,
+    /// };
+    ///
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let access = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+    /// let backend = MockBackend::new(
+    ///     64,
+    ///     [10, 11, 12, 13, 14].map(|page| MockStep {
+    ///         accesses: vec![access(page)],
+    ///         ..Default::default()
+    ///     }),
+    /// );
+    /// let layout = TlblurLayout::mock(64);
+    ///
+    /// let path = dir.path().join("coarse-page-fault.vcd");
+    /// let summary = {
+    ///     let mut simulator = SimulatorBuilder::new(&path)
+    ///         .attacker(Attacker::CoarsePageFault { period: 2, steps: 0 })
+    ///         .prefetch(false)
+    ///         .build_with_backend(backend, layout)
+    ///         .unwrap();
+    ///     simulator.replay().unwrap();
+    ///     simulator.summary()
+    /// };
+    /// // Faults after every second step, observing the accesses of both steps
+    /// assert_eq!((summary.steps, summary.interrupts, summary.observations), (5, 2, 2));
+    /// let trace = Trace::read(&path).unwrap();
+    /// let accessed = |ts: u64| {
+    ///     let mut pages = trace.changes[&ts]
+    ///         .iter()
+    ///         .filter(|(_, value)| value == "1")
+    ///         .map(|(name, _)| name.as_str())
+    ///         .collect::<Vec<_>>();
+    ///     pages.sort();
+    ///     pages
+    /// };
+    /// assert_eq!(accessed(0), ["trace._10", "trace._11"]);
+    /// assert_eq!(accessed(1), ["trace._12", "trace._13"]);
+    /// ```
+    CoarsePageFault {
+        period: usize,
+        steps: usize,
+    },
+    /// Never interrupts, but reads and clears the PTE A/D bits every `clear_interval` steps.
+    ///
+    /// Accesses made in between are coalesced into a single observation.
+    Stealthy {
+        clear_interval: usize,
+        steps: usize,
+    },
+}
+
+impl From<InterruptPattern> for Attacker {
+    fn from(value: InterruptPattern) -> Self {
+        match value {
+            InterruptPattern::DebugSingleStep => Attacker::DebugSingleStep,
+            InterruptPattern::SingleStep => Attacker::SingleStep,
+            InterruptPattern::PageFault => Attacker::PageFault {
+                live_pages: Vec::new(),
+                observe_ptes: true,
+            },
+            InterruptPattern::CoarsePageFault => Attacker::CoarsePageFault {
+                period: 1,
+                steps: 0,
+            },
+            InterruptPattern::Stealthy => Attacker::Stealthy {
+                clear_interval: 1,
+                steps: 0,
+            },
+        }
+    }
+}
+
+impl Display for Attacker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        InterruptPattern::from(self).fmt(f)
+    }
+}
+
+/// Parses the default attacker of an [`InterruptPattern`]
+impl FromStr for Attacker {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<InterruptPattern>().map(Self::from)
+    }
+}
+
+/// Models an imperfect attacker that misses some of its observations.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoiseModel {
+    /// Probability that an observed page access is not seen by the attacker
+    pub drop_probability: f64,
+}
+
+impl NoiseModel {
+    /// Filter the given observations, dropping each page with `drop_probability`.
+    ///
+    /// The RNG is only consumed if the drop probability is nonzero.
+    pub fn apply<'a>(
+        &self,
+        pages: impl Iterator<Item = &'a PageAccess>,
+        rng: &mut StdRng,
+    ) -> Vec<PageAccess> {
+        if self.drop_probability > 0. {
+            pages
+                .filter(|_| !rng.gen_bool(self.drop_probability))
+                .copied()
+                .collect()
+        } else {
+            pages.copied().collect()
+        }
+    }
+}
+
+/// Models an enclave that uses AEX-Notify to restore its working set after every
+/// interrupt, like the `--aexnotify` mode of the libjpeg attack.
+///
+/// With AEX-Notify, the enclave runs a handler before it resumes from an AEX. The
+/// modelled handler touches the `working_set` most recently accessed pages, so they
+/// are cached in the hardware TLB again and the next instructions that access them
+/// cannot be interrupted by an attacker that relies on TLB misses. The accesses of
+/// the handler set the PTE A bits like those of the TLBlur prefetcher, so the
+/// attacker still sees the restored pages, but it has fewer chances to interrupt.
+#[derive(Debug, Clone, Default)]
+pub struct AexNotify {
+    working_set: usize,
+    pages: VecDeque<PageAccess>,
+}
+
+impl AexNotify {
+    /// Restore the `working_set` most recently accessed pages after an interrupt
+    pub fn new(working_set: usize) -> Self {
+        Self {
+            working_set,
+            pages: VecDeque::with_capacity(working_set + 1),
+        }
+    }
+
+    /// Record the pages accessed in a step, keeping the most recent ones
+    pub(crate) fn record(&mut self, accessed: &[PageAccess]) {
+        for access in accessed {
+            self.pages.retain(|p| p.page != access.page);
+            self.pages.push_back(*access);
+            if self.pages.len() > self.working_set {
+                self.pages.pop_front();
+            }
+        }
+    }
+
+    /// Pages the handler accesses before resuming from an interrupt
+    pub(crate) fn restore(&self) -> impl Iterator<Item = &PageAccess> {
+        self.pages.iter()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CanObserve {
+    Always,
+    Interrupt,
+    Never,
+}
+
+impl Attacker {
+    /// Given the behaviour of the attacker, the state of the HW TLB and the pages
+    /// accessed in this step, would the attacker be able to interrupt at this point.
+    pub(crate) fn can_trigger_interrupt(
+        &self,
+        accessed: &[PageAccess],
+        hw_tlb: &HardwareTLB,
+    ) -> bool {
+        match self {
+            Attacker::DebugSingleStep => {
+                // A single-stepping adversary can always interrupt, regardless of the state of
+                // the hardware TLB.
+                //
+                // This is an unrealistic attacker model, as our defense prohibits such behavior
+                //
+                // An attack like this would require an enclave in debug mode with trap interrupts
+                true
+            }
+            Attacker::SingleStep => {
+                // We assume that this attacker can interrupt if there is some page accessed
+                // (change in PTE A bit) that was not in the hardware TLB, or written
+                // while cached for reading only (change in PTE D bit).
+                //
+                // This is essentially the SGX-Step attacker
+                accessed.iter().any(|p| !hw_tlb.test(p))
+            }
+            Attacker::PageFault { live_pages, .. } => {
+                // The page fault attacker is like the single-stepping attacker, but has a
+                // set of live pages that are mapped. An interrupt can only be triggered
+                // by this attacker if there is a page that is not in the hardware TLB
+                // *and* not in the set of pages that the attacker made accessible.
+
+                accessed
+                    .iter()
+                    .any(|p| !hw_tlb.test(p) && !live_pages.contains(&p.page))
+            }
+            Attacker::CoarsePageFault { period, steps } => {
+                // The pages are only unmapped once the period has passed, after which
+                // the first page that is not in the hardware TLB faults
+                steps + 1 >= *period && accessed.iter().any(|p| !hw_tlb.test(p))
+            }
+            Attacker::Stealthy { .. } => {
+                // The stealthy attacker only observes changes to PTE bits, but never interrupts
+                false
+            }
+        }
+    }
+
+    /// Write the observations of the attacker to the trace, returning the observed
+    /// page accesses.
+    ///
+    /// Accesses to the `hidden` ranges of pages are never written.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn observe<'d, S: TracePageSet>(
+        &self,
+        entry: &mut VCDEntry<'d, S>,
+        accessed: &[PageAccess],
+        hw_tlb: &HardwareTLB,
+        observations: &mut PageTableObservations,
+        noise: &NoiseModel,
+        hidden: &[Range<usize>],
+        rng: &mut StdRng,
+    ) -> Vec<PageAccess> {
+        let visible = |p: &&PageAccess| !hidden.iter().any(|r| r.contains(&p.page.get()));
+        let observed = match self {
+            Attacker::PageFault {
+                ref live_pages,
+                observe_ptes: false,
+            } => noise.apply(
+                accessed
+                    .iter()
+                    .filter(|p| !hw_tlb.test(p) && !live_pages.contains(&p.page))
+                    .filter(visible),
+                rng,
+            ),
+            _ => noise.apply(observations.iter().filter(visible), rng),
+        };
+        entry.write_page_accesses(observed.iter());
+        observed
+    }
+
+    /// Returns true if the interrupts of this attacker are exceptions raised by the
+    /// enclave, rather than asynchronous interrupts.
+    pub(crate) fn interrupts_with_exception(&self) -> bool {
+        match self {
+            // Debug traps and page faults are raised by the interrupted instruction
+            Attacker::DebugSingleStep
+            | Attacker::PageFault { .. }
+            | Attacker::CoarsePageFault { .. } => true,
+            // SGX-Step interrupts the enclave with the APIC timer
+            Attacker::SingleStep | Attacker::Stealthy { .. } => false,
+        }
+    }
+
+    pub(crate) fn can_observe(&self) -> CanObserve {
+        match self {
+            // Stealthy attacker sees everything without interrupts,
+            // but only when it clears the PTE bits
+            Attacker::Stealthy {
+                clear_interval,
+                steps,
+            } => {
+                if (steps + 1) % (*clear_interval).max(1) == 0 {
+                    CanObserve::Always
+                } else {
+                    CanObserve::Never
+                }
+            }
+            // Other attackers only observe on interrupt
+            _ => CanObserve::Interrupt,
+        }
+    }
+
+    pub(crate) fn handle_step(&mut self, observations: &mut PageTableObservations) {
+        match self {
+            Attacker::Stealthy {
+                clear_interval,
+                steps,
+            } => {
+                *steps += 1;
+                if *steps % (*clear_interval).max(1) == 0 {
+                    observations.clear()
+                }
+            }
+            // Observations accumulate until the next fault
+            Attacker::CoarsePageFault { steps, .. } => *steps += 1,
+            _ => {}
+        }
+    }
+
+    pub(crate) fn handle_interrupt(
+        &mut self,
+        accessed: &[PageAccess],
+        observations: &mut PageTableObservations,
+    ) {
+        match self {
+            Attacker::PageFault {
+                ref mut live_pages, ..
+            } => {
+                // This attacker maps the pages that are necessary for the current instruction
+                // to execute. It can then not trigger page faults on those pages, so
+                // we record it in the live pages set to remember the current capabilities of
+                // the attacker.
+                live_pages.clear();
+                for page in accessed {
+                    live_pages.push(page.page);
+                }
+                observations.clear();
+            }
+            Attacker::CoarsePageFault { ref mut steps, .. } => {
+                // The pages are mapped again until the next period has passed
+                *steps = 0;
+                observations.clear();
+            }
+            Attacker::Stealthy { .. } => {}
+            _ => {
+                // All other attackers clear PTE bits as often as possible
+                observations.clear();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::{tlb::HardwareTLBConfig, Perms};
+
+    fn access(page: usize, perms: Perms) -> PageAccess {
+        PageAccess::new(RelativePage::new(page), perms)
+    }
+
+    fn sorted_pages<'a>(pages: impl Iterator<Item = &'a PageAccess>) -> Vec<usize> {
+        let mut pages = pages.map(|p| p.page.get()).collect::<Vec<_>>();
+        pages.sort();
+        pages
+    }
+
+    #[test]
+    fn patterns_round_trip_through_strings() {
+        let names = InterruptPattern::value_variants()
+            .iter()
+            .map(|pattern| pattern.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "debug-single-step",
+                "single-step",
+                "page-fault",
+                "coarse-page-fault",
+                "stealthy"
+            ]
+        );
+        for (&pattern, name) in InterruptPattern::value_variants().iter().zip(&names) {
+            assert_eq!(name.parse(), Ok(pattern));
+            // Strings are parsed the same way by clap
+            assert_eq!(
+                <InterruptPattern as ValueEnum>::from_str(name, false),
+                Ok(pattern)
+            );
+            let json = serde_json::to_string(&pattern).unwrap();
+            assert_eq!(json, format!("\"{name}\""));
+            assert_eq!(
+                serde_json::from_str::<InterruptPattern>(&json).unwrap(),
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn attackers_round_trip_through_patterns() {
+        let configured = [
+            Attacker::DebugSingleStep,
+            Attacker::SingleStep,
+            Attacker::PageFault {
+                live_pages: vec![RelativePage::new(3)],
+                observe_ptes: false,
+            },
+            Attacker::CoarsePageFault {
+                period: 4,
+                steps: 2,
+            },
+            Attacker::Stealthy {
+                clear_interval: 8,
+                steps: 1,
+            },
+        ];
+        for (attacker, &pattern) in configured.iter().zip(InterruptPattern::value_variants()) {
+            // The configuration of an attacker does not change its pattern or name
+            assert_eq!(InterruptPattern::from(attacker), pattern);
+            assert_eq!(attacker.to_string(), pattern.to_string());
+
+            // but parsing gives the default attacker of the pattern
+            let parsed = attacker.to_string().parse::<Attacker>().unwrap();
+            assert_eq!(parsed, Attacker::from(pattern));
+            assert_eq!(InterruptPattern::from(&parsed), pattern);
+        }
+    }
+
+    #[test]
+    fn unknown_patterns_are_rejected() {
+        for name in ["", "single_step", "Single-Step", "page-fault ", "zero-step"] {
+            assert_eq!(
+                name.parse::<InterruptPattern>(),
+                Err(format!("unknown interrupt pattern `{name}`"))
+            );
+            assert!(name.parse::<Attacker>().is_err());
+        }
+        assert!(serde_json::from_str::<InterruptPattern>(r#""nemesis""#).is_err());
+    }
+
+    #[test]
+    fn observations_of_a_page_are_merged() {
+        let mut observations = PageTableObservations::new();
+        assert!(observations.is_empty());
+        observations.update([access(1, Perms::READ), access(2, Perms::READ)].iter());
+        observations.update([access(1, Perms::WRITE)].iter());
+        assert_eq!(observations.len(), 2);
+        let first = observations.iter().find(|p| p.page.get() == 1).unwrap();
+        assert_eq!(first.perms, Perms::READ | Perms::WRITE);
+
+        observations.clear();
+        assert_eq!(observations.len(), 0);
+        assert!(observations.is_empty());
+    }
+
+    #[test]
+    fn accessed_ranges_coalesce_the_observed_pages() {
+        let mut observations = PageTableObservations::new();
+        assert!(observations.accessed_ranges().is_empty());
+        observations.update([6, 2, 4].map(|page| access(page, Perms::READ)).iter());
+        assert_eq!(observations.accessed_ranges(), [2..3, 4..5, 6..7]);
+        // Pages between observed ones join their ranges, whatever the permissions
+        observations.update([access(3, Perms::WRITE), access(5, Perms::EXECUTE)].iter());
+        assert_eq!(observations.accessed_ranges(), vec![2..7]);
+        assert_eq!(observations.bitmap(), (2..7).collect());
+    }
+
+    #[test]
+    fn subtract_tlb_keeps_misses_and_upgrades() {
+        let mut observations = PageTableObservations::new();
+        observations.update(
+            [
+                access(1, Perms::READ),
+                access(2, Perms::READ),
+                access(3, Perms::READ | Perms::WRITE),
+            ]
+            .iter(),
+        );
+
+        let mut tlb = HardwareTLB::from(HardwareTLBConfig::Perfect { capacity: None });
+        assert_eq!(sorted_pages(observations.subtract_tlb(&tlb)), [1, 2, 3]);
+
+        // Page 3 is only cached for reading, so its write is still missed
+        tlb.update(
+            [access(2, Perms::READ), access(3, Perms::READ)].iter(),
+            &mut StdRng::seed_from_u64(0),
+        );
+        assert_eq!(sorted_pages(observations.subtract_tlb(&tlb)), [1, 3]);
+
+        tlb.update(
+            [access(1, Perms::READ), access(3, Perms::WRITE)].iter(),
+            &mut StdRng::seed_from_u64(0),
+        );
+        assert_eq!(observations.subtract_tlb(&tlb).count(), 0);
+    }
+
+    #[test]
+    fn subtract_pages_ignores_permissions() {
+        let mut observations = PageTableObservations::new();
+        observations.update(
+            [
+                access(1, Perms::READ),
+                access(2, Perms::WRITE),
+                access(3, Perms::EXECUTE),
+            ]
+            .iter(),
+        );
+
+        assert_eq!(sorted_pages(observations.subtract_pages(&[])), [1, 2, 3]);
+        let live = [RelativePage::new(2), RelativePage::new(4)];
+        assert_eq!(sorted_pages(observations.subtract_pages(&live)), [1, 3]);
+        let all = [1, 2, 3].map(RelativePage::new);
+        assert_eq!(observations.subtract_pages(&all).count(), 0);
+    }
+
+    #[test]
+    fn aex_notify_restores_the_most_recently_accessed_pages() {
+        let mut aex_notify = AexNotify::new(3);
+        assert_eq!(aex_notify.restore().count(), 0);
+        aex_notify.record(&[access(1, Perms::READ), access(2, Perms::READ)]);
+        aex_notify.record(&[access(3, Perms::READ), access(4, Perms::WRITE)]);
+        assert_eq!(sorted_pages(aex_notify.restore()), [2, 3, 4]);
+
+        // Accessing a page again makes it the most recent one, with its new permissions
+        aex_notify.record(&[access(2, Perms::EXECUTE), access(5, Perms::READ)]);
+        assert_eq!(sorted_pages(aex_notify.restore()), [2, 4, 5]);
+        let restored = aex_notify.restore().collect::<Vec<_>>();
+        assert_eq!(restored.last().unwrap().page.get(), 5);
+        assert!(restored.contains(&&access(2, Perms::EXECUTE)));
+
+        let mut disabled = AexNotify::new(0);
+        disabled.record(&[access(1, Perms::READ)]);
+        assert_eq!(disabled.restore().count(), 0);
+    }
+
+    #[test]
+    fn coarse_page_fault_waits_for_the_period() {
+        let mut attacker = Attacker::CoarsePageFault {
+            period: 3,
+            steps: 0,
+        };
+        let mut observations = PageTableObservations::new();
+        let mut tlb = HardwareTLB::from(HardwareTLBConfig::Perfect { capacity: None });
+        let accessed = [access(1, Perms::READ)];
+
+        for period in 0..3 {
+            for step in 0..3 {
+                let can_fault = attacker.can_trigger_interrupt(&accessed, &tlb);
+                assert_eq!(can_fault, step == 2, "step {step} of period {period}");
+                observations.update(accessed.iter());
+                attacker.handle_step(&mut observations);
+                // Accesses accumulate until the fault
+                assert!(!observations.is_empty());
+            }
+            attacker.handle_interrupt(&accessed, &mut observations);
+            assert!(observations.is_empty());
+        }
+
+        // Once the period has passed, only a page that misses the TLB faults
+        for _ in 0..3 {
+            attacker.handle_step(&mut observations);
+        }
+        tlb.update(accessed.iter(), &mut StdRng::seed_from_u64(0));
+        assert!(!attacker.can_trigger_interrupt(&accessed, &tlb));
+        assert!(attacker.can_trigger_interrupt(&[access(2, Perms::READ)], &tlb));
+        assert!(attacker.interrupts_with_exception());
+    }
+}
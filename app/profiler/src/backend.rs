@@ -0,0 +1,558 @@
+//! Sources of the page accesses and PAM state read by the simulator
+//!
+//! [`HardwareBackend`] reads them from a live enclave, [`MockBackend`] replays a
+//! scripted sequence of steps so that the simulator can be run without SGX.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, BufRead},
+    ops::Range,
+};
+
+use sgx_step::page::{RelativePage, PAGE_SIZE};
+#[cfg(feature = "sgx")]
+use sgx_step::{memory::EnclaveMemory, sgx_step_sys::edbgrd_erip, EnclaveRef};
+
+use crate::{
+    dump::{PamRecord, TraceHeader, TraceReader},
+    PageAccess, Perms, ProfilerError,
+};
+#[cfg(feature = "sgx")]
+use crate::{resolve_symbol_pages, PageTable};
+
+/// Source of page accesses and PAM state for the simulator
+pub trait Backend {
+    /// Called at the start of every step, before any state is read.
+    fn next_step(&mut self) {}
+
+    /// Record the pages accessed since the last call to `clear_accesses`.
+    fn update_page_accesses(&mut self);
+
+    /// Pages accessed in the last step, as recorded by `update_page_accesses`.
+    fn accessed_pages(&self) -> &[PageAccess];
+
+    /// Reset the accessed pages, so the next step only records its own accesses.
+    fn clear_accesses(&mut self);
+
+    /// Read the global PAM counter of the instrumented enclave.
+    ///
+    /// Returns `ProfilerError::PamRead` if the enclave memory can not be read.
+    fn read_pam_counter(&mut self) -> Result<u64, ProfilerError>;
+
+    /// Read the PAM of the instrumented enclave, which holds one counter per page.
+    ///
+    /// Returns `ProfilerError::PamRead` if the enclave memory can not be read.
+    fn read_pam(&mut self, pam: &mut [u64]) -> Result<(), ProfilerError>;
+
+    /// Page containing the stack pointer of the interrupted enclave thread, if any.
+    fn stack_page(&self) -> Option<RelativePage>;
+
+    /// Absolute address of the next instruction of the interrupted enclave thread.
+    fn erip(&self) -> usize;
+
+    /// Address of the enclave base.
+    fn base(&self) -> usize;
+
+    /// Range of pages, relative to the enclave base, covered by the given enclave symbol.
+    fn symbol_pages(&self, name: &str) -> Option<Range<usize>>;
+
+    /// Enclave page containing the given absolute address, if any.
+    fn page_containing(&self, address: usize) -> Option<RelativePage>;
+}
+
+/// Reads page accesses from the page table and the PAM from enclave memory
+#[cfg(feature = "sgx")]
+#[derive(Debug)]
+pub struct HardwareBackend {
+    page_table: PageTable,
+    pam_memory: EnclaveMemory,
+    pam_counter_memory: EnclaveMemory,
+    enclave: EnclaveRef,
+}
+
+#[cfg(feature = "sgx")]
+impl HardwareBackend {
+    pub fn new(
+        enclave: &EnclaveRef,
+        page_table: PageTable,
+        pam_address: u64,
+        pam_counter_address: u64,
+    ) -> Self {
+        Self {
+            page_table,
+            pam_memory: EnclaveMemory::in_enclave(enclave, pam_address as usize),
+            pam_counter_memory: EnclaveMemory::in_enclave(enclave, pam_counter_address as usize),
+            // Don't do this, this is a hacky way to get around Rust's aliasing rules
+            enclave: unsafe { EnclaveRef::from_raw(enclave.id()) },
+        }
+    }
+
+    pub fn page_table(&self) -> &PageTable {
+        &self.page_table
+    }
+
+    pub fn enclave(&self) -> &EnclaveRef {
+        &self.enclave
+    }
+}
+
+#[cfg(feature = "sgx")]
+impl Backend for HardwareBackend {
+    fn update_page_accesses(&mut self) {
+        self.page_table.update_page_accesses();
+    }
+
+    fn accessed_pages(&self) -> &[PageAccess] {
+        &self.page_table.pages
+    }
+
+    fn clear_accesses(&mut self) {
+        self.page_table.clear_all_ad_bits();
+    }
+
+    fn read_pam_counter(&mut self) -> Result<u64, ProfilerError> {
+        let mut counter = [0u64];
+        self.pam_counter_memory
+            .read_into(&mut counter)
+            .map_err(ProfilerError::PamRead)?;
+        Ok(counter[0])
+    }
+
+    fn read_pam(&mut self, pam: &mut [u64]) -> Result<(), ProfilerError> {
+        self.pam_memory
+            .read_into(pam)
+            .map_err(ProfilerError::PamRead)
+    }
+
+    fn stack_page(&self) -> Option<RelativePage> {
+        let stack_ptr = self.enclave.registers().ok()?.rsp;
+        RelativePage::containing(stack_ptr as usize, &self.enclave)
+    }
+
+    fn erip(&self) -> usize {
+        unsafe { edbgrd_erip() as usize }
+    }
+
+    fn base(&self) -> usize {
+        self.enclave.base() as usize
+    }
+
+    fn symbol_pages(&self, name: &str) -> Option<Range<usize>> {
+        resolve_symbol_pages(&self.enclave, name).ok()
+    }
+
+    fn page_containing(&self, address: usize) -> Option<RelativePage> {
+        RelativePage::containing(address, &self.enclave)
+    }
+}
+
+/// A single scripted step of a [`MockBackend`]
+#[derive(Debug, Clone, Default)]
+pub struct MockStep {
+    /// Pages accessed by the instruction executed in this step,
+    /// optionally with the cache lines that were accessed
+    pub accesses: Vec<PageAccess>,
+    /// Pages the instrumentation records in the PAM before this step
+    pub pam_updates: Vec<RelativePage>,
+    /// PAM counter and changed PAM entries before this step, as recorded in a
+    /// schedule, applied before the `pam_updates`
+    pub pam: Option<PamRecord>,
+    pub stack_page: Option<RelativePage>,
+    /// Address of the instruction executed in this step, relative to the base of
+    /// the backend
+    pub erip: usize,
+}
+
+/// Backend that replays a scripted sequence of steps
+///
+/// Every step of the simulator after the first one, which only primes the
+/// simulator, consumes one [`MockStep`]. Once the script is exhausted, no more
+/// pages are accessed.
+///
+/// ```
+/// use sgx_profiler::{
+///     attacker::Attacker,
+///     backend::{MockBackend, MockStep},
+///     simulator::TlblurLayout,
+///     sgx_step::page::RelativePage,
+///     PageAccess, Perms, SimulatorBuilder,
+/// };
+///
+/// let dir = tempfile::tempdir().unwrap();
+/// let access = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+/// let backend = MockBackend::new(
+///     64,
+///     [vec![access(10)], vec![access(10)], vec![access(11)]]
+///         .into_iter()
+///         .map(|accesses| MockStep {
+///             accesses,
+///             ..Default::default()
+///         }),
+/// );
+/// let layout = TlblurLayout::mock(64);
+///
+/// let trace = dir.path().join("mock-trace.vcd");
+/// let mut simulator = SimulatorBuilder::new(&trace)
+///     .attacker(Attacker::SingleStep)
+///     .build_with_backend(backend, layout)
+///     .unwrap();
+/// // The first step only primes the simulator
+/// for _ in 0..4 {
+///     simulator.step().unwrap();
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct MockBackend {
+    script: VecDeque<MockStep>,
+    current: MockStep,
+    pam: Vec<u64>,
+    pam_counter: u64,
+    /// Erip once the script is exhausted
+    final_erip: usize,
+    /// Address of the enclave base, which the erips of the script are relative to
+    base: usize,
+    symbols: HashMap<String, Range<usize>>,
+}
+
+impl MockBackend {
+    pub fn new(num_pages: usize, script: impl IntoIterator<Item = MockStep>) -> Self {
+        Self {
+            // The accesses of the first step are discarded when priming the simulator
+            script: std::iter::once(MockStep::default()).chain(script).collect(),
+            current: MockStep::default(),
+            pam: vec![0; num_pages],
+            pam_counter: 0,
+            final_erip: 0,
+            base: 0,
+            symbols: HashMap::new(),
+        }
+    }
+
+    /// Replay the steps of a record trace, e.g. written by `sgx_tracer --records`,
+    /// which holds every page accessed by the enclave in every step.
+    ///
+    /// Returns the backend and the number of pages of the traced enclave. The PAM
+    /// is not recorded, so it is never updated and PAM prefetching has no effect,
+    /// unless the trace is a schedule recorded by
+    /// [`SimulatorBuilder::record_schedule`](crate::SimulatorBuilder::record_schedule).
+    /// The backend then reads what the simulator read from the enclave, starting
+    /// with the step that primed it.
+    ///
+    /// ```
+    /// use sgx_profiler::{
+    ///     backend::{Backend, MockBackend},
+    ///     dump::{PageRecord, TraceHeader, TraceReader, TraceRecord, TraceWriter},
+    ///     Perms,
+    /// };
+    ///
+    /// let mut trace = Vec::new();
+    /// let header = TraceHeader { num_pages: 16, has_counts: false, schedule: false };
+    /// let mut writer = TraceWriter::new(&mut trace, header).unwrap();
+    /// let page = PageRecord { page: 3, perms: PageRecord::WRITE, count: 1 };
+    /// let record = TraceRecord {
+    ///     step: 0,
+    ///     erip: Some(0x3000),
+    ///     pages: vec![page],
+    ///     ..Default::default()
+    /// };
+    /// writer.write(&record).unwrap();
+    ///
+    /// let reader = TraceReader::new(trace.as_slice()).unwrap();
+    /// let (mut backend, num_pages) = MockBackend::from_records(reader).unwrap();
+    /// assert_eq!(num_pages, 16);
+    /// // The priming step, then the recorded one
+    /// backend.next_step();
+    /// backend.next_step();
+    /// assert_eq!(backend.accessed_pages()[0].page.get(), 3);
+    /// assert_eq!(backend.accessed_pages()[0].perms, Perms::WRITE);
+    /// assert!(backend.is_done());
+    /// ```
+    pub fn from_records<R: BufRead>(reader: TraceReader<R>) -> io::Result<(Self, usize)> {
+        let TraceHeader {
+            num_pages,
+            schedule,
+            ..
+        } = reader.header();
+        // The erip of a record is where the step was interrupted, i.e. the
+        // instruction executed in the next step
+        let mut erip = 0;
+        let script = reader
+            .map(|record| {
+                let record = record?;
+                let step = MockStep {
+                    accesses: record
+                        .pages
+                        .iter()
+                        .map(|page| {
+                            PageAccess::new(
+                                RelativePage::new(page.page),
+                                Perms::from_bits_truncate(page.perms),
+                            )
+                        })
+                        .collect(),
+                    pam: record.pam,
+                    stack_page: record.stack_page.map(RelativePage::new),
+                    erip,
+                    ..Default::default()
+                };
+                erip = record.erip.unwrap_or(0) as usize;
+                Ok(step)
+            })
+            .collect::<io::Result<VecDeque<_>>>()?;
+        let mut backend = Self::new(num_pages, []);
+        if schedule {
+            // The first record is the priming step
+            backend.script = script;
+        } else {
+            backend.script.extend(script);
+        }
+        backend.final_erip = erip;
+        Ok((backend, num_pages))
+    }
+
+    /// Base the scripted enclave at `base` instead of address zero, e.g. to replay a
+    /// schedule with the symbols of an enclave that was created again.
+    pub fn with_base(mut self, base: usize) -> Self {
+        self.base = base;
+        self
+    }
+
+    /// Define an enclave symbol covering the given range of pages
+    pub fn with_symbol(mut self, name: &str, pages: Range<usize>) -> Self {
+        self.symbols.insert(name.to_owned(), pages);
+        self
+    }
+
+    /// Returns true if all scripted steps were replayed
+    pub fn is_done(&self) -> bool {
+        self.script.is_empty()
+    }
+}
+
+impl Backend for MockBackend {
+    fn next_step(&mut self) {
+        self.current = self.script.pop_front().unwrap_or_default();
+        if let Some(pam) = &self.current.pam {
+            self.pam_counter = pam.counter;
+            for &(entry, value) in &pam.entries {
+                if let Some(entry) = self.pam.get_mut(entry) {
+                    *entry = value;
+                }
+            }
+        }
+        if !self.current.pam_updates.is_empty() {
+            self.pam_counter += 1;
+            for page in &self.current.pam_updates {
+                self.pam[page.get()] = self.pam_counter;
+            }
+        }
+    }
+
+    fn update_page_accesses(&mut self) {}
+
+    fn accessed_pages(&self) -> &[PageAccess] {
+        &self.current.accesses
+    }
+
+    fn clear_accesses(&mut self) {
+        self.current.accesses.clear();
+    }
+
+    fn read_pam_counter(&mut self) -> Result<u64, ProfilerError> {
+        Ok(self.pam_counter)
+    }
+
+    fn read_pam(&mut self, pam: &mut [u64]) -> Result<(), ProfilerError> {
+        let len = pam.len().min(self.pam.len());
+        pam[..len].copy_from_slice(&self.pam[..len]);
+        Ok(())
+    }
+
+    fn stack_page(&self) -> Option<RelativePage> {
+        self.current.stack_page
+    }
+
+    fn erip(&self) -> usize {
+        self.base + self.script.front().map_or(self.final_erip, |s| s.erip)
+    }
+
+    fn base(&self) -> usize {
+        self.base
+    }
+
+    fn symbol_pages(&self, name: &str) -> Option<Range<usize>> {
+        self.symbols.get(name).cloned()
+    }
+
+    /// Scripted enclaves are based at address zero, unless given another base.
+    fn page_containing(&self, address: usize) -> Option<RelativePage> {
+        let page = address.checked_sub(self.base)? / PAGE_SIZE;
+        (page < self.pam.len()).then(|| RelativePage::new(page))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::dump::{PageRecord, TraceRecord, TraceWriter};
+
+    fn read(page: usize) -> PageAccess {
+        PageAccess::new(RelativePage::new(page), Perms::READ)
+    }
+
+    fn pages(backend: &MockBackend) -> Vec<usize> {
+        backend
+            .accessed_pages()
+            .iter()
+            .map(|p| p.page.get())
+            .collect()
+    }
+
+    #[test]
+    fn mock_backend_replays_the_script() {
+        let script = [
+            MockStep {
+                accesses: vec![read(3), read(4)],
+                pam_updates: vec![RelativePage::new(3)],
+                erip: 0x3010,
+                ..Default::default()
+            },
+            MockStep {
+                accesses: vec![read(5)],
+                pam_updates: vec![RelativePage::new(4), RelativePage::new(5)],
+                stack_page: Some(RelativePage::new(7)),
+                erip: 0x5020,
+                ..Default::default()
+            },
+        ];
+        let mut backend = MockBackend::new(8, script).with_base(0x10_0000);
+        let mut pam = [0; 8];
+
+        // The priming step accesses nothing, and is interrupted before the first
+        // scripted instruction
+        backend.next_step();
+        assert!(pages(&backend).is_empty());
+        assert_eq!(backend.erip(), 0x10_3010);
+
+        backend.next_step();
+        assert_eq!(pages(&backend), [3, 4]);
+        assert_eq!(backend.read_pam_counter().unwrap(), 1);
+        assert_eq!(backend.erip(), 0x10_5020);
+        backend.clear_accesses();
+        assert!(pages(&backend).is_empty());
+
+        backend.next_step();
+        assert_eq!(pages(&backend), [5]);
+        assert_eq!(backend.stack_page(), Some(RelativePage::new(7)));
+        backend.read_pam(&mut pam).unwrap();
+        assert_eq!(pam, [0, 0, 0, 1, 2, 2, 0, 0]);
+        assert!(backend.is_done());
+
+        // Once the script is exhausted, no more pages are accessed
+        backend.next_step();
+        assert!(pages(&backend).is_empty());
+        assert_eq!(backend.read_pam_counter().unwrap(), 2);
+        assert_eq!(backend.stack_page(), None);
+    }
+
+    #[test]
+    fn recorded_pam_is_applied_before_the_updates() {
+        let script = [MockStep {
+            pam: Some(PamRecord {
+                counter: 10,
+                entries: vec![(1, 9), (2, 10), (100, 1)],
+            }),
+            pam_updates: vec![RelativePage::new(3)],
+            ..Default::default()
+        }];
+        let mut backend = MockBackend::new(4, script);
+        backend.next_step();
+        backend.next_step();
+
+        // Entries beyond the PAM are ignored
+        let mut pam = [0; 4];
+        backend.read_pam(&mut pam).unwrap();
+        assert_eq!(pam, [0, 9, 10, 11]);
+        assert_eq!(backend.read_pam_counter().unwrap(), 11);
+    }
+
+    #[test]
+    fn mock_enclave_pages_and_symbols() {
+        let backend = MockBackend::new(4, [])
+            .with_base(0x2000)
+            .with_symbol("pam", 1..3);
+        assert_eq!(backend.base(), 0x2000);
+        assert_eq!(backend.symbol_pages("pam"), Some(1..3));
+        assert_eq!(backend.symbol_pages("pam_counter"), None);
+        assert_eq!(backend.page_containing(0x1fff), None);
+        assert_eq!(
+            backend.page_containing(0x2000 + 3 * PAGE_SIZE + 5),
+            Some(RelativePage::new(3))
+        );
+        assert_eq!(backend.page_containing(0x2000 + 4 * PAGE_SIZE), None);
+    }
+
+    #[test]
+    fn records_are_replayed_with_the_erip_of_their_interrupt() {
+        let mut rng = StdRng::seed_from_u64(1389);
+        let records = (0..20)
+            .map(|step| {
+                let pages = (0..8)
+                    .map(|page| (page, rng.gen_bool(0.3), rng.gen_range(1..8)))
+                    .filter(|&(_, accessed, _)| accessed)
+                    .map(|(page, _, perms)| PageRecord {
+                        page,
+                        perms,
+                        count: 1,
+                    })
+                    .collect();
+                TraceRecord {
+                    step,
+                    // The erip can not always be read
+                    erip: (step % 5 != 3).then_some(0x1000 + step * 0x10),
+                    pages,
+                    ..Default::default()
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut trace = Vec::new();
+        let header = TraceHeader {
+            num_pages: 8,
+            has_counts: false,
+            schedule: false,
+        };
+        {
+            let mut writer = TraceWriter::new(&mut trace, header).unwrap();
+            for record in &records {
+                writer.write(record).unwrap();
+            }
+        }
+
+        let reader = TraceReader::new(trace.as_slice()).unwrap();
+        let (mut backend, num_pages) = MockBackend::from_records(reader).unwrap();
+        assert_eq!(num_pages, 8);
+        backend.next_step();
+        assert!(pages(&backend).is_empty());
+        for record in &records {
+            assert!(!backend.is_done());
+            backend.next_step();
+            let accesses = backend
+                .accessed_pages()
+                .iter()
+                .map(|p| (p.page.get(), p.perms.bits()))
+                .collect::<Vec<_>>();
+            let expected = record
+                .pages
+                .iter()
+                .map(|p| (p.page, p.perms))
+                .collect::<Vec<_>>();
+            assert_eq!(accesses, expected, "step {}", record.step);
+            assert_eq!(backend.erip() as u64, record.erip.unwrap_or(0));
+            assert_eq!(backend.read_pam_counter().unwrap(), 0);
+            backend.clear_accesses();
+        }
+        assert!(backend.is_done());
+    }
+}
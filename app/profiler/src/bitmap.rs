@@ -0,0 +1,217 @@
+//! Compact sets of pages with one bit per page
+//!
+//! A [`PageBitmap`] takes 8 bytes per 64 pages of the enclave, and unions,
+//! intersections and differences of two bitmaps handle 64 pages per operation.
+//! Their cost depends on the size of the enclave rather than the number of pages in
+//! the sets: for an enclave of 64Ki pages, a union takes about 0.5 µs in the
+//! `bitmap` bench (`cargo bench --bench bitmap`), half as long as a hash set union of
+//! 10 pages, and 200 times less than one of 1000 pages. Bitmaps pay off for the large
+//! sets of e.g. accumulated observations, while the few pages of a single step are
+//! cheaper to handle as a `Vec<PageAccess>`. Bitmaps only hold which pages were
+//! accessed, not their permissions.
+
+use std::ops::{BitAnd, BitOr, Range, Sub};
+
+use crate::{page_ranges, PageAccess};
+
+/// Set of page numbers, stored as a bitmap
+///
+/// Bitmaps grow to hold the largest page inserted, so bitmaps of different sizes
+/// can be combined, and compare equal if they hold the same pages.
+///
+/// ```
+/// use sgx_profiler::bitmap::PageBitmap;
+///
+/// let a = PageBitmap::from_iter([1, 2, 70]);
+/// let b = PageBitmap::from_iter([2, 3]);
+/// assert_eq!((&a | &b).iter().collect::<Vec<_>>(), [1, 2, 3, 70]);
+/// assert_eq!((&a & &b).iter().collect::<Vec<_>>(), [2]);
+/// assert_eq!((&a - &b).iter().collect::<Vec<_>>(), [1, 70]);
+/// assert_eq!(a.len(), 3);
+/// assert!(a.contains(70) && !a.contains(3) && !a.contains(1000));
+///
+/// // Trailing empty words don't matter
+/// let mut c = PageBitmap::with_capacity(4096);
+/// c.insert(2);
+/// assert_eq!(c, &a & &b);
+/// assert_eq!((&a - &a), PageBitmap::new());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PageBitmap {
+    words: Vec<u64>,
+}
+
+impl PageBitmap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an empty bitmap with room for `num_pages` pages without growing
+    pub fn with_capacity(num_pages: usize) -> Self {
+        Self {
+            words: vec![0; num_pages.div_ceil(u64::BITS as usize)],
+        }
+    }
+
+    /// Create a bitmap of the accessed pages, ignoring their permissions
+    pub fn from_accesses<'a>(accesses: impl IntoIterator<Item = &'a PageAccess>) -> Self {
+        accesses
+            .into_iter()
+            .map(|access| access.page.get())
+            .collect()
+    }
+
+    fn position(page: usize) -> (usize, u64) {
+        let bits = u64::BITS as usize;
+        (page / bits, 1 << (page % bits))
+    }
+
+    pub fn insert(&mut self, page: usize) {
+        let (word, bit) = Self::position(page);
+        if word >= self.words.len() {
+            self.words.resize(word + 1, 0);
+        }
+        self.words[word] |= bit;
+    }
+
+    pub fn remove(&mut self, page: usize) {
+        let (word, bit) = Self::position(page);
+        if let Some(word) = self.words.get_mut(word) {
+            *word &= !bit;
+        }
+    }
+
+    pub fn contains(&self, page: usize) -> bool {
+        let (word, bit) = Self::position(page);
+        self.words.get(word).is_some_and(|word| word & bit != 0)
+    }
+
+    /// Remove all pages, keeping the capacity
+    pub fn clear(&mut self) {
+        self.words.fill(0);
+    }
+
+    /// Number of pages in the set
+    pub fn len(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|&word| word == 0)
+    }
+
+    /// The pages in the set, in ascending order
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(i, &word)| {
+            let mut word = word;
+            std::iter::from_fn(move || {
+                if word == 0 {
+                    return None;
+                }
+                let bit = word.trailing_zeros() as usize;
+                word &= word - 1;
+                Some(i * u64::BITS as usize + bit)
+            })
+        })
+    }
+
+    /// The pages as ranges of consecutive pages, see [`page_ranges`]
+    pub fn ranges(&self) -> Vec<Range<usize>> {
+        page_ranges(self.iter())
+    }
+
+    /// Add the pages of `other`
+    pub fn union_with(&mut self, other: &Self) {
+        if other.words.len() > self.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word |= other;
+        }
+    }
+
+    /// Keep only the pages that are also in `other`
+    pub fn intersect_with(&mut self, other: &Self) {
+        self.words.truncate(other.words.len());
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word &= other;
+        }
+    }
+
+    /// Remove the pages of `other`
+    pub fn difference_with(&mut self, other: &Self) {
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word &= !other;
+        }
+    }
+
+    /// Whether all pages of the set are also in `other`
+    pub fn is_subset(&self, other: &Self) -> bool {
+        self.words
+            .iter()
+            .enumerate()
+            .all(|(i, &word)| word & !other.words.get(i).copied().unwrap_or(0) == 0)
+    }
+}
+
+impl PartialEq for PageBitmap {
+    fn eq(&self, other: &Self) -> bool {
+        let (short, long) = if self.words.len() <= other.words.len() {
+            (&self.words, &other.words)
+        } else {
+            (&other.words, &self.words)
+        };
+        long[..short.len()] == short[..] && long[short.len()..].iter().all(|&word| word == 0)
+    }
+}
+
+impl Eq for PageBitmap {}
+
+impl FromIterator<usize> for PageBitmap {
+    fn from_iter<T: IntoIterator<Item = usize>>(pages: T) -> Self {
+        let mut bitmap = Self::new();
+        bitmap.extend(pages);
+        bitmap
+    }
+}
+
+impl Extend<usize> for PageBitmap {
+    fn extend<T: IntoIterator<Item = usize>>(&mut self, pages: T) {
+        for page in pages {
+            self.insert(page);
+        }
+    }
+}
+
+impl BitOr for &PageBitmap {
+    type Output = PageBitmap;
+
+    fn bitor(self, rhs: Self) -> PageBitmap {
+        let mut union = self.clone();
+        union.union_with(rhs);
+        union
+    }
+}
+
+impl BitAnd for &PageBitmap {
+    type Output = PageBitmap;
+
+    fn bitand(self, rhs: Self) -> PageBitmap {
+        let mut intersection = self.clone();
+        intersection.intersect_with(rhs);
+        intersection
+    }
+}
+
+impl Sub for &PageBitmap {
+    type Output = PageBitmap;
+
+    fn sub(self, rhs: Self) -> PageBitmap {
+        let mut difference = self.clone();
+        difference.difference_with(rhs);
+        difference
+    }
+}
@@ -1,12 +1,36 @@
-use std::{fs::File, path::Path};
+use std::{
+    arch::x86_64::_rdtsc,
+    fmt::Display,
+    fs::File,
+    io::{self, BufWriter},
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
+use clap::ValueEnum;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+pub use sgx_profiler_core::trace::{
+    is_trace, PageRecord, PamRecord, TraceHeader, TraceReader, TraceRecord, TraceWriter,
+};
+use sgx_step::page::RelativePage;
+#[cfg(feature = "sgx")]
 use sgx_step::sgx_step_sys::edbgrd_erip;
 
-use crate::PageAccess;
+use crate::{PageAccess, ProfilerError, TrapCause};
 
+/// Wires of the pages in a trace
+///
+/// Pages are identified by the index of their wires, which equals the page number
+/// unless the trace is restricted with a [`PageFilter`].
 pub trait TracePageSet: Sized {
+    /// Whether the wires count the accesses to a page in a step
+    const COUNTS: bool = false;
+
+    /// Create a set of `size` wires.
     fn new(size: usize) -> Self;
-    fn add_wires(&mut self, writer: &mut vcd::Writer<File>);
+    /// Add the wires, named after the page numbers in `pages`, one per wire.
+    fn add_wires(&mut self, writer: &mut vcd::Writer<File>, pages: &[usize]);
     fn init_wires(&mut self, writer: &mut vcd::Writer<File>);
     fn update_state<'a>(
         &mut self,
@@ -36,10 +60,10 @@ impl TracePageSet for RWXSet {
         }
     }
 
-    fn add_wires(&mut self, writer: &mut vcd::Writer<File>) {
-        self.r.add_wires(writer);
-        self.w.add_wires(writer);
-        self.x.add_wires(writer);
+    fn add_wires(&mut self, writer: &mut vcd::Writer<File>, pages: &[usize]) {
+        self.r.add_wires(writer, pages);
+        self.w.add_wires(writer, pages);
+        self.x.add_wires(writer, pages);
     }
 
     fn init_wires(&mut self, writer: &mut vcd::Writer<File>) {
@@ -57,14 +81,14 @@ impl TracePageSet for RWXSet {
         self.write.clear();
         self.execute.clear();
         for item in items {
-            if item.read {
-                self.read.push(item.page);
+            if item.read() {
+                self.read.push(item.page.get());
             }
-            if item.write {
-                self.write.push(item.page);
+            if item.write() {
+                self.write.push(item.page.get());
             }
-            if item.execute {
-                self.execute.push(item.page);
+            if item.execute() {
+                self.execute.push(item.page.get());
             }
         }
         self.r.update_state(writer, &self.read);
@@ -85,8 +109,8 @@ impl TracePageSet for RSet {
             read: Vec::with_capacity(10),
         }
     }
-    fn add_wires(&mut self, writer: &mut vcd::Writer<File>) {
-        self.r.add_wires(writer);
+    fn add_wires(&mut self, writer: &mut vcd::Writer<File>, pages: &[usize]) {
+        self.r.add_wires(writer, pages);
     }
 
     fn init_wires(&mut self, writer: &mut vcd::Writer<File>) {
@@ -100,14 +124,339 @@ impl TracePageSet for RSet {
     ) {
         self.read.clear();
         for item in items {
-            if item.read {
-                self.read.push(item.page);
+            if item.read() {
+                self.read.push(item.page.get());
             }
         }
         self.r.update_state(writer, &self.read);
     }
 }
 
+/// Records only the pages that were written in a step, with one wire per page
+///
+/// Pairs with [`PageTable::set_writes_only`](crate::PageTable::set_writes_only),
+/// which only scans for writes.
+///
+/// ```
+/// use sgx_profiler::{
+///     dump::{VCDDumper, WSet},
+///     golden::Trace,
+///     sgx_step::page::RelativePage,
+///     PageAccess, Perms,
+/// };
+///
+/// let dir = tempfile::tempdir().unwrap();
+/// let access = |page, perms| PageAccess::new(RelativePage::new(page), perms);
+/// let path = dir.path().join("w-set.vcd");
+/// {
+///     let mut dumper = VCDDumper::<WSet>::new(&path, 4);
+///     dumper.next_step(|entry| {
+///         let accesses = [access(1, Perms::READ), access(2, Perms::READ | Perms::WRITE)];
+///         entry.write_page_accesses(accesses.iter())
+///     });
+/// }
+///
+/// let trace = Trace::read(&path).unwrap();
+/// assert!(trace.changes[&0].contains(&("trace._2".into(), "1".into())));
+/// assert!(!trace.changes[&0].contains(&("trace._1".into(), "1".into())));
+/// ```
+pub struct WSet {
+    w: VCDStatefulSet,
+    write: Vec<usize>,
+}
+
+impl TracePageSet for WSet {
+    fn new(size: usize) -> Self {
+        Self {
+            w: VCDStatefulSet::new(size, None),
+            write: Vec::with_capacity(10),
+        }
+    }
+    fn add_wires(&mut self, writer: &mut vcd::Writer<File>, pages: &[usize]) {
+        self.w.add_wires(writer, pages);
+    }
+
+    fn init_wires(&mut self, writer: &mut vcd::Writer<File>) {
+        self.w.init_wires(writer);
+    }
+
+    fn update_state<'a>(
+        &mut self,
+        writer: &mut vcd::Writer<File>,
+        items: impl Iterator<Item = &'a PageAccess>,
+    ) {
+        self.write.clear();
+        for item in items {
+            if item.write() {
+                self.write.push(item.page.get());
+            }
+        }
+        self.w.update_state(writer, &self.write);
+    }
+}
+
+/// Number of bits of the counter wires of a [`CountSet`]
+const COUNT_WIDTH: u32 = 16;
+
+/// Number of bits of the `cause` wire, see [`VCDEntry::write_cause`]
+const CAUSE_WIDTH: u32 = 8;
+
+/// Records how often each page was accessed in a step, rather than only whether it was
+///
+/// Every page has a 16-bit wire, which is reset to zero in steps in which
+/// the page is not accessed. Counts saturate at the largest value of the wire.
+///
+/// ```
+/// use sgx_profiler::{
+///     dump::{CountSet, VCDDumper},
+///     sgx_step::page::RelativePage,
+///     PageAccess, Perms,
+/// };
+///
+/// let dir = tempfile::tempdir().unwrap();
+/// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+/// let path = dir.path().join("count-set.vcd");
+/// {
+///     let mut dumper = VCDDumper::<CountSet>::new(&path, 4);
+///     dumper.next_step(|entry| entry.write_page_accesses([read(1), read(2), read(1)].iter()));
+///     dumper.next_step(|entry| entry.write_page_accesses([read(2)].iter()));
+/// }
+///
+/// let file = std::io::BufReader::new(std::fs::File::open(&path).unwrap());
+/// let mut parser = vcd::Parser::new(file);
+/// let header = parser.parse_header().unwrap();
+/// let page = |page: usize| header.find_var(&["trace", &format!("_{page}")]).unwrap().code;
+/// let changes = parser
+///     .filter_map(|c| match c.unwrap() {
+///         vcd::Command::ChangeVector(code, v) => Some((code, v.to_string())),
+///         _ => None,
+///     })
+///     .collect::<Vec<_>>();
+/// let count = |n: u16| format!("{n:016b}");
+/// assert!(changes.contains(&(page(1), count(2))));
+/// assert!(changes.contains(&(page(2), count(1))));
+/// // Page 1 is reset in the second step, page 2 keeps its count and is not rewritten
+/// assert_eq!(changes.iter().filter(|(code, _)| *code == page(1)).count(), 3);
+/// assert_eq!(changes.iter().filter(|(code, _)| *code == page(2)).count(), 2);
+/// ```
+pub struct CountSet {
+    vars: Vec<vcd::IdCode>,
+    counts: Vec<u64>,
+    step_counts: Vec<u64>,
+}
+
+/// Write the lowest `width` bits of `value` to a vector wire
+fn write_bits(writer: &mut vcd::Writer<File>, var: vcd::IdCode, width: u32, value: u64) {
+    writer
+        .change_vector(
+            var,
+            (0..width).rev().map(|n| (((value >> n) & 1) != 0).into()),
+        )
+        .unwrap();
+}
+
+impl CountSet {
+    fn write_count(writer: &mut vcd::Writer<File>, var: vcd::IdCode, count: u64) {
+        write_bits(writer, var, COUNT_WIDTH, count.min((1 << COUNT_WIDTH) - 1));
+    }
+}
+
+impl TracePageSet for CountSet {
+    const COUNTS: bool = true;
+
+    fn new(size: usize) -> Self {
+        Self {
+            vars: Vec::new(),
+            counts: vec![0; size],
+            step_counts: vec![0; size],
+        }
+    }
+
+    fn add_wires(&mut self, writer: &mut vcd::Writer<File>, pages: &[usize]) {
+        self.vars = pages
+            .iter()
+            .map(|page| writer.add_wire(COUNT_WIDTH, &format!("_{page}")))
+            .collect::<Result<_, _>>()
+            .unwrap();
+    }
+
+    fn init_wires(&mut self, writer: &mut vcd::Writer<File>) {
+        for &var in &self.vars {
+            Self::write_count(writer, var, 0);
+        }
+    }
+
+    fn update_state<'a>(
+        &mut self,
+        writer: &mut vcd::Writer<File>,
+        items: impl Iterator<Item = &'a PageAccess>,
+    ) {
+        self.step_counts.fill(0);
+        for item in items {
+            self.step_counts[item.page.get()] += 1;
+        }
+
+        for (page, (count, &step_count)) in
+            self.counts.iter_mut().zip(&self.step_counts).enumerate()
+        {
+            if *count != step_count {
+                *count = step_count;
+                Self::write_count(writer, self.vars[page], step_count);
+            }
+        }
+    }
+}
+
+/// Records which cache lines of each page were accessed in a step
+///
+/// Every page has a 64-bit wire with one bit per cache line, see [`PageAccess::lines`].
+/// Accesses without cache lines, i.e. at page granularity, set all bits, as any
+/// line of the page may have been accessed. Pages that are not accessed are zero.
+///
+/// ```
+/// use sgx_profiler::{
+///     dump::{LineSet, VCDDumper},
+///     golden::Trace,
+///     sgx_step::page::RelativePage,
+///     PageAccess, Perms,
+/// };
+///
+/// let dir = tempfile::tempdir().unwrap();
+/// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+/// let path = dir.path().join("line-set.vcd");
+/// {
+///     let mut dumper = VCDDumper::<LineSet>::new(&path, 4);
+///     dumper.next_step(|entry| {
+///         entry.write_page_accesses([read(1).with_line(0x1040), read(2)].iter())
+///     });
+/// }
+///
+/// let trace = Trace::read(&path).unwrap();
+/// let lines = |page: usize, lines: u64| (format!("trace._{page}"), format!("{lines:064b}"));
+/// assert!(trace.changes[&0].contains(&lines(1, 0b10)));
+/// assert!(trace.changes[&0].contains(&lines(2, u64::MAX)));
+/// ```
+pub struct LineSet {
+    vars: Vec<vcd::IdCode>,
+    lines: Vec<u64>,
+    step_lines: Vec<u64>,
+}
+
+impl TracePageSet for LineSet {
+    fn new(size: usize) -> Self {
+        Self {
+            vars: Vec::new(),
+            lines: vec![0; size],
+            step_lines: vec![0; size],
+        }
+    }
+
+    fn add_wires(&mut self, writer: &mut vcd::Writer<File>, pages: &[usize]) {
+        self.vars = pages
+            .iter()
+            .map(|page| writer.add_wire(u64::BITS, &format!("_{page}")))
+            .collect::<Result<_, _>>()
+            .unwrap();
+    }
+
+    fn init_wires(&mut self, writer: &mut vcd::Writer<File>) {
+        for &var in &self.vars {
+            write_bits(writer, var, u64::BITS, 0);
+        }
+    }
+
+    fn update_state<'a>(
+        &mut self,
+        writer: &mut vcd::Writer<File>,
+        items: impl Iterator<Item = &'a PageAccess>,
+    ) {
+        self.step_lines.fill(0);
+        for item in items {
+            self.step_lines[item.page.get()] |= match item.lines {
+                0 => u64::MAX,
+                lines => lines,
+            };
+        }
+
+        for (page, (lines, &step_lines)) in self.lines.iter_mut().zip(&self.step_lines).enumerate()
+        {
+            if *lines != step_lines {
+                *lines = step_lines;
+                write_bits(writer, self.vars[page], u64::BITS, step_lines);
+            }
+        }
+    }
+}
+
+/// Writes the wires of two sets to the same trace
+///
+/// The wires of `A` are in a scope `a` and those of `B` in a scope `b`, so that
+/// sets with the same wire names can be combined, e.g. an [`RSet`] with the
+/// [`RWXSet`] of the same accesses. Sets can be nested to write more than two.
+///
+/// ```
+/// use sgx_profiler::{
+///     dump::{CountSet, RSet, Tee, VCDDumper},
+///     golden::Trace,
+///     sgx_step::page::RelativePage,
+///     PageAccess, Perms,
+/// };
+///
+/// let dir = tempfile::tempdir().unwrap();
+/// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+/// let path = dir.path().join("tee-set.vcd");
+/// {
+///     let mut dumper = VCDDumper::<Tee<RSet, CountSet>>::new(&path, 4);
+///     dumper.next_step(|entry| entry.write_page_accesses([read(1), read(1)].iter()));
+/// }
+///
+/// let trace = Trace::read(&path).unwrap();
+/// assert!(trace.changes[&0].contains(&("trace.a._1".into(), "1".into())));
+/// assert!(trace.changes[&0].contains(&("trace.b._1".into(), format!("{:016b}", 2))));
+/// ```
+pub struct Tee<A, B> {
+    a: A,
+    b: B,
+    items: Vec<PageAccess>,
+}
+
+impl<A: TracePageSet, B: TracePageSet> TracePageSet for Tee<A, B> {
+    const COUNTS: bool = A::COUNTS || B::COUNTS;
+
+    fn new(size: usize) -> Self {
+        Self {
+            a: A::new(size),
+            b: B::new(size),
+            items: Vec::with_capacity(10),
+        }
+    }
+
+    fn add_wires(&mut self, writer: &mut vcd::Writer<File>, pages: &[usize]) {
+        writer.add_module("a").unwrap();
+        self.a.add_wires(writer, pages);
+        writer.upscope().unwrap();
+        writer.add_module("b").unwrap();
+        self.b.add_wires(writer, pages);
+        writer.upscope().unwrap();
+    }
+
+    fn init_wires(&mut self, writer: &mut vcd::Writer<File>) {
+        self.a.init_wires(writer);
+        self.b.init_wires(writer);
+    }
+
+    fn update_state<'a>(
+        &mut self,
+        writer: &mut vcd::Writer<File>,
+        items: impl Iterator<Item = &'a PageAccess>,
+    ) {
+        self.items.clear();
+        self.items.extend(items);
+        self.a.update_state(writer, self.items.iter());
+        self.b.update_state(writer, self.items.iter());
+    }
+}
+
 struct VCDStatefulSet {
     vars: Vec<vcd::IdCode>,
     state: Vec<bool>,
@@ -123,16 +472,17 @@ impl VCDStatefulSet {
         }
     }
 
-    fn add_wires(&mut self, writer: &mut vcd::Writer<File>) {
-        self.vars = (0..self.state.len())
-            .map(|i| {
+    fn add_wires(&mut self, writer: &mut vcd::Writer<File>, pages: &[usize]) {
+        self.vars = pages
+            .iter()
+            .map(|page| {
                 writer.add_wire(
                     1,
                     &self
                         .wire_suffix
                         .as_ref()
-                        .map(|s| format!("_{i}_{s}"))
-                        .unwrap_or(format!("_{i}")),
+                        .map(|s| format!("_{page}_{s}"))
+                        .unwrap_or(format!("_{page}")),
                 )
             })
             .collect::<Result<_, _>>()
@@ -141,7 +491,7 @@ impl VCDStatefulSet {
 
     fn init_wires(&mut self, writer: &mut vcd::Writer<File>) {
         self.vars.iter().for_each(|id| {
-            writer.change_scalar(id.clone(), false).unwrap();
+            writer.change_scalar(*id, false).unwrap();
         });
     }
 
@@ -167,38 +517,528 @@ impl VCDStatefulSet {
     }
 }
 
+/// List of pages, parsed from a comma-separated list of pages and `START-END`
+/// ranges, where `END` is exclusive, e.g. `100-200,300`
+///
+/// Lists are displayed and serialized in the same format.
+///
+/// ```
+/// use sgx_profiler::dump::PageList;
+///
+/// let pages = "100-200,300".parse::<PageList>().unwrap();
+/// assert_eq!(pages, PageList(vec![100..200, 300..301]));
+/// assert_eq!(pages.to_string(), "100-200,300");
+/// let json = serde_json::to_string(&pages).unwrap();
+/// assert_eq!(json, r#""100-200,300""#);
+/// assert_eq!(serde_json::from_str::<PageList>(&json).unwrap(), pages);
+/// assert!(serde_json::from_str::<PageList>(r#""1-x""#).is_err());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PageList(pub Vec<Range<usize>>);
+
+impl PageList {
+    pub fn contains(&self, page: usize) -> bool {
+        self.0.iter().any(|pages| pages.contains(&page))
+    }
+}
+
+impl FromStr for PageList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |page: &str| {
+            page.trim()
+                .parse::<usize>()
+                .map_err(|e| format!("invalid page `{page}`: {e}"))
+        };
+        s.split(',')
+            .map(|item| match item.split_once('-') {
+                Some((start, end)) => Ok(parse(start)?..parse(end)?),
+                None => parse(item).map(|page| page..page + 1),
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl Display for PageList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, pages) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            match pages.len() {
+                1 => write!(f, "{}", pages.start)?,
+                _ => write!(f, "{}-{}", pages.start, pages.end)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for PageList {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PageList {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Pages that are recorded in a trace
+///
+/// Accesses to excluded pages are dropped, and they get no wires.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum PageFilter {
+    #[default]
+    All,
+    /// Only record the pages in the list
+    Only(PageList),
+    /// Record all pages except the ones in the list
+    Except(PageList),
+}
+
+impl PageFilter {
+    /// Filter from an allowlist or denylist, of which at most one should be given.
+    /// The allowlist takes precedence.
+    pub fn from_lists(only: Option<PageList>, except: Option<PageList>) -> Self {
+        match (only, except) {
+            (Some(only), _) => Self::Only(only),
+            (None, Some(except)) => Self::Except(except),
+            (None, None) => Self::All,
+        }
+    }
+
+    pub fn includes(&self, page: usize) -> bool {
+        match self {
+            Self::All => true,
+            Self::Only(pages) => pages.contains(page),
+            Self::Except(pages) => !pages.contains(page),
+        }
+    }
+}
+
+/// Source of the VCD timestamps
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TimestampSource {
+    /// Count the number of steps
+    #[default]
+    Steps,
+    /// Read the time stamp counter, relative to the creation of the dumper
+    Cycles,
+}
+
+/// Options of a [`VCDDumper`]
+///
+/// ```
+/// use sgx_profiler::{
+///     dump::{CountSet, DumperConfig, PageRecord, TraceReader, VCDDumper},
+///     sgx_step::page::RelativePage,
+///     PageAccess, Perms,
+/// };
+///
+/// let dir = tempfile::tempdir().unwrap();
+/// let access = |page, perms| PageAccess::new(RelativePage::new(page), perms);
+/// let records = dir.path().join("records.trace");
+/// {
+///     let config = DumperConfig {
+///         records: Some(records.clone()),
+///         ..Default::default()
+///     };
+///     let vcd = dir.path().join("records.vcd");
+///     let mut dumper = VCDDumper::<CountSet>::with_config(&vcd, 8, config).unwrap();
+///     let step = [access(2, Perms::READ), access(5, Perms::WRITE), access(2, Perms::EXECUTE)];
+///     dumper.next_step(|entry| entry.write_page_accesses(step.iter()));
+///     dumper.next_step(|entry| entry.write_page_accesses([access(5, Perms::READ)].iter()));
+/// }
+///
+/// let file = std::io::BufReader::new(std::fs::File::open(&records).unwrap());
+/// let reader = TraceReader::new(file).unwrap();
+/// assert!(reader.header().has_counts);
+/// let records = reader.collect::<Result<Vec<_>, _>>().unwrap();
+/// assert_eq!(records.iter().map(|r| r.step).collect::<Vec<_>>(), [0, 1]);
+/// let read_exec = PageRecord::READ | PageRecord::EXECUTE;
+/// assert_eq!(records[0].pages, [
+///     PageRecord { page: 2, perms: read_exec, count: 2 },
+///     PageRecord { page: 5, perms: PageRecord::WRITE, count: 1 },
+/// ]);
+/// assert_eq!(records[1].pages, [PageRecord { page: 5, perms: PageRecord::READ, count: 1 }]);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct DumperConfig {
+    pub timestamp: TimestampSource,
+    /// Pages shown as accessed at the start of the trace, see [`VCDDumper::with_baseline`]
+    pub baseline: Vec<PageAccess>,
+    pub filter: PageFilter,
+    /// Also write every step as a [`TraceRecord`] to this file
+    pub records: Option<PathBuf>,
+    /// Give each of up to this many enclave threads a scope of its own,
+    /// see [`VCDEntry::write_thread`]. Otherwise all steps are in one scope.
+    pub threads: Option<usize>,
+    /// Add a `cause` wire with the reason of every interrupt, see [`VCDEntry::write_cause`]
+    pub cause: bool,
+    /// Add a `marker` string wire for labels of user events, see [`VCDDumper::mark`]
+    pub markers: bool,
+}
+
+/// Collects the accesses of a step into a [`TraceRecord`] and writes it at the end of the step
+struct Recorder {
+    writer: TraceWriter<BufWriter<File>>,
+    record: TraceRecord,
+}
+
+impl Recorder {
+    fn add(&mut self, access: &PageAccess) {
+        let page = access.page.get();
+        match self.record.pages.iter_mut().find(|p| p.page == page) {
+            Some(record) => {
+                record.perms |= access.perms.bits();
+                record.count += 1;
+            }
+            None => self.record.pages.push(PageRecord {
+                page,
+                perms: access.perms.bits(),
+                count: 1,
+            }),
+        }
+    }
+
+    fn finish_step(&mut self, ts: u64) {
+        let mut record = std::mem::take(&mut self.record);
+        record.step = ts;
+        record.pages.sort_by_key(|p| p.page);
+        self.writer.write(&record).unwrap();
+    }
+}
+
 /// `VCDDumper` is used to write profiler output to a VCD file.
 ///
 /// The `vcd_entry` function can be called to get a handle to update
 /// the VCD state at the current step during enclave execution.
-/// The timestamp is incremented when this handle is dropped.
+/// The timestamp is advanced when this handle is dropped.
 pub struct VCDDumper<S> {
-    pages: S,
+    /// Wires of the pages, one set per thread scope
+    pages: Vec<S>,
+    /// Thread scope of the current step
+    thread: usize,
+    /// TCS addresses of the threads seen so far, by scope
+    threads: Vec<usize>,
+    tcs: Option<vcd::IdCode>,
+    cause: Option<vcd::IdCode>,
+    marker: Option<vcd::IdCode>,
+    /// Wire of every page, only if not every page has a wire
+    wires: Option<Vec<Option<usize>>>,
+    /// Sorted accesses of the current step, by wire if pages are filtered
+    wire_accesses: Vec<PageAccess>,
     rip: Option<vcd::IdCode>,
     ts: u64,
+    timestamp: TimestampSource,
+    start_tsc: u64,
     vcd_writer: vcd::Writer<File>,
+    /// Path of the VCD file
+    path: PathBuf,
+    records: Option<Recorder>,
 }
 
 impl<S: TracePageSet> VCDDumper<S> {
     pub fn new(file: impl AsRef<Path>, num_pages: usize) -> Self {
-        let mut vcd_writer = vcd::Writer::new(File::create(file).unwrap());
-        let mut pages = S::new(num_pages);
-        vcd_writer.timescale(1, vcd::TimescaleUnit::MS).unwrap();
+        Self::with_timestamp(file, num_pages, TimestampSource::Steps)
+    }
+
+    /// Create a dumper with the given timestamp source.
+    ///
+    /// VCD has no unit for cycles, so cycle timestamps use a timescale of 1 ns.
+    pub fn with_timestamp(
+        file: impl AsRef<Path>,
+        num_pages: usize,
+        timestamp: TimestampSource,
+    ) -> Self {
+        Self::with_config(
+            file,
+            num_pages,
+            DumperConfig {
+                timestamp,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    }
+
+    /// Create a dumper whose trace starts with the `baseline` pages accessed,
+    /// e.g. the pages that are always resident, so that their first accesses
+    /// in the trace are not mistaken for cold misses.
+    ///
+    /// The baseline is written at timestamp 0 like a step of its own, so the steps
+    /// that follow start at timestamp 1. Without a baseline, no extra step is written.
+    ///
+    /// ```
+    /// use sgx_profiler::{
+    ///     dump::{RSet, TimestampSource, VCDDumper},
+    ///     golden::Trace,
+    ///     sgx_step::page::RelativePage,
+    ///     PageAccess, Perms,
+    /// };
+    ///
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+    /// let path = dir.path().join("baseline.vcd");
+    /// {
+    ///     let baseline = [read(1), read(2)];
+    ///     let mut dumper =
+    ///         VCDDumper::<RSet>::with_baseline(&path, 4, TimestampSource::Steps, &baseline);
+    ///     dumper.next_step(|entry| entry.write_page_accesses([read(2), read(3)].iter()));
+    /// }
+    ///
+    /// let trace = Trace::read(&path).unwrap();
+    /// let high = |ts, page: usize| trace.changes[&ts].contains(&(format!("trace._{page}"), "1".into()));
+    /// let low = |ts, page: usize| trace.changes[&ts].contains(&(format!("trace._{page}"), "0".into()));
+    /// assert!(high(0, 1) && high(0, 2) && !high(0, 3));
+    /// // The first step only changes the pages that differ from the baseline
+    /// assert!(low(1, 1) && high(1, 3));
+    /// assert_eq!(trace.changes[&1].len(), 2);
+    /// ```
+    pub fn with_baseline(
+        file: impl AsRef<Path>,
+        num_pages: usize,
+        timestamp: TimestampSource,
+        baseline: &[PageAccess],
+    ) -> Self {
+        Self::with_config(
+            file,
+            num_pages,
+            DumperConfig {
+                timestamp,
+                baseline: baseline.to_vec(),
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    }
+
+    /// Create a dumper with the given options.
+    ///
+    /// Only the pages included by the filter get a wire, named after the page number,
+    /// and accesses to other pages are dropped.
+    ///
+    /// Returns `ProfilerError::Output` if the VCD file or the record trace can not be
+    /// created.
+    ///
+    /// ```
+    /// use sgx_profiler::{
+    ///     dump::{DumperConfig, PageFilter, RSet, VCDDumper},
+    ///     golden::Trace,
+    ///     sgx_step::page::RelativePage,
+    ///     PageAccess, Perms,
+    /// };
+    ///
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+    /// let dump = |filter| {
+    ///     let path = dir.path().join("filter.vcd");
+    ///     {
+    ///         let config = DumperConfig { filter, ..Default::default() };
+    ///         let mut dumper = VCDDumper::<RSet>::with_config(&path, 8, config).unwrap();
+    ///         for step in [[1, 3, 5], [0, 2, 6], [4, 6, 7]] {
+    ///             dumper.next_step(|entry| entry.write_page_accesses(step.map(read).iter()));
+    ///         }
+    ///     }
+    ///     Trace::read(&path).unwrap()
+    /// };
+    /// let wires = |pages: &[usize]| {
+    ///     let mut wires = pages.iter().map(|page| format!("trace._{page}")).collect::<Vec<_>>();
+    ///     wires.push("trace.erip".into());
+    ///     wires
+    /// };
+    /// let high = |trace: &Trace, ts, page: usize| {
+    ///     trace.changes[&ts].contains(&(format!("trace._{page}"), "1".into()))
+    /// };
+    ///
+    /// // Excluded pages have no wires, so they can't appear in the changes either
+    /// let trace = dump(PageFilter::Only("2-4,6".parse().unwrap()));
+    /// assert!(trace.wires.keys().eq(&wires(&[2, 3, 6])));
+    /// assert!(high(&trace, 0, 3) && high(&trace, 1, 2) && high(&trace, 1, 6));
+    ///
+    /// let trace = dump(PageFilter::Except("0-2,3-5,7".parse().unwrap()));
+    /// assert!(trace.wires.keys().eq(&wires(&[2, 5, 6])));
+    /// assert!(high(&trace, 0, 5) && high(&trace, 1, 2) && high(&trace, 1, 6));
+    /// // Page 6 stays high, only page 2 changes
+    /// assert_eq!(trace.changes[&2].len(), 1);
+    /// ```
+    pub fn with_config(
+        file: impl AsRef<Path>,
+        num_pages: usize,
+        config: DumperConfig,
+    ) -> Result<Self, ProfilerError> {
+        let DumperConfig {
+            timestamp,
+            baseline,
+            filter,
+            records,
+            threads,
+            cause,
+            markers,
+        } = config;
+        let records = records
+            .map(|path| {
+                let header = TraceHeader {
+                    num_pages,
+                    has_counts: S::COUNTS,
+                    schedule: false,
+                };
+                File::create(&path)
+                    .and_then(|file| TraceWriter::new(BufWriter::new(file), header))
+                    .map(|writer| Recorder {
+                        writer,
+                        record: TraceRecord::default(),
+                    })
+                    .map_err(|error| ProfilerError::Output { path, error })
+            })
+            .transpose()?;
+        let file = file.as_ref();
+        let vcd_file = File::create(file).map_err(|error| ProfilerError::Output {
+            path: file.to_path_buf(),
+            error,
+        })?;
+        let mut vcd_writer = vcd::Writer::new(vcd_file);
+        let traced = (0..num_pages)
+            .filter(|&page| filter.includes(page))
+            .collect::<Vec<_>>();
+        let wires = (traced.len() != num_pages).then(|| {
+            let mut wires = vec![None; num_pages];
+            for (wire, &page) in traced.iter().enumerate() {
+                wires[page] = Some(wire);
+            }
+            wires
+        });
+        match timestamp {
+            TimestampSource::Steps => vcd_writer.timescale(1, vcd::TimescaleUnit::MS).unwrap(),
+            TimestampSource::Cycles => vcd_writer.timescale(1, vcd::TimescaleUnit::NS).unwrap(),
+        }
 
         vcd_writer.add_module("trace").unwrap();
-        pages.add_wires(&mut vcd_writer);
+        let mut pages = Vec::new();
+        match threads {
+            None => {
+                let mut set = S::new(traced.len());
+                set.add_wires(&mut vcd_writer, &traced);
+                pages.push(set);
+            }
+            Some(threads) => {
+                for thread in 0..threads.max(1) {
+                    vcd_writer.add_module(&format!("thread_{thread}")).unwrap();
+                    let mut set = S::new(traced.len());
+                    set.add_wires(&mut vcd_writer, &traced);
+                    pages.push(set);
+                    vcd_writer.upscope().unwrap();
+                }
+            }
+        }
         let rip = Some(vcd_writer.add_wire(64, "erip").unwrap());
+        let tcs = threads.map(|_| vcd_writer.add_wire(64, "tcs").unwrap());
+        let cause = cause.then(|| vcd_writer.add_wire(CAUSE_WIDTH, "cause").unwrap());
+        let marker = markers.then(|| {
+            vcd_writer
+                .add_var(vcd::VarType::String, 1, "marker", None)
+                .unwrap()
+        });
         vcd_writer.upscope().unwrap();
 
         vcd_writer.enddefinitions().unwrap();
 
-        pages.init_wires(&mut vcd_writer);
+        for set in &mut pages {
+            set.init_wires(&mut vcd_writer);
+        }
 
-        Self {
+        let mut dumper = Self {
             pages,
+            thread: 0,
+            threads: Vec::new(),
+            tcs,
+            cause,
+            marker,
+            wires,
+            wire_accesses: Vec::new(),
             rip,
             ts: 0,
+            timestamp,
+            start_tsc: unsafe { _rdtsc() },
             vcd_writer,
+            path: file.to_path_buf(),
+            records,
+        };
+        if !baseline.is_empty() {
+            dumper.next_step(|entry| {
+                entry.write_page_accesses(baseline.iter());
+                // The baseline holds for all threads
+                for thread in 1..entry.dumper.pages.len() {
+                    entry.dumper.thread = thread;
+                    entry.write_wires(baseline.iter());
+                }
+                entry.dumper.thread = 0;
+            });
+        }
+        Ok(dumper)
+    }
+
+    /// Path of the VCD file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Flush the steps written so far to the VCD file, and the record trace if any
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(records) = &mut self.records {
+            records.writer.flush()?;
+        }
+        self.vcd_writer.flush()
+    }
+
+    /// Label the current step with `label` in the `marker` wire, e.g. to annotate
+    /// the trace with events of the enclave that are known outside of it.
+    ///
+    /// Between steps, the current step is the one written next, so the label appears
+    /// at the timestamp of its changes. VCD values can't contain whitespace, so it is
+    /// replaced by underscores. Does nothing without `markers` in the [`DumperConfig`].
+    ///
+    /// ```
+    /// use sgx_profiler::{
+    ///     dump::{DumperConfig, RSet, VCDDumper},
+    ///     golden::Trace,
+    ///     sgx_step::page::RelativePage,
+    ///     PageAccess, Perms,
+    /// };
+    ///
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+    /// let path = dir.path().join("markers.vcd");
+    /// {
+    ///     let config = DumperConfig { markers: true, ..Default::default() };
+    ///     let mut dumper = VCDDumper::<RSet>::with_config(&path, 4, config).unwrap();
+    ///     dumper.mark("decompression start");
+    ///     dumper.next_step(|entry| entry.write_page_accesses([read(1)].iter()));
+    ///     dumper.next_step(|entry| entry.write_page_accesses([read(2)].iter()));
+    ///     dumper.next_step(|entry| {
+    ///         entry.write_marker("row 42");
+    ///         entry.write_page_accesses([read(3)].iter());
+    ///     });
+    /// }
+    ///
+    /// let trace = Trace::read(&path).unwrap();
+    /// let marker = |value: &str| ("trace.marker".to_string(), value.to_string());
+    /// assert!(trace.changes[&0].contains(&marker("decompression_start")));
+    /// assert!(!trace.changes[&1].iter().any(|(wire, _)| wire == "trace.marker"));
+    /// assert!(trace.changes[&2].contains(&marker("row_42")));
+    /// ```
+    pub fn mark(&mut self, label: &str) {
+        if let Some(var) = self.marker {
+            let label = label.replace(char::is_whitespace, "_");
+            self.vcd_writer.change_string(var, &label).unwrap();
         }
     }
 
@@ -208,6 +1048,9 @@ impl<S: TracePageSet> VCDDumper<S> {
     }
 
     fn write_erip(&mut self, rip: usize) {
+        if let Some(records) = &mut self.records {
+            records.record.erip = Some(rip as u64);
+        }
         self.vcd_writer
             .change_vector(
                 self.rip.unwrap(),
@@ -216,8 +1059,21 @@ impl<S: TracePageSet> VCDDumper<S> {
             .unwrap();
     }
 
-    fn next_timestamp(&mut self) {
-        self.ts += 1;
+    /// Tick count of the current step, according to the timestamp source
+    fn ticks(&self) -> u64 {
+        match self.timestamp {
+            TimestampSource::Steps => self.ts + 1,
+            TimestampSource::Cycles => (unsafe { _rdtsc() }) - self.start_tsc,
+        }
+    }
+
+    /// Advance the timestamp to `ticks`.
+    ///
+    /// VCD timestamps must be strictly increasing, so if `ticks` does not exceed
+    /// the previous timestamp, e.g. because two reads of the TSC collide,
+    /// the timestamp is advanced by one instead.
+    fn next_timestamp(&mut self, ticks: u64) {
+        self.ts = ticks.max(self.ts + 1);
         self.vcd_writer.timestamp(self.ts).unwrap();
     }
 }
@@ -233,20 +1089,607 @@ impl<'d, S: TracePageSet> VCDEntry<'d, S> {
     }
 
     /// Write the erip.
+    #[cfg(feature = "sgx")]
     pub fn write_erip(&mut self) {
-        self.dumper.write_erip(unsafe { edbgrd_erip() as usize });
+        self.write_erip_at(unsafe { edbgrd_erip() as usize });
+    }
+
+    /// Write the given erip, e.g. one read by a [`Backend`](crate::backend::Backend).
+    pub fn write_erip_at(&mut self, erip: usize) {
+        self.dumper.write_erip(erip);
+    }
+
+    /// Attribute the current step to the enclave thread with the TCS at address `tcs`,
+    /// before writing its page accesses, see [`crate::create_thread_trap_handler`].
+    ///
+    /// Threads get the scopes `thread_0`, `thread_1`, ... in the order in which they
+    /// are first seen, and the `tcs` wire holds the TCS address of every step. Only
+    /// the scope of the thread of a step changes, so the wires of the other threads
+    /// keep the accesses of their last steps. Returns the index of the thread, or
+    /// `ProfilerError::TooManyThreads` if all scopes are taken. Without `threads`
+    /// in the [`DumperConfig`], all steps are in one scope and this does nothing.
+    ///
+    /// ```
+    /// use sgx_profiler::{
+    ///     dump::{DumperConfig, RSet, VCDDumper},
+    ///     golden::Trace,
+    ///     sgx_step::page::RelativePage,
+    ///     PageAccess, Perms, ProfilerError,
+    /// };
+    ///
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+    /// let path = dir.path().join("threads.vcd");
+    /// {
+    ///     let config = DumperConfig { threads: Some(2), ..Default::default() };
+    ///     let mut dumper = VCDDumper::<RSet>::with_config(&path, 8, config).unwrap();
+    ///     // Two enclave threads take turns
+    ///     for (tcs, page) in [(0x7000, 1), (0x8000, 2), (0x7000, 3)] {
+    ///         dumper.next_step(|entry| {
+    ///             entry.write_thread(tcs).unwrap();
+    ///             entry.write_page_accesses([read(page)].iter());
+    ///         });
+    ///     }
+    ///     dumper.next_step(|entry| {
+    ///         assert!(matches!(
+    ///             entry.write_thread(0x9000),
+    ///             Err(ProfilerError::TooManyThreads { threads: 2 })
+    ///         ));
+    ///     });
+    /// }
+    ///
+    /// let trace = Trace::read(&path).unwrap();
+    /// let change = |ts, wire: &str, value: &str| trace.changes[&ts].contains(&(wire.into(), value.into()));
+    /// assert!(change(0, "trace.thread_0._1", "1"));
+    /// assert!(change(0, "trace.tcs", &format!("{:064b}", 0x7000)));
+    /// assert!(change(1, "trace.thread_1._2", "1"));
+    /// // The second thread does not change the wires of the first one
+    /// assert!(!trace.changes[&1].iter().any(|(wire, _)| wire.starts_with("trace.thread_0")));
+    /// assert!(change(2, "trace.thread_0._1", "0") && change(2, "trace.thread_0._3", "1"));
+    /// ```
+    pub fn write_thread(&mut self, tcs: usize) -> Result<usize, ProfilerError> {
+        let dumper = &mut *self.dumper;
+        let Some(var) = dumper.tcs else {
+            return Ok(0);
+        };
+        let thread = match dumper.threads.iter().position(|&t| t == tcs) {
+            Some(thread) => thread,
+            None if dumper.threads.len() < dumper.pages.len() => {
+                dumper.threads.push(tcs);
+                dumper.threads.len() - 1
+            }
+            None => {
+                return Err(ProfilerError::TooManyThreads {
+                    threads: dumper.pages.len(),
+                })
+            }
+        };
+        dumper.thread = thread;
+        write_bits(&mut dumper.vcd_writer, var, u64::BITS, tcs as u64);
+        Ok(thread)
+    }
+
+    /// Write why the enclave was interrupted at the current step, as the value of
+    /// the [`TrapCause`] in the `cause` wire. Does nothing without `cause` in the
+    /// [`DumperConfig`].
+    ///
+    /// ```
+    /// use sgx_profiler::{
+    ///     dump::{DumperConfig, RSet, VCDDumper},
+    ///     golden::Trace,
+    ///     TrapCause,
+    /// };
+    ///
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let path = dir.path().join("cause.vcd");
+    /// {
+    ///     let config = DumperConfig { cause: true, ..Default::default() };
+    ///     let mut dumper = VCDDumper::<RSet>::with_config(&path, 4, config).unwrap();
+    ///     dumper.next_step(|entry| entry.write_cause(TrapCause::SingleStep));
+    ///     dumper.next_step(|entry| entry.write_cause(TrapCause::Spurious));
+    /// }
+    ///
+    /// let trace = Trace::read(&path).unwrap();
+    /// assert!(trace.changes[&0].contains(&("trace.cause".into(), "00000001".into())));
+    /// assert!(trace.changes[&1].contains(&("trace.cause".into(), "00000000".into())));
+    /// ```
+    pub fn write_cause(&mut self, cause: TrapCause) {
+        if let Some(var) = self.dumper.cause {
+            write_bits(&mut self.dumper.vcd_writer, var, CAUSE_WIDTH, cause as u64);
+        }
+    }
+
+    /// Label the current step with `label`, see [`VCDDumper::mark`]
+    pub fn write_marker(&mut self, label: &str) {
+        self.dumper.mark(label);
     }
 
     /// Write the pages accessed at the current step.
+    ///
+    /// The accesses are sorted first, so the changes within a step are written in the
+    /// same order regardless of the order in which the pages were found.
+    ///
+    /// ```
+    /// use sgx_profiler::{
+    ///     dump::{RSet, VCDDumper},
+    ///     sgx_step::page::RelativePage,
+    ///     PageAccess, Perms,
+    /// };
+    ///
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+    /// let dump = |steps: &[&[usize]], name| {
+    ///     let path = dir.path().join(name);
+    ///     {
+    ///         let mut dumper = VCDDumper::<RSet>::new(&path, 8);
+    ///         for step in steps {
+    ///             let accesses = step.iter().map(|&page| read(page)).collect::<Vec<_>>();
+    ///             dumper.next_step(|entry| entry.write_page_accesses(accesses.iter()));
+    ///         }
+    ///     }
+    ///     std::fs::read(&path).unwrap()
+    /// };
+    ///
+    /// // E.g. PTE scan order and PAM order
+    /// let scanned = dump(&[&[1, 5, 3], &[7, 2]], "scan-order.vcd");
+    /// let pam = dump(&[&[5, 3, 1], &[2, 7]], "pam-order.vcd");
+    /// assert_eq!(scanned, pam);
+    /// ```
     pub fn write_page_accesses<'a>(&mut self, pages: impl Iterator<Item = &'a PageAccess>) {
-        self.dumper
-            .pages
-            .update_state(&mut self.dumper.vcd_writer, pages)
+        if self.dumper.records.is_none() {
+            return self.write_wires(pages);
+        }
+
+        let pages = pages.collect::<Vec<_>>();
+        let dumper = &mut *self.dumper;
+        if let Some(records) = &mut dumper.records {
+            for access in &pages {
+                let traced = dumper
+                    .wires
+                    .as_ref()
+                    .is_none_or(|wires| wires.get(access.page.get()).is_some_and(Option::is_some));
+                if traced {
+                    records.add(access);
+                }
+            }
+        }
+        self.write_wires(pages.into_iter())
+    }
+
+    fn write_wires<'a>(&mut self, pages: impl Iterator<Item = &'a PageAccess>) {
+        let dumper = &mut *self.dumper;
+        dumper.wire_accesses.clear();
+        match &dumper.wires {
+            None => dumper.wire_accesses.extend(pages),
+            // Identify the accesses to traced pages by their wire
+            Some(wires) => dumper.wire_accesses.extend(pages.filter_map(|access| {
+                let wire = (*wires.get(access.page.get())?)?;
+                Some(PageAccess {
+                    page: RelativePage::new(wire),
+                    ..*access
+                })
+            })),
+        }
+        dumper.wire_accesses.sort_unstable();
+        dumper.pages[dumper.thread]
+            .update_state(&mut dumper.vcd_writer, dumper.wire_accesses.iter())
     }
 }
 
 impl<'d, S: TracePageSet> Drop for VCDEntry<'d, S> {
     fn drop(&mut self) {
-        self.dumper.next_timestamp();
+        // The changes of this step are at the timestamp before advancing it
+        let ts = self.dumper.ts;
+        if let Some(records) = &mut self.dumper.records {
+            records.finish_step(ts);
+        }
+        let ticks = self.dumper.ticks();
+        self.dumper.next_timestamp(ticks);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::{golden::Trace, Perms};
+
+    fn read(page: usize) -> PageAccess {
+        PageAccess::new(RelativePage::new(page), Perms::READ)
+    }
+
+    /// Trace the `steps` with a [`CountSet`] of 4 pages
+    fn count_trace(dir: &Path, steps: &[Vec<PageAccess>]) -> Trace {
+        let path = dir.join("counts.vcd");
+        {
+            let mut dumper = VCDDumper::<CountSet>::new(&path, 4);
+            for step in steps {
+                dumper.next_step(|entry| entry.write_page_accesses(step.iter()));
+            }
+        }
+        Trace::read(&path).unwrap()
+    }
+
+    fn count(page: usize, count: u64) -> (String, String) {
+        (format!("trace._{page}"), format!("{count:016b}"))
+    }
+
+    #[test]
+    fn count_set_counts_the_accesses_of_every_step() {
+        let dir = tempfile::tempdir().unwrap();
+        let steps = [
+            vec![read(1), read(1), read(1), read(2)],
+            vec![read(1), read(2), read(1)],
+            vec![],
+            vec![read(3), read(3)],
+        ];
+        let trace = count_trace(dir.path(), &steps);
+
+        assert_eq!(trace.wires["trace._1"], COUNT_WIDTH);
+        let changes = |ts: u64| trace.changes.get(&ts).cloned().unwrap_or_default();
+        // The initial zeros and the first step share the first timestamp
+        assert!(changes(0).is_superset(&[count(0, 0), count(1, 3), count(2, 1)].into()));
+        // Only changed counts are written
+        assert_eq!(changes(1), [count(1, 2)].into());
+        assert_eq!(changes(2), [count(1, 0), count(2, 0)].into());
+        assert_eq!(changes(3), [count(3, 2)].into());
+    }
+
+    #[test]
+    fn count_set_saturates() {
+        let dir = tempfile::tempdir().unwrap();
+        let steps = [
+            vec![read(2); 1 << COUNT_WIDTH],
+            vec![read(2); 1 << COUNT_WIDTH],
+        ];
+        let trace = count_trace(dir.path(), &steps);
+
+        assert!(trace.changes[&0].contains(&count(2, (1 << COUNT_WIDTH) - 1)));
+        assert!(!trace.changes.contains_key(&1));
+    }
+
+    fn high(trace: &Trace, ts: u64, wire: &str) -> bool {
+        trace.changes[&ts].contains(&(wire.into(), "1".into()))
+    }
+
+    #[test]
+    fn baseline_is_high_in_every_thread_before_the_first_step() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("baseline.vcd");
+        {
+            let config = DumperConfig {
+                baseline: vec![read(1), read(2)],
+                threads: Some(2),
+                ..Default::default()
+            };
+            let mut dumper = VCDDumper::<RSet>::with_config(&path, 4, config).unwrap();
+            dumper.next_step(|entry| {
+                entry.write_thread(0x7000).unwrap();
+                entry.write_page_accesses([read(2)].iter());
+            });
+        }
+
+        let trace = Trace::read(&path).unwrap();
+        for thread in ["thread_0", "thread_1"] {
+            assert!(high(&trace, 0, &format!("trace.{thread}._1")));
+            assert!(high(&trace, 0, &format!("trace.{thread}._2")));
+            assert!(!high(&trace, 0, &format!("trace.{thread}._3")));
+        }
+        // The first step is written after the baseline, and only changes its own thread
+        let tcs = ("trace.tcs".into(), format!("{:064b}", 0x7000));
+        assert!(trace.changes[&1].contains(&tcs));
+        assert!(trace.changes[&1].contains(&("trace.thread_0._1".into(), "0".into())));
+        assert!(!trace.changes[&1]
+            .iter()
+            .any(|(wire, _)| wire.starts_with("trace.thread_1")));
+    }
+
+    #[test]
+    fn baseline_respects_the_page_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("filtered-baseline.vcd");
+        {
+            let config = DumperConfig {
+                baseline: vec![read(1), read(2)],
+                filter: PageFilter::Except("2".parse().unwrap()),
+                ..Default::default()
+            };
+            let mut dumper = VCDDumper::<RSet>::with_config(&path, 4, config).unwrap();
+            dumper.next_step(|entry| entry.write_page_accesses([read(1)].iter()));
+        }
+
+        let trace = Trace::read(&path).unwrap();
+        assert!(!trace.wires.contains_key("trace._2"));
+        assert!(high(&trace, 0, "trace._1"));
+        // Page 1 stays accessed, so the first step changes no page
+        let changed_pages = trace
+            .changes
+            .get(&1)
+            .into_iter()
+            .flatten()
+            .filter(|(wire, _)| wire.starts_with("trace._"));
+        assert_eq!(changed_pages.count(), 0);
+    }
+
+    #[test]
+    fn page_lists_are_parsed_and_displayed() {
+        let pages = " 3, 5-8 ,10-11".parse::<PageList>().unwrap();
+        assert_eq!(pages, PageList(vec![3..4, 5..8, 10..11]));
+        assert_eq!(pages.to_string(), "3,5-8,10");
+        assert_eq!(pages.to_string().parse::<PageList>().unwrap(), pages);
+        let included = (0..12)
+            .filter(|&page| pages.contains(page))
+            .collect::<Vec<_>>();
+        assert_eq!(included, [3, 5, 6, 7, 10]);
+        for list in ["", "1,", "1-", "-2", "1-2-3", "a"] {
+            assert!(list.parse::<PageList>().is_err(), "accepted `{list}`");
+        }
+    }
+
+    #[test]
+    fn allowlist_takes_precedence_over_denylist() {
+        let list = |s: &str| Some(s.parse::<PageList>().unwrap());
+        let filter = PageFilter::from_lists(list("1-3"), list("2"));
+        assert_eq!(filter, PageFilter::Only(list("1-3").unwrap()));
+        assert!(filter.includes(2) && !filter.includes(3));
+
+        let filter = PageFilter::from_lists(None, list("2"));
+        assert!(filter.includes(1) && !filter.includes(2));
+        let filter = PageFilter::from_lists(None, None);
+        assert!((0..100).all(|page| filter.includes(page)));
+    }
+
+    /// Trace random steps of accesses to 32 pages with the `filter`, returning the
+    /// changes of the page wires at every timestamp, with the page of every wire
+    fn filtered_changes<S: TracePageSet>(
+        dir: &Path,
+        filter: PageFilter,
+    ) -> BTreeMap<u64, BTreeSet<(usize, String, String)>> {
+        let path = dir.join("filtered.vcd");
+        {
+            let config = DumperConfig {
+                filter,
+                ..Default::default()
+            };
+            let mut dumper = VCDDumper::<S>::with_config(&path, 32, config).unwrap();
+            let mut rng = StdRng::seed_from_u64(1349);
+            for _ in 0..200 {
+                let step = (0..rng.gen_range(0..6))
+                    .map(|_| {
+                        let perms = Perms::from_bits_truncate(rng.gen_range(1..8));
+                        PageAccess::new(RelativePage::new(rng.gen_range(0..32)), perms)
+                    })
+                    .collect::<Vec<_>>();
+                dumper.next_step(|entry| entry.write_page_accesses(step.iter()));
+            }
+        }
+
+        let trace = Trace::read(&path).unwrap();
+        trace
+            .changes
+            .into_iter()
+            .map(|(ts, changes)| {
+                let pages = changes
+                    .into_iter()
+                    .filter_map(|(wire, value)| {
+                        // Page wires are named `_PAGE`, or `_PAGE_PERM` in an `RWXSet`
+                        let (_, name) = wire.rsplit_once("._")?;
+                        let page = name.split('_').next().unwrap().parse().unwrap();
+                        Some((page, wire, value))
+                    })
+                    .collect();
+                (ts, pages)
+            })
+            .collect()
+    }
+
+    /// Check that excluded pages never appear in a filtered trace, and that the
+    /// wires of the included pages change as in an unfiltered one
+    fn check_filter<S: TracePageSet>(filter: PageFilter) {
+        let dir = tempfile::tempdir().unwrap();
+        let all = filtered_changes::<S>(dir.path(), PageFilter::All);
+        let filtered = filtered_changes::<S>(dir.path(), filter.clone());
+
+        assert!(filtered
+            .values()
+            .flatten()
+            .all(|(page, _, _)| filter.includes(*page)));
+        for (ts, changes) in all {
+            let expected = changes
+                .into_iter()
+                .filter(|(page, _, _)| filter.includes(*page))
+                .collect::<BTreeSet<_>>();
+            assert_eq!(
+                filtered.get(&ts).cloned().unwrap_or_default(),
+                expected,
+                "at timestamp {ts}"
+            );
+        }
+    }
+
+    #[test]
+    fn filtered_traces_only_contain_included_pages() {
+        for filter in [
+            PageFilter::Only("3,5-9,30-32".parse().unwrap()),
+            PageFilter::Except("0-4,17,20-31".parse().unwrap()),
+        ] {
+            check_filter::<RSet>(filter.clone());
+            check_filter::<RWXSet>(filter.clone());
+            check_filter::<CountSet>(filter);
+        }
+    }
+
+    #[test]
+    fn records_only_contain_included_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let records = dir.path().join("filtered.trace");
+        {
+            let config = DumperConfig {
+                filter: PageFilter::Only("2-4".parse().unwrap()),
+                records: Some(records.clone()),
+                ..Default::default()
+            };
+            let mut dumper =
+                VCDDumper::<RSet>::with_config(dir.path().join("filtered.vcd"), 8, config).unwrap();
+            dumper.next_step(|entry| entry.write_page_accesses([read(1), read(3)].iter()));
+            dumper.next_step(|entry| entry.write_page_accesses([read(5)].iter()));
+        }
+
+        let reader = TraceReader::new(io::BufReader::new(File::open(&records).unwrap())).unwrap();
+        let pages = reader
+            .map(|record| {
+                record
+                    .unwrap()
+                    .pages
+                    .iter()
+                    .map(|p| p.page)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(pages, [vec![3], vec![]]);
+    }
+
+    #[test]
+    fn without_a_baseline_the_first_step_is_at_timestamp_0() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("no-baseline.vcd");
+        {
+            let mut dumper =
+                VCDDumper::<RSet>::with_baseline(&path, 4, TimestampSource::Steps, &[]);
+            dumper.next_step(|entry| entry.write_page_accesses([read(3)].iter()));
+        }
+
+        let trace = Trace::read(&path).unwrap();
+        assert!(high(&trace, 0, "trace._3"));
+        assert!(!trace.changes.contains_key(&1));
+    }
+
+    /// Trace random steps over 8 pages with the page set `S`
+    fn random_trace<S: TracePageSet>(path: &Path, seed: u64) -> Trace {
+        let mut rng = StdRng::seed_from_u64(seed);
+        {
+            let mut dumper = VCDDumper::<S>::new(path, 8);
+            for _ in 0..50 {
+                let step = (0..rng.gen_range(0..6))
+                    .map(|_| {
+                        let perms =
+                            [Perms::READ, Perms::WRITE, Perms::EXECUTE][rng.gen_range(0..3)];
+                        PageAccess::new(RelativePage::new(rng.gen_range(0..8)), perms)
+                    })
+                    .collect::<Vec<_>>();
+                dumper.next_step(|entry| entry.write_page_accesses(step.iter()));
+            }
+        }
+        Trace::read(path).unwrap()
+    }
+
+    /// Page wires and their changes in `scope`, renamed as if they were at the top
+    fn unscoped(trace: &Trace, scope: &str) -> Trace {
+        let prefix = match scope {
+            "" => "trace.".to_string(),
+            scope => format!("trace.{scope}."),
+        };
+        let rename = |name: &str| {
+            name.strip_prefix(&prefix)
+                .filter(|name| name.starts_with('_') && !name.contains('.'))
+                .map(|name| format!("trace.{name}"))
+        };
+        let changes = trace
+            .changes
+            .iter()
+            .map(|(&ts, changes)| {
+                let changes = changes
+                    .iter()
+                    .filter_map(|(name, value)| Some((rename(name)?, value.clone())))
+                    .collect::<BTreeSet<_>>();
+                (ts, changes)
+            })
+            .filter(|(_, changes)| !changes.is_empty())
+            .collect();
+        Trace {
+            timescale: trace.timescale.clone(),
+            wires: trace
+                .wires
+                .iter()
+                .filter_map(|(name, &width)| Some((rename(name)?, width)))
+                .collect(),
+            changes,
+        }
+    }
+
+    #[test]
+    fn tee_writes_both_sets_in_their_own_scope() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        let tee = random_trace::<Tee<RSet, RWXSet>>(&path("tee.vcd"), 1382);
+        let rset = random_trace::<RSet>(&path("r.vcd"), 1382);
+        let rwxset = random_trace::<RWXSet>(&path("rwx.vcd"), 1382);
+
+        // The erip is only written once, outside the scopes
+        assert_eq!(tee.wires.len() + 1, rset.wires.len() + rwxset.wires.len());
+        assert_eq!(unscoped(&tee, "a"), unscoped(&rset, ""));
+        assert_eq!(unscoped(&tee, "b"), unscoped(&rwxset, ""));
+    }
+
+    #[test]
+    fn tees_can_be_nested() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        let tee = random_trace::<Tee<RSet, Tee<RSet, CountSet>>>(&path("tee.vcd"), 7);
+        let rset = unscoped(&random_trace::<RSet>(&path("r.vcd"), 7), "");
+        let counts = random_trace::<CountSet>(&path("counts.vcd"), 7);
+
+        assert_eq!(unscoped(&tee, "a"), rset);
+        assert_eq!(unscoped(&tee, "b.a"), rset);
+        assert_eq!(unscoped(&tee, "b.b"), unscoped(&counts, ""));
+        assert_eq!(tee.wires["trace.b.b._3"], COUNT_WIDTH);
+    }
+
+    #[test]
+    fn cause_wire_holds_the_trap_cause_of_every_step() {
+        let dir = tempfile::tempdir().unwrap();
+        let trace = |cause| {
+            let path = dir.path().join("cause.vcd");
+            {
+                let config = DumperConfig {
+                    cause,
+                    ..Default::default()
+                };
+                let mut dumper = VCDDumper::<RSet>::with_config(&path, 4, config).unwrap();
+                for trap in [
+                    TrapCause::SingleStep,
+                    TrapCause::SingleStep,
+                    TrapCause::Spurious,
+                    TrapCause::Breakpoint,
+                ] {
+                    dumper.next_step(|entry| {
+                        entry.write_cause(trap);
+                        entry.write_page_accesses([read(1)].iter());
+                    });
+                }
+            }
+            Trace::read(&path).unwrap()
+        };
+
+        let with_cause = trace(true);
+        assert_eq!(with_cause.wires["trace.cause"], CAUSE_WIDTH);
+        let cause = |ts: u64| {
+            with_cause.changes[&ts]
+                .iter()
+                .find(|(name, _)| name == "trace.cause")
+                .map(|(_, value)| u8::from_str_radix(value, 2).unwrap())
+        };
+        // The cause is written in every step
+        assert_eq!(cause(0), Some(TrapCause::SingleStep as u8));
+        assert_eq!(cause(1), Some(TrapCause::SingleStep as u8));
+        assert_eq!(cause(2), Some(TrapCause::Spurious as u8));
+        assert_eq!(cause(3), Some(TrapCause::Breakpoint as u8));
+
+        let without_cause = trace(false);
+        assert!(!without_cause.wires.contains_key("trace.cause"));
+        assert_eq!(without_cause.wires.len() + 1, with_cause.wires.len());
     }
 }
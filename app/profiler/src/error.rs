@@ -0,0 +1,177 @@
+use std::{error::Error, fmt::Display, io, ops::Range, path::PathBuf};
+
+/// Errors returned by the profiler library
+#[derive(Debug)]
+pub enum ProfilerError {
+    /// A trap handler was already registered, only one handler is supported
+    HandlerAlreadyRegistered,
+    /// A signal handler was still running after the enclave returned, so its state
+    /// could not be taken back
+    HandlerBusy,
+    /// The enclave does not contain the given symbol
+    SymbolNotFound(String),
+    /// The given symbol name contains a NUL byte, so it can not be looked up
+    InvalidSymbolName(String),
+    /// The enclave could not be created
+    EnclaveCreate(Box<dyn Error + Send + Sync>),
+    /// The profiler shared object could not be loaded, or lacks a required export
+    Library(libloading::Error),
+    /// Enclave memory could not be locked
+    Mlock(io::Error),
+    /// The PAM could not be read from enclave memory
+    PamRead(io::Error),
+    /// The signal handler could not be installed
+    SignalInstall(nix::Error),
+    /// Access to the given enclave pages could not be revoked or restored
+    Mprotect {
+        pages: Range<usize>,
+        error: io::Error,
+    },
+    /// The given pages do not lie within the first `num_pages` enclave pages
+    PagesOutOfBounds {
+        pages: Range<usize>,
+        num_pages: usize,
+    },
+    /// The enclave of another process could not be attached to
+    Attach { pid: u32, error: io::Error },
+    /// The PAM of the enclave has `size` bytes, fewer than the `expected` bytes
+    /// of an entry for every enclave page
+    PamSize { size: usize, expected: usize },
+    /// An output file of the profiler could not be written
+    Output { path: PathBuf, error: io::Error },
+    /// More enclave threads were seen than the `threads` the trace has scopes for
+    TooManyThreads { threads: usize },
+    /// More than `capacity` pages faulted in a single step, see
+    /// [`FaultQueue`](crate::FaultQueue)
+    FaultQueueFull { capacity: usize },
+    /// The profiler thread could not be pinned to the given CPU
+    CpuAffinity { cpu: usize, error: nix::Error },
+    /// There is no enclave or profiler library at `path`, which was resolved
+    /// against `base_dir` if given
+    AppPathNotFound {
+        path: PathBuf,
+        base_dir: Option<PathBuf>,
+    },
+    /// A signal handler failed, see [`check_signal_handlers`](crate::check_signal_handlers)
+    SignalHandler(HandlerError),
+}
+
+/// Why a signal handler failed.
+///
+/// Handlers can not log or wait, as they may interrupt a thread that holds a lock,
+/// so they only record the code of their first failure, which
+/// [`check_signal_handlers`](crate::check_signal_handlers) reports afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum HandlerError {
+    /// A trap or sample of one thread arrived while another thread ran the handler,
+    /// so it was skipped
+    Concurrent = 1,
+    /// The fault handler could not restore access to the faulting page, so the
+    /// fault terminates the process with the default action
+    FaultNotHandled = 2,
+}
+
+impl HandlerError {
+    /// The error with the given code, `None` for 0 and unknown codes
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(Self::Concurrent),
+            2 => Some(Self::FaultNotHandled),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ProfilerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::HandlerAlreadyRegistered => f.write_str("handler already registered!"),
+            Self::HandlerBusy => {
+                f.write_str("signal handler still running after the enclave returned")
+            }
+            Self::SymbolNotFound(name) => write!(f, "symbol not found in enclave: {name}"),
+            Self::InvalidSymbolName(name) => {
+                write!(f, "invalid symbol name {name:?}: contains a NUL byte")
+            }
+            Self::EnclaveCreate(e) => write!(f, "failed to create enclave: {e}"),
+            Self::Library(e) => write!(f, "failed to load profiler library: {e}"),
+            Self::Mlock(e) => write!(f, "failed to lock enclave memory: {e}"),
+            Self::PamRead(e) => write!(f, "failed to read the PAM from enclave memory: {e}"),
+            Self::SignalInstall(e) => write!(f, "failed to install signal handler: {e}"),
+            Self::Mprotect { pages, error } => write!(
+                f,
+                "failed to change protection of enclave pages {pages:?}: {error}"
+            ),
+            Self::PagesOutOfBounds { pages, num_pages } => write!(
+                f,
+                "enclave pages {pages:?} out of bounds of the {num_pages} enclave pages"
+            ),
+            Self::Attach { pid, error } => {
+                write!(f, "failed to attach to enclave of process {pid}: {error}")
+            }
+            Self::PamSize { size, expected } => write!(
+                f,
+                "PAM of the enclave has {size} bytes, expected at least {expected} bytes"
+            ),
+            Self::Output { path, error } => {
+                write!(f, "failed to write {}: {error}", path.display())
+            }
+            Self::TooManyThreads { threads } => {
+                write!(f, "enclave has more than the {threads} traced threads")
+            }
+            Self::FaultQueueFull { capacity } => {
+                write!(f, "more than {capacity} pages faulted in a single step")
+            }
+            Self::CpuAffinity { cpu, error } => write!(f, "failed to pin to CPU {cpu}: {error}"),
+            Self::AppPathNotFound {
+                path,
+                base_dir: Some(base_dir),
+            } => write!(
+                f,
+                "{} not found, relative paths are resolved against {}",
+                path.display(),
+                base_dir.display()
+            ),
+            Self::AppPathNotFound {
+                path,
+                base_dir: None,
+            } => write!(f, "{} not found", path.display()),
+            Self::SignalHandler(HandlerError::Concurrent) => f.write_str(
+                "signal handler skipped a signal that arrived while another thread ran it",
+            ),
+            Self::SignalHandler(HandlerError::FaultNotHandled) => {
+                f.write_str("fault handler failed to restore access to the faulting page")
+            }
+        }
+    }
+}
+
+impl Error for ProfilerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::EnclaveCreate(e) => Some(e.as_ref()),
+            Self::Library(e) => Some(e),
+            Self::Mlock(e) => Some(e),
+            Self::PamRead(e) => Some(e),
+            Self::SignalInstall(e) => Some(e),
+            Self::Mprotect { error, .. } => Some(error),
+            Self::Attach { error, .. } => Some(error),
+            Self::Output { error, .. } => Some(error),
+            Self::CpuAffinity { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<libloading::Error> for ProfilerError {
+    fn from(value: libloading::Error) -> Self {
+        Self::Library(value)
+    }
+}
+
+impl From<nix::Error> for ProfilerError {
+    fn from(value: nix::Error) -> Self {
+        Self::SignalInstall(value)
+    }
+}
@@ -0,0 +1,116 @@
+//! Semantic comparison of VCD traces
+//!
+//! A golden trace is a VCD file recorded once for a fixed input, usually a
+//! [`MockBackend`](crate::backend::MockBackend) script. The integration tests
+//! compare freshly produced traces against the golden traces in `tests/golden`
+//! with [`Trace::first_difference`], so changes to the TLB, PAM or attacker logic
+//! that alter the output are caught.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fs::File,
+    io::BufReader,
+    path::Path,
+};
+
+/// Semantic contents of a VCD trace
+///
+/// Wires are identified by their scoped name instead of their id code, and the
+/// changes within a timestamp are unordered.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    pub timescale: Option<(u32, String)>,
+    /// Width of every wire, by scoped name
+    pub wires: BTreeMap<String, u32>,
+    /// Wire values set at every timestamp. Initial values are at timestamp 0.
+    pub changes: BTreeMap<u64, BTreeSet<(String, String)>>,
+}
+
+impl Trace {
+    /// Read a VCD file.
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let mut parser = vcd::Parser::new(BufReader::new(File::open(path)?));
+        let header = parser.parse_header()?;
+
+        let mut trace = Trace {
+            timescale: header.timescale.map(|(n, unit)| (n, unit.to_string())),
+            ..Default::default()
+        };
+        let mut names = BTreeMap::new();
+        let mut scopes = vec![(String::new(), &header.items)];
+        while let Some((prefix, items)) = scopes.pop() {
+            for item in items {
+                match item {
+                    vcd::ScopeItem::Scope(scope) => {
+                        scopes.push((format!("{prefix}{}.", scope.identifier), &scope.items))
+                    }
+                    vcd::ScopeItem::Var(var) => {
+                        let name = format!("{prefix}{}", var.reference);
+                        trace.wires.insert(name.clone(), var.size);
+                        names.insert(var.code, name);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut ts = 0;
+        for command in parser {
+            let (code, value) = match command? {
+                vcd::Command::Timestamp(t) => {
+                    ts = t;
+                    continue;
+                }
+                vcd::Command::ChangeScalar(code, value) => (code, value.to_string()),
+                vcd::Command::ChangeVector(code, value) => (code, value.to_string()),
+                vcd::Command::ChangeReal(code, value) => (code, value.to_string()),
+                vcd::Command::ChangeString(code, value) => (code, value),
+                _ => continue,
+            };
+            let name = names
+                .get(&code)
+                .ok_or_else(|| format!("change of undeclared wire `{code}`"))?;
+            trace
+                .changes
+                .entry(ts)
+                .or_default()
+                .insert((name.clone(), value));
+        }
+        Ok(trace)
+    }
+
+    /// Describe the first difference with `other`, if any.
+    pub fn first_difference(&self, other: &Self) -> Option<String> {
+        if self.timescale != other.timescale {
+            return Some(format!(
+                "timescale {:?} != {:?}",
+                self.timescale, other.timescale
+            ));
+        }
+        if self.wires != other.wires {
+            let missing = self.wires.keys().filter(|w| !other.wires.contains_key(*w));
+            let extra = other.wires.keys().filter(|w| !self.wires.contains_key(*w));
+            return Some(format!(
+                "wires differ: missing {:?}, extra {:?}",
+                missing.collect::<Vec<_>>(),
+                extra.collect::<Vec<_>>()
+            ));
+        }
+
+        let empty = BTreeSet::new();
+        let timestamps = self.changes.keys().chain(other.changes.keys());
+        for ts in timestamps.collect::<BTreeSet<_>>() {
+            let a = self.changes.get(ts).unwrap_or(&empty);
+            let b = other.changes.get(ts).unwrap_or(&empty);
+            if a != b {
+                return Some(format!(
+                    "changes at #{ts} differ: missing {:?}, extra {:?}",
+                    a.difference(b).collect::<Vec<_>>(),
+                    b.difference(a).collect::<Vec<_>>()
+                ));
+            }
+        }
+        None
+    }
+}
@@ -1,106 +1,742 @@
+pub mod attacker;
+pub mod backend;
+pub mod bitmap;
 pub mod dump;
+pub mod error;
+pub mod golden;
+pub mod metrics;
+pub mod pam;
+pub mod prefetch;
+pub mod repair;
+pub mod simulator;
+pub mod stats;
+pub mod tlb;
 
-use dump::{TracePageSet, VCDDumper};
-use libloading::Symbol;
-use nix::libc::{self, mlock};
-use nix::sys::signal;
-use sgx_step::{page_table::PageTableEntry, sgx_step_sys::PAGE_SIZE_4KiB, Enclave, EnclaveRef};
+pub use error::{HandlerError, ProfilerError};
+use log::{debug, warn};
+use nix::libc;
+use nix::sys::{
+    signal,
+    timer::{Expiration, Timer, TimerSetTimeFlags},
+};
+use nix::{
+    sched::{sched_setaffinity, CpuSet},
+    time::ClockId,
+    unistd::{gettid, Pid},
+};
+use sgx_step::page::{RelativePage, PAGE_SIZE};
+pub use simulator::{Simulator, SimulatorBuilder};
+
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, Ordering};
+use std::{
+    ffi::OsString,
+    ops::Range,
+    path::{Path, PathBuf},
+    ptr,
+    time::Duration,
+};
 
-use once_cell::sync::OnceCell;
-use std::sync::Mutex;
+// Tracing a live enclave
+#[cfg(feature = "sgx")]
+use bitmap::PageBitmap;
+#[cfg(feature = "sgx")]
+use dump::{DumperConfig, TracePageSet, VCDDumper};
+#[cfg(feature = "sgx")]
+use libloading::Symbol;
+#[cfg(feature = "sgx")]
+use nix::libc::{mlock, munlock};
+#[cfg(feature = "sgx")]
+use rayon::prelude::*;
+#[cfg(feature = "sgx")]
+use sgx_step::{
+    page_table::{PageTableEntry, PageTableLevel, PteFlags},
+    sgx_step_sys::{restore_pages, revoke_pages, PAGE_SIZE_4KiB},
+    Enclave, EnclaveRef, Segment,
+};
+#[cfg(feature = "sgx")]
 use std::{
-    error::Error,
+    collections::BTreeSet,
     ffi::{c_char, c_void, CString},
-    path::Path,
+    fs::{self, File},
+    io,
+    sync::atomic::AtomicUsize,
 };
 
+pub use sgx_profiler_core::{check, state_machine};
 pub use sgx_step;
+#[cfg(feature = "sgx")]
 pub use sgx_urts_sys;
 
+bitflags::bitflags! {
+    /// Permissions with which a page is accessed
+    ///
+    /// Serialized as the names of the flags, e.g. `"READ | WRITE"`, in text formats.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+    #[serde(transparent)]
+    pub struct Perms: u8 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXECUTE = 1 << 2;
+    }
+}
+
+/// Size of a cache line, the granularity of [`PageAccess::lines`]
+pub const CACHE_LINE_SIZE: usize = 64;
+
 /// Represents an access to a page with certain permissions
-#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+///
+/// ```
+/// use sgx_profiler::{sgx_step::page::RelativePage, PageAccess, Perms};
+///
+/// let access = PageAccess::new(RelativePage::new(3), Perms::READ | Perms::WRITE).with_line(0x40);
+/// let json = serde_json::to_string(&access).unwrap();
+/// assert_eq!(json, r#"{"perms":"READ | WRITE","page":3,"lines":2}"#);
+/// assert_eq!(serde_json::from_str::<PageAccess>(&json).unwrap(), access);
+/// // Accesses without cache lines may leave them out
+/// let json = r#"{"perms":"EXECUTE","page":7}"#;
+/// let access = PageAccess::new(RelativePage::new(7), Perms::EXECUTE);
+/// assert_eq!(serde_json::from_str::<PageAccess>(json).unwrap(), access);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct PageAccess {
-    pub read: bool,
-    pub write: bool,
-    pub execute: bool,
-    pub page: usize,
+    pub perms: Perms,
+    pub page: RelativePage,
+    /// Cache lines of the page that were accessed, one bit per [`CACHE_LINE_SIZE`] bytes.
+    /// Zero if only the page is known, which is the default granularity.
+    #[serde(default)]
+    pub lines: u64,
+}
+
+/// Accesses are ordered by page, then by permissions and cache lines, which is the
+/// order in which the accesses of a step are written to a trace
+///
+/// ```
+/// use sgx_profiler::{sgx_step::page::RelativePage, PageAccess, Perms};
+///
+/// let access = |page, perms| PageAccess::new(RelativePage::new(page), perms);
+/// let mut accesses = [access(3, Perms::READ), access(1, Perms::WRITE), access(1, Perms::READ)];
+/// accesses.sort();
+/// assert_eq!(accesses, [access(1, Perms::READ), access(1, Perms::WRITE), access(3, Perms::READ)]);
+/// ```
+impl Ord for PageAccess {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.page, self.perms, self.lines).cmp(&(other.page, other.perms, other.lines))
+    }
+}
+
+impl PartialOrd for PageAccess {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
 impl PageAccess {
-    pub fn covers(&self, other: &Self) -> bool {
-        if self.page == other.page {
-            let mut result = true;
-            if other.read && !self.read {
-                result = false;
-            }
-            if other.write && !self.write {
-                result = false;
-            }
-            if other.execute && !self.execute {
-                result = false;
-            }
-            result
-        } else {
-            false
+    pub fn new(page: RelativePage, perms: Perms) -> Self {
+        Self {
+            perms,
+            page,
+            lines: 0,
         }
     }
 
+    /// Also record an access to the cache line at `address`.
+    /// Only the offset of `address` within its page is used.
+    ///
+    /// ```
+    /// use sgx_profiler::{sgx_step::page::RelativePage, PageAccess, Perms};
+    ///
+    /// let access = PageAccess::new(RelativePage::new(1), Perms::READ);
+    /// assert_eq!(access.with_line(0x1000).lines, 1);
+    /// assert_eq!(access.with_line(0x1fc8).with_line(0x40).lines, 1 << 63 | 1 << 1);
+    /// ```
+    pub fn with_line(self, address: usize) -> Self {
+        let line = (address % PAGE_SIZE) / CACHE_LINE_SIZE;
+        Self {
+            lines: self.lines | 1 << line,
+            ..self
+        }
+    }
+
+    pub fn read(&self) -> bool {
+        self.perms.contains(Perms::READ)
+    }
+
+    pub fn write(&self) -> bool {
+        self.perms.contains(Perms::WRITE)
+    }
+
+    pub fn execute(&self) -> bool {
+        self.perms.contains(Perms::EXECUTE)
+    }
+
+    /// Returns true if `other` is an access to the same page with a subset of the permissions,
+    /// regardless of the cache lines accessed
+    ///
+    /// ```
+    /// use sgx_profiler::{sgx_step::page::RelativePage, PageAccess, Perms};
+    ///
+    /// let page = RelativePage::new(1);
+    /// let accesses = (0..8)
+    ///     .map(|bits| PageAccess::new(page, Perms::from_bits(bits).unwrap()))
+    ///     .collect::<Vec<_>>();
+    /// // Same results as comparing every permission separately
+    /// let implies = |a: bool, b: bool| !a || b;
+    /// for a in &accesses {
+    ///     for b in &accesses {
+    ///         assert_eq!(
+    ///             a.covers(b),
+    ///             implies(b.read(), a.read())
+    ///                 && implies(b.write(), a.write())
+    ///                 && implies(b.execute(), a.execute())
+    ///         );
+    ///         let union = a.union(b);
+    ///         assert_eq!(union.page, page);
+    ///         assert_eq!(
+    ///             [union.read(), union.write(), union.execute()],
+    ///             [a.read() || b.read(), a.write() || b.write(), a.execute() || b.execute()]
+    ///         );
+    ///     }
+    /// }
+    /// assert!(!accesses[7].covers(&PageAccess::new(RelativePage::new(2), Perms::empty())));
+    /// ```
+    pub fn covers(&self, other: &Self) -> bool {
+        self.page == other.page && self.perms.contains(other.perms)
+    }
+
     pub fn union(&self, other: &Self) -> Self {
         Self {
-            read: self.read || other.read,
-            write: self.write || other.write,
-            execute: self.execute || other.execute,
+            perms: self.perms | other.perms,
             page: self.page,
+            lines: self.lines | other.lines,
+        }
+    }
+
+    /// Returns the permissions that both accesses have in common,
+    /// or `None` if they are to different pages.
+    ///
+    /// ```
+    /// use sgx_profiler::{sgx_step::page::RelativePage, PageAccess, Perms};
+    ///
+    /// let page = RelativePage::new(1);
+    /// let rw = PageAccess::new(page, Perms::READ | Perms::WRITE);
+    /// let rx = PageAccess::new(page, Perms::READ | Perms::EXECUTE);
+    /// let r = PageAccess::new(page, Perms::READ);
+    /// assert_eq!(rw.intersect(&rx), Some(r));
+    /// assert_eq!(rw.intersect(&PageAccess { page: RelativePage::new(2), ..rw }), None);
+    /// ```
+    pub fn intersect(&self, other: &Self) -> Option<Self> {
+        (self.page == other.page).then_some(Self::new(self.page, self.perms & other.perms))
+    }
+
+    /// Returns the permissions of this access that `other` does not have,
+    /// or `None` if there are none.
+    ///
+    /// An access to a different page has no permissions on this page,
+    /// so then this access is returned unchanged.
+    ///
+    /// ```
+    /// use sgx_profiler::{sgx_step::page::RelativePage, PageAccess, Perms};
+    ///
+    /// let page = RelativePage::new(1);
+    /// let rw = PageAccess::new(page, Perms::READ | Perms::WRITE);
+    /// let r = PageAccess::new(page, Perms::READ);
+    /// assert_eq!(rw.difference(&r), Some(PageAccess::new(page, Perms::WRITE)));
+    /// assert_eq!(r.difference(&rw), None);
+    /// assert_eq!(rw.difference(&PageAccess { page: RelativePage::new(2), ..rw }), Some(rw));
+    /// ```
+    pub fn difference(&self, other: &Self) -> Option<Self> {
+        let other = self.intersect(other).unwrap_or_default();
+        let perms = self.perms - other.perms;
+        (!perms.is_empty()).then_some(Self { perms, ..*self })
+    }
+}
+
+/// Coalesce the accessed `pages` into ranges of consecutive pages, sorted by start page.
+///
+/// Pages may be given in any order and more than once.
+///
+/// ```
+/// use sgx_profiler::page_ranges;
+///
+/// // Adjacent pages are merged, isolated pages get a range of their own
+/// assert_eq!(page_ranges([4, 2, 3, 9, 7, 3]), [2..5, 7..8, 9..10]);
+/// assert_eq!(page_ranges([5]), [5..6]);
+/// assert!(page_ranges([]).is_empty());
+/// ```
+pub fn page_ranges(pages: impl IntoIterator<Item = usize>) -> Vec<Range<usize>> {
+    let mut pages = pages.into_iter().collect::<Vec<_>>();
+    pages.sort_unstable();
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for page in pages {
+        match ranges.last_mut() {
+            Some(range) if page <= range.end => range.end = range.end.max(page + 1),
+            _ => ranges.push(page..page + 1),
+        }
+    }
+    ranges
+}
+
+/// Minimum number of PTEs handled by a single worker when scanning in parallel
+#[cfg(feature = "sgx")]
+const PARALLEL_SCAN_MIN_CHUNK: usize = 4096;
+
+/// Configuration for scanning only the PTEs near recently accessed pages.
+///
+/// A cached scan reads the PTEs of pages within `neighborhood` pages of a page that
+/// was accessed in the previous step. An access outside of this neighborhood is only
+/// possible in a 2 MiB region whose PMD entry has its accessed bit set, as the page
+/// walk to any of its PTEs sets it, so a cached scan falls back to a full scan of
+/// those regions. Its result thus equals that of a full scan on every step.
+/// Every `full_scan_interval` cached scans, the full page table is scanned again.
+#[derive(Debug, Clone, Copy)]
+pub struct ScanCacheConfig {
+    pub neighborhood: usize,
+    pub full_scan_interval: usize,
+}
+
+#[cfg(feature = "sgx")]
+#[derive(Debug)]
+struct ScanCache {
+    config: ScanCacheConfig,
+    /// Pages that will be scanned by the next cached scan
+    candidates: BTreeSet<usize>,
+    /// Pages scanned in the last step, only valid if `last_scan_cached` is set
+    scanned: Vec<usize>,
+    /// Whether the last scan was a cached scan
+    last_scan_cached: bool,
+    cached_scans: usize,
+    /// PMD entries of the 2 MiB regions of the enclave, `None` if it could not be
+    /// mapped, in which case the region is always scanned
+    regions: Vec<Option<PageTableEntry>>,
+    /// Number of pages of the first region before the enclave base
+    region_offset: usize,
+}
+
+#[cfg(feature = "sgx")]
+impl ScanCache {
+    /// Pages covered by the page table of a PMD entry
+    const REGION_PAGES: usize = 512;
+
+    /// Scan cache of the `num_pages` pages of the enclave at `base`, with the PMD
+    /// entry of the region of an address mapped by `map_pmd`
+    fn new(
+        config: ScanCacheConfig,
+        base: usize,
+        num_pages: usize,
+        map_pmd: impl Fn(usize) -> Option<PageTableEntry>,
+    ) -> Self {
+        let region_offset = base / PAGE_SIZE_4KiB as usize % Self::REGION_PAGES;
+        let regions = (0..(region_offset + num_pages).div_ceil(Self::REGION_PAGES))
+            .map(|region| {
+                let page = (region * Self::REGION_PAGES).saturating_sub(region_offset);
+                map_pmd(base + page * PAGE_SIZE_4KiB as usize)
+            })
+            .collect();
+        Self {
+            config,
+            candidates: BTreeSet::new(),
+            scanned: Vec::new(),
+            last_scan_cached: false,
+            // Start with a full scan
+            cached_scans: config.full_scan_interval,
+            regions,
+            region_offset,
+        }
+    }
+
+    fn full_scan_due(&self) -> bool {
+        self.cached_scans >= self.config.full_scan_interval
+    }
+
+    /// Collect the pages to scan in a cached scan in `scanned`, in page order: the
+    /// candidates, and all pages of the regions whose PMD entry was accessed
+    fn collect_scanned(&mut self, num_pages: usize) {
+        self.scanned.clear();
+        for (region, pmd) in self.regions.iter().enumerate() {
+            let start = (region * Self::REGION_PAGES).saturating_sub(self.region_offset);
+            let end = ((region + 1) * Self::REGION_PAGES - self.region_offset).min(num_pages);
+            if pmd.as_ref().is_none_or(PageTableEntry::accessed) {
+                self.scanned.extend(start..end);
+            } else {
+                self.scanned.extend(self.candidates.range(start..end));
+            }
+        }
+    }
+
+    /// Clear the accessed bits of the PMD entries, after the PTEs
+    fn clear_regions(&mut self) {
+        for pmd in self.regions.iter_mut().flatten() {
+            if pmd.accessed() {
+                pmd.mark_not_accessed();
+            }
+        }
+    }
+
+    fn record(&mut self, pages: &[PageAccess], cached: bool, num_pages: usize) {
+        self.last_scan_cached = cached;
+        if cached {
+            self.cached_scans += 1;
+        } else {
+            self.cached_scans = 0;
+            self.scanned.clear();
         }
+
+        let n = self.config.neighborhood;
+        self.candidates.clear();
+        self.candidates.extend(
+            pages.iter().flat_map(|p| {
+                p.page.get().saturating_sub(n)..(p.page.get() + n + 1).min(num_pages)
+            }),
+        );
     }
 }
 
+/// Address ranges of the segments of the enclave, see [`EnclaveRef::segments`]
+#[cfg(feature = "sgx")]
+pub fn segment_ranges(enclave: &EnclaveRef) -> Vec<Range<usize>> {
+    enclave.segments().iter().map(Segment::range).collect()
+}
+
 /// Interface to access and manipulate page table entries of the enclave
+#[cfg(feature = "sgx")]
 #[derive(Debug)]
 pub struct PageTable {
     pub page_table_map: Vec<Option<PageTableEntry>>,
+    /// Pages accessed in the last step, updated by `update_page_accesses`
     pub pages: Vec<PageAccess>,
+    /// Accessed pages of the last step, paired with the index of their PTE in
+    /// `page_table_map`. Like `pages`, this is reset on every step.
     pub accessed_ptes: Vec<(PageAccess, usize)>,
+    /// Indices of the PTEs with A/D bits set found by the last step, including those
+    /// of pages that are not present and thus not in `pages`
+    set_ptes: Vec<usize>,
+    parallel_scan: bool,
+    scan_cache: Option<ScanCache>,
+    /// Only track the dirty bits, see `set_writes_only`
+    writes_only: bool,
+    /// Accessed pages of the last step, if enabled with `set_accessed_bitmap`
+    bitmap: Option<PageBitmap>,
+    /// Address and size of the memory regions locked with `mlock`
+    locked: Vec<(usize, usize)>,
+    /// Base address of the enclave, the address of page 0 of `page_table_map`
+    base: usize,
+    /// Pages whose PTEs are tracked, see `refresh_mapping`
+    tracked: Vec<Range<usize>>,
+    /// Process that maps the enclave, if it is not the calling process
+    pid: Option<u32>,
 }
 
+#[cfg(feature = "sgx")]
 unsafe impl Sync for PageTable {}
+#[cfg(feature = "sgx")]
 unsafe impl Send for PageTable {}
 
+#[cfg(feature = "sgx")]
 impl PageTable {
-    pub fn new(enclave: &EnclaveRef) -> Self {
-        let mut page_table = Self {
-            page_table_map: Vec::new(),
-            pages: Vec::new(),
-            accessed_ptes: Vec::new(),
-        };
+    /// Map the PTEs of the enclave.
+    ///
+    /// If `ranges` is given, only the pages in these address ranges are locked in
+    /// memory and tracked, e.g. the [`segment_ranges`] of the enclave. The entries in `page_table_map` for all other pages of the
+    /// enclave are `None`, so `page_table_map` is still indexed by the page number
+    /// relative to the enclave base.
+    ///
+    /// For an enclave of another process, see [`attach_enclave`], the PTEs of that
+    /// process are mapped instead. Its memory can not be locked from here, but EPC
+    /// pages are only swapped out under EPC pressure.
+    pub fn new(
+        enclave: &EnclaveRef,
+        ranges: Option<&[Range<usize>]>,
+    ) -> Result<Self, ProfilerError> {
+        let mut page_table = Self::empty(enclave.base() as usize, enclave.pid());
 
-        page_table.map_all_ptes(enclave.base() as usize, enclave.end() as usize);
+        let pid = enclave.pid();
+        match ranges {
+            Some(ranges) => page_table.map_ptes(
+                enclave.base() as usize,
+                enclave.end() as usize,
+                ranges,
+                |address| Self::map_pte(pid, address),
+            )?,
+            None => page_table.map_all_ptes(enclave.base() as usize, enclave.end() as usize)?,
+        }
         page_table.clear_all_ad_bits();
 
-        page_table
+        Ok(page_table)
+    }
+
+    /// Page table of the enclave at `base` without any PTEs mapped
+    fn empty(base: usize, pid: Option<u32>) -> Self {
+        Self {
+            page_table_map: Vec::new(),
+            pages: Vec::new(),
+            accessed_ptes: Vec::new(),
+            set_ptes: Vec::new(),
+            parallel_scan: false,
+            writes_only: false,
+            bitmap: None,
+            scan_cache: None,
+            locked: Vec::new(),
+            base,
+            tracked: Vec::new(),
+            pid,
+        }
     }
 
-    fn map_all_ptes(&mut self, base_adrs: usize, end_adrs: usize) {
-        unsafe { mlock(base_adrs as *mut c_void, end_adrs - base_adrs) };
+    fn map_all_ptes(&mut self, base_adrs: usize, end_adrs: usize) -> Result<(), ProfilerError> {
+        self.lock(base_adrs, end_adrs - base_adrs)?;
         self.page_table_map = (0..=end_adrs - base_adrs)
             .step_by(PAGE_SIZE_4KiB as usize)
-            .map(|a| PageTableEntry::new(base_adrs + a))
+            .map(|a| Self::map_pte(self.pid, base_adrs + a))
             .collect();
+        self.tracked.push(0..self.page_table_map.len());
+        Ok(())
+    }
+
+    fn map_ptes(
+        &mut self,
+        base_adrs: usize,
+        end_adrs: usize,
+        ranges: &[Range<usize>],
+        map_pte: impl Fn(usize) -> Option<PageTableEntry>,
+    ) -> Result<(), ProfilerError> {
+        let page_size = PAGE_SIZE_4KiB as usize;
+        self.page_table_map
+            .resize_with((end_adrs - base_adrs) / page_size + 1, || None);
+
+        for range in ranges {
+            let start = range.start.max(base_adrs) & !(page_size - 1);
+            let end = range.end.min(end_adrs + page_size);
+            if start >= end {
+                continue;
+            }
+
+            self.lock(start, end - start)?;
+            for address in (start..end).step_by(page_size) {
+                self.page_table_map[(address - base_adrs) / page_size] = map_pte(address);
+            }
+            self.tracked.push(
+                (start - base_adrs) / page_size
+                    ..((end - base_adrs).div_ceil(page_size)).min(self.page_table_map.len()),
+            );
+        }
+        Ok(())
+    }
+
+    /// Map the PTEs of tracked pages that were not present when the page table was
+    /// created, e.g. pages added to the enclave with EDMM, and return how many were
+    /// found.
+    ///
+    /// Only the missing entries of `page_table_map` are looked up, and the PTEs that
+    /// are already mapped keep their A/D bits, so this is cheap if no pages were added.
+    /// New pages are locked in memory like the others.
+    pub fn refresh_mapping(&mut self) -> Result<usize, ProfilerError> {
+        let pid = self.pid;
+        self.refresh_mapping_with(|address| Self::map_pte(pid, address))
+    }
+
+    fn refresh_mapping_with(
+        &mut self,
+        map_pte: impl Fn(usize) -> Option<PageTableEntry>,
+    ) -> Result<usize, ProfilerError> {
+        let page_size = PAGE_SIZE_4KiB as usize;
+        let mut found = 0;
+        for pages in self.tracked.clone() {
+            for page in pages {
+                if self.page_table_map[page].is_some() {
+                    continue;
+                }
+                let address = self.base + page * page_size;
+                let Some(pte) = map_pte(address) else {
+                    continue;
+                };
+                if !self
+                    .locked
+                    .iter()
+                    .any(|&(start, size)| (start..start + size).contains(&address))
+                {
+                    self.lock(address, page_size)?;
+                }
+                self.page_table_map[page] = Some(pte);
+                found += 1;
+            }
+        }
+        if found > 0 {
+            debug!("mapped the PTEs of {found} new enclave pages");
+        }
+        Ok(found)
+    }
+
+    /// Map the PTE of `address` in the calling process, or in process `pid`
+    fn map_pte(pid: Option<u32>, address: usize) -> Option<PageTableEntry> {
+        match pid {
+            Some(pid) => PageTableEntry::new_in(pid, address),
+            None => PageTableEntry::new(address),
+        }
+    }
+
+    fn lock(&mut self, address: usize, size: usize) -> Result<(), ProfilerError> {
+        if self.pid.is_some() {
+            return Ok(());
+        }
+        if unsafe { mlock(address as *mut c_void, size) } != 0 {
+            return Err(ProfilerError::Mlock(io::Error::last_os_error()));
+        }
+        self.locked.push((address, size));
+        Ok(())
+    }
+
+    /// Scan and clear PTEs using a pool of worker threads.
+    ///
+    /// The PTE scan is executed from within the SIGTRAP handler. The worker pool is
+    /// created here, before the enclave is entered, so that no threads have to be
+    /// spawned from signal context. The interrupted thread only blocks until the workers
+    /// finish, and the workers never touch state that the interrupted enclave thread
+    /// could hold locked. The order of `pages` and `accessed_ptes` is the same as for
+    /// a sequential scan.
+    pub fn set_parallel_scan(&mut self, parallel_scan: bool) {
+        if parallel_scan {
+            // Force initialization of the global thread pool
+            rayon::current_num_threads();
+        }
+        self.parallel_scan = parallel_scan;
+    }
+
+    /// Only scan the PTEs of recently accessed pages, see `ScanCacheConfig`.
+    pub fn set_scan_cache(&mut self, config: Option<ScanCacheConfig>) {
+        let pid = self.pid;
+        self.set_scan_cache_with(config, |address| match pid {
+            Some(pid) => PageTableEntry::at_level_in(pid, address, PageTableLevel::PMD),
+            None => PageTableEntry::at_level(address, PageTableLevel::PMD),
+        });
+    }
+
+    fn set_scan_cache_with(
+        &mut self,
+        config: Option<ScanCacheConfig>,
+        map_pmd: impl Fn(usize) -> Option<PageTableEntry>,
+    ) {
+        let num_pages = self.page_table_map.len();
+        self.scan_cache =
+            config.map(|config| ScanCache::new(config, self.base, num_pages, map_pmd));
+    }
+
+    /// Only record the pages that are written, as `Perms::READ | Perms::WRITE` like in
+    /// a full scan, and leave the accessed bits alone.
+    ///
+    /// The accessed bit of a page then stays set after its first access, and only the
+    /// dirty bits are cleared, see [`PageTable::clear_dirty_only`]. PTEs of pages that
+    /// are only read are not written on every step, which saves most PTE writes of
+    /// workloads where only the writes matter.
+    pub fn set_writes_only(&mut self, writes_only: bool) {
+        self.writes_only = writes_only;
+    }
+
+    /// Also keep the accessed pages of every step in a [`PageBitmap`], see
+    /// [`PageTable::accessed_bitmap`].
+    pub fn set_accessed_bitmap(&mut self, enabled: bool) {
+        self.bitmap = enabled.then(|| PageBitmap::with_capacity(self.page_table_map.len()));
+    }
+
+    /// The pages accessed in the last step as a bitmap, if enabled with
+    /// [`PageTable::set_accessed_bitmap`]. Unlike `pages`, it does not hold the
+    /// permissions, but is cheaper to combine with the pages of other steps.
+    pub fn accessed_bitmap(&self) -> Option<&PageBitmap> {
+        self.bitmap.as_ref()
     }
 
+    /// Clear the A/D bits of all PTEs, or only the dirty bits in writes-only mode.
     pub fn clear_all_ad_bits(&mut self) {
-        self.page_table_map.iter_mut().for_each(|pte| {
-            if let Some(pte) = pte {
-                pte.mark_not_accessed();
+        let writes_only = self.writes_only;
+        let clear = |pte: &mut Option<PageTableEntry>| match pte {
+            Some(pte) if writes_only && pte.dirty() => pte.mark_clean(),
+            Some(pte) if !writes_only => pte.mark_not_accessed_clean(),
+            _ => {}
+        };
+
+        if self
+            .scan_cache
+            .as_ref()
+            .is_some_and(|cache| cache.last_scan_cached)
+        {
+            // A cached scan finds every PTE with its bits set, so only these are cleared
+            for &i in &self.set_ptes {
+                clear(&mut self.page_table_map[i]);
+            }
+        } else if self.parallel_scan {
+            self.page_table_map
+                .par_iter_mut()
+                .with_min_len(PARALLEL_SCAN_MIN_CHUNK)
+                .for_each(clear);
+        } else {
+            self.page_table_map.iter_mut().for_each(clear);
+        }
+        if let Some(cache) = &mut self.scan_cache {
+            cache.clear_regions();
+        }
+    }
+
+    /// Clear the A/D bits of only the PTEs that had any of them set in the last call to
+    /// `update_page_accesses`.
+    ///
+    /// These are the accessed pages, and pages whose bits were set before they became
+    /// not present, e.g. because they faulted. All other PTEs already have both bits
+    /// cleared, so this is equivalent to [`PageTable::clear_all_ad_bits`], but its cost
+    /// is proportional to the working set rather than the enclave size.
+    ///
+    /// In writes-only mode, this only clears the dirty bits like
+    /// [`PageTable::clear_dirty_only`].
+    pub fn clear_ad_bits_accessed_only(&mut self) {
+        if self.writes_only {
+            return self.clear_dirty_only();
+        }
+        for &i in &self.set_ptes {
+            if let Some(pte) = &mut self.page_table_map[i] {
+                pte.mark_not_accessed_clean();
+            }
+        }
+        if let Some(cache) = &mut self.scan_cache {
+            cache.clear_regions();
+        }
+    }
+
+    /// Clear only the dirty bits of the PTEs that were found to be dirty by the last
+    /// call to `update_page_accesses`, leaving the accessed bits set.
+    ///
+    /// All other PTEs are clean, so this is the only PTE write of a step in
+    /// writes-only mode, see [`PageTable::set_writes_only`].
+    pub fn clear_dirty_only(&mut self) {
+        for &i in &self.set_ptes {
+            if let Some(pte) = &mut self.page_table_map[i] {
                 pte.mark_clean();
             }
-        });
+        }
+        if let Some(cache) = &mut self.scan_cache {
+            cache.clear_regions();
+        }
+    }
+
+    /// Snapshot of the flags of the PTE of `page`, if it is tracked.
+    ///
+    /// This only reads the PTE, so comparing snapshots taken before and after
+    /// clearing the A/D bits with [`PteFlags::diff`] shows whether the clear stuck,
+    /// or e.g. the kernel set the bits again.
+    pub fn pte_snapshot(&self, page: usize) -> Option<PteFlags> {
+        self.page_table_map
+            .get(page)?
+            .as_ref()
+            .map(PageTableEntry::flags)
     }
 
     pub fn get_all_accessed_pages(&self) -> impl Iterator<Item = &PageAccess> {
         self.pages.iter()
     }
 
+    /// The accessed pages as ranges of consecutive pages, see [`page_ranges`]
+    pub fn accessed_ranges(&self) -> Vec<Range<usize>> {
+        page_ranges(self.pages.iter().map(|p| p.page.get()))
+    }
+
     pub fn get_accessed_pages(
         &self,
         filter: impl Fn(&PageAccess) -> bool,
@@ -110,55 +746,345 @@ impl PageTable {
 
     pub fn update_page_accesses(&mut self) {
         self.pages.clear();
+        self.accessed_ptes.clear();
+        self.set_ptes.clear();
+        let writes_only = self.writes_only;
 
-        for (i, pte) in self.page_table_map.iter().enumerate() {
-            if let Some(pte) = pte {
-                if pte.accessed() && pte.present() {
-                    let p = PageAccess {
-                        read: true,
-                        write: pte.dirty(),
-                        execute: false,
-                        page: i,
-                    };
-                    self.pages.push(p);
-                    self.accessed_ptes.push((p, i));
+        let cached = self
+            .scan_cache
+            .as_ref()
+            .is_some_and(|cache| !cache.full_scan_due());
+
+        let num_pages = self.page_table_map.len();
+        if let (true, Some(cache)) = (cached, &mut self.scan_cache) {
+            cache.collect_scanned(num_pages);
+            for &i in &cache.scanned {
+                if let Some(pte) = &self.page_table_map[i] {
+                    Self::scan_pte(i, pte, writes_only, &mut self.set_ptes, &mut self.pages);
+                }
+            }
+        } else if self.parallel_scan {
+            // Each chunk collects its set PTEs, which are merged in page order
+            self.set_ptes.par_extend(
+                self.page_table_map
+                    .par_iter()
+                    .with_min_len(PARALLEL_SCAN_MIN_CHUNK)
+                    .enumerate()
+                    .filter(|(_, pte)| {
+                        pte.as_ref()
+                            .is_some_and(|pte| Self::ad_bits_set(pte, writes_only))
+                    })
+                    .map(|(i, _)| i),
+            );
+            self.pages.extend(self.set_ptes.iter().filter_map(|&i| {
+                let pte = self.page_table_map[i].as_ref()?;
+                Self::page_access(RelativePage::new(i), pte, writes_only)
+            }));
+        } else {
+            for (i, pte) in self.page_table_map.iter().enumerate() {
+                if let Some(pte) = pte {
+                    Self::scan_pte(i, pte, writes_only, &mut self.set_ptes, &mut self.pages);
                 }
             }
         }
+        self.accessed_ptes
+            .extend(self.pages.iter().map(|&p| (p, p.page.get())));
+
+        if let Some(cache) = &mut self.scan_cache {
+            cache.record(&self.pages, cached, num_pages);
+        }
+        if let Some(bitmap) = &mut self.bitmap {
+            bitmap.clear();
+            bitmap.extend(self.pages.iter().map(|p| p.page.get()));
+        }
+    }
+
+    /// Whether the A/D bits of `pte` need to be cleared, only the dirty bit with
+    /// `writes_only`
+    fn ad_bits_set(pte: &PageTableEntry, writes_only: bool) -> bool {
+        pte.dirty() || (!writes_only && pte.accessed())
     }
+
+    /// Record the PTE at index `i` in `set_ptes` if its A/D bits are set, and its
+    /// access in `pages` if it is present
+    fn scan_pte(
+        i: usize,
+        pte: &PageTableEntry,
+        writes_only: bool,
+        set_ptes: &mut Vec<usize>,
+        pages: &mut Vec<PageAccess>,
+    ) {
+        if !Self::ad_bits_set(pte, writes_only) {
+            return;
+        }
+        set_ptes.push(i);
+        pages.extend(Self::page_access(RelativePage::new(i), pte, writes_only));
+    }
+
+    /// Access to `page` recorded in its PTE, if any. With `writes_only`, the accessed
+    /// bit is ignored as it is never cleared, and only writes are reported.
+    fn page_access(
+        page: RelativePage,
+        pte: &PageTableEntry,
+        writes_only: bool,
+    ) -> Option<PageAccess> {
+        if writes_only {
+            return (pte.dirty() && pte.present())
+                .then(|| PageAccess::new(page, Perms::READ | Perms::WRITE));
+        }
+        if pte.accessed() && pte.present() {
+            let perms = if pte.dirty() {
+                Perms::READ | Perms::WRITE
+            } else {
+                Perms::READ
+            };
+            Some(PageAccess::new(page, perms))
+        } else {
+            None
+        }
+    }
+}
+
+/// Unlocks the enclave memory that was locked when creating the `PageTable`.
+///
+/// The original A/D bits of the enclave PTEs are lost when they are cleared
+/// for profiling, so they cannot be restored.
+#[cfg(feature = "sgx")]
+impl Drop for PageTable {
+    fn drop(&mut self) {
+        for &(address, size) in &self.locked {
+            unsafe { munlock(address as *mut c_void, size) };
+        }
+    }
+}
+
+/// Outcome of [`TargetRange::step`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetStep {
+    /// The previous instruction was in the target range, record its page accesses
+    Record,
+    /// Execution enters the target range, discard the accesses made outside of it
+    Enter,
+    /// Execution is outside of the target range
+    Skip,
+}
+
+/// Restricts profiling to the instructions within a range of enclave addresses,
+/// e.g. a single function.
+///
+/// Every trap still interrupts the enclave, but steps outside of the range skip
+/// scanning the page table, which is the most expensive part of a step.
+#[derive(Debug, Clone)]
+pub struct TargetRange {
+    range: Range<usize>,
+    prev_in_target: bool,
 }
 
+impl TargetRange {
+    /// Only profile instructions with an (absolute) address in `range`
+    pub fn new(range: Range<usize>) -> Self {
+        Self {
+            range,
+            prev_in_target: false,
+        }
+    }
+
+    /// Decide what to do on a trap, given the erip of the next instruction.
+    ///
+    /// The page accesses recorded on a trap belong to the previous instruction,
+    /// so these are recorded if the previous erip was in the target range.
+    pub fn step(&mut self, erip: usize) -> TargetStep {
+        let in_target = self.range.contains(&erip);
+        let step = match (self.prev_in_target, in_target) {
+            (true, _) => TargetStep::Record,
+            (false, true) => TargetStep::Enter,
+            (false, false) => TargetStep::Skip,
+        };
+        self.prev_in_target = in_target;
+        step
+    }
+}
+
+/// Number of pages past the end of the enclave that [`create_dumper`] adds wires for,
+/// so that pages derived from addresses near the end, such as the neighbors of a
+/// prefetched stack page, still fit in the trace
+pub const DUMPER_EXTRA_PAGES: usize = 100;
+
+/// Create a dumper with a wire for every page of the enclave that passes the filter
+/// of the `config`, see [`VCDDumper::with_config`].
+#[cfg(feature = "sgx")]
 pub fn create_dumper<S: TracePageSet>(
     enclave: &EnclaveRef,
     vcd_file: impl AsRef<Path>,
-) -> VCDDumper<S> {
-    VCDDumper::new(
-        vcd_file,
-        (enclave.size() as usize) / PAGE_SIZE_4KiB as usize + 100,
-    )
+    config: DumperConfig,
+) -> Result<VCDDumper<S>, ProfilerError> {
+    VCDDumper::with_config(vcd_file, enclave.page_count() + DUMPER_EXTRA_PAGES, config)
+}
+
+type SignalHandler = Box<dyn FnMut(&libc::siginfo_t, &libc::ucontext_t) + Send + Sync + 'static>;
+
+/// Registered handler of a signal, and whether a thread is running it
+struct HandlerSlot {
+    handler: AtomicPtr<SignalHandler>,
+    busy: AtomicBool,
+}
+
+impl HandlerSlot {
+    const fn new() -> Self {
+        Self {
+            handler: AtomicPtr::new(ptr::null_mut()),
+            busy: AtomicBool::new(false),
+        }
+    }
+}
+
+// Registered handlers are set once and never freed, and are called without
+// a lock that the interrupted code could hold, which would deadlock.
+//
+// This is sound because a handler is only ever called by one thread at a time:
+// - The handler is stored before the signal action is installed, which happens
+//   before the enclave is entered, so the wrapper never sees a partial handler.
+// - The kernel blocks a signal while its handler runs, so the handler does not
+//   re-enter itself.
+// - The threads of a multithreaded enclave may trap at the same time. The wrapper
+//   does not wait for the other thread, which it may have interrupted, but skips
+//   the signal if `busy` is set and records `HandlerError::Concurrent`.
+static TRAP_HANDLER: HandlerSlot = HandlerSlot::new();
+static FAULT_HANDLER: HandlerSlot = HandlerSlot::new();
+static SAMPLE_HANDLER: HandlerSlot = HandlerSlot::new();
+
+/// Handler slot for the given signal, one handler per signal is supported
+fn signal_handler_slot(signum: libc::c_int) -> Option<&'static HandlerSlot> {
+    match signum {
+        libc::SIGTRAP => Some(&TRAP_HANDLER),
+        libc::SIGSEGV => Some(&FAULT_HANDLER),
+        libc::SIGALRM => Some(&SAMPLE_HANDLER),
+        _ => None,
+    }
 }
 
-static TRAP_HANDLER: OnceCell<Mutex<Box<dyn FnMut() + Send + Sync + 'static>>> = OnceCell::new();
+/// Trap flag in RFLAGS, which raises `SIGTRAP` after every instruction
+const RFLAGS_TF: libc::greg_t = 1 << 8;
 
-extern "C" fn trap_handler_wrapper(
-    _signum: libc::c_int,
-    _si: *mut libc::siginfo_t,
-    _vuctx: *mut libc::c_void,
+extern "C" fn signal_handler_wrapper(
+    signum: libc::c_int,
+    si: *mut libc::siginfo_t,
+    vuctx: *mut libc::c_void,
 ) {
-    (TRAP_HANDLER.get().unwrap().lock().unwrap())()
+    let Some((slot, handler)) = signal_handler_slot(signum)
+        .map(|slot| (slot, slot.handler.load(Ordering::Acquire)))
+        .filter(|(_, handler)| !handler.is_null())
+    else {
+        return;
+    };
+    let uctx = unsafe { &mut *(vuctx as *mut libc::ucontext_t) };
+    if slot.busy.swap(true, Ordering::Acquire) {
+        // A skipped fault is not lost: the faulting instruction faults again
+        if signum != libc::SIGSEGV {
+            set_handler_error(HandlerError::Concurrent);
+        }
+        return;
+    }
+    // SAFETY: see `TRAP_HANDLER`, no other reference to the handler exists
+    (unsafe { &mut *handler })(unsafe { &*si }, uctx);
+    slot.busy.store(false, Ordering::Release);
+
+    if signum == libc::SIGTRAP && stop_requested() {
+        // Resume without single-stepping, the trap handler has finished its last step
+        uctx.uc_mcontext.gregs[libc::REG_EFL as usize] &= !RFLAGS_TF;
+    }
 }
 
-pub fn create_trap_handler(
-    handler: impl FnMut() + Send + Sync + 'static,
-) -> Result<(), Box<dyn Error>> {
-    TRAP_HANDLER
-        .set(Mutex::new(Box::new(handler)))
-        .map_err(|_| "handler already registered!")?;
+/// Code of the first [`HandlerError`] since the last [`check_signal_handlers`], 0 if none
+static HANDLER_ERROR: AtomicU8 = AtomicU8::new(0);
+
+fn set_handler_error(error: HandlerError) {
+    let _ = HANDLER_ERROR.compare_exchange(0, error as u8, Ordering::AcqRel, Ordering::Acquire);
+}
+
+/// Return the first failure of a signal handler since the last call, as
+/// `ProfilerError::SignalHandler`.
+///
+/// Handlers only record why they failed, so this is called once the enclave returned.
+pub fn check_signal_handlers() -> Result<(), ProfilerError> {
+    match HandlerError::from_code(HANDLER_ERROR.swap(0, Ordering::AcqRel)) {
+        Some(error) => Err(ProfilerError::SignalHandler(error)),
+        None => Ok(()),
+    }
+}
+
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn stop_handler(_signum: libc::c_int) {
+    if STOP_REQUESTED.swap(true, Ordering::SeqCst) {
+        // Interrupted again while stopping, give up on a clean stop
+        unsafe { libc::_exit(130) }
+    }
+}
+
+/// Stop profiling cleanly on Ctrl-C.
+///
+/// `SIGINT` only sets a flag, which handlers check with [`stop_requested`] to
+/// finish their current step and flush their outputs. After the trap handler
+/// returns, single-stepping is disabled, so the enclave runs to completion
+/// and the profiler and enclave are torn down as usual. A second `SIGINT`
+/// terminates the process immediately.
+pub fn create_stop_handler() -> Result<(), ProfilerError> {
     unsafe {
         signal::sigaction(
-            signal::SIGTRAP,
+            signal::SIGINT,
             &signal::SigAction::new(
-                signal::SigHandler::SigAction(trap_handler_wrapper),
+                signal::SigHandler::Handler(stop_handler),
+                signal::SaFlags::SA_RESTART,
+                signal::SigSet::empty(),
+            ),
+        )
+    }?;
+    Ok(())
+}
+
+/// Returns true once `SIGINT` was received after [`create_stop_handler`]
+pub fn stop_requested() -> bool {
+    STOP_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Register a handler for `SIGTRAP`, `SIGSEGV` or `SIGALRM`, called with the signal info.
+///
+/// Returns `ProfilerError::SignalInstall` for other signals.
+pub fn create_signal_handler(
+    signal: signal::Signal,
+    mut handler: impl FnMut(&libc::siginfo_t) + Send + Sync + 'static,
+) -> Result<(), ProfilerError> {
+    install_signal_handler(signal, Box::new(move |si, _| handler(si)))
+}
+
+/// Register a handler that is also called with the context of the interrupted thread
+fn install_signal_handler(
+    signal: signal::Signal,
+    handler: SignalHandler,
+) -> Result<(), ProfilerError> {
+    let slot = signal_handler_slot(signal as libc::c_int)
+        .ok_or(ProfilerError::SignalInstall(nix::Error::EINVAL))?;
+    let handler: *mut SignalHandler = Box::into_raw(Box::new(handler));
+    if slot
+        .handler
+        .compare_exchange(
+            ptr::null_mut(),
+            handler,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+        .is_err()
+    {
+        // SAFETY: the handler was not published
+        drop(unsafe { Box::from_raw(handler) });
+        return Err(ProfilerError::HandlerAlreadyRegistered);
+    }
+    unsafe {
+        signal::sigaction(
+            signal,
+            &signal::SigAction::new(
+                signal::SigHandler::SigAction(signal_handler_wrapper),
                 signal::SaFlags::SA_RESTART | signal::SaFlags::SA_SIGINFO,
                 signal::SigSet::empty(),
             ),
@@ -167,6 +1093,459 @@ pub fn create_trap_handler(
     Ok(())
 }
 
+/// State that a signal handler uses while the profiler runs, and that is taken back
+/// afterwards, without a lock that the interrupted thread could hold.
+///
+/// The handler borrows the state for one call with [`HandlerState::with`]. Once the
+/// profiler returned, [`HandlerState::take`] moves it out again, e.g. to write the
+/// outputs, so it does not live on in the handler, which is never freed.
+///
+/// ```
+/// use sgx_profiler::HandlerState;
+///
+/// let state = HandlerState::new(0);
+/// assert_eq!(state.with(|steps| { *steps += 1; *steps }), Some(1));
+/// assert_eq!(state.take(), Some(1));
+/// // Calls of the handler after the state was taken are ignored
+/// assert_eq!(state.with(|steps| *steps), None);
+/// ```
+#[derive(Debug)]
+pub struct HandlerState<T>(AtomicPtr<T>);
+
+// The state is only accessed by the one thread that took the pointer
+unsafe impl<T: Send> Send for HandlerState<T> {}
+unsafe impl<T: Send> Sync for HandlerState<T> {}
+
+impl<T> HandlerState<T> {
+    pub fn new(state: T) -> Self {
+        Self(AtomicPtr::new(Box::into_raw(Box::new(state))))
+    }
+
+    /// Call `f` with the state, or return `None` if it was taken.
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        let state = self.0.swap(ptr::null_mut(), Ordering::Acquire);
+        if state.is_null() {
+            return None;
+        }
+        // SAFETY: swapping out the pointer gives this call exclusive access
+        let result = f(unsafe { &mut *state });
+        self.0.store(state, Ordering::Release);
+        Some(result)
+    }
+
+    /// Move the state out, or return `None` if it was taken or is in use by
+    /// [`HandlerState::with`].
+    pub fn take(&self) -> Option<T> {
+        let state = self.0.swap(ptr::null_mut(), Ordering::Acquire);
+        // SAFETY: the pointer was created by `Box::into_raw` and is no longer shared
+        (!state.is_null()).then(|| *unsafe { Box::from_raw(state) })
+    }
+}
+
+impl<T> Drop for HandlerState<T> {
+    fn drop(&mut self) {
+        drop(self.take());
+    }
+}
+
+/// Register a handler for the single-step trap raised after every enclave instruction.
+pub fn create_trap_handler(
+    mut handler: impl FnMut() + Send + Sync + 'static,
+) -> Result<(), ProfilerError> {
+    create_trap_event_handler(move |_| handler())
+}
+
+/// Why the enclave was interrupted, as written to the `cause` wire of a trace,
+/// see [`VCDEntry::write_cause`](dump::VCDEntry::write_cause)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TrapCause {
+    /// Signal sent by a process or thread rather than raised by the processor,
+    /// e.g. a stray `kill`
+    Spurious = 0,
+    /// Debug exception of the trap flag, the expected cause of every step
+    SingleStep = 1,
+    /// Breakpoint or any other trap raised by the kernel
+    Breakpoint = 2,
+    PageFault = 3,
+}
+
+/// Signal that interrupted the enclave, passed to the handler of
+/// [`create_trap_event_handler`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrapEvent {
+    pub signal: libc::c_int,
+    /// `si_code` of the signal info
+    pub code: libc::c_int,
+}
+
+impl TrapEvent {
+    pub fn from_siginfo(si: &libc::siginfo_t) -> Self {
+        Self {
+            signal: si.si_signo,
+            code: si.si_code,
+        }
+    }
+
+    /// Classify the signal by its number and `si_code`.
+    ///
+    /// Codes of at most zero are sent by user space, see `sigaction(2)`.
+    pub fn cause(&self) -> TrapCause {
+        match (self.signal, self.code) {
+            (_, code) if code <= 0 => TrapCause::Spurious,
+            (libc::SIGSEGV, _) => TrapCause::PageFault,
+            (libc::SIGTRAP, libc::TRAP_TRACE) => TrapCause::SingleStep,
+            _ => TrapCause::Breakpoint,
+        }
+    }
+}
+
+/// Register a handler for the single-step trap like [`create_trap_handler`], called
+/// with the signal that raised the trap, to tell genuine single-steps apart from
+/// other traps.
+///
+/// ```
+/// use std::sync::mpsc;
+///
+/// use nix::sys::signal::{raise, SIGTRAP};
+/// use sgx_profiler::{create_trap_event_handler, TrapCause};
+///
+/// let (tx, rx) = mpsc::channel();
+/// let tx = std::sync::Mutex::new(tx);
+/// create_trap_event_handler(move |event| tx.lock().unwrap().send(event.cause()).unwrap())
+///     .unwrap();
+///
+/// // A trap sent by a thread, rather than by the trap flag
+/// raise(SIGTRAP).unwrap();
+/// assert_eq!(rx.recv().unwrap(), TrapCause::Spurious);
+/// ```
+pub fn create_trap_event_handler(
+    mut handler: impl FnMut(&TrapEvent) + Send + Sync + 'static,
+) -> Result<(), ProfilerError> {
+    create_signal_handler(signal::SIGTRAP, move |si| {
+        handler(&TrapEvent::from_siginfo(si))
+    })
+}
+
+/// Register a handler for the single-step trap like [`create_trap_handler`], called
+/// with the address of the TCS of the enclave thread that executed the instruction.
+///
+/// On an AEX, the processor sets RBX to the address of the TCS of the interrupted
+/// thread before the trap is raised at the AEP, which tells the threads of a
+/// multithreaded enclave apart. The page table is shared by all threads, so accesses
+/// are only attributed to the right thread if the threads do not run concurrently,
+/// e.g. if they are pinned to a single CPU.
+pub fn create_thread_trap_handler(
+    mut handler: impl FnMut(usize) + Send + Sync + 'static,
+) -> Result<(), ProfilerError> {
+    install_signal_handler(
+        signal::SIGTRAP,
+        Box::new(move |_, uctx| handler(uctx.uc_mcontext.gregs[libc::REG_RBX as usize] as usize)),
+    )
+}
+
+/// Timer that periodically interrupts the thread that created it, see
+/// [`create_sample_handler`]
+#[derive(Debug)]
+pub struct SampleTimer(Timer);
+
+impl SampleTimer {
+    /// Stop the timer and call the sample handler a last time, to record the
+    /// accesses made since the last sample.
+    pub fn finish(self) -> Result<(), ProfilerError> {
+        drop(self.0);
+        signal::raise(signal::SIGALRM)?;
+        Ok(())
+    }
+}
+
+/// Register a handler that is called every `interval`, to sample the pages
+/// accessed by the enclave without single-stepping it.
+///
+/// The timer raises `SIGALRM` in the calling thread, which must be the thread that
+/// enters the enclave. The signal interrupts the enclave with an AEX, which also
+/// flushes its TLB entries, so pages accessed after a sample set their A/D bits
+/// again. The timer runs until the returned [`SampleTimer`] is finished or dropped.
+pub fn create_sample_handler(
+    interval: Duration,
+    mut handler: impl FnMut() + Send + Sync + 'static,
+) -> Result<SampleTimer, ProfilerError> {
+    create_signal_handler(signal::SIGALRM, move |_| handler())?;
+    let mut timer = Timer::new(
+        ClockId::CLOCK_MONOTONIC,
+        signal::SigEvent::new(signal::SigevNotify::SigevThreadId {
+            signal: signal::SIGALRM,
+            thread_id: gettid().as_raw(),
+            si_value: 0,
+        }),
+    )?;
+    timer.set(
+        Expiration::Interval(interval.into()),
+        TimerSetTimeFlags::empty(),
+    )?;
+    Ok(SampleTimer(timer))
+}
+
+/// Register a handler for page faults, called with the faulting enclave page.
+///
+/// The handler must restore access to the page, or the faulting instruction
+/// faults again when the enclave is resumed. If it returns an error, the page
+/// could not be restored, so `HandlerError::FaultNotHandled` is recorded and the
+/// fault terminates the process like faults outside of the enclave, which are
+/// not handled.
+#[cfg(feature = "sgx")]
+pub fn create_fault_handler(
+    enclave: &EnclaveRef,
+    mut handler: impl FnMut(RelativePage) -> Result<(), ProfilerError> + Send + Sync + 'static,
+) -> Result<(), ProfilerError> {
+    let (base, limit) = (enclave.base() as usize, enclave.limit() as usize);
+    create_signal_handler(signal::SIGSEGV, move |si| {
+        let address = unsafe { si.si_addr() } as usize;
+        if (base..limit).contains(&address) {
+            let page = RelativePage::new((address - base) / PAGE_SIZE_4KiB as usize);
+            // The error can not be logged here, see `HandlerError`
+            if handler(page).is_ok() {
+                return;
+            }
+            set_handler_error(HandlerError::FaultNotHandled);
+        }
+        // Returning re-executes the faulting instruction, which now faults
+        // with the default action
+        let _ = unsafe { signal::signal(signal::SIGSEGV, signal::SigHandler::SigDfl) };
+    })
+}
+
+/// Tracks the pages accessed in every step using page faults instead of A/D bits.
+///
+/// This works for production enclaves, which cannot be read with the debug
+/// instructions. Access to all enclave pages is revoked up front, so the first
+/// access to a page in a step faults. The fault handler calls [`FaultTracker::record`],
+/// which restores access to the page so that the instruction can continue, and
+/// [`FaultTracker::clear`] revokes the recorded pages again after the step.
+///
+/// Faults do not tell which kind of access was made, so every page is recorded as read.
+#[cfg(feature = "sgx")]
+#[derive(Debug, Default)]
+pub struct FaultTracker {
+    pages: Vec<PageAccess>,
+    num_pages: usize,
+}
+
+#[cfg(feature = "sgx")]
+impl FaultTracker {
+    /// Revoke access to all enclave pages, except the TCS and SSA pages that
+    /// the processor needs to enter and exit the enclave.
+    pub fn new(enclave: &EnclaveRef) -> Result<Self, ProfilerError> {
+        let num_pages = protected_pages(enclave);
+        protect_pages(0..num_pages, num_pages, revoke_pages)?;
+        Ok(Self {
+            pages: Vec::new(),
+            num_pages,
+        })
+    }
+
+    /// Record an access to `page` and restore access to it.
+    pub fn record(&mut self, page: RelativePage) -> Result<(), ProfilerError> {
+        protect_pages(page.get()..page.get() + 1, self.num_pages, restore_pages)?;
+        self.pages.push(PageAccess::new(page, Perms::READ));
+        Ok(())
+    }
+
+    /// A queue through which a fault handler that can't share the tracker
+    /// restores and records faulting pages, see [`FaultQueue`]
+    pub fn queue(&self, capacity: usize) -> FaultQueue {
+        FaultQueue {
+            pages: (0..capacity).map(|_| AtomicUsize::new(0)).collect(),
+            len: AtomicUsize::new(0),
+            num_pages: self.num_pages,
+            restore: restore_pages,
+        }
+    }
+
+    /// Record the pages that faulted since the last call, whose access the fault
+    /// handler already restored.
+    ///
+    /// Returns `ProfilerError::FaultQueueFull` if more pages faulted than fit in the
+    /// queue, after recording those that did.
+    pub fn record_queued(&mut self, queue: &FaultQueue) -> Result<(), ProfilerError> {
+        let len = queue.len.swap(0, Ordering::Acquire);
+        self.pages.extend(
+            queue.pages[..len.min(queue.pages.len())]
+                .iter()
+                .map(|page| {
+                    PageAccess::new(RelativePage::new(page.load(Ordering::Relaxed)), Perms::READ)
+                }),
+        );
+        if len > queue.pages.len() {
+            return Err(ProfilerError::FaultQueueFull {
+                capacity: queue.pages.len(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Pages accessed since the last call to `clear`
+    pub fn accessed_pages(&self) -> &[PageAccess] {
+        &self.pages
+    }
+
+    /// Revoke access to the recorded pages again, so their next access faults.
+    pub fn clear(&mut self) -> Result<(), ProfilerError> {
+        self.retain_last(0)
+    }
+
+    /// Revoke access to all but the `n` most recently recorded pages.
+    ///
+    /// Keeping the last few pages accessible lets an instruction that accesses
+    /// multiple pages, e.g. its code page and a data page, make progress.
+    pub fn retain_last(&mut self, n: usize) -> Result<(), ProfilerError> {
+        let revoked = self.pages.len().saturating_sub(n);
+        for access in self.pages.drain(..revoked) {
+            let page = access.page.get();
+            protect_pages(page..page + 1, self.num_pages, revoke_pages)?;
+        }
+        Ok(())
+    }
+
+    /// Restore access to all enclave pages, so that the enclave no longer faults.
+    pub fn restore_all(&mut self) -> Result<(), ProfilerError> {
+        self.pages.clear();
+        protect_pages(0..self.num_pages, self.num_pages, restore_pages)
+    }
+}
+
+/// Pages that faulted in the current step, passed from the fault handler to the
+/// trap handler that owns the [`FaultTracker`] without a lock, see
+/// [`FaultTracker::queue`]
+///
+/// Both handlers run on the thread that enters the enclave, so pushing and draining
+/// never overlap, and the atomics only make sharing the queue sound.
+///
+/// ```no_run
+/// use std::sync::Arc;
+///
+/// use sgx_profiler::{create_fault_handler, create_trap_handler, FaultTracker};
+/// # fn trace(enclave: &sgx_profiler::sgx_step::EnclaveRef) -> Result<(), sgx_profiler::ProfilerError> {
+/// let mut tracker = FaultTracker::new(enclave)?;
+/// let queue = Arc::new(tracker.queue(64));
+/// let faults = queue.clone();
+/// create_fault_handler(enclave, move |page| faults.push(page))?;
+/// create_trap_handler(move || {
+///     if tracker.record_queued(&queue).is_ok() {
+///         println!("{} pages accessed", tracker.accessed_pages().len());
+///     }
+///     let _ = tracker.clear();
+/// })?;
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "sgx")]
+#[derive(Debug)]
+pub struct FaultQueue {
+    pages: Box<[AtomicUsize]>,
+    /// Number of pushed pages, which exceeds the capacity if pages were dropped
+    len: AtomicUsize,
+    num_pages: usize,
+    /// `restore_pages` of libsgxstep, replaced in tests
+    restore: unsafe extern "C" fn(usize, usize) -> libc::c_int,
+}
+
+#[cfg(feature = "sgx")]
+impl FaultQueue {
+    /// Restore access to the faulting `page` and queue it for
+    /// [`FaultTracker::record_queued`].
+    ///
+    /// A page that does not fit in the queue is still restored, and reported as
+    /// `ProfilerError::FaultQueueFull` by the next `record_queued`.
+    pub fn push(&self, page: RelativePage) -> Result<(), ProfilerError> {
+        protect_pages(page.get()..page.get() + 1, self.num_pages, self.restore)?;
+        let index = self.len.fetch_add(1, Ordering::AcqRel);
+        if let Some(slot) = self.pages.get(index) {
+            slot.store(page.get(), Ordering::Release);
+        }
+        Ok(())
+    }
+}
+
+/// Revoke and restore access to enclave pages, so that accesses to them fault
+///
+/// These are checked wrappers around `revoke_pages`/`restore_pages` of libsgxstep,
+/// which may be replaced with more clever PTE manipulation. Pages are relative to
+/// the enclave base, and only the pages before the TCS and SSA pages at the end
+/// of the enclave can be protected.
+pub trait PageProtection {
+    /// Revoke access to `pages`.
+    ///
+    /// Returns `ProfilerError::PagesOutOfBounds` if the range exceeds the enclave.
+    fn revoke(&self, pages: Range<usize>) -> Result<(), ProfilerError>;
+
+    /// Restore access to `pages`.
+    ///
+    /// Returns `ProfilerError::PagesOutOfBounds` if the range exceeds the enclave.
+    fn restore(&self, pages: Range<usize>) -> Result<(), ProfilerError>;
+}
+
+#[cfg(feature = "sgx")]
+impl PageProtection for EnclaveRef {
+    fn revoke(&self, pages: Range<usize>) -> Result<(), ProfilerError> {
+        protect_pages(pages, protected_pages(self), revoke_pages)
+    }
+
+    fn restore(&self, pages: Range<usize>) -> Result<(), ProfilerError> {
+        protect_pages(pages, protected_pages(self), restore_pages)
+    }
+}
+
+/// Number of enclave pages that can be protected, up to the TCS and SSA pages
+#[cfg(feature = "sgx")]
+fn protected_pages(enclave: &EnclaveRef) -> usize {
+    (enclave.end() as usize - enclave.base() as usize) / PAGE_SIZE_4KiB as usize
+}
+
+/// Check that `pages` is a range of pages within the first `num_pages` enclave pages.
+///
+/// ```
+/// use sgx_profiler::{check_page_range, ProfilerError};
+///
+/// assert!(check_page_range(&(0..4), 4).is_ok());
+/// assert!(check_page_range(&(4..4), 4).is_ok());
+/// for pages in [3..5, 4..5, 3..2, usize::MAX..usize::MAX] {
+///     assert!(matches!(
+///         check_page_range(&pages, 4),
+///         Err(ProfilerError::PagesOutOfBounds { num_pages: 4, .. })
+///     ));
+/// }
+/// ```
+pub fn check_page_range(pages: &Range<usize>, num_pages: usize) -> Result<(), ProfilerError> {
+    if pages.start <= pages.end && pages.end <= num_pages {
+        Ok(())
+    } else {
+        Err(ProfilerError::PagesOutOfBounds {
+            pages: pages.clone(),
+            num_pages,
+        })
+    }
+}
+
+/// Call `revoke_pages`/`restore_pages` on `pages` and convert its return value to a `Result`
+#[cfg(feature = "sgx")]
+fn protect_pages(
+    pages: Range<usize>,
+    num_pages: usize,
+    protect: unsafe extern "C" fn(usize, usize) -> libc::c_int,
+) -> Result<(), ProfilerError> {
+    check_page_range(&pages, num_pages)?;
+    if pages.is_empty() || unsafe { protect(pages.start, pages.len()) } == 0 {
+        Ok(())
+    } else {
+        Err(ProfilerError::Mprotect {
+            pages,
+            error: io::Error::last_os_error(),
+        })
+    }
+}
+
+#[cfg(feature = "sgx")]
 #[derive(Debug)]
 pub struct ProfilerLibrary<'l> {
     profiler_setup: Symbol<'l, extern "C" fn(u64, u64, u64, u64, *const *const c_char)>,
@@ -174,8 +1553,9 @@ pub struct ProfilerLibrary<'l> {
     profiler_destroy: Symbol<'l, extern "C" fn(u64)>,
 }
 
+#[cfg(feature = "sgx")]
 impl<'l> ProfilerLibrary<'l> {
-    pub fn new(lib: &'l libloading::Library) -> Result<Self, Box<dyn Error>> {
+    pub fn new(lib: &'l libloading::Library) -> Result<Self, ProfilerError> {
         unsafe {
             Ok(Self {
                 profiler_setup: lib.get(b"profiler_setup")?,
@@ -186,9 +1566,74 @@ impl<'l> ProfilerLibrary<'l> {
     }
 }
 
+/// Real-time priority of a thread isolated with [`isolate_thread`], the lowest one,
+/// which is still above all threads of the default policy
+const ISOLATED_PRIORITY: libc::c_int = 1;
+
+/// Pin the calling thread to `cpu` and run it with the `SCHED_FIFO` real-time policy,
+/// so that the scheduler neither migrates it nor preempts it for other threads, which
+/// makes single-stepping and timing more reliable.
+///
+/// The enclave runs on the thread that calls [`run_profiler`], and signal handlers on
+/// the thread they interrupt, so call this from that thread before entering the
+/// enclave. Threads created afterwards, such as the other threads of a multithreaded
+/// enclave, inherit the affinity and the policy, so they take turns on `cpu`. For
+/// the least noise, `cpu` should be isolated from other work, e.g. with `isolcpus`.
+///
+/// Lacking the permission to change the policy (`CAP_SYS_NICE`) only logs a warning.
+/// Returns `ProfilerError::CpuAffinity` if the thread can not run on `cpu`.
+///
+/// ```no_run
+/// use nix::{sched::sched_getaffinity, unistd::Pid};
+/// use sgx_profiler::isolate_thread;
+///
+/// let allowed = sched_getaffinity(Pid::from_raw(0)).unwrap();
+/// let cpu = (0..).find(|&cpu| allowed.is_set(cpu).unwrap()).unwrap();
+/// isolate_thread(cpu).unwrap();
+///
+/// let pinned = sched_getaffinity(Pid::from_raw(0)).unwrap();
+/// assert!(pinned.is_set(cpu).unwrap());
+/// assert!((0..nix::sched::CpuSet::count()).all(|c| c == cpu || !pinned.is_set(c).unwrap()));
+/// ```
+pub fn isolate_thread(cpu: usize) -> Result<(), ProfilerError> {
+    pin_thread(cpu)?;
+    let param = libc::sched_param {
+        sched_priority: ISOLATED_PRIORITY,
+    };
+    let policy =
+        nix::errno::Errno::result(unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) });
+    check_realtime_policy(cpu, policy.map(drop))
+}
+
+/// Pin the calling thread to `cpu` only, without changing its policy
+fn pin_thread(cpu: usize) -> Result<(), ProfilerError> {
+    let mut cpus = CpuSet::new();
+    cpus.set(cpu)
+        .and_then(|_| sched_setaffinity(Pid::from_raw(0), &cpus))
+        .map_err(|error| ProfilerError::CpuAffinity { cpu, error })
+}
+
+/// Handle the result of switching a thread pinned to `cpu` to the real-time policy,
+/// where a missing permission only logs a warning
+fn check_realtime_policy(cpu: usize, policy: nix::Result<()>) -> Result<(), ProfilerError> {
+    match policy {
+        Ok(()) => debug!("pinned to CPU {cpu} with the real-time policy"),
+        Err(nix::Error::EPERM) => {
+            warn!("not permitted to run with the real-time policy, only pinned to CPU {cpu}")
+        }
+        Err(error) => return Err(ProfilerError::CpuAffinity { cpu, error }),
+    }
+    Ok(())
+}
+
+#[cfg(feature = "sgx")]
 pub fn run_profiler(lib: ProfilerLibrary<'_>, enclave: &EnclaveRef, args: &[impl AsRef<str>]) {
     let ebase_address = enclave.base() as u64;
     let esize = enclave.size() as u64;
+    debug!(
+        "running profiler on enclave at {ebase_address:#x} ({esize:#x} bytes) with arguments {:?}",
+        args.iter().map(AsRef::as_ref).collect::<Vec<_>>()
+    );
 
     let profiler_args = args
         .iter()
@@ -210,6 +1655,972 @@ pub fn run_profiler(lib: ProfilerLibrary<'_>, enclave: &EnclaveRef, args: &[impl
     (*lib.profiler_destroy)(enclave.id().sgx_eid().unwrap());
 }
 
-pub fn create_enclave(enclave: &str) -> Result<Enclave, Box<dyn Error>> {
-    Enclave::new_sgx(enclave, true)
+/// Initialize logging to stderr for the binaries.
+///
+/// Diagnostics are logged at the `info` level by default, which `RUST_LOG` can
+/// override. With `quiet`, only warnings and errors are logged, so that only the
+/// requested outputs remain when the tools are used in scripts.
+pub fn init_logger(quiet: bool) {
+    let mut builder = if quiet {
+        let mut builder = env_logger::Builder::new();
+        builder.filter_level(log::LevelFilter::Warn);
+        builder
+    } else {
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+    };
+    builder.init();
+}
+
+/// Environment variable with the directory that relative enclave and profiler
+/// library paths are resolved against, see [`resolve_app_path`]
+pub const APP_DIR_VAR: &str = "SGX_APP_DIR";
+
+/// Resolve the path of an enclave or profiler library, so experiments can refer to
+/// them relative to a checkout wherever it is.
+///
+/// Relative paths are resolved against `base_dir`, or the directory in the
+/// [`APP_DIR_VAR`] environment variable if it is not given, or the working directory
+/// if neither is set. Absolute paths are kept. Fails if no file exists at the
+/// resolved path.
+///
+/// ```no_run
+/// use sgx_profiler::{resolve_app_path, APP_DIR_VAR};
+///
+/// let dir = tempfile::tempdir().unwrap();
+/// let base = dir.path().join("app-dir");
+/// std::fs::create_dir_all(base.join("enclave")).unwrap();
+/// std::fs::write(base.join("enclave/enclave.so"), []).unwrap();
+///
+/// let path = resolve_app_path("enclave/enclave.so", Some(&base)).unwrap();
+/// assert_eq!(path, base.join("enclave/enclave.so"));
+/// let absolute = base.join("enclave/enclave.so");
+/// assert_eq!(resolve_app_path(&absolute, Some("/nonexistent".as_ref())).unwrap(), absolute);
+///
+/// // The environment variable is used without an explicit base directory
+/// std::env::set_var(APP_DIR_VAR, &base);
+/// assert_eq!(resolve_app_path("enclave/enclave.so", None).unwrap(), path);
+/// std::env::remove_var(APP_DIR_VAR);
+///
+/// let e = resolve_app_path("enclave/missing.so", Some(&base)).unwrap_err();
+/// assert!(e.to_string().contains("resolved against"));
+/// ```
+pub fn resolve_app_path(
+    path: impl AsRef<Path>,
+    base_dir: Option<&Path>,
+) -> Result<PathBuf, ProfilerError> {
+    resolve_app_path_in(path.as_ref(), base_dir, std::env::var_os(APP_DIR_VAR))
+}
+
+/// [`resolve_app_path`] with the value of [`APP_DIR_VAR`], if it is set
+fn resolve_app_path_in(
+    path: &Path,
+    base_dir: Option<&Path>,
+    app_dir: Option<OsString>,
+) -> Result<PathBuf, ProfilerError> {
+    let base_dir = base_dir
+        .map(Path::to_path_buf)
+        .or_else(|| app_dir.map(PathBuf::from))
+        .filter(|_| path.is_relative());
+    let resolved = match &base_dir {
+        Some(base_dir) => base_dir.join(path),
+        None => path.to_path_buf(),
+    };
+    if resolved.is_file() {
+        Ok(resolved)
+    } else {
+        Err(ProfilerError::AppPathNotFound {
+            path: resolved,
+            base_dir,
+        })
+    }
+}
+
+/// Create the enclave, in debug mode if `debug` is set.
+///
+/// Production enclaves can not be read with the debug instructions, so the erip,
+/// the registers and enclave memory are not available. See [`FaultTracker`] for
+/// tracking page accesses without them.
+#[cfg(feature = "sgx")]
+pub fn create_enclave(enclave: &str, debug: bool) -> Result<Enclave, ProfilerError> {
+    Enclave::new_sgx(enclave, debug).map_err(ProfilerError::EnclaveCreate)
+}
+
+/// Create an enclave like [`create_enclave`], reusing the launch token in `token_file`
+/// if given, see [`Enclave::new_sgx_with_token`].
+#[cfg(feature = "sgx")]
+pub fn create_enclave_with_token(
+    enclave: &str,
+    debug: bool,
+    token_file: Option<&Path>,
+) -> Result<Enclave, ProfilerError> {
+    match token_file {
+        Some(path) => Enclave::new_sgx_with_token(enclave, debug, path),
+        None => Enclave::new_sgx(enclave, debug),
+    }
+    .map_err(ProfilerError::EnclaveCreate)
+}
+
+/// Attach to an enclave launched by another process, e.g. a real application
+/// instead of a test harness.
+///
+/// The enclave must be mapped at `base..base + size` in process `pid`, as listed
+/// in `/proc/<pid>/maps`. A [`PageTable`] of the returned enclave tracks the A/D
+/// bits in the page tables of that process. They are located by the sgx-step kernel
+/// driver and mapped through `/dev/mem`, so the target does not have to be stopped.
+/// The enclave is still single-stepped by the target process, so the caller decides
+/// when to scan the page table. Symbols, registers and page faults of the enclave
+/// are not available, see [`EnclaveRef::from_external`].
+///
+/// # Privileges
+///
+/// The calling process must be allowed to ptrace the target, which is checked
+/// here by opening `/proc/<pid>/mem`. This requires the same user and
+/// `kernel.yama.ptrace_scope` 0, a descendant process and `ptrace_scope` 1, or
+/// `CAP_SYS_PTRACE`. Mapping the PTEs requires access to `/dev/sgx-step` and
+/// `/dev/mem`, i.e. root, as for enclaves created by the profiler.
+///
+/// ```
+/// use std::process::Command;
+///
+/// // A cooperating child process, attach to its first mapping
+/// let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+/// let maps = std::fs::read_to_string(format!("/proc/{}/maps", child.id())).unwrap();
+/// let (start, end) = maps.split_whitespace().next().unwrap().split_once('-').unwrap();
+/// let base = usize::from_str_radix(start, 16).unwrap();
+/// let size = usize::from_str_radix(end, 16).unwrap() - base;
+///
+/// let enclave = sgx_profiler::attach_enclave(child.id(), base, size).unwrap();
+/// assert_eq!(enclave.pid(), Some(child.id()));
+/// assert_eq!(enclave.base() as usize, base);
+/// assert_eq!(enclave.limit() as usize, base + size);
+///
+/// // The page at address 0 is never mapped
+/// assert!(sgx_profiler::attach_enclave(child.id(), 0, 0x1000).is_err());
+/// child.kill().unwrap();
+/// child.wait().unwrap();
+/// ```
+#[cfg(feature = "sgx")]
+pub fn attach_enclave(pid: u32, base: usize, size: usize) -> Result<EnclaveRef, ProfilerError> {
+    let error = |error| ProfilerError::Attach { pid, error };
+    let page_size = PAGE_SIZE_4KiB as usize;
+    if size == 0 || (base | size) & (page_size - 1) != 0 {
+        return Err(error(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("enclave {base:#x}+{size:#x} is not page aligned"),
+        )));
+    }
+
+    File::open(format!("/proc/{pid}/mem")).map_err(error)?;
+    let maps = fs::read_to_string(format!("/proc/{pid}/maps")).map_err(error)?;
+    // The enclave may consist of multiple adjacent mappings with different permissions
+    let mut mapped = base;
+    for range in maps.lines().filter_map(|line| {
+        let (start, end) = line.split_whitespace().next()?.split_once('-')?;
+        Some(usize::from_str_radix(start, 16).ok()?..usize::from_str_radix(end, 16).ok()?)
+    }) {
+        if range.contains(&mapped) {
+            mapped = range.end;
+        }
+    }
+    if mapped < base + size {
+        return Err(error(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("address {mapped:#x} of enclave {base:#x}+{size:#x} is not mapped"),
+        )));
+    }
+
+    debug!("attached to enclave at {base:#x}+{size:#x} of process {pid}");
+    Ok(EnclaveRef::from_external(pid, base, size))
+}
+
+/// Error for a symbol `name` that can not be passed to the symbol lookup
+#[cfg(feature = "sgx")]
+fn invalid_symbol_name(name: &str) -> ProfilerError {
+    ProfilerError::InvalidSymbolName(name.to_string())
+}
+
+/// Resolve the addresses of the given enclave symbols.
+///
+/// Returns `ProfilerError::SymbolNotFound` for the first symbol that is missing, or
+/// `ProfilerError::InvalidSymbolName` for a name with a NUL byte.
+#[cfg(feature = "sgx")]
+pub fn resolve_symbols(enclave: &EnclaveRef, names: &[&str]) -> Result<Vec<u64>, ProfilerError> {
+    let addresses = enclave
+        .symbol_addresses(names)
+        .map_err(|e| invalid_symbol_name(&String::from_utf8_lossy(&e.into_vec())))?;
+    names
+        .iter()
+        .zip(addresses)
+        .map(|(name, address)| {
+            address.ok_or_else(|| ProfilerError::SymbolNotFound(name.to_string()))
+        })
+        .collect()
+}
+
+/// Resolve the range of pages covered by the given enclave symbol.
+///
+/// Symbols without a size, such as assembly labels, only cover the page they are on.
+#[cfg(feature = "sgx")]
+pub fn resolve_symbol_pages(
+    enclave: &EnclaveRef,
+    name: &str,
+) -> Result<Range<usize>, ProfilerError> {
+    let not_found = || ProfilerError::SymbolNotFound(name.to_string());
+    let range = match enclave
+        .symbol_range(name)
+        .map_err(|_| invalid_symbol_name(name))?
+    {
+        Some(range) => range,
+        None => {
+            let offset = enclave
+                .symbol_offset(name)
+                .map_err(|_| invalid_symbol_name(name))? as usize;
+            if offset == 0 {
+                return Err(not_found());
+            }
+            let address = enclave.base() as usize + offset;
+            address..address + 1
+        }
+    };
+    let start = RelativePage::containing(range.start, enclave).ok_or_else(not_found)?;
+    let end = RelativePage::containing(range.end - 1, enclave).ok_or_else(not_found)?;
+    Ok(start.get()..end.get() + 1)
+}
+
+/// Resolve the range of absolute addresses covered by the given enclave symbol.
+///
+/// See [`EnclaveRef::symbol_range`] for how the size of the symbol is determined.
+#[cfg(feature = "sgx")]
+pub fn resolve_symbol_range(
+    enclave: &EnclaveRef,
+    name: &str,
+) -> Result<Range<usize>, ProfilerError> {
+    enclave
+        .symbol_range(name)
+        .map_err(|_| invalid_symbol_name(name))?
+        .ok_or_else(|| ProfilerError::SymbolNotFound(name.to_string()))
+}
+
+#[cfg(all(test, feature = "sgx"))]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::sync::{Mutex, PoisonError};
+
+    /// Serializes the tests that lock memory, as the limit is per process
+    static MLOCK: Mutex<()> = Mutex::new(());
+
+    /// Serializes the tests that record handler errors, which are per process
+    static HANDLER_ERRORS: Mutex<()> = Mutex::new(());
+
+    #[repr(C, align(4096))]
+    struct Page([u8; PAGE_SIZE]);
+
+    /// Page table of a fake enclave in `memory`, with the PTEs of the pages in `ranges`
+    /// mapped to the fake PTEs in `ptes`
+    fn fake_enclave(
+        memory: &[Page],
+        ranges: &[Range<usize>],
+        ptes: &mut [u64],
+    ) -> Result<PageTable, ProfilerError> {
+        let base = memory.as_ptr() as usize;
+        let end = base + (memory.len() - 1) * PAGE_SIZE;
+        let ptes = ptes.as_mut_ptr();
+        let mut page_table = PageTable::empty(base, None);
+        page_table.map_ptes(base, end, ranges, |address| {
+            Some(unsafe { PageTableEntry::from_raw(ptes.add((address - base) / PAGE_SIZE)) })
+        })?;
+        Ok(page_table)
+    }
+
+    /// Page table over the fake PTEs in `ptes`, which must outlive it, with all pages
+    /// present
+    fn fake_page_table(ptes: &mut [u64]) -> PageTable {
+        let mut page_table = PageTable::empty(0, None);
+        page_table.page_table_map = ptes
+            .iter_mut()
+            .map(|pte| {
+                let mut pte = unsafe { PageTableEntry::from_raw(pte) };
+                pte.mark_present();
+                Some(pte)
+            })
+            .collect();
+        page_table.tracked.push(0..ptes.len());
+        page_table
+    }
+
+    /// Let the "hardware" access random pages, some of which are no longer present
+    fn access_random_pages(rng: &mut StdRng, page_table: &mut PageTable) {
+        for _ in 0..rng.gen_range(0..8) {
+            let i = rng.gen_range(0..page_table.page_table_map.len());
+            let pte = page_table.page_table_map[i].as_mut().unwrap();
+            pte.mark_accessed();
+            if rng.gen_bool(0.3) {
+                pte.mark_dirty();
+            }
+            if rng.gen_bool(0.2) {
+                pte.mark_not_present();
+            } else {
+                pte.mark_present();
+            }
+        }
+    }
+
+    #[test]
+    fn only_pages_in_ranges_are_mapped() {
+        let memory = (0..8).map(|_| Page([0; PAGE_SIZE])).collect::<Vec<_>>();
+        let base = memory.as_ptr() as usize;
+        let mut ptes = vec![0; memory.len()];
+        let ranges = [
+            base + PAGE_SIZE..base + 3 * PAGE_SIZE,
+            base + 5 * PAGE_SIZE + 100..base + 5 * PAGE_SIZE + 200,
+        ];
+
+        let _lock = MLOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let page_table = fake_enclave(&memory, &ranges, &mut ptes).unwrap();
+        let mapped = page_table
+            .page_table_map
+            .iter()
+            .map(Option::is_some)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            mapped,
+            [false, true, true, false, false, true, false, false]
+        );
+        assert_eq!(page_table.tracked, [1..3, 5..6]);
+    }
+
+    /// Memory locked by this process in KiB
+    fn locked_kib() -> usize {
+        let status = std::fs::read_to_string("/proc/self/status").unwrap();
+        let line = status.lines().find(|l| l.starts_with("VmLck:")).unwrap();
+        line.split_whitespace().nth(1).unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn drop_unlocks_memory() {
+        let memory = (0..8).map(|_| Page([0; PAGE_SIZE])).collect::<Vec<_>>();
+        let base = memory.as_ptr() as usize;
+        let mut ptes = vec![0; memory.len()];
+        let all = base..base + 8 * PAGE_SIZE;
+
+        let _lock = MLOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let before = locked_kib();
+        let page_table = fake_enclave(&memory, std::slice::from_ref(&all), &mut ptes).unwrap();
+        assert_eq!(locked_kib(), before + 8 * PAGE_SIZE / 1024);
+        drop(page_table);
+        assert_eq!(locked_kib(), before);
+    }
+
+    #[test]
+    fn accessed_ptes_are_bounded() {
+        let mut rng = StdRng::seed_from_u64(1301);
+        let mut ptes = vec![0; 64];
+        let mut page_table = fake_page_table(&mut ptes);
+        let mut capacity = 0;
+        for step in 0..10_000 {
+            access_random_pages(&mut rng, &mut page_table);
+            page_table.update_page_accesses();
+            page_table.clear_ad_bits_accessed_only();
+            assert!(page_table.accessed_ptes.len() <= page_table.page_table_map.len());
+            if step == 1000 {
+                capacity = page_table.accessed_ptes.capacity();
+            }
+        }
+        // Once it has grown to the largest step, the buffer is reused
+        assert!(page_table.accessed_ptes.capacity() <= capacity.max(64));
+    }
+
+    #[test]
+    fn cached_scan_equals_full_scan() {
+        for writes_only in [false, true] {
+            let mut rng = StdRng::seed_from_u64(1300);
+            let (mut full_ptes, mut cached_ptes) = (vec![0; 2000], vec![0; 2000]);
+            let mut full = fake_page_table(&mut full_ptes);
+            let mut cached = fake_page_table(&mut cached_ptes);
+            // One fake PMD entry per 2 MiB region, the last one is not mapped
+            let mut pmds = [0u64; 3];
+            let pmd_ptr = pmds.as_mut_ptr();
+            full.set_writes_only(writes_only);
+            cached.set_writes_only(writes_only);
+            cached.set_scan_cache_with(
+                Some(ScanCacheConfig {
+                    neighborhood: 2,
+                    full_scan_interval: 50,
+                }),
+                |address| {
+                    let region = address / PAGE_SIZE / ScanCache::REGION_PAGES;
+                    (region < 3).then(|| unsafe { PageTableEntry::from_raw(pmd_ptr.add(region)) })
+                },
+            );
+
+            for _ in 0..5000 {
+                let mut accesses = Vec::new();
+                for _ in 0..rng.gen_range(0..6) {
+                    // Mostly near a recently accessed page, but also anywhere else
+                    let page = match full.pages.len() {
+                        len if len > 0 && rng.gen_bool(0.8) => {
+                            let near = full.pages[rng.gen_range(0..len)].page.get();
+                            (near + rng.gen_range(0..7)).saturating_sub(3).min(1999)
+                        }
+                        _ => rng.gen_range(0..2000),
+                    };
+                    accesses.push((page, rng.gen_bool(0.3), rng.gen_bool(0.1)));
+                }
+                for page_table in [&mut full, &mut cached] {
+                    for &(page, dirty, not_present) in &accesses {
+                        let pte = page_table.page_table_map[page].as_mut().unwrap();
+                        pte.mark_accessed();
+                        if dirty {
+                            pte.mark_dirty();
+                        }
+                        if not_present {
+                            pte.mark_not_present();
+                        } else {
+                            pte.mark_present();
+                        }
+                    }
+                }
+                // The page walk also sets the accessed bit of the PMD entry
+                for &(page, ..) in &accesses {
+                    let region = page / ScanCache::REGION_PAGES;
+                    if region < 3 {
+                        unsafe { PageTableEntry::from_raw(pmd_ptr.add(region)) }.mark_accessed();
+                    }
+                }
+                if rng.gen_bool(0.1) {
+                    let cache = cached.scan_cache.as_mut().unwrap();
+                    cache.cached_scans = cache.config.full_scan_interval;
+                }
+
+                full.update_page_accesses();
+                cached.update_page_accesses();
+                assert_eq!(full.pages, cached.pages);
+                assert_eq!(full.set_ptes, cached.set_ptes);
+                if rng.gen_bool(0.5) {
+                    full.clear_all_ad_bits();
+                    cached.clear_all_ad_bits();
+                } else {
+                    full.clear_ad_bits_accessed_only();
+                    cached.clear_ad_bits_accessed_only();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn refresh_maps_newly_present_pages() {
+        let memory = (0..8).map(|_| Page([0; PAGE_SIZE])).collect::<Vec<_>>();
+        let base = memory.as_ptr() as usize;
+        let mut ptes = vec![0; memory.len()];
+        let ptes = ptes.as_mut_ptr();
+        // Page 5 is only added to the enclave after the page table was created
+        let added = AtomicBool::new(false);
+        let map_pte = |address: usize| {
+            let page = (address - base) / PAGE_SIZE;
+            (page != 5 || added.load(Ordering::Relaxed))
+                .then(|| unsafe { PageTableEntry::from_raw(ptes.add(page)) })
+        };
+
+        let all = base..base + 8 * PAGE_SIZE;
+
+        let _lock = MLOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut page_table = PageTable::empty(base, None);
+        page_table
+            .map_ptes(
+                base,
+                all.end - PAGE_SIZE,
+                std::slice::from_ref(&all),
+                map_pte,
+            )
+            .unwrap();
+        for pte in page_table.page_table_map.iter_mut().flatten() {
+            pte.mark_present();
+        }
+        assert!(page_table.page_table_map[5].is_none());
+        page_table.page_table_map[2]
+            .as_mut()
+            .unwrap()
+            .mark_accessed();
+        assert_eq!(page_table.refresh_mapping_with(map_pte).unwrap(), 0);
+
+        added.store(true, Ordering::Relaxed);
+        assert_eq!(page_table.refresh_mapping_with(map_pte).unwrap(), 1);
+        let pte = page_table.page_table_map[5].as_mut().unwrap();
+        pte.mark_present();
+        pte.mark_accessed();
+        // Nothing changed since the last refresh
+        assert_eq!(page_table.refresh_mapping_with(map_pte).unwrap(), 0);
+
+        // The access to page 2 before the refresh is kept, and the new page is scanned
+        page_table.update_page_accesses();
+        let pages = page_table
+            .pages
+            .iter()
+            .map(|p| p.page.get())
+            .collect::<Vec<_>>();
+        assert_eq!(pages, [2, 5]);
+    }
+
+    #[test]
+    fn clear_accessed_only_equals_full_clear() {
+        for writes_only in [false, true] {
+            let mut rng = StdRng::seed_from_u64(1302);
+            let (mut full_ptes, mut accessed_ptes) = (vec![0; 512], vec![0; 512]);
+            let mut full = fake_page_table(&mut full_ptes);
+            let mut accessed = fake_page_table(&mut accessed_ptes);
+            let flags = |page_table: &PageTable| {
+                (0..page_table.page_table_map.len())
+                    .map(|page| page_table.pte_snapshot(page))
+                    .collect::<Vec<_>>()
+            };
+            full.set_writes_only(writes_only);
+            accessed.set_writes_only(writes_only);
+
+            for _ in 0..1000 {
+                full.update_page_accesses();
+                accessed.update_page_accesses();
+                assert_eq!(full.pages, accessed.pages);
+                full.clear_all_ad_bits();
+                accessed.clear_ad_bits_accessed_only();
+                assert_eq!(flags(&full), flags(&accessed));
+
+                let seed = rng.gen();
+                access_random_pages(&mut StdRng::seed_from_u64(seed), &mut full);
+                access_random_pages(&mut StdRng::seed_from_u64(seed), &mut accessed);
+            }
+        }
+    }
+
+    unsafe extern "C" fn restore_nothing(_page: usize, _num_pages: usize) -> libc::c_int {
+        0
+    }
+
+    thread_local! {
+        /// Arguments of the calls to `record_protect` on this thread
+        static PROTECTED: std::cell::RefCell<Vec<(usize, usize)>> = Default::default();
+    }
+
+    unsafe extern "C" fn record_protect(page: usize, num_pages: usize) -> libc::c_int {
+        PROTECTED.with(|calls| calls.borrow_mut().push((page, num_pages)));
+        0
+    }
+
+    unsafe extern "C" fn deny_protect(_page: usize, _num_pages: usize) -> libc::c_int {
+        *libc::__errno_location() = libc::EACCES;
+        -1
+    }
+
+    #[test]
+    fn protected_pages_are_checked_against_the_enclave() {
+        let protect = |pages| {
+            PROTECTED.with(|calls| calls.borrow_mut().clear());
+            let result = protect_pages(pages, 8, record_protect);
+            (result, PROTECTED.with(|calls| calls.take()))
+        };
+
+        let (result, calls) = protect(2..5);
+        assert!(result.is_ok());
+        assert_eq!(calls, [(2, 3)]);
+        // Empty ranges are not passed on
+        let (result, calls) = protect(8..8);
+        assert!(result.is_ok() && calls.is_empty());
+        // Out of bounds ranges are rejected before they are passed on
+        #[allow(clippy::reversed_empty_ranges)]
+        for pages in [7..9, 9..10, 5..4] {
+            let (result, calls) = protect(pages.clone());
+            assert!(
+                matches!(
+                    result,
+                    Err(ProfilerError::PagesOutOfBounds { pages: ref p, num_pages: 8 }) if *p == pages
+                ),
+                "{pages:?} accepted"
+            );
+            assert!(calls.is_empty());
+        }
+    }
+
+    #[test]
+    fn failed_protection_reports_the_pages_and_errno() {
+        match protect_pages(1..3, 8, deny_protect) {
+            Err(ProfilerError::Mprotect { pages, error }) => {
+                assert_eq!(pages, 1..3);
+                assert_eq!(error.raw_os_error(), Some(libc::EACCES));
+            }
+            result => panic!("unexpected result {result:?}"),
+        }
+    }
+
+    #[test]
+    fn fault_queue_hands_pages_to_tracker() {
+        let mut tracker = FaultTracker {
+            pages: Vec::new(),
+            num_pages: 8,
+        };
+        let mut queue = tracker.queue(2);
+        queue.restore = restore_nothing;
+        let accessed = |tracker: &FaultTracker| {
+            tracker
+                .accessed_pages()
+                .iter()
+                .map(|access| access.page.get())
+                .collect::<Vec<_>>()
+        };
+
+        queue.push(RelativePage::new(3)).unwrap();
+        queue.push(RelativePage::new(5)).unwrap();
+        tracker.record_queued(&queue).unwrap();
+        assert_eq!(accessed(&tracker), [3, 5]);
+        // The queue is empty after it was drained
+        tracker.record_queued(&queue).unwrap();
+        assert_eq!(accessed(&tracker), [3, 5]);
+
+        assert!(matches!(
+            queue.push(RelativePage::new(8)),
+            Err(ProfilerError::PagesOutOfBounds { num_pages: 8, .. })
+        ));
+        for page in [1, 2, 4] {
+            queue.push(RelativePage::new(page)).unwrap();
+        }
+        assert!(matches!(
+            tracker.record_queued(&queue),
+            Err(ProfilerError::FaultQueueFull { capacity: 2 })
+        ));
+        assert_eq!(accessed(&tracker), [3, 5, 1, 2]);
+    }
+
+    #[test]
+    fn invalid_symbol_name_is_reported() {
+        let enclave = EnclaveRef::from_external(1, 0x7000_0000, 0x1000);
+        let error = resolve_symbols(&enclave, &["ecall_a", "ecall\0b", "ecall_c"]).unwrap_err();
+        assert!(
+            matches!(&error, ProfilerError::InvalidSymbolName(name) if name == "ecall\0b"),
+            "{error:?}"
+        );
+    }
+
+    #[test]
+    fn busy_handler_skips_the_signal_and_records_an_error() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        let _lock = HANDLER_ERRORS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        create_signal_handler(signal::SIGALRM, |_| {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap();
+        let mut si: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let mut uctx: libc::ucontext_t = unsafe { std::mem::zeroed() };
+        let raise = |si: &mut libc::siginfo_t, uctx: &mut libc::ucontext_t| {
+            signal_handler_wrapper(libc::SIGALRM, si, uctx as *mut _ as *mut libc::c_void)
+        };
+
+        raise(&mut si, &mut uctx);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+        assert!(check_signal_handlers().is_ok());
+
+        // Another thread is running the handler
+        SAMPLE_HANDLER.busy.store(true, Ordering::SeqCst);
+        raise(&mut si, &mut uctx);
+        SAMPLE_HANDLER.busy.store(false, Ordering::SeqCst);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+        assert!(matches!(
+            check_signal_handlers(),
+            Err(ProfilerError::SignalHandler(HandlerError::Concurrent))
+        ));
+        // Errors are reported once
+        assert!(check_signal_handlers().is_ok());
+    }
+
+    fn trap_event(signal: libc::c_int, code: libc::c_int) -> TrapEvent {
+        TrapEvent { signal, code }
+    }
+
+    #[test]
+    fn trap_events_are_classified_by_signal_and_code() {
+        assert_eq!(
+            trap_event(libc::SIGTRAP, libc::TRAP_TRACE).cause(),
+            TrapCause::SingleStep
+        );
+        assert_eq!(
+            trap_event(libc::SIGTRAP, libc::TRAP_BRKPT).cause(),
+            TrapCause::Breakpoint
+        );
+        // SEGV_ACCERR, a protection fault
+        assert_eq!(trap_event(libc::SIGSEGV, 2).cause(), TrapCause::PageFault);
+        // Sent by `kill`, `tgkill` or `sigqueue`, whatever the signal
+        for code in [libc::SI_USER, libc::SI_TKILL, libc::SI_QUEUE] {
+            for signal in [libc::SIGTRAP, libc::SIGSEGV] {
+                assert_eq!(trap_event(signal, code).cause(), TrapCause::Spurious);
+            }
+        }
+    }
+
+    #[test]
+    fn trap_event_handler_receives_the_signal_info() {
+        static CAUSE: AtomicU8 = AtomicU8::new(u8::MAX);
+        create_trap_event_handler(|event| CAUSE.store(event.cause() as u8, Ordering::SeqCst))
+            .unwrap();
+        let mut uctx: libc::ucontext_t = unsafe { std::mem::zeroed() };
+        let mut deliver = |code| {
+            let mut si: libc::siginfo_t = unsafe { std::mem::zeroed() };
+            si.si_signo = libc::SIGTRAP;
+            si.si_code = code;
+            signal_handler_wrapper(
+                libc::SIGTRAP,
+                &mut si,
+                &mut uctx as *mut _ as *mut libc::c_void,
+            );
+            CAUSE.swap(u8::MAX, Ordering::SeqCst)
+        };
+
+        assert_eq!(deliver(libc::TRAP_TRACE), TrapCause::SingleStep as u8);
+        assert_eq!(deliver(libc::SI_TKILL), TrapCause::Spurious as u8);
+        assert_eq!(deliver(libc::TRAP_BRKPT), TrapCause::Breakpoint as u8);
+        assert!(matches!(
+            create_trap_handler(|| {}),
+            Err(ProfilerError::HandlerAlreadyRegistered)
+        ));
+    }
+
+    #[test]
+    fn app_paths_are_resolved_against_the_base_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let (base, app_dir) = (dir.path().join("base"), dir.path().join("app"));
+        for dir in [&base, &app_dir] {
+            fs::create_dir_all(dir.join("enclave")).unwrap();
+            fs::write(dir.join("enclave/enclave.so"), []).unwrap();
+        }
+        let relative = Path::new("enclave/enclave.so");
+        let resolve = |path: &Path, base_dir: Option<&Path>, app_dir: Option<&Path>| {
+            resolve_app_path_in(path, base_dir, app_dir.map(OsString::from))
+        };
+
+        // The base directory takes precedence over the environment variable
+        let enclave = base.join(relative);
+        assert_eq!(resolve(relative, Some(&base), None).unwrap(), enclave);
+        assert_eq!(
+            resolve(relative, Some(&base), Some(&app_dir)).unwrap(),
+            enclave
+        );
+        assert_eq!(
+            resolve(relative, None, Some(&app_dir)).unwrap(),
+            app_dir.join(relative)
+        );
+        // Absolute paths are kept
+        let missing = Path::new("/nonexistent");
+        assert_eq!(
+            resolve(&enclave, Some(missing), Some(missing)).unwrap(),
+            enclave
+        );
+        // Without either, relative to the working directory, the package in tests
+        assert_eq!(
+            resolve(Path::new("Cargo.toml"), None, None).unwrap(),
+            Path::new("Cargo.toml")
+        );
+    }
+
+    #[test]
+    fn missing_app_paths_report_their_base_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().to_path_buf();
+        fs::create_dir(base.join("enclave")).unwrap();
+
+        let error = resolve_app_path_in(Path::new("missing.so"), Some(&base), None).unwrap_err();
+        assert!(
+            matches!(
+                &error,
+                ProfilerError::AppPathNotFound { path, base_dir: Some(base_dir) }
+                    if *path == base.join("missing.so") && *base_dir == base
+            ),
+            "{error:?}"
+        );
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "{} not found, relative paths are resolved against {}",
+                base.join("missing.so").display(),
+                base.display()
+            )
+        );
+
+        // A directory is not an enclave
+        let error = resolve_app_path_in(&base.join("enclave"), None, None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("{} not found", base.join("enclave").display())
+        );
+    }
+
+    #[test]
+    fn first_handler_error_is_kept() {
+        let _lock = HANDLER_ERRORS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        set_handler_error(HandlerError::FaultNotHandled);
+        set_handler_error(HandlerError::Concurrent);
+        assert!(matches!(
+            check_signal_handlers(),
+            Err(ProfilerError::SignalHandler(HandlerError::FaultNotHandled))
+        ));
+        assert!(check_signal_handlers().is_ok());
+    }
+
+    /// Every combination of permissions
+    fn all_perms() -> impl Iterator<Item = Perms> + Clone {
+        (0..=Perms::all().bits()).map(Perms::from_bits_truncate)
+    }
+
+    #[test]
+    fn intersection_and_difference_partition_the_permissions() {
+        let page = RelativePage::new(5);
+        for (a, b) in all_perms().flat_map(|a| all_perms().map(move |b| (a, b))) {
+            let (a, b) = (PageAccess::new(page, a), PageAccess::new(page, b));
+            let common = a.intersect(&b).unwrap();
+            assert_eq!(common, b.intersect(&a).unwrap());
+            assert_eq!(common.perms, a.perms & b.perms);
+
+            let only_a = a.difference(&b).map_or(Perms::empty(), |d| d.perms);
+            assert_eq!(only_a | common.perms, a.perms);
+            assert!((only_a & b.perms).is_empty());
+            assert_eq!(a.difference(&b).is_none(), b.covers(&a));
+        }
+    }
+
+    #[test]
+    fn accesses_to_different_pages_do_not_intersect() {
+        let rw = PageAccess::new(RelativePage::new(1), Perms::READ | Perms::WRITE);
+        let other = PageAccess::new(RelativePage::new(2), Perms::all());
+        assert_eq!(rw.intersect(&other), None);
+        assert_eq!(rw.difference(&other), Some(rw));
+        assert_eq!(
+            PageAccess::new(RelativePage::new(1), Perms::empty()).difference(&other),
+            None
+        );
+    }
+
+    #[test]
+    fn page_ranges_cover_exactly_the_pages() {
+        let mut rng = StdRng::seed_from_u64(1365);
+        for _ in 0..1000 {
+            let pages = (0..rng.gen_range(0..20))
+                .map(|_| rng.gen_range(0..40))
+                .collect::<Vec<_>>();
+            let ranges = page_ranges(pages.iter().copied());
+
+            let mut covered = ranges.iter().cloned().flatten().collect::<Vec<_>>();
+            let mut expected = pages.clone();
+            expected.sort();
+            expected.dedup();
+            assert_eq!(covered, expected, "{pages:?} as {ranges:?}");
+            covered.dedup();
+            assert_eq!(covered.len(), expected.len(), "{ranges:?} overlap");
+            // Ranges are sorted, and separated by at least one page
+            assert!(ranges.iter().all(|range| !range.is_empty()));
+            assert!(
+                ranges.windows(2).all(|w| w[0].end < w[1].start),
+                "{ranges:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn accessed_ranges_of_the_page_table() {
+        let mut ptes = vec![0; 32];
+        let mut page_table = fake_page_table(&mut ptes);
+        for page in [3, 4, 5, 9, 20, 31] {
+            page_table.page_table_map[page]
+                .as_mut()
+                .unwrap()
+                .mark_accessed();
+        }
+        page_table.update_page_accesses();
+        assert_eq!(page_table.accessed_ranges(), [3..6, 9..10, 20..21, 31..32]);
+
+        page_table.clear_ad_bits_accessed_only();
+        page_table.update_page_accesses();
+        assert!(page_table.accessed_ranges().is_empty());
+    }
+
+    #[test]
+    fn covers_and_union_match_the_separate_permissions() {
+        let page = RelativePage::new(3);
+        let bools = |perms: Perms| {
+            let access = PageAccess::new(page, perms);
+            [access.read(), access.write(), access.execute()]
+        };
+        for (a, b) in all_perms().flat_map(|a| all_perms().map(move |b| (a, b))) {
+            let (a_bits, b_bits) = (bools(a), bools(b));
+            let (a, b) = (PageAccess::new(page, a), PageAccess::new(page, b));
+            assert_eq!(
+                a.covers(&b),
+                (0..3).all(|i| !b_bits[i] || a_bits[i]),
+                "{a:?} covers {b:?}"
+            );
+            let union = a.with_line(0).union(&b.with_line(0x40));
+            assert_eq!(
+                bools(union.perms),
+                [0, 1, 2].map(|i| a_bits[i] || b_bits[i])
+            );
+            assert_eq!((union.page, union.lines), (page, 0b11));
+            assert!(union.covers(&a) && union.covers(&b));
+        }
+        // No access covers an access to another page, not even an empty one
+        let empty = PageAccess::new(RelativePage::new(4), Perms::empty());
+        assert!(!PageAccess::new(page, Perms::all()).covers(&empty));
+    }
+
+    #[test]
+    fn perms_are_serialized_by_name() {
+        for perms in all_perms() {
+            let json = serde_json::to_string(&perms).unwrap();
+            let names = perms.iter_names().map(|(name, _)| name).collect::<Vec<_>>();
+            assert_eq!(json, format!("\"{}\"", names.join(" | ")));
+            assert_eq!(serde_json::from_str::<Perms>(&json).unwrap(), perms);
+
+            let access = PageAccess::new(RelativePage::new(9), perms);
+            let toml = toml::to_string(&access).unwrap();
+            assert_eq!(toml::from_str::<PageAccess>(&toml).unwrap(), access);
+        }
+        assert!(serde_json::from_str::<Perms>(r#""READ | SHARED""#).is_err());
+    }
+
+    #[test]
+    fn threads_are_pinned_to_a_single_cpu() {
+        use nix::sched::sched_getaffinity;
+
+        let allowed = sched_getaffinity(Pid::from_raw(0)).unwrap();
+        let cpu = (0..CpuSet::count())
+            .find(|&cpu| allowed.is_set(cpu).unwrap())
+            .unwrap();
+        // A thread of its own, so the test process keeps its affinity
+        let pinned = std::thread::spawn(move || {
+            pin_thread(cpu).unwrap();
+            sched_getaffinity(Pid::from_raw(0)).unwrap()
+        })
+        .join()
+        .unwrap();
+        assert!((0..CpuSet::count()).all(|c| pinned.is_set(c).unwrap() == (c == cpu)));
+        assert_eq!(sched_getaffinity(Pid::from_raw(0)).unwrap(), allowed);
+
+        let invalid = CpuSet::count();
+        let e = std::thread::spawn(move || pin_thread(invalid))
+            .join()
+            .unwrap();
+        assert!(matches!(e, Err(ProfilerError::CpuAffinity { cpu, .. }) if cpu == invalid));
+    }
+
+    #[test]
+    fn only_a_missing_permission_for_the_realtime_policy_is_tolerated() {
+        assert!(check_realtime_policy(0, Ok(())).is_ok());
+        assert!(check_realtime_policy(0, Err(nix::Error::EPERM)).is_ok());
+        let e = check_realtime_policy(1, Err(nix::Error::EINVAL));
+        assert!(matches!(
+            e,
+            Err(ProfilerError::CpuAffinity {
+                cpu: 1,
+                error: nix::Error::EINVAL
+            })
+        ));
+    }
 }
@@ -0,0 +1,396 @@
+//! Live counters of a profiling run, served over HTTP for monitoring
+//!
+//! The trap handler updates the [`Metrics`] with relaxed atomic operations, so it
+//! never waits for the thread that serves them. [`Metrics::serve`] answers every
+//! request with the current values in the Prometheus text format, which dashboards
+//! can scrape while a long run is in progress. The responder only implements what
+//! scrapers need, a `GET` of `/metrics`, to avoid depending on an HTTP server.
+
+use std::{
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+/// Timeout of reading a request and writing its response
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Counters of a run, the live counterpart of the summary JSON
+///
+/// ```
+/// use std::{io::{Read, Write}, net::TcpStream, sync::Arc};
+/// use sgx_profiler::metrics::Metrics;
+///
+/// let metrics = Arc::new(Metrics::new());
+/// let addr = metrics.clone().serve("127.0.0.1:0").unwrap();
+/// metrics.record_step(3, Some(0x1234));
+/// metrics.record_step(1, None);
+/// metrics.record_tlb(3, true);
+///
+/// let mut stream = TcpStream::connect(addr).unwrap();
+/// stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+/// let mut response = String::new();
+/// stream.read_to_string(&mut response).unwrap();
+/// assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+/// assert!(response.contains("\nsgx_profiler_steps_total 2\n"));
+/// assert!(response.contains("\nsgx_profiler_pages_accessed_total 4\n"));
+/// assert!(response.contains("\nsgx_profiler_tlb_hit_rate 0.75\n"));
+/// assert!(response.contains("\nsgx_profiler_erip 4660\n"));
+/// // The PAM is only reported by the simulator
+/// assert!(!response.contains("sgx_profiler_pam_pages"));
+/// ```
+#[derive(Debug)]
+pub struct Metrics {
+    start: Instant,
+    steps: AtomicU64,
+    pages_accessed: AtomicU64,
+    /// Set once the TLB is simulated, otherwise the TLB metrics are left out
+    simulated: AtomicBool,
+    interrupts: AtomicU64,
+    tlb_hits: AtomicU64,
+    /// Set once the PAM is recorded, otherwise the PAM metrics are left out
+    pam: AtomicBool,
+    pam_pages: AtomicU64,
+    pam_updates: AtomicU64,
+    /// Erip of the last step plus one, zero if it is unknown
+    erip: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            steps: AtomicU64::new(0),
+            pages_accessed: AtomicU64::new(0),
+            simulated: AtomicBool::new(false),
+            interrupts: AtomicU64::new(0),
+            tlb_hits: AtomicU64::new(0),
+            pam: AtomicBool::new(false),
+            pam_pages: AtomicU64::new(0),
+            pam_updates: AtomicU64::new(0),
+            erip: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a step that accessed `pages_accessed` pages, at `erip` if it is known
+    pub fn record_step(&self, pages_accessed: usize, erip: Option<usize>) {
+        self.steps.fetch_add(1, Ordering::Relaxed);
+        self.pages_accessed
+            .fetch_add(pages_accessed as u64, Ordering::Relaxed);
+        if let Some(erip) = erip {
+            self.erip.store(erip as u64 + 1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count the accesses of the last step that hit the simulated TLB, and whether
+    /// the attacker interrupted
+    pub fn record_tlb(&self, hits: usize, interrupt: bool) {
+        self.simulated.store(true, Ordering::Relaxed);
+        self.tlb_hits.fetch_add(hits as u64, Ordering::Relaxed);
+        if interrupt {
+            self.interrupts.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Set the number of pages in the PAM active set and the PAM updates so far
+    pub fn record_pam(&self, pages: usize, updates: u64) {
+        self.pam.store(true, Ordering::Relaxed);
+        self.pam_pages.store(pages as u64, Ordering::Relaxed);
+        self.pam_updates.store(updates, Ordering::Relaxed);
+    }
+
+    /// The current values in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: &dyn std::fmt::Display| {
+            let name = format!("sgx_profiler_{name}");
+            let _ = write!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+            );
+        };
+
+        let steps = self.steps.load(Ordering::Relaxed);
+        let pages_accessed = self.pages_accessed.load(Ordering::Relaxed);
+        let elapsed = self.start.elapsed().as_secs_f64();
+        metric("steps_total", "counter", "Steps recorded so far", &steps);
+        metric(
+            "steps_per_second",
+            "gauge",
+            "Steps per second since the start of the run",
+            &(steps as f64 / elapsed.max(f64::EPSILON)),
+        );
+        metric(
+            "pages_accessed_total",
+            "counter",
+            "Page accesses recorded so far",
+            &pages_accessed,
+        );
+        if self.simulated.load(Ordering::Relaxed) {
+            metric(
+                "interrupts_total",
+                "counter",
+                "Steps at which the attacker interrupted the enclave",
+                &self.interrupts.load(Ordering::Relaxed),
+            );
+            if pages_accessed > 0 {
+                let hits = self.tlb_hits.load(Ordering::Relaxed);
+                metric(
+                    "tlb_hit_rate",
+                    "gauge",
+                    "Fraction of the page accesses that hit the simulated TLB",
+                    &(hits as f64 / pages_accessed as f64),
+                );
+            }
+        }
+        if self.pam.load(Ordering::Relaxed) {
+            metric(
+                "pam_pages",
+                "gauge",
+                "Pages in the PAM active set",
+                &self.pam_pages.load(Ordering::Relaxed),
+            );
+            metric(
+                "pam_updates_total",
+                "counter",
+                "Updates of the PAM by the enclave",
+                &self.pam_updates.load(Ordering::Relaxed),
+            );
+        }
+        if let Some(erip) = self.erip.load(Ordering::Relaxed).checked_sub(1) {
+            metric("erip", "gauge", "Erip of the last step", &erip);
+        }
+        out
+    }
+
+    /// Serve the metrics at `addr` from a background thread, and return the address
+    /// it listens on, e.g. to find the port if `addr` has port 0.
+    pub fn serve(self: Arc<Self>, addr: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        thread::Builder::new()
+            .name("metrics".into())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if let Err(e) = stream.and_then(|stream| self.respond(stream)) {
+                        debug!("failed to serve metrics: {e}");
+                    }
+                }
+            })?;
+        Ok(local_addr)
+    }
+
+    /// Answer a single request, closing the connection afterwards
+    fn respond(&self, mut stream: TcpStream) -> io::Result<()> {
+        // Requests are answered one at a time, so a stalled client must not block
+        // the others for long
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        // The request line is all that is needed, and comes first
+        let mut request = [0; 1024];
+        let len = stream.read(&mut request)?;
+        let request = String::from_utf8_lossy(&request[..len]);
+        let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+        let (status, body) = match (request_line.next(), request_line.next()) {
+            (Some("GET"), Some("/metrics" | "/")) => ("200 OK", self.render()),
+            (Some("GET"), _) => ("404 Not Found", "not found\n".to_owned()),
+            _ => (
+                "405 Method Not Allowed",
+                "only GET is supported\n".to_owned(),
+            ),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {status}\r\n\
+             Content-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use sgx_step::page::RelativePage;
+
+    use super::*;
+    use crate::{
+        attacker::Attacker,
+        backend::{MockBackend, MockStep},
+        simulator::TlblurLayout,
+        PageAccess, Perms, SimulatorBuilder,
+    };
+
+    /// Values of the rendered metrics by name, checking that every metric is
+    /// preceded by its help and type
+    fn parse(rendered: &str) -> BTreeMap<String, f64> {
+        let lines = rendered.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len() % 3, 0, "{rendered}");
+        lines
+            .chunks(3)
+            .map(|metric| {
+                let (name, value) = metric[2].split_once(' ').unwrap();
+                assert!(metric[0].starts_with(&format!("# HELP {name} ")));
+                let kind = metric[1].strip_prefix(&format!("# TYPE {name} ")).unwrap();
+                assert!(["counter", "gauge"].contains(&kind));
+                assert_eq!(kind == "counter", name.ends_with("_total"), "{name}");
+                (name.to_owned(), value.parse().unwrap())
+            })
+            .collect()
+    }
+
+    /// Send `request` to the server at `addr` and return the response
+    fn get(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn metrics_are_only_rendered_once_recorded() {
+        let metrics = Metrics::new();
+        let names = |metrics: &Metrics| parse(&metrics.render()).into_keys().collect::<Vec<_>>();
+        assert_eq!(
+            names(&metrics),
+            [
+                "sgx_profiler_pages_accessed_total",
+                "sgx_profiler_steps_per_second",
+                "sgx_profiler_steps_total",
+            ]
+        );
+
+        // An erip of zero is still known
+        metrics.record_step(0, Some(0));
+        metrics.record_tlb(0, true);
+        let values = parse(&metrics.render());
+        assert_eq!(values["sgx_profiler_erip"], 0.);
+        assert_eq!(values["sgx_profiler_interrupts_total"], 1.);
+        // The hit rate is unknown without accesses
+        assert!(!values.contains_key("sgx_profiler_tlb_hit_rate"));
+
+        metrics.record_pam(5, 2);
+        metrics.record_pam(4, 3);
+        let values = parse(&metrics.render());
+        assert_eq!(values["sgx_profiler_pam_pages"], 4.);
+        assert_eq!(values["sgx_profiler_pam_updates_total"], 3.);
+    }
+
+    #[test]
+    fn concurrent_updates_are_counted() {
+        let metrics = Arc::new(Metrics::new());
+        let threads = (0..4)
+            .map(|thread| {
+                let metrics = metrics.clone();
+                thread::spawn(move || {
+                    for step in 0..1000 {
+                        metrics.record_step(2, Some(thread * 1000 + step));
+                        metrics.record_tlb(1, step % 2 == 0);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let values = parse(&metrics.render());
+        assert_eq!(values["sgx_profiler_steps_total"], 4000.);
+        assert_eq!(values["sgx_profiler_pages_accessed_total"], 8000.);
+        assert_eq!(values["sgx_profiler_interrupts_total"], 2000.);
+        assert_eq!(values["sgx_profiler_tlb_hit_rate"], 0.5);
+        assert!(values["sgx_profiler_erip"] % 1000. == 999.);
+    }
+
+    #[test]
+    fn responder_only_serves_get_requests() {
+        let metrics = Arc::new(Metrics::new());
+        let addr = metrics.clone().serve("127.0.0.1:0").unwrap();
+        metrics.record_step(1, None);
+
+        for path in ["/metrics", "/"] {
+            let response = get(addr, &format!("GET {path} HTTP/1.1\r\n\r\n"));
+            let (head, body) = response.split_once("\r\n\r\n").unwrap();
+            assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+            assert!(head.contains(&format!("\r\nContent-Length: {}", body.len())));
+            assert_eq!(parse(body)["sgx_profiler_steps_total"], 1.);
+        }
+        let response = get(addr, "GET /other HTTP/1.1\r\n\r\n");
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{response}"
+        );
+        let response = get(addr, "POST /metrics HTTP/1.1\r\n\r\n");
+        assert!(
+            response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn stalled_client_times_out() {
+        let metrics = Arc::new(Metrics::new());
+        let addr = metrics.serve("127.0.0.1:0").unwrap();
+        // Never sends a request
+        let _stalled = TcpStream::connect(addr).unwrap();
+
+        let start = Instant::now();
+        let response = get(addr, "GET /metrics HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(start.elapsed() < TIMEOUT * 3, "{:?}", start.elapsed());
+    }
+
+    #[test]
+    fn simulator_metrics_match_its_summary() {
+        let script = (0..50).map(|step| MockStep {
+            accesses: vec![PageAccess::new(
+                RelativePage::new(1 + step % 7),
+                Perms::READ,
+            )],
+            pam_updates: vec![RelativePage::new(1 + step % 5)],
+            erip: 0x1000 + step,
+            ..Default::default()
+        });
+        let metrics = Arc::new(Metrics::new());
+        let dir = tempfile::tempdir().unwrap();
+        let mut simulator = SimulatorBuilder::new(dir.path().join("metrics.vcd"))
+            .attacker(Attacker::SingleStep)
+            .metrics(metrics.clone())
+            .build_with_backend(MockBackend::new(64, script), TlblurLayout::mock(64))
+            .unwrap();
+        simulator.replay().unwrap();
+        let summary = simulator.summary();
+
+        let values = parse(&metrics.render());
+        assert_eq!(values["sgx_profiler_steps_total"], summary.steps as f64);
+        assert_eq!(
+            values["sgx_profiler_interrupts_total"],
+            summary.interrupts as f64
+        );
+        assert_eq!(
+            values["sgx_profiler_pam_updates_total"],
+            summary.pam_updates as f64
+        );
+        let hit_rate = values["sgx_profiler_tlb_hit_rate"];
+        assert!((hit_rate - summary.tlb_hit_rate.unwrap()).abs() < 1e-9);
+        assert!(values["sgx_profiler_pam_pages"] > 0.);
+    }
+}
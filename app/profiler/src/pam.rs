@@ -0,0 +1,399 @@
+//! Local model of the page access map (PAM) maintained by TLBlur-instrumented enclaves
+
+use clap::ValueEnum;
+use log::{trace, warn};
+use serde::{Deserialize, Serialize};
+use sgx_step::page::RelativePage;
+
+use crate::{backend::Backend, PageAccess, Perms, ProfilerError};
+
+/// Which entry of the PAM active set is replaced when a new page is added to it
+///
+/// This should match the replacement of the TLBlur instrumentation, or the local
+/// PAM diverges from the one in the enclave.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EvictionPolicy {
+    /// Replace the entry whose page has the oldest PAM counter
+    #[default]
+    Lru,
+    /// Replace the entries in the order they were filled
+    Fifo,
+    /// Replace the next entry that was not updated since the clock hand last
+    /// passed it, giving updated entries a second chance
+    Clock,
+}
+
+/// Chooses the entry of the PAM active set to replace, according to an [`EvictionPolicy`]
+///
+/// ```
+/// use sgx_profiler::pam::{EvictionPolicy, Evictor};
+///
+/// // Insert pages into a set of three entries, where `Some(entry)` updates
+/// // the page of an entry instead. Every event increments the PAM counter.
+/// let events = [None, None, None, None, Some(1), None, None];
+/// let victims = |policy| {
+///     let mut evictor = Evictor::new(policy, 3);
+///     let mut counters = [0; 3];
+///     let mut victims = Vec::new();
+///     for (counter, event) in (1..).zip(events) {
+///         let entry = event.unwrap_or_else(|| {
+///             let victim = evictor.victim(&counters);
+///             victims.push(victim);
+///             victim
+///         });
+///         counters[entry] = counter;
+///         if event.is_some() {
+///             evictor.touch(entry);
+///         }
+///     }
+///     victims
+/// };
+///
+/// // Empty entries are filled first
+/// assert_eq!(victims(EvictionPolicy::Lru), [0, 1, 2, 0, 2, 0]);
+/// assert_eq!(victims(EvictionPolicy::Fifo), [0, 1, 2, 0, 1, 2]);
+/// assert_eq!(victims(EvictionPolicy::Clock), [0, 1, 2, 0, 2, 1]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Evictor {
+    policy: EvictionPolicy,
+    /// Next entry to replace, or to consider for replacement with `Clock`
+    hand: usize,
+    /// Whether every entry was inserted or updated since the hand last passed it
+    referenced: Vec<bool>,
+}
+
+impl Evictor {
+    pub fn new(policy: EvictionPolicy, size: usize) -> Self {
+        Self {
+            policy,
+            hand: 0,
+            referenced: vec![false; size],
+        }
+    }
+
+    /// Record that the page of `entry` was updated in the PAM
+    pub fn touch(&mut self, entry: usize) {
+        self.referenced[entry] = true;
+    }
+
+    /// Choose the entry to replace with a new page.
+    ///
+    /// `counters` are the PAM counters of the pages of the entries, which are zero
+    /// for empty entries.
+    pub fn victim(&mut self, counters: &[u64]) -> usize {
+        let size = counters.len();
+        let victim = match self.policy {
+            EvictionPolicy::Lru => {
+                return (0..size).min_by_key(|&i| counters[i]).unwrap_or(0);
+            }
+            EvictionPolicy::Fifo => self.hand,
+            EvictionPolicy::Clock => {
+                while counters[self.hand] != 0 && self.referenced[self.hand] {
+                    self.referenced[self.hand] = false;
+                    self.hand = (self.hand + 1) % size;
+                }
+                self.referenced[self.hand] = true;
+                self.hand
+            }
+        };
+        self.hand = (self.hand + 1) % size;
+        victim
+    }
+}
+
+/// Copy of the state of the profiler [`PAM`] at some point of the simulation
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PamSnapshot {
+    /// Value of the PAM counter at the last update
+    pub counter: u64,
+    /// Pages in the active set, in the order of its entries
+    pub pages: Vec<usize>,
+}
+
+pub struct PAM {
+    pub(crate) pam_buffer: Vec<u64>,
+    pam_active: Vec<PageAccess>,
+    pam_counter: u64,
+    updates: u64,
+    evictor: Evictor,
+}
+
+impl PAM {
+    pub fn new(pam_size: usize, pws_size: usize) -> Self {
+        Self {
+            pam_buffer: vec![0; pam_size],
+            pam_active: vec![PageAccess::default(); pws_size],
+            pam_counter: 0,
+            updates: 0,
+            evictor: Evictor::new(EvictionPolicy::default(), pws_size),
+        }
+    }
+
+    /// Replace the entries of the active set according to `policy`
+    pub fn with_eviction(mut self, policy: EvictionPolicy) -> Self {
+        self.evictor = Evictor::new(policy, self.pam_active.len());
+        self
+    }
+
+    /// Number of PAM updates seen so far, i.e. changes of the PAM counter
+    pub fn updates(&self) -> u64 {
+        self.updates
+    }
+
+    pub(crate) fn get_pam(&self) -> impl Iterator<Item = &PageAccess> {
+        self.pam_active.iter()
+    }
+
+    /// Value of the PAM counter at the last update
+    pub fn counter(&self) -> u64 {
+        self.pam_counter
+    }
+
+    /// Pages in the active set, in the order of its entries.
+    ///
+    /// Entries that were not filled yet are skipped.
+    ///
+    /// ```
+    /// use sgx_profiler::{
+    ///     backend::{Backend, MockBackend, MockStep},
+    ///     pam::PAM,
+    ///     sgx_step::page::RelativePage,
+    /// };
+    ///
+    /// let update = |page| MockStep {
+    ///     pam_updates: vec![RelativePage::new(page)],
+    ///     ..Default::default()
+    /// };
+    /// let mut backend = MockBackend::new(64, [update(3), update(5), update(7)]);
+    /// let mut pam = PAM::new(64, 2);
+    ///
+    /// let mut step = |pam: &mut PAM| {
+    ///     backend.next_step();
+    ///     pam.update_pam(&mut backend).unwrap();
+    /// };
+    /// step(&mut pam);
+    /// assert!(pam.active_pages().is_empty());
+    /// step(&mut pam);
+    /// step(&mut pam);
+    /// assert_eq!(pam.active_pages(), [3, 5]);
+    /// // The least recently updated page is evicted
+    /// step(&mut pam);
+    /// assert_eq!(pam.active_pages(), [7, 5]);
+    /// assert_eq!(pam.counter(), 3);
+    /// assert_eq!(pam.snapshot().pages, pam.active_pages());
+    /// ```
+    pub fn active_pages(&self) -> Vec<usize> {
+        self.pam_active
+            .iter()
+            .map(|p| p.page.get())
+            .filter(|&page| page != 0)
+            .collect()
+    }
+
+    /// Copy of the current state, e.g. to log it
+    pub fn snapshot(&self) -> PamSnapshot {
+        PamSnapshot {
+            counter: self.pam_counter,
+            pages: self.active_pages(),
+        }
+    }
+
+    /// Fill the active set with the most recently updated pages of the PAM in the enclave,
+    /// as it would be if the profiler had followed every update before.
+    ///
+    /// Entries are filled from the least to the most recently updated page.
+    pub fn warm_up(&mut self, backend: &mut impl Backend) -> Result<(), ProfilerError> {
+        self.pam_counter = backend.read_pam_counter()?;
+        backend.read_pam(&mut self.pam_buffer)?;
+
+        let mut pages = (1..self.pam_buffer.len())
+            .filter(|&page| self.pam_buffer[page] > 0)
+            .collect::<Vec<_>>();
+        pages.sort_by_key(|&page| std::cmp::Reverse(self.pam_buffer[page]));
+        pages.truncate(self.pam_active.len());
+        pages.reverse();
+        trace!("warmed up PAM with pages {pages:?}");
+
+        for (entry, page) in self.pam_active.iter_mut().zip(pages) {
+            entry.page = RelativePage::new(page);
+            entry.perms = Perms::all();
+        }
+        Ok(())
+    }
+
+    pub fn update_pam(&mut self, backend: &mut impl Backend) -> Result<(), ProfilerError> {
+        let old_counter = self.pam_counter;
+
+        // Read the new PAM counter from enclave memory
+        let new_counter = backend.read_pam_counter()?;
+
+        // If the counter changed compared to previous step of execution,
+        // then our local view of the PAM must be updated to match the one in enclave memory.
+        //
+        // In contrast to the representation of the PAM in enclave memory,
+        // PAM stored by the profiler more closely aligns with a real TLB, as it
+        // only contains the N most recent pages.
+        //
+        // It should match the behavior of the PAM, but it should not try to mimic the real TLB.
+        //
+        // NOTE: an assumption is made that at the time the counter is incremented, the PAM is
+        // already updated as well. We use the PAM global counter as a way to signal the
+        // profiler of a PAM update, to avoid having to walk through the entire PAM each step.
+        // This requires the instrumentation to be written in a specific way.
+        if old_counter != new_counter {
+            trace!("PAM counter: {new_counter}");
+            // Read the PAM from enclave memory
+            backend.read_pam(&mut self.pam_buffer)?;
+
+            let mut found = false;
+            for (page, &value) in self.pam_buffer.iter().enumerate() {
+                // Only update this entry in profiler PAM if it was recently updated.
+                if value >= new_counter - 1 && value > 0 {
+                    self.pam_counter = new_counter;
+                    // Only update if not already in profiler PAM
+                    found = true;
+                    if let Some(index) = self.pam_active.iter().position(|p| p.page.get() == page) {
+                        trace!("page {page} already in PAM");
+                        self.evictor.touch(index);
+                    } else {
+                        trace!("new entry in PAM: {page}");
+                        // Find the entry to evict according to the state of the PAM
+                        let counters = self
+                            .pam_active
+                            .iter()
+                            .map(|p| {
+                                if p.page.get() == 0 {
+                                    0
+                                } else {
+                                    self.pam_buffer[p.page.get()]
+                                }
+                            })
+                            .collect::<Vec<_>>();
+                        if !counters.is_empty() {
+                            let index = self.evictor.victim(&counters);
+                            // Replace the entry
+                            trace!(
+                                "replaced PAM entry of page {}",
+                                self.pam_active[index].page.get()
+                            );
+                            self.pam_active[index].page = RelativePage::new(page);
+
+                            // The real prefetcher can't do this,
+                            // but we can in the profiler because we don't care about
+                            // the permissions of pages.
+                            //
+                            // The real prefetcher would instead use the maximum
+                            // allowed permissions, we should be equivalent.
+                            self.pam_active[index].perms = Perms::all();
+                        }
+                    }
+                }
+            }
+            if found {
+                self.updates += 1;
+            } else if new_counter - old_counter > 1 {
+                warn!("PAM counter incremented, but new entry not found");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{MockBackend, MockStep};
+
+    /// Active set of a PAM of three entries after the enclave updated `pages` in order
+    fn active_pages(policy: EvictionPolicy, pages: &[usize]) -> Vec<usize> {
+        let steps = pages.iter().map(|&page| MockStep {
+            pam_updates: vec![RelativePage::new(page)],
+            ..Default::default()
+        });
+        let mut backend = MockBackend::new(64, steps);
+        let mut pam = PAM::new(64, 3).with_eviction(policy);
+        // The first step only primes the backend
+        for _ in 0..=pages.len() {
+            backend.next_step();
+            pam.update_pam(&mut backend).unwrap();
+        }
+        assert_eq!(pam.updates(), pages.len() as u64);
+        pam.active_pages()
+    }
+
+    #[test]
+    fn active_set_follows_the_eviction_policy() {
+        use EvictionPolicy::*;
+
+        // Page 2 is updated after page 4 replaced page 1
+        let pages = [1, 2, 3, 4, 2, 5];
+        assert_eq!(active_pages(Lru, &pages), [4, 2, 5]);
+        assert_eq!(active_pages(Fifo, &pages), [4, 5, 3]);
+        assert_eq!(active_pages(Clock, &pages), [4, 2, 5]);
+
+        // Pages 3 and 2 are updated, so the clock hand passes all entries
+        let pages = [1, 2, 3, 4, 3, 2, 5];
+        assert_eq!(active_pages(Lru, &pages), [5, 2, 3]);
+        assert_eq!(active_pages(Fifo, &pages), [4, 5, 3]);
+        assert_eq!(active_pages(Clock, &pages), [4, 5, 3]);
+    }
+
+    #[test]
+    fn snapshot_only_contains_filled_entries() {
+        let pam = PAM::new(64, 4);
+        assert_eq!(pam.snapshot(), PamSnapshot::default());
+        assert_eq!(pam.counter(), 0);
+
+        let steps = [3, 5, 7].map(|page| MockStep {
+            pam_updates: vec![RelativePage::new(page)],
+            ..Default::default()
+        });
+        let mut backend = MockBackend::new(64, steps);
+        let mut pam = PAM::new(64, 4);
+        let mut snapshots = Vec::new();
+        for _ in 0..4 {
+            backend.next_step();
+            pam.update_pam(&mut backend).unwrap();
+            let snapshot = pam.snapshot();
+            assert_eq!(snapshot.pages, pam.active_pages());
+            assert_eq!(snapshot.counter, pam.counter());
+            snapshots.push(snapshot);
+        }
+        let snapshot = |counter, pages: &[usize]| PamSnapshot {
+            counter,
+            pages: pages.to_vec(),
+        };
+        assert_eq!(
+            snapshots,
+            [
+                snapshot(0, &[]),
+                snapshot(1, &[3]),
+                snapshot(2, &[3, 5]),
+                snapshot(3, &[3, 5, 7]),
+            ]
+        );
+
+        // A PAM warmed up from the enclave holds the most recent pages, oldest first
+        let mut warm = PAM::new(64, 2);
+        warm.warm_up(&mut backend).unwrap();
+        assert_eq!(warm.snapshot(), snapshot(3, &[5, 7]));
+    }
+
+    #[test]
+    fn updated_pages_are_not_inserted_again() {
+        for policy in EvictionPolicy::value_variants() {
+            assert_eq!(
+                active_pages(*policy, &[1, 2, 1, 2, 1]),
+                [1, 2],
+                "{policy:?}"
+            );
+            assert_eq!(
+                active_pages(*policy, &[6, 7, 8, 6, 7, 8]),
+                [6, 7, 8],
+                "{policy:?}"
+            );
+        }
+    }
+}
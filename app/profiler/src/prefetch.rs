@@ -0,0 +1,468 @@
+//! Pages prefetched by TLBlur when resuming from an interrupt
+//!
+//! The prefetched pages consist of fixed [`PrefetchRegion`]s, such as the stack and
+//! the TLBlur instrumentation itself, and the pages chosen by a [`Prefetcher`] based
+//! on the execution so far.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{Debug, Display},
+    fs::File,
+    io::BufReader,
+    ops::Range,
+    path::Path,
+    str::FromStr,
+};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sgx_step::page::RelativePage;
+
+use crate::{pam::PAM, simulator::DeterministicState, PageAccess, Perms, ProfilerError};
+
+/// Chooses the pages to prefetch after an interrupt
+pub trait Prefetcher: Debug {
+    /// Pages to prefetch when resuming from an interrupt,
+    /// given the pages accessed by the interrupted instruction.
+    fn prefetch(&mut self, pam: &PAM, accessed: &[PageAccess]) -> Vec<PageAccess>;
+}
+
+/// Prefetches the pages in the PAM, as TLBlur does
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PamPrefetcher;
+
+impl Prefetcher for PamPrefetcher {
+    fn prefetch(&mut self, pam: &PAM, _accessed: &[PageAccess]) -> Vec<PageAccess> {
+        pam.get_pam().copied().collect()
+    }
+}
+
+/// Prefetches the pages that most often followed the accessed pages in a prior trace
+///
+/// ```
+/// use sgx_profiler::{
+///     pam::PAM,
+///     prefetch::{MarkovPrefetcher, Prefetcher},
+///     sgx_step::page::RelativePage,
+///     PageAccess, Perms,
+/// };
+///
+/// // A -> B -> C
+/// let mut prefetcher = MarkovPrefetcher::from_sequence([1, 2, 3], 1);
+/// let pam = PAM::new(0, 0);
+/// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+/// let predict = |prefetcher: &mut MarkovPrefetcher, page| {
+///     prefetcher.prefetch(&pam, &[read(page)]).iter().map(|p| p.page.get()).collect::<Vec<_>>()
+/// };
+/// assert_eq!(predict(&mut prefetcher, 1), [2]);
+/// assert_eq!(predict(&mut prefetcher, 2), [3]);
+/// assert!(predict(&mut prefetcher, 3).is_empty());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MarkovPrefetcher {
+    /// For every page, the pages accessed next, most frequent first
+    transition_table: HashMap<usize, Vec<usize>, DeterministicState>,
+    /// Number of successors to prefetch per accessed page
+    degree: usize,
+}
+
+impl MarkovPrefetcher {
+    /// Learn the transitions between consecutive pages of `pages`,
+    /// prefetching up to `degree` successors per accessed page.
+    pub fn from_sequence(pages: impl IntoIterator<Item = usize>, degree: usize) -> Self {
+        let mut counts: HashMap<usize, HashMap<usize, usize>, DeterministicState> =
+            HashMap::default();
+        let mut pages = pages.into_iter().peekable();
+        while let (Some(from), Some(&to)) = (pages.next(), pages.peek()) {
+            if from != to {
+                *counts.entry(from).or_default().entry(to).or_default() += 1;
+            }
+        }
+
+        let transition_table = counts
+            .into_iter()
+            .map(|(from, successors)| {
+                let mut successors = successors.into_iter().collect::<Vec<_>>();
+                // Ties are broken by page number to keep the table deterministic
+                successors.sort_by_key(|&(page, count)| (std::cmp::Reverse(count), page));
+                (from, successors.into_iter().map(|(page, _)| page).collect())
+            })
+            .collect();
+
+        Self {
+            transition_table,
+            degree,
+        }
+    }
+
+    /// Learn the transitions from a VCD trace written by the tracer.
+    ///
+    /// The sequence of pages is the order in which the page wires are set.
+    pub fn from_vcd(path: impl AsRef<Path>, degree: usize) -> Result<Self, Box<dyn Error>> {
+        let mut parser = vcd::Parser::new(BufReader::new(File::open(path)?));
+        let header = parser.parse_header()?;
+        let scope = header
+            .find_scope(&["trace"])
+            .ok_or("VCD file has no `trace` scope")?;
+
+        let wires = scope
+            .items
+            .iter()
+            .filter_map(|item| match item {
+                vcd::ScopeItem::Var(var) => {
+                    let page = var.reference.strip_prefix('_')?.parse::<usize>().ok()?;
+                    Some((var.code, page))
+                }
+                _ => None,
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut pages = Vec::new();
+        for command in parser {
+            if let vcd::Command::ChangeScalar(code, vcd::Value::V1) = command? {
+                if let Some(&page) = wires.get(&code) {
+                    pages.push(page);
+                }
+            }
+        }
+        Ok(Self::from_sequence(pages, degree))
+    }
+}
+
+impl Prefetcher for MarkovPrefetcher {
+    fn prefetch(&mut self, _pam: &PAM, accessed: &[PageAccess]) -> Vec<PageAccess> {
+        let mut pages = accessed
+            .iter()
+            .filter_map(|p| self.transition_table.get(&p.page.get()))
+            .flat_map(|successors| successors.iter().take(self.degree))
+            .copied()
+            .collect::<Vec<_>>();
+        pages.sort();
+        pages.dedup();
+        pages
+            .into_iter()
+            .map(|page| PageAccess::new(RelativePage::new(page), Perms::READ))
+            .collect()
+    }
+}
+
+/// Location of a [`PrefetchRegion`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PrefetchKind {
+    /// `radius` pages on either side of the page containing the stack pointer
+    /// of the interrupted enclave thread, which is read on every interrupt
+    Stack { radius: usize },
+    /// Pages covered by an enclave symbol
+    Symbol(String),
+    /// Pages relative to the enclave base
+    Range(Range<usize>),
+}
+
+/// Region of enclave memory that is prefetched after every interrupt
+///
+/// Regions are parsed from strings of the form `stack:RADIUS:PERMS`,
+/// `symbol:NAME:PERMS` or `range:START-END:PERMS`, where `PERMS` is a
+/// combination of `r`, `w` and `x` and `END` is exclusive.
+///
+/// ```
+/// use sgx_profiler::{prefetch::{PrefetchKind, PrefetchRegion}, Perms};
+///
+/// let region = "range:10-12:rw".parse::<PrefetchRegion>().unwrap();
+/// assert_eq!(region.kind, PrefetchKind::Range(10..12));
+/// assert_eq!(region.perms, Perms::READ | Perms::WRITE);
+/// let json = serde_json::to_string(&PrefetchRegion::tlblur_defaults()).unwrap();
+/// assert!(json.starts_with(r#"["stack:1:rx","symbol:tlblur_pam_update:rx""#));
+/// let regions = serde_json::from_str::<Vec<PrefetchRegion>>(&json).unwrap();
+/// assert_eq!(regions, PrefetchRegion::tlblur_defaults());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefetchRegion {
+    pub kind: PrefetchKind,
+    pub perms: Perms,
+}
+
+impl PrefetchRegion {
+    /// Regions prefetched by the reference TLBlur instrumentation:
+    /// the stack, the PAM update code, the PAM counter and the PAM itself.
+    pub fn tlblur_defaults() -> Vec<Self> {
+        [
+            "stack:1:rx",
+            "symbol:tlblur_pam_update:rx",
+            "symbol:__tlblur_counter:rw",
+            "symbol:__tlblur_pam:rw",
+        ]
+        .into_iter()
+        .map(|region| region.parse().unwrap())
+        .collect()
+    }
+
+    /// Access to `page` with the permissions of this region
+    pub fn access(&self, page: RelativePage) -> PageAccess {
+        PageAccess::new(page, self.perms)
+    }
+
+    /// Replace a `Symbol` region by the `Range` of pages it covers, as given by `symbol_pages`.
+    pub(crate) fn resolve(
+        self,
+        symbol_pages: impl Fn(&str) -> Option<Range<usize>>,
+    ) -> Result<Self, ProfilerError> {
+        match self.kind {
+            PrefetchKind::Symbol(ref name) => {
+                let pages = symbol_pages(name)
+                    .ok_or_else(|| ProfilerError::SymbolNotFound(name.clone()))?;
+                Ok(Self {
+                    kind: PrefetchKind::Range(pages),
+                    ..self
+                })
+            }
+            _ => Ok(self),
+        }
+    }
+}
+
+impl FromStr for PrefetchRegion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, rest) = s
+            .split_once(':')
+            .ok_or_else(|| format!("invalid prefetch region `{s}`"))?;
+        let (arg, perms) = rest
+            .rsplit_once(':')
+            .ok_or_else(|| format!("missing permissions in prefetch region `{s}`"))?;
+
+        let kind = match kind {
+            "stack" => PrefetchKind::Stack {
+                radius: arg
+                    .parse()
+                    .map_err(|e| format!("invalid stack radius `{arg}`: {e}"))?,
+            },
+            "symbol" => PrefetchKind::Symbol(arg.to_owned()),
+            "range" => {
+                let parse = |page: &str| {
+                    page.parse::<usize>()
+                        .map_err(|e| format!("invalid page `{page}`: {e}"))
+                };
+                let (start, end) = arg
+                    .split_once('-')
+                    .ok_or_else(|| format!("invalid page range `{arg}`, expected START-END"))?;
+                PrefetchKind::Range(parse(start)?..parse(end)?)
+            }
+            _ => return Err(format!("unknown prefetch region kind `{kind}`")),
+        };
+
+        let perms = perms
+            .chars()
+            .map(|c| match c {
+                'r' => Ok(Perms::READ),
+                'w' => Ok(Perms::WRITE),
+                'x' => Ok(Perms::EXECUTE),
+                c => Err(format!("invalid permission `{c}` in prefetch region `{s}`")),
+            })
+            .collect::<Result<Perms, _>>()?;
+        Ok(Self { kind, perms })
+    }
+}
+
+impl Display for PrefetchRegion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            PrefetchKind::Stack { radius } => write!(f, "stack:{radius}:")?,
+            PrefetchKind::Symbol(name) => write!(f, "symbol:{name}:")?,
+            PrefetchKind::Range(pages) => write!(f, "range:{}-{}:", pages.start, pages.end)?,
+        }
+        for (perm, c) in [
+            (Perms::READ, 'r'),
+            (Perms::WRITE, 'w'),
+            (Perms::EXECUTE, 'x'),
+        ] {
+            if self.perms.contains(perm) {
+                write!(f, "{c}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Regions are serialized in the format they are parsed from
+impl Serialize for PrefetchRegion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PrefetchRegion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{Backend, MockBackend, MockStep};
+
+    fn read(page: usize) -> PageAccess {
+        PageAccess::new(RelativePage::new(page), Perms::READ)
+    }
+
+    fn pages(accesses: Vec<PageAccess>) -> Vec<usize> {
+        accesses.iter().map(|p| p.page.get()).collect()
+    }
+
+    #[test]
+    fn pam_prefetcher_prefetches_the_active_set() {
+        let update = |page| MockStep {
+            pam_updates: vec![RelativePage::new(page)],
+            ..Default::default()
+        };
+        let mut backend = MockBackend::new(64, [update(3), update(5)]);
+        let mut pam = PAM::new(64, 4);
+        for _ in 0..3 {
+            backend.next_step();
+            pam.update_pam(&mut backend).unwrap();
+        }
+
+        let prefetched = PamPrefetcher.prefetch(&pam, &[read(9)]);
+        let mut active = prefetched
+            .into_iter()
+            .filter(|p| p.page.get() != 0)
+            .collect::<Vec<_>>();
+        active.sort_by_key(|p| p.page.get());
+        assert_eq!(
+            active,
+            [3, 5].map(|page| PageAccess::new(RelativePage::new(page), Perms::all()))
+        );
+    }
+
+    #[test]
+    fn markov_prefetches_the_most_frequent_successors() {
+        // 1 is followed by 2 twice and by 3 and 4 once, repeated pages are not transitions
+        let sequence = [1, 2, 1, 1, 4, 1, 3, 1, 2, 5];
+        let pam = PAM::new(0, 0);
+        let predict = |degree, accessed: &[usize]| {
+            let accessed = accessed.iter().map(|&page| read(page)).collect::<Vec<_>>();
+            let mut prefetcher = MarkovPrefetcher::from_sequence(sequence, degree);
+            pages(prefetcher.prefetch(&pam, &accessed))
+        };
+
+        assert_eq!(predict(1, &[1]), [2]);
+        // Ties are broken by page number
+        assert_eq!(predict(2, &[1]), [2, 3]);
+        assert_eq!(predict(10, &[1]), [2, 3, 4]);
+        // The successors of all accessed pages are prefetched once, in page order
+        assert_eq!(predict(1, &[4, 2]), [1]);
+        assert_eq!(predict(2, &[2, 3]), [1, 5]);
+        assert!(predict(1, &[5, 6]).is_empty());
+        assert!(predict(0, &[1]).is_empty());
+        let prefetched = MarkovPrefetcher::from_sequence(sequence, 1).prefetch(&pam, &[read(1)]);
+        assert_eq!(prefetched, [read(2)]);
+    }
+
+    #[test]
+    fn markov_learns_the_order_of_set_wires() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.vcd");
+        std::fs::write(
+            &path,
+            "$timescale 1 ms $end\n\
+             $scope module trace $end\n\
+             $var wire 1 ! _7 $end\n\
+             $var wire 1 \" _8 $end\n\
+             $var wire 1 # _9 $end\n\
+             $var wire 64 $ erip $end\n\
+             $upscope $end\n\
+             $enddefinitions $end\n\
+             0!\n0\"\n0#\n\
+             1!\n#1\n0!\n1#\nb1000 $\n#2\n1\"\n0#\n#3\n",
+        )
+        .unwrap();
+
+        // 7 -> 9 -> 8, cleared wires are not accesses
+        let pam = PAM::new(0, 0);
+        let mut prefetcher = MarkovPrefetcher::from_vcd(&path, 1).unwrap();
+        assert_eq!(pages(prefetcher.prefetch(&pam, &[read(7)])), [9]);
+        assert_eq!(pages(prefetcher.prefetch(&pam, &[read(9)])), [8]);
+        assert!(prefetcher.prefetch(&pam, &[read(8)]).is_empty());
+
+        std::fs::write(
+            &path,
+            "$scope module other $end\n$upscope $end\n$enddefinitions $end\n",
+        )
+        .unwrap();
+        assert!(MarkovPrefetcher::from_vcd(&path, 1).is_err());
+        assert!(MarkovPrefetcher::from_vcd(dir.path().join("missing.vcd"), 1).is_err());
+    }
+
+    #[test]
+    fn regions_round_trip_through_their_strings() {
+        for (region, kind, perms) in [
+            (
+                "stack:2:rw",
+                PrefetchKind::Stack { radius: 2 },
+                Perms::READ | Perms::WRITE,
+            ),
+            (
+                "symbol:ns::table:x",
+                PrefetchKind::Symbol("ns::table".to_owned()),
+                Perms::EXECUTE,
+            ),
+            ("range:3-7:r", PrefetchKind::Range(3..7), Perms::READ),
+        ] {
+            let parsed = region.parse::<PrefetchRegion>().unwrap();
+            assert_eq!(parsed, PrefetchRegion { kind, perms });
+            assert_eq!(parsed.to_string(), region);
+        }
+        // Permissions are always displayed in the order `rwx`
+        let region = "range:0-1:xwr".parse::<PrefetchRegion>().unwrap();
+        assert_eq!(region.to_string(), "range:0-1:rwx");
+    }
+
+    #[test]
+    fn malformed_regions_are_rejected() {
+        for region in [
+            "stack",
+            "stack:1",
+            "stack:one:r",
+            "heap:1:r",
+            "range:3:r",
+            "range:a-7:r",
+            "range:3-7:rz",
+        ] {
+            assert!(
+                region.parse::<PrefetchRegion>().is_err(),
+                "accepted `{region}`"
+            );
+        }
+        assert!(serde_json::from_str::<PrefetchRegion>(r#""range:3:r""#).is_err());
+    }
+
+    #[test]
+    fn symbols_are_resolved_to_ranges() {
+        let symbol_pages = |name: &str| (name == "table").then_some(4..6);
+        let resolve = |region: &str| {
+            region
+                .parse::<PrefetchRegion>()
+                .unwrap()
+                .resolve(symbol_pages)
+        };
+
+        let region = resolve("symbol:table:rw").unwrap();
+        assert_eq!(region.kind, PrefetchKind::Range(4..6));
+        assert_eq!(region.perms, Perms::READ | Perms::WRITE);
+        assert_eq!(
+            region.access(RelativePage::new(5)),
+            PageAccess::new(RelativePage::new(5), Perms::READ | Perms::WRITE)
+        );
+        // Other regions are left alone
+        for region in ["stack:1:r", "range:1-2:r"] {
+            assert_eq!(resolve(region).unwrap(), region.parse().unwrap());
+        }
+        assert!(matches!(
+            resolve("symbol:missing:r"),
+            Err(ProfilerError::SymbolNotFound(name)) if name == "missing"
+        ));
+    }
+}
@@ -0,0 +1,326 @@
+//! Recovery of VCD traces of runs that crashed or were killed
+//!
+//! A [`VCDDumper`](crate::dump::VCDDumper) writes the changes of a step followed by
+//! the timestamp of the next step, so a step is complete once the timestamp after it
+//! has been written. A trace that was cut off ends with a partial step, possibly in
+//! the middle of a line, or even within the definitions, which some viewers reject.
+//! [`repair_vcd`] keeps every complete step and drops the rest.
+
+use std::{
+    collections::HashSet,
+    error::Error,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+/// Outcome of [`repair_vcd`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Complete steps written to the repaired trace, one per timestamp
+    pub recovered: u64,
+    /// Steps that were cut off or came after a corrupted command
+    pub discarded: u64,
+    /// Whether the definitions were cut off, and their open scopes were closed
+    pub header_repaired: bool,
+}
+
+/// Write the complete steps of the VCD trace at `input` to `output`.
+///
+/// Everything after the last complete timestamp is dropped, as is everything after
+/// a command that can't be parsed or changes an undeclared wire. If the trace ends
+/// within the definitions, the open scopes are closed, so the repaired trace has the
+/// wires but no steps. Fails if the definitions are invalid otherwise.
+///
+/// ```
+/// use sgx_profiler::{
+///     dump::{RSet, VCDDumper},
+///     golden::Trace,
+///     repair::{repair_vcd, RepairReport},
+///     sgx_step::page::RelativePage,
+///     PageAccess, Perms,
+/// };
+///
+/// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+/// let dir = tempfile::tempdir().unwrap();
+/// let (path, repaired) = (dir.path().join("truncated.vcd"), dir.path().join("repaired.vcd"));
+/// {
+///     let mut dumper = VCDDumper::<RSet>::new(&path, 4);
+///     for page in [1, 2, 3] {
+///         dumper.next_step(|entry| entry.write_page_accesses([read(page)].iter()));
+///     }
+/// }
+/// let complete = Trace::read(&path).unwrap();
+///
+/// // Crash while writing the changes of the last step
+/// let mut vcd = std::fs::read_to_string(&path).unwrap();
+/// vcd.truncate(vcd.rfind("#3").unwrap() - 2);
+/// std::fs::write(&path, vcd).unwrap();
+///
+/// let report = repair_vcd(&path, &repaired).unwrap();
+/// assert_eq!(report, RepairReport { recovered: 2, discarded: 1, header_repaired: false });
+/// let trace = Trace::read(&repaired).unwrap();
+/// assert_eq!(trace.wires, complete.wires);
+/// assert_eq!(trace.changes[&1], complete.changes[&1]);
+/// assert!(!trace.changes.contains_key(&2));
+///
+/// // Crash while writing the definitions
+/// let vcd = std::fs::read_to_string(dir.path().join("repaired.vcd")).unwrap();
+/// std::fs::write(&path, &vcd[..vcd.find("_2").unwrap()]).unwrap();
+/// let report = repair_vcd(&path, &repaired).unwrap();
+/// assert!(report.header_repaired && report.recovered == 0);
+/// assert_eq!(Trace::read(&repaired).unwrap().wires.len(), 2);
+/// ```
+pub fn repair_vcd(
+    input: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+) -> Result<RepairReport, Box<dyn Error>> {
+    let input = input.as_ref();
+    let mut report = RepairReport::default();
+    let (len, partial_line) = complete_len(input)?;
+    let timestamps = open_prefix(input, len)?
+        .split(b'\n')
+        .map_while(Result::ok)
+        .filter(|line| line.starts_with(b"#"))
+        .count() as u64;
+
+    let mut parser = vcd::Parser::new(open_prefix(input, len)?);
+    let header = match parser.parse_header() {
+        Ok(header) => header,
+        Err(e) => {
+            let mut definitions = String::new();
+            open_prefix(input, len)?.read_to_string(&mut definitions)?;
+            if definitions.contains("$enddefinitions") {
+                return Err(e.into());
+            }
+            // Only the definitions were written, parse them with their scopes closed
+            report.header_repaired = true;
+            let open =
+                definitions.matches("$scope").count() - definitions.matches("$upscope").count();
+            definitions.push_str(&"$upscope $end\n".repeat(open));
+            definitions.push_str("$enddefinitions $end\n");
+            vcd::Parser::new(definitions.as_bytes()).parse_header()?
+        }
+    };
+    let mut declared = HashSet::new();
+    let mut scopes = vec![&header.items];
+    while let Some(items) = scopes.pop() {
+        for item in items {
+            match item {
+                vcd::ScopeItem::Scope(scope) => scopes.push(&scope.items),
+                vcd::ScopeItem::Var(var) => {
+                    declared.insert(var.code);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut writer = vcd::Writer::new(BufWriter::new(File::create(output)?));
+    writer.header(&header)?;
+    // Commands of the current step, written once the step is complete
+    let mut step = Vec::new();
+    let mut corrupted = false;
+    if !report.header_repaired {
+        for command in parser {
+            let Ok(command) = command else {
+                corrupted = true;
+                break;
+            };
+            let code = match &command {
+                vcd::Command::Timestamp(_) => {
+                    for change in step.drain(..) {
+                        writer.command(&change)?;
+                    }
+                    writer.command(&command)?;
+                    report.recovered += 1;
+                    continue;
+                }
+                vcd::Command::ChangeScalar(code, _)
+                | vcd::Command::ChangeVector(code, _)
+                | vcd::Command::ChangeReal(code, _)
+                | vcd::Command::ChangeString(code, _) => Some(code),
+                _ => None,
+            };
+            if code.is_some_and(|code| !declared.contains(code)) {
+                corrupted = true;
+                break;
+            }
+            step.push(command);
+        }
+    }
+    writer.flush()?;
+
+    // A partial line within the definitions is not a step
+    let partial_step = !report.header_repaired && (partial_line || corrupted || !step.is_empty());
+    report.discarded = timestamps - report.recovered + partial_step as u64;
+    Ok(report)
+}
+
+/// Length of the complete lines of the file at `path`, and whether it ends with a
+/// partial line after them
+fn complete_len(path: &Path) -> io::Result<(u64, bool)> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    // Find the last newline, reading backwards in chunks
+    let mut end = len;
+    let mut chunk = vec![0; 4096];
+    while end > 0 {
+        let start = end.saturating_sub(chunk.len() as u64);
+        let chunk = &mut chunk[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(chunk)?;
+        if let Some(newline) = chunk.iter().rposition(|&b| b == b'\n') {
+            let complete = start + newline as u64 + 1;
+            return Ok((complete, complete != len));
+        }
+        end = start;
+    }
+    Ok((0, len != 0))
+}
+
+/// Read the first `len` bytes of the file at `path`
+fn open_prefix(path: &Path, len: u64) -> io::Result<BufReader<io::Take<File>>> {
+    Ok(BufReader::new(File::open(path)?.take(len)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use sgx_step::page::RelativePage;
+
+    use super::*;
+    use crate::{
+        dump::{DumperConfig, RSet, VCDDumper},
+        golden::Trace,
+        PageAccess, Perms,
+    };
+
+    /// Write a trace of `steps` steps with two threads to `path`
+    fn write_trace(path: &Path, steps: usize) {
+        let config = DumperConfig {
+            threads: Some(2),
+            ..Default::default()
+        };
+        let mut dumper = VCDDumper::<RSet>::with_config(path, 4, config).unwrap();
+        for step in 0..steps {
+            dumper.next_step(|entry| {
+                entry.write_thread(0x7000 + (step % 2) * 0x1000).unwrap();
+                let page = PageAccess::new(RelativePage::new(step % 4), Perms::READ);
+                entry.write_page_accesses([page].iter());
+            });
+        }
+    }
+
+    #[test]
+    fn every_prefix_of_a_trace_is_repaired() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, truncated, repaired) = (
+            dir.path().join("complete.vcd"),
+            dir.path().join("truncated.vcd"),
+            dir.path().join("repaired.vcd"),
+        );
+        write_trace(&path, 6);
+        let vcd = fs::read(&path).unwrap();
+        let complete = Trace::read(&path).unwrap();
+        let definitions = String::from_utf8_lossy(&vcd)
+            .find("$enddefinitions")
+            .unwrap();
+
+        for len in 0..=vcd.len() {
+            let prefix = &vcd[..len];
+            fs::write(&truncated, prefix).unwrap();
+            let report = repair_vcd(&truncated, &repaired)
+                .unwrap_or_else(|e| panic!("prefix of {len} bytes: {e}"));
+            let trace = Trace::read(&repaired).unwrap();
+
+            // Complete lines up to the end of the definitions
+            let complete_definitions = prefix
+                .iter()
+                .rposition(|&b| b == b'\n')
+                .is_some_and(|newline| newline > definitions);
+            assert_eq!(report.header_repaired, !complete_definitions, "{len} bytes");
+            if report.header_repaired {
+                assert_eq!(
+                    report,
+                    RepairReport {
+                        header_repaired: true,
+                        ..Default::default()
+                    }
+                );
+                assert!(trace.changes.is_empty());
+                continue;
+            }
+            assert_eq!(trace.wires, complete.wires, "{len} bytes");
+
+            // Every complete timestamp is recovered, and at most the step after it is lost
+            let timestamps = prefix
+                .split(|&b| b == b'\n')
+                .filter(|line| line.starts_with(b"#") && line.len() > 1)
+                .count() as u64;
+            let lines = prefix.split(|&b| b == b'\n').collect::<Vec<_>>();
+            let complete_timestamp = lines.len() >= 2 && lines[lines.len() - 2].starts_with(b"#");
+            let ends_with_step = prefix.ends_with(b"\n") && complete_timestamp;
+            assert!(report.recovered <= timestamps);
+            assert!(report.recovered + 1 >= timestamps);
+            assert!(report.discarded <= 1, "{len} bytes: {report:?}");
+            if ends_with_step || len == vcd.len() {
+                assert_eq!((report.recovered, report.discarded), (timestamps, 0));
+            }
+            for ts in 0..report.recovered {
+                assert_eq!(
+                    trace.changes.get(&ts),
+                    complete.changes.get(&ts),
+                    "{len} bytes"
+                );
+            }
+            assert!(trace.changes.keys().all(|&ts| ts < report.recovered.max(1)));
+        }
+    }
+
+    #[test]
+    fn steps_after_a_corrupted_command_are_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, repaired) = (
+            dir.path().join("corrupted.vcd"),
+            dir.path().join("repaired.vcd"),
+        );
+        write_trace(&path, 6);
+        let vcd = fs::read_to_string(&path).unwrap();
+        // A change of an undeclared wire in the third step
+        let third = vcd.find("#2\n").unwrap() + 3;
+        fs::write(&path, format!("{}1~~~\n{}", &vcd[..third], &vcd[third..])).unwrap();
+
+        let report = repair_vcd(&path, &repaired).unwrap();
+        assert_eq!(
+            report,
+            RepairReport {
+                recovered: 2,
+                discarded: 5,
+                header_repaired: false
+            }
+        );
+        assert!(Trace::read(&repaired)
+            .unwrap()
+            .changes
+            .keys()
+            .all(|&ts| ts < 2));
+    }
+
+    #[test]
+    fn invalid_definitions_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, repaired) = (
+            dir.path().join("invalid.vcd"),
+            dir.path().join("repaired.vcd"),
+        );
+        fs::write(
+            &path,
+            "$scope module trace $end\n$var wire 1 ! _0 $end\n$bogus $end\n$enddefinitions $end\n#1\n",
+        )
+        .unwrap();
+        assert!(repair_vcd(&path, &repaired).is_err());
+        assert!(repair_vcd(dir.path().join("missing.vcd"), &repaired).is_err());
+    }
+}
@@ -0,0 +1,2197 @@
+//! TLBlur simulator
+//!
+//! Simulates the hardware TLB of a TLBlur-instrumented enclave while it is being
+//! single-stepped, and records what a given attacker would observe.
+//!
+//! # Randomness
+//!
+//! All stochastic components of the simulator draw from a single [`StdRng`] that is
+//! seeded with [`SimulatorBuilder::seed`], so two runs with the same seed and inputs
+//! produce identical VCD output. The RNG is consumed in the following order on every
+//! simulated step:
+//!
+//! 1. [`NoiseModel::apply`], once per page the attacker would observe (only if the
+//!    drop probability is nonzero).
+//! 2. [`HardwareTLB::update`], once per eviction from a full set (only with
+//!    [`ReplacementPolicy::Random`](crate::tlb::ReplacementPolicy::Random)).
+//!
+//! Hash-based collections use [`DeterministicState`] so that their iteration order,
+//! and thus the order of VCD value changes, does not vary between runs.
+
+use std::{
+    borrow::Cow,
+    collections::{hash_map::DefaultHasher, BTreeSet},
+    fs::File,
+    hash::BuildHasherDefault,
+    io::{self, BufWriter},
+    marker::PhantomData,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use log::{debug, trace};
+use rand::{rngs::StdRng, SeedableRng};
+use sgx_step::page::{RelativePage, PAGE_SIZE};
+#[cfg(feature = "sgx")]
+use sgx_step::EnclaveRef;
+
+use crate::{
+    attacker::{AexNotify, Attacker, CanObserve, NoiseModel, PageTableObservations},
+    backend::{Backend, MockBackend},
+    dump::{
+        DumperConfig, PageFilter, PageRecord, PamRecord, RSet, TimestampSource, TraceHeader,
+        TracePageSet, TraceRecord, TraceWriter, VCDDumper,
+    },
+    metrics::Metrics,
+    pam::{EvictionPolicy, PAM},
+    prefetch::{PamPrefetcher, PrefetchKind, PrefetchRegion, Prefetcher},
+    stats::{
+        AttackerComparison, InformationGain, PamCoverage, SimulationStats, SimulationSummary,
+        WorkingSets,
+    },
+    tlb::{AdjacentPrefetch, FlushPolicy, HardwareTLB, HardwareTLBConfig},
+    PageAccess, Perms, ProfilerError, ScanCacheConfig, TargetRange, TargetStep,
+};
+#[cfg(feature = "sgx")]
+use crate::{
+    backend::HardwareBackend, check_signal_handlers, create_dumper, create_trap_handler,
+    resolve_symbols, run_profiler, segment_ranges, stop_requested, HandlerState, PageTable,
+    ProfilerLibrary,
+};
+
+/// Hasher with a fixed key, which makes iteration order reproducible across runs.
+pub type DeterministicState = BuildHasherDefault<DefaultHasher>;
+
+/// Symbols that TLBlur adds to instrumented enclaves
+pub const TLBLUR_SYMBOLS: [&str; 3] = ["__tlblur_pam", "__tlblur_counter", "tlblur_pam_update"];
+
+/// Size in bytes of an entry of the PAM, the counter value at the last update of a page
+pub const PAM_ENTRY_SIZE: usize = 8;
+
+/// Location of the TLBlur instrumentation in the enclave
+#[derive(Debug, Clone, Copy)]
+pub struct TlblurLayout {
+    /// Number of pages in the enclave
+    pub num_pages: usize,
+    /// First page of the PAM
+    pub pam_page: RelativePage,
+    pub pam_counter_page: RelativePage,
+    /// Page containing the code that updates the PAM
+    pub pam_update_code_page: RelativePage,
+}
+
+impl TlblurLayout {
+    /// Locate the TLBlur instrumentation of `enclave`.
+    ///
+    /// Returns `ProfilerError::SymbolNotFound` if the enclave is not instrumented with TLBlur.
+    #[cfg(feature = "sgx")]
+    pub fn of_enclave(enclave: &EnclaveRef) -> Result<Self, ProfilerError> {
+        Ok(Self::from_symbols(
+            enclave,
+            &resolve_symbols(enclave, &TLBLUR_SYMBOLS)?,
+        ))
+    }
+
+    /// Layout of `enclave` given the addresses of its `TLBLUR_SYMBOLS`
+    #[cfg(feature = "sgx")]
+    fn from_symbols(enclave: &EnclaveRef, symbols: &[u64]) -> Self {
+        let page_of = |address: u64| {
+            RelativePage::containing(address as usize, enclave)
+                .expect("TLBlur symbols lie within the enclave")
+        };
+        Self {
+            num_pages: enclave.page_count(),
+            pam_page: page_of(symbols[0]),
+            pam_counter_page: page_of(symbols[1]),
+            pam_update_code_page: page_of(symbols[2]),
+        }
+    }
+
+    /// Layout of a mock enclave of `num_pages` pages, with the TLBlur instrumentation
+    /// in the pages before the last one, e.g. for a [`MockBackend`].
+    pub fn mock(num_pages: usize) -> Self {
+        Self {
+            num_pages,
+            pam_page: RelativePage::new(num_pages - 4),
+            pam_counter_page: RelativePage::new(num_pages - 3),
+            pam_update_code_page: RelativePage::new(num_pages - 2),
+        }
+    }
+
+    /// Number of entries of the PAM, one per enclave page, indexed by the page number
+    pub fn pam_entries(&self) -> usize {
+        self.num_pages
+    }
+
+    /// Size in bytes of the PAM buffer that is read from the enclave
+    pub fn pam_size(&self) -> usize {
+        self.pam_entries() * PAM_ENTRY_SIZE
+    }
+
+    /// Pages covered by one of the `TLBLUR_SYMBOLS`
+    fn symbol_pages(&self, name: &str) -> Option<Range<usize>> {
+        let page = match name {
+            "__tlblur_pam" => {
+                // The PAM need not be page aligned, so it may span one more page
+                let pam_pages = self.pam_size() / PAGE_SIZE;
+                return Some(self.pam_page.get()..self.pam_page.get() + pam_pages + 1);
+            }
+            "__tlblur_counter" => self.pam_counter_page,
+            "tlblur_pam_update" => self.pam_update_code_page,
+            _ => return None,
+        };
+        Some(page.get()..page.get() + 1)
+    }
+}
+
+/// Builder for a [`Simulator`]
+///
+/// ```no_run
+/// # #[cfg(feature = "sgx")]
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use sgx_profiler::{
+///     attacker::Attacker, create_enclave, tlb::HardwareTLBConfig, ProfilerLibrary,
+///     SimulatorBuilder,
+/// };
+///
+/// let enclave = create_enclave("enclave.signed.so", true)?;
+/// let library = unsafe { libloading::Library::new("libprofiler.so")? };
+///
+/// let simulator = SimulatorBuilder::new("trace.vcd")
+///     .pws_size(10)
+///     .hardware_tlb(HardwareTLBConfig::Perfect { capacity: None })
+///     .attacker(Attacker::SingleStep)
+///     .build(&enclave)?;
+/// simulator.run(ProfilerLibrary::new(&library)?, &["input"])?;
+/// # Ok(())
+/// # }
+/// # #[cfg(not(feature = "sgx"))]
+/// # fn main() {}
+/// ```
+#[derive(Debug)]
+pub struct SimulatorBuilder<S = RSet> {
+    config: SimulatorConfig,
+    trace_set: PhantomData<S>,
+}
+
+/// Options of a [`SimulatorBuilder`], which do not depend on its trace page set
+#[derive(Debug)]
+struct SimulatorConfig {
+    trace_output: PathBuf,
+    records: Option<PathBuf>,
+    schedule: Option<PathBuf>,
+    debug_pam: Option<PathBuf>,
+    debug_hardware_tlb: Option<PathBuf>,
+    ground_truth_output: Option<PathBuf>,
+    pam_coverage_log: Option<PathBuf>,
+    working_sets: Option<PathBuf>,
+    information_gain: Option<(PathBuf, u64)>,
+    metrics: Option<Arc<Metrics>>,
+    write_erip: bool,
+    pws_size: usize,
+    hardware_tlb: HardwareTLBConfig,
+    hw_prefetch: AdjacentPrefetch,
+    flush_policy: FlushPolicy,
+    pam_eviction: EvictionPolicy,
+    attacker: Attacker,
+    noise: NoiseModel,
+    aex_notify: Option<AexNotify>,
+    seed: u64,
+    prefetch: bool,
+    warmup: bool,
+    hide_instrumentation: bool,
+    parallel_scan: bool,
+    segments_only: bool,
+    scan_cache: Option<ScanCacheConfig>,
+    target: Option<Range<usize>>,
+    timestamp: TimestampSource,
+    page_filter: PageFilter,
+    prefetch_regions: Vec<PrefetchRegion>,
+    prefetcher: Box<dyn Prefetcher + Send + Sync>,
+    baseline_symbols: Vec<String>,
+    cache_line_symbols: Vec<String>,
+}
+
+impl SimulatorBuilder {
+    /// Write the attacker observations to `trace_output`.
+    pub fn new(trace_output: impl AsRef<Path>) -> Self {
+        Self {
+            config: SimulatorConfig {
+                trace_output: trace_output.as_ref().to_owned(),
+                records: None,
+                schedule: None,
+                debug_pam: None,
+                debug_hardware_tlb: None,
+                ground_truth_output: None,
+                pam_coverage_log: None,
+                working_sets: None,
+                information_gain: None,
+                metrics: None,
+                write_erip: false,
+                pws_size: 10,
+                hardware_tlb: HardwareTLBConfig::Perfect { capacity: None },
+                hw_prefetch: AdjacentPrefetch::default(),
+                flush_policy: FlushPolicy::default(),
+                pam_eviction: EvictionPolicy::default(),
+                attacker: Attacker::SingleStep,
+                noise: NoiseModel::default(),
+                aex_notify: None,
+                seed: 0,
+                prefetch: true,
+                warmup: false,
+                hide_instrumentation: false,
+                parallel_scan: false,
+                segments_only: false,
+                scan_cache: None,
+                target: None,
+                timestamp: TimestampSource::Steps,
+                page_filter: PageFilter::All,
+                prefetch_regions: PrefetchRegion::tlblur_defaults(),
+                prefetcher: Box::new(PamPrefetcher),
+                baseline_symbols: Vec::new(),
+                cache_line_symbols: Vec::new(),
+            },
+            trace_set: PhantomData,
+        }
+    }
+}
+
+impl<S: TracePageSet> SimulatorBuilder<S> {
+    /// Page set used to write the VCD outputs, [`RSet`] by default.
+    ///
+    /// Use [`RWXSet`](crate::dump::RWXSet) to keep writes apart from reads.
+    pub fn trace_set<T: TracePageSet>(self) -> SimulatorBuilder<T> {
+        SimulatorBuilder {
+            config: self.config,
+            trace_set: PhantomData,
+        }
+    }
+
+    /// Size of the software TLB to simulate
+    pub fn pws_size(mut self, pws_size: usize) -> Self {
+        self.config.pws_size = pws_size;
+        self
+    }
+
+    pub fn hardware_tlb(mut self, config: HardwareTLBConfig) -> Self {
+        self.config.hardware_tlb = config;
+        self
+    }
+
+    /// Prefetching of adjacent translations by the hardware TLB, off by default
+    pub fn hw_prefetch(mut self, prefetch: AdjacentPrefetch) -> Self {
+        self.config.hw_prefetch = prefetch;
+        self
+    }
+
+    /// When the hardware TLB is flushed on an interrupt, defaults to
+    /// [`FlushPolicy::AlwaysOnInterrupt`]
+    ///
+    /// ```
+    /// use sgx_profiler::{
+    ///     attacker::Attacker,
+    ///     backend::{MockBackend, MockStep},
+    ///     simulator::TlblurLayout,
+    ///     sgx_step::page::RelativePage,
+    ///     tlb::FlushPolicy,
+    ///     PageAccess, Perms, SimulatorBuilder,
+    /// };
+    ///
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let read = PageAccess::new(RelativePage::new(10), Perms::READ);
+    /// let layout = TlblurLayout::mock(64);
+    /// let cached_after_interrupt = |policy| {
+    ///     let step = MockStep {
+    ///         accesses: vec![read],
+    ///         ..Default::default()
+    ///     };
+    ///     let mut simulator = SimulatorBuilder::new(dir.path().join("flush.vcd"))
+    ///         .attacker(Attacker::SingleStep)
+    ///         .flush_policy(policy)
+    ///         .build_with_backend(MockBackend::new(64, [step]), layout)
+    ///         .unwrap();
+    ///     simulator.step().unwrap();
+    ///     simulator.step().unwrap();
+    ///     simulator.hardware_tlb().test(&read)
+    /// };
+    /// assert!(!cached_after_interrupt(FlushPolicy::AlwaysOnInterrupt));
+    /// // Timer interrupts of a single-stepping attacker don't flush
+    /// assert!(cached_after_interrupt(FlushPolicy::OnlyOnEresume));
+    /// assert!(cached_after_interrupt(FlushPolicy::Never));
+    /// ```
+    pub fn flush_policy(mut self, policy: FlushPolicy) -> Self {
+        self.config.flush_policy = policy;
+        self
+    }
+
+    /// Replacement policy of the PAM active set, matching the TLBlur variant
+    /// of the instrumented enclave
+    pub fn pam_eviction(mut self, policy: EvictionPolicy) -> Self {
+        self.config.pam_eviction = policy;
+        self
+    }
+
+    pub fn attacker(mut self, attacker: Attacker) -> Self {
+        self.config.attacker = attacker;
+        self
+    }
+
+    pub fn noise(mut self, noise: NoiseModel) -> Self {
+        self.config.noise = noise;
+        self
+    }
+
+    /// Simulate an enclave that restores its working set with AEX-Notify after every
+    /// interrupt, see [`AexNotify`]. Disabled by default.
+    ///
+    /// ```
+    /// use sgx_profiler::{
+    ///     attacker::{AexNotify, Attacker},
+    ///     backend::{MockBackend, MockStep},
+    ///     simulator::TlblurLayout,
+    ///     sgx_step::page::RelativePage,
+    ///     PageAccess, Perms, SimulatorBuilder,
+    /// };
+    ///
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let layout = TlblurLayout::mock(64);
+    /// let observations = |aex_notify: Option<AexNotify>| {
+    ///     // A loop that alternates between two pages
+    ///     let script = (0..8).map(|i| MockStep {
+    ///         accesses: vec![PageAccess::new(RelativePage::new(10 + i % 2), Perms::READ)],
+    ///         ..Default::default()
+    ///     });
+    ///     let mut builder = SimulatorBuilder::new(dir.path().join("aex-notify.vcd"))
+    ///         .attacker(Attacker::SingleStep)
+    ///         .prefetch(false);
+    ///     if let Some(aex_notify) = aex_notify {
+    ///         builder = builder.aex_notify(aex_notify);
+    ///     }
+    ///     let mut simulator = builder
+    ///         .build_with_backend(MockBackend::new(64, script), layout)
+    ///         .unwrap();
+    ///     for _ in 0..8 {
+    ///         simulator.step().unwrap();
+    ///     }
+    ///     simulator.summary().observations
+    /// };
+    ///
+    /// // Every step misses the flushed TLB without AEX-Notify, but with it both pages
+    /// // stay cached once they have been accessed
+    /// assert_eq!(observations(None), 7);
+    /// assert_eq!(observations(Some(AexNotify::new(2))), 2);
+    /// ```
+    pub fn aex_notify(mut self, aex_notify: AexNotify) -> Self {
+        self.config.aex_notify = Some(aex_notify);
+        self
+    }
+
+    /// Seed for all randomness in the simulator
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.config.seed = seed;
+        self
+    }
+
+    /// Write erip to the VCD outputs
+    pub fn write_erip(mut self, write_erip: bool) -> Self {
+        self.config.write_erip = write_erip;
+        self
+    }
+
+    /// Prefetch the PAM, stack and instrumentation pages after every interrupt,
+    /// enabled by default.
+    pub fn prefetch(mut self, prefetch: bool) -> Self {
+        self.config.prefetch = prefetch;
+        self
+    }
+
+    /// Fill the hardware TLB and the PAM with the pages expected to be resident at
+    /// enclave entry before the first recorded step, instead of starting empty.
+    ///
+    /// These are the prefetch regions, the most recently updated pages of the PAM
+    /// and the code page the enclave was interrupted at.
+    ///
+    /// ```
+    /// use sgx_profiler::{
+    ///     backend::MockBackend, simulator::TlblurLayout, sgx_step::page::RelativePage,
+    ///     PageAccess, Perms, SimulatorBuilder,
+    /// };
+    ///
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let layout = TlblurLayout::mock(64);
+    /// let counter = PageAccess::new(RelativePage::new(61), Perms::READ);
+    /// let cached_after_entry = |warmup| {
+    ///     let trace = dir.path().join(format!("warmup-{warmup}.vcd"));
+    ///     let mut simulator = SimulatorBuilder::new(&trace)
+    ///         .warmup(warmup)
+    ///         .build_with_backend(MockBackend::new(64, []), layout)
+    ///         .unwrap();
+    ///     simulator.step().unwrap();
+    ///     simulator.hardware_tlb().test(&counter)
+    /// };
+    ///
+    /// assert!(cached_after_entry(true));
+    /// assert!(!cached_after_entry(false));
+    /// ```
+    pub fn warmup(mut self, warmup: bool) -> Self {
+        self.config.warmup = warmup;
+        self
+    }
+
+    /// Leave the pages of the TLBlur instrumentation, i.e. the PAM, its counter and the
+    /// code that updates it, out of the trace of the attacker.
+    ///
+    /// They are still cached in the hardware TLB and prefetched as before, so this only
+    /// separates the internal state of the defense from what it reveals.
+    ///
+    /// ```
+    /// use std::collections::BTreeSet;
+    /// use sgx_profiler::{
+    ///     backend::{MockBackend, MockStep},
+    ///     simulator::TlblurLayout,
+    ///     sgx_step::page::RelativePage,
+    ///     PageAccess, Perms, SimulatorBuilder,
+    /// };
+    ///
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let layout = TlblurLayout::mock(64);
+    /// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+    /// let traced_pages = |hide| {
+    ///     let script = [vec![read(10), read(61)], vec![read(62), read(11)]].map(|accesses| {
+    ///         MockStep {
+    ///             accesses,
+    ///             pam_updates: vec![RelativePage::new(10)],
+    ///             ..Default::default()
+    ///         }
+    ///     });
+    ///     let trace = dir.path().join(format!("hide-instrumentation-{hide}.vcd"));
+    ///     let mut simulator = SimulatorBuilder::new(&trace)
+    ///         .hide_instrumentation(hide)
+    ///         .build_with_backend(MockBackend::new(64, script), layout)
+    ///         .unwrap();
+    ///     for _ in 0..3 {
+    ///         simulator.step().unwrap();
+    ///     }
+    ///     simulator.flush().unwrap();
+    ///
+    ///     let file = std::io::BufReader::new(std::fs::File::open(&trace).unwrap());
+    ///     let mut parser = vcd::Parser::new(file);
+    ///     let header = parser.parse_header().unwrap();
+    ///     let page_of = |code| {
+    ///         (0..64).find(|page| {
+    ///             header.find_var(&["trace", &format!("_{page}")]).unwrap().code == code
+    ///         })
+    ///     };
+    ///     parser
+    ///         .filter_map(|c| match c.unwrap() {
+    ///             vcd::Command::ChangeScalar(code, vcd::Value::V1) => page_of(code),
+    ///             _ => None,
+    ///         })
+    ///         .collect::<BTreeSet<_>>()
+    /// };
+    ///
+    /// assert!(traced_pages(false).is_superset(&BTreeSet::from([10, 11, 60, 61, 62])));
+    /// assert_eq!(traced_pages(true), BTreeSet::from([10, 11]));
+    /// ```
+    pub fn hide_instrumentation(mut self, hide: bool) -> Self {
+        self.config.hide_instrumentation = hide;
+        self
+    }
+
+    /// Regions prefetched after every interrupt in addition to the pages of the prefetcher,
+    /// [`PrefetchRegion::tlblur_defaults`] by default.
+    ///
+    /// ```
+    /// use sgx_profiler::{
+    ///     attacker::Attacker,
+    ///     backend::{MockBackend, MockStep},
+    ///     prefetch::PrefetchRegion,
+    ///     sgx_step::page::RelativePage,
+    ///     simulator::TlblurLayout,
+    ///     PageAccess, Perms, SimulatorBuilder,
+    /// };
+    ///
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+    /// let step = MockStep { accesses: vec![read(10)], ..Default::default() };
+    /// let backend = MockBackend::new(64, [step]).with_symbol("lookup_table", 20..22);
+    /// let layout = TlblurLayout::mock(64);
+    ///
+    /// let trace = dir.path().join("prefetch-trace.vcd");
+    /// let mut simulator = SimulatorBuilder::new(&trace)
+    ///     .attacker(Attacker::SingleStep)
+    ///     .prefetch_regions(vec!["symbol:lookup_table:r".parse().unwrap()])
+    ///     .build_with_backend(backend, layout)
+    ///     .unwrap();
+    /// // Prime the simulator, then interrupt on the access to page 10
+    /// simulator.step().unwrap();
+    /// simulator.step().unwrap();
+    ///
+    /// for page in [20, 21] {
+    ///     assert!(simulator.hardware_tlb().test(&read(page)));
+    ///     assert!(simulator.observations().iter().any(|p| p.covers(&read(page))));
+    /// }
+    /// ```
+    pub fn prefetch_regions(mut self, regions: Vec<PrefetchRegion>) -> Self {
+        self.config.prefetch_regions = regions;
+        self
+    }
+
+    /// Prefetcher that chooses the pages to prefetch after an interrupt in addition to
+    /// the prefetch regions, [`PamPrefetcher`] by default.
+    pub fn prefetcher(mut self, prefetcher: Box<dyn Prefetcher + Send + Sync>) -> Self {
+        self.config.prefetcher = prefetcher;
+        self
+    }
+
+    /// Start the trace with the pages of these symbols accessed,
+    /// see [`VCDDumper::with_baseline`].
+    ///
+    /// Symbols are resolved like those of the prefetch regions.
+    pub fn baseline_symbols(mut self, symbols: Vec<String>) -> Self {
+        self.config.baseline_symbols = symbols;
+        self
+    }
+
+    /// Record the cache lines accessed within the pages of these symbols,
+    /// see [`PageAccess::lines`]. Other pages are recorded at page granularity.
+    ///
+    /// The page table only reveals which pages are accessed, so the lines are
+    /// approximated: the line of every executed instruction is known from its
+    /// address, but data lines are only recorded if the backend provides them,
+    /// e.g. a [`MockBackend`](crate::backend::MockBackend) script.
+    ///
+    /// ```
+    /// use sgx_profiler::{
+    ///     attacker::Attacker,
+    ///     backend::{MockBackend, MockStep},
+    ///     dump::LineSet,
+    ///     golden::Trace,
+    ///     simulator::TlblurLayout,
+    ///     sgx_step::page::RelativePage,
+    ///     PageAccess, Perms, SimulatorBuilder,
+    /// };
+    ///
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+    /// let step = MockStep {
+    ///     accesses: vec![read(1), read(2).with_line(0x2080), read(3)],
+    ///     erip: 0x1040,
+    ///     ..Default::default()
+    /// };
+    /// let backend = MockBackend::new(64, [step]).with_symbol("hot", 1..3);
+    /// let layout = TlblurLayout::mock(64);
+    ///
+    /// let path = dir.path().join("cache-lines.vcd");
+    /// let mut simulator = SimulatorBuilder::new(&path)
+    ///     .trace_set::<LineSet>()
+    ///     .attacker(Attacker::SingleStep)
+    ///     .cache_line_symbols(vec!["hot".into()])
+    ///     .build_with_backend(backend, layout)
+    ///     .unwrap();
+    /// simulator.step().unwrap();
+    /// simulator.step().unwrap();
+    /// drop(simulator);
+    ///
+    /// let trace = Trace::read(&path).unwrap();
+    /// let lines = |page: usize, lines: u64| (format!("trace._{page}"), format!("{lines:064b}"));
+    /// // The code line is known from the erip, the data line from the script
+    /// assert!(trace.changes[&0].contains(&lines(1, 1 << 1)));
+    /// assert!(trace.changes[&0].contains(&lines(2, 1 << 2)));
+    /// // Page 3 is recorded at page granularity
+    /// assert!(trace.changes[&0].contains(&lines(3, u64::MAX)));
+    /// ```
+    pub fn cache_line_symbols(mut self, symbols: Vec<String>) -> Self {
+        self.config.cache_line_symbols = symbols;
+        self
+    }
+
+    /// Additionally write the simulated PAM to a VCD file
+    pub fn debug_pam(mut self, path: impl AsRef<Path>) -> Self {
+        self.config.debug_pam = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Additionally write the simulated hardware TLB to a VCD file
+    pub fn debug_hardware_tlb(mut self, path: impl AsRef<Path>) -> Self {
+        self.config.debug_hardware_tlb = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Additionally write all pages accessed in every step to a VCD file, regardless
+    /// of what the attacker observes.
+    ///
+    /// Like the debug traces, it has a timestamp for every simulated step, while the
+    /// trace of the attacker only has the steps the attacker observes.
+    ///
+    /// ```
+    /// use sgx_profiler::{
+    ///     attacker::Attacker,
+    ///     backend::{MockBackend, MockStep},
+    ///     golden::Trace,
+    ///     simulator::TlblurLayout,
+    ///     sgx_step::page::RelativePage,
+    ///     tlb::FlushPolicy,
+    ///     PageAccess, Perms, SimulatorBuilder,
+    /// };
+    ///
+    /// let layout = TlblurLayout::mock(64);
+    /// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+    /// let script = [vec![read(10)], vec![read(10), read(11)]]
+    ///     .map(|accesses| MockStep { accesses, ..Default::default() });
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let (attacker, truth) = (dir.path().join("attacker.vcd"), dir.path().join("ground-truth.vcd"));
+    /// {
+    ///     // Page 10 stays in the hardware TLB, so the attacker only sees its first access
+    ///     let mut simulator = SimulatorBuilder::new(&attacker)
+    ///         .attacker(Attacker::SingleStep)
+    ///         .flush_policy(FlushPolicy::Never)
+    ///         .prefetch(false)
+    ///         .ground_truth_output(&truth)
+    ///         .build_with_backend(MockBackend::new(64, script), layout)
+    ///         .unwrap();
+    ///     for _ in 0..3 {
+    ///         simulator.step().unwrap();
+    ///     }
+    /// }
+    ///
+    /// let set = |ts, page, value: &str| (ts, format!("trace._{page}"), value.to_owned());
+    /// let has = |path, (ts, wire, value)| {
+    ///     Trace::read(path).unwrap().changes[&ts].contains(&(wire, value))
+    /// };
+    /// assert!(has(&attacker, set(1, 10, "0")) && has(&attacker, set(1, 11, "1")));
+    /// assert!(!has(&truth, set(1, 10, "0")) && has(&truth, set(1, 11, "1")));
+    /// ```
+    pub fn ground_truth_output(mut self, path: impl AsRef<Path>) -> Self {
+        self.config.ground_truth_output = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Track the pages that are accessed while they are not in the PAM, and write them
+    /// to `path` as CSV at the end of [`Simulator::run`], see [`PamCoverage`].
+    ///
+    /// This only observes the simulation, it does not change the trace.
+    ///
+    /// ```
+    /// use sgx_profiler::{
+    ///     backend::{MockBackend, MockStep},
+    ///     simulator::TlblurLayout,
+    ///     sgx_step::page::RelativePage,
+    ///     PageAccess, Perms, SimulatorBuilder,
+    /// };
+    ///
+    /// let layout = TlblurLayout::mock(64);
+    /// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+    /// // Page 11 is accessed before the instrumentation records it in the PAM
+    /// let script = [(vec![read(10), read(11)], 10), (vec![read(11)], 11)].map(
+    ///     |(accesses, update)| MockStep {
+    ///         accesses,
+    ///         pam_updates: vec![RelativePage::new(update)],
+    ///         ..Default::default()
+    ///     },
+    /// );
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let mut simulator = SimulatorBuilder::new(dir.path().join("pam-coverage.vcd"))
+    ///     .pam_coverage_log(dir.path().join("pam-coverage.csv"))
+    ///     .build_with_backend(MockBackend::new(64, script), layout)
+    ///     .unwrap();
+    /// for _ in 0..3 {
+    ///     simulator.step().unwrap();
+    /// }
+    ///
+    /// let coverage = simulator.pam_coverage().unwrap();
+    /// assert_eq!(coverage.get(11), Some((1, 0)));
+    /// assert_eq!(coverage.get(10), None);
+    /// assert_eq!(simulator.summary().pam_uncovered_pages, Some(1));
+    /// ```
+    pub fn pam_coverage_log(mut self, path: impl AsRef<Path>) -> Self {
+        self.config.pam_coverage_log = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Count the distinct sets of pages the attacker observed in a step, and write
+    /// them to `path` as JSON at the end of [`Simulator::run`], see [`WorkingSets`].
+    ///
+    /// ```
+    /// use sgx_profiler::{
+    ///     attacker::Attacker,
+    ///     backend::{MockBackend, MockStep},
+    ///     simulator::TlblurLayout,
+    ///     sgx_step::page::RelativePage,
+    ///     PageAccess, Perms, SimulatorBuilder,
+    /// };
+    ///
+    /// let layout = TlblurLayout::mock(64);
+    /// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+    /// let script = [vec![read(10), read(11)], vec![read(11), read(10)], vec![read(12)]]
+    ///     .map(|accesses| MockStep { accesses, ..Default::default() });
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let mut simulator = SimulatorBuilder::new(dir.path().join("working-sets.vcd"))
+    ///     .attacker(Attacker::SingleStep)
+    ///     .prefetch(false)
+    ///     .working_sets(dir.path().join("working-sets.json"))
+    ///     .build_with_backend(MockBackend::new(64, script), layout)
+    ///     .unwrap();
+    /// for _ in 0..4 {
+    ///     simulator.step().unwrap();
+    /// }
+    ///
+    /// let working_sets = simulator.working_sets().unwrap();
+    /// assert_eq!(working_sets.get(&[10, 11]), 2);
+    /// assert_eq!(working_sets.get(&[12]), 1);
+    /// ```
+    pub fn working_sets(mut self, path: impl AsRef<Path>) -> Self {
+        self.config.working_sets = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Track how many distinct (page, bucket) pairs the attacker has observed, with
+    /// buckets of `bucket_steps` observation steps, and write the series to `path` as
+    /// CSV at the end of [`Simulator::run`], see [`InformationGain`].
+    ///
+    /// ```
+    /// use sgx_profiler::{
+    ///     attacker::Attacker,
+    ///     backend::{MockBackend, MockStep},
+    ///     simulator::TlblurLayout,
+    ///     sgx_step::page::RelativePage,
+    ///     PageAccess, Perms, SimulatorBuilder,
+    /// };
+    ///
+    /// let layout = TlblurLayout::mock(64);
+    /// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+    /// let script = [vec![read(10), read(11)], vec![read(11)], vec![read(12)]]
+    ///     .map(|accesses| MockStep { accesses, ..Default::default() });
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let mut simulator = SimulatorBuilder::new(dir.path().join("infogain.vcd"))
+    ///     .attacker(Attacker::SingleStep)
+    ///     .prefetch(false)
+    ///     .information_gain(dir.path().join("infogain.csv"), 2)
+    ///     .build_with_backend(MockBackend::new(64, script), layout)
+    ///     .unwrap();
+    /// for _ in 0..4 {
+    ///     simulator.step().unwrap();
+    /// }
+    ///
+    /// // Pages 10 and 11 in the first bucket, and 12 in the second
+    /// assert_eq!(simulator.information_gain().unwrap().total(), 3);
+    /// assert_eq!(simulator.summary().information_gain, Some(3));
+    /// ```
+    pub fn information_gain(mut self, path: impl AsRef<Path>, bucket_steps: u64) -> Self {
+        self.config.information_gain = Some((path.as_ref().to_owned(), bucket_steps));
+        self
+    }
+
+    /// Update `metrics` on every simulated step, e.g. to serve them with
+    /// [`Metrics::serve`]. The erip of every step is then read for the metrics.
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.config.metrics = Some(metrics);
+        self
+    }
+
+    /// See [`PageTable::set_parallel_scan`]
+    pub fn parallel_scan(mut self, parallel_scan: bool) -> Self {
+        self.config.parallel_scan = parallel_scan;
+        self
+    }
+
+    /// Only map the PTEs of the enclave segments, see [`PageTable::new`]
+    pub fn segments_only(mut self, segments_only: bool) -> Self {
+        self.config.segments_only = segments_only;
+        self
+    }
+
+    /// See [`PageTable::set_scan_cache`]
+    pub fn scan_cache(mut self, config: Option<ScanCacheConfig>) -> Self {
+        self.config.scan_cache = config;
+        self
+    }
+
+    /// Only simulate instructions with an absolute address in the given range,
+    /// see [`TargetRange`].
+    ///
+    /// The simulated hardware TLB is not updated outside of the range,
+    /// so it is flushed whenever execution enters the range.
+    pub fn target_range(mut self, range: Option<Range<usize>>) -> Self {
+        self.config.target = range;
+        self
+    }
+
+    /// Source of the timestamps of the VCD outputs
+    pub fn timestamp(mut self, timestamp: TimestampSource) -> Self {
+        self.config.timestamp = timestamp;
+        self
+    }
+
+    /// Only record the pages included by the filter in the VCD outputs
+    pub fn page_filter(mut self, filter: PageFilter) -> Self {
+        self.config.page_filter = filter;
+        self
+    }
+
+    /// Also write the attacker observations to `path` as a record trace,
+    /// see [`TraceRecord`](crate::dump::TraceRecord).
+    pub fn records(mut self, path: impl AsRef<Path>) -> Self {
+        self.config.records = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Record the interrupt schedule of the run to `path`: what the simulator read
+    /// from the enclave at every interrupt, i.e. the accessed pages, erip, stack page
+    /// and PAM, as a record trace.
+    ///
+    /// Simulating the schedule with [`MockBackend::from_records`] reproduces the
+    /// observations of the run exactly, independent of the timing of the interrupts
+    /// on hardware, as long as the simulator is configured the same.
+    ///
+    /// ```
+    /// use std::{fs::File, io::BufReader};
+    /// use sgx_profiler::{
+    ///     attacker::Attacker,
+    ///     backend::{MockBackend, MockStep},
+    ///     dump::TraceReader,
+    ///     golden::Trace,
+    ///     simulator::TlblurLayout,
+    ///     sgx_step::page::RelativePage,
+    ///     tlb::HardwareTLBConfig,
+    ///     PageAccess, Perms, SimulatorBuilder,
+    /// };
+    ///
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let access = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+    /// let script = (0..20).map(|i| MockStep {
+    ///     accesses: vec![access(10 + i % 5), access(20 + i % 3)],
+    ///     pam_updates: if i % 4 == 0 { vec![RelativePage::new(30 + i)] } else { vec![] },
+    ///     stack_page: Some(RelativePage::new(50)),
+    ///     erip: 0x3000 + i,
+    ///     ..Default::default()
+    /// });
+    /// let layout = TlblurLayout::mock(64);
+    /// let builder = |output: &str| {
+    ///     SimulatorBuilder::new(dir.path().join(output))
+    ///         .attacker(Attacker::SingleStep)
+    ///         .hardware_tlb(HardwareTLBConfig::Perfect { capacity: Some(4) })
+    ///         .pws_size(3)
+    ///         .warmup(true)
+    /// };
+    ///
+    /// let schedule = dir.path().join("schedule.trace");
+    /// let recorded = {
+    ///     let mut simulator = builder("recorded.vcd")
+    ///         .record_schedule(&schedule)
+    ///         .build_with_backend(MockBackend::new(64, script), layout)
+    ///         .unwrap();
+    ///     simulator.replay().unwrap();
+    ///     simulator.summary()
+    /// };
+    ///
+    /// let reader = TraceReader::new(BufReader::new(File::open(&schedule).unwrap())).unwrap();
+    /// assert!(reader.header().schedule);
+    /// let (backend, num_pages) = MockBackend::from_records(reader).unwrap();
+    /// assert_eq!(num_pages, 64);
+    /// let mut simulator = builder("replayed.vcd")
+    ///     .build_with_backend(backend, layout)
+    ///     .unwrap();
+    /// simulator.replay().unwrap();
+    /// assert_eq!(simulator.summary(), recorded);
+    /// // The PAM was updated in the replay as it was in the recorded run
+    /// assert_eq!((recorded.steps, recorded.pam_updates), (20, 5));
+    /// drop(simulator);
+    ///
+    /// let read = |output| Trace::read(dir.path().join(output)).unwrap();
+    /// assert_eq!(read("replayed.vcd"), read("recorded.vcd"));
+    /// ```
+    pub fn record_schedule(mut self, path: impl AsRef<Path>) -> Self {
+        self.config.schedule = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Resolve the TLBlur symbols of the enclave and set up the simulator state.
+    ///
+    /// Returns `ProfilerError::SymbolNotFound` if the enclave is not instrumented with TLBlur.
+    #[cfg(feature = "sgx")]
+    pub fn build(
+        self,
+        enclave: &EnclaveRef,
+    ) -> Result<Simulator<HardwareBackend, S>, ProfilerError> {
+        let symbols = resolve_symbols(enclave, &TLBLUR_SYMBOLS)?;
+        let (pam_address, pam_counter_address) = (symbols[0], symbols[1]);
+
+        let segments = self.config.segments_only.then(|| segment_ranges(enclave));
+        let mut page_table = PageTable::new(enclave, segments.as_deref())?;
+        page_table.set_parallel_scan(self.config.parallel_scan);
+        page_table.set_scan_cache(self.config.scan_cache);
+
+        let layout = TlblurLayout {
+            num_pages: page_table.page_table_map.len(),
+            ..TlblurLayout::from_symbols(enclave, &symbols)
+        };
+
+        // The PAM is read as a whole on every update, so it must not be smaller than
+        // the buffer. Its size is unknown if the symbol has none, e.g. in assembly.
+        if let Ok(Some(pam)) = enclave.symbol_range(TLBLUR_SYMBOLS[0]) {
+            if pam.len() < layout.pam_size() {
+                return Err(ProfilerError::PamSize {
+                    size: pam.len(),
+                    expected: layout.pam_size(),
+                });
+            }
+            if pam.len() > layout.pam_size() {
+                debug!(
+                    "PAM of {} bytes is larger than the {} bytes for {} pages",
+                    pam.len(),
+                    layout.pam_size(),
+                    layout.num_pages
+                );
+            }
+        }
+
+        let backend = HardwareBackend::new(enclave, page_table, pam_address, pam_counter_address);
+        self.assemble(backend, layout, |f, config| {
+            create_dumper(enclave, f, config)
+        })
+    }
+
+    /// Set up the simulator state to read page accesses from the given backend,
+    /// e.g. a [`MockBackend`](crate::backend::MockBackend).
+    ///
+    /// The scan options have no effect, as they only apply to the hardware page table.
+    ///
+    /// Returns `ProfilerError::SymbolNotFound` if a prefetch region refers to a symbol
+    /// that is not known to the backend.
+    pub fn build_with_backend<B: Backend>(
+        self,
+        backend: B,
+        layout: TlblurLayout,
+    ) -> Result<Simulator<B, S>, ProfilerError> {
+        self.assemble(backend, layout, |f, config| {
+            VCDDumper::with_config(f, layout.num_pages, config)
+        })
+    }
+
+    fn assemble<B: Backend>(
+        self,
+        backend: B,
+        layout: TlblurLayout,
+        create_dumper: impl Fn(&Path, DumperConfig) -> Result<VCDDumper<S>, ProfilerError>,
+    ) -> Result<Simulator<B, S>, ProfilerError> {
+        let config = self.config;
+        let symbol_pages = |name: &str| {
+            layout
+                .symbol_pages(name)
+                .or_else(|| backend.symbol_pages(name))
+        };
+        let symbol_ranges = |names: &[String]| {
+            names
+                .iter()
+                .map(|name| {
+                    symbol_pages(name).ok_or_else(|| ProfilerError::SymbolNotFound(name.clone()))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let baseline = symbol_ranges(&config.baseline_symbols)?
+            .into_iter()
+            .flatten()
+            .map(|page| PageAccess::new(RelativePage::new(page), Perms::READ))
+            .collect::<Vec<_>>();
+        let cache_line_pages = symbol_ranges(&config.cache_line_symbols)?;
+        // Only the main trace starts with the baseline
+        let debug_config = DumperConfig {
+            timestamp: config.timestamp,
+            filter: config.page_filter,
+            ..Default::default()
+        };
+        let hidden_pages = if config.hide_instrumentation {
+            TLBLUR_SYMBOLS
+                .iter()
+                .filter_map(|name| layout.symbol_pages(name))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let prefetch_regions = config
+            .prefetch_regions
+            .into_iter()
+            .map(|region| region.resolve(symbol_pages))
+            .collect::<Result<Vec<_>, _>>()?;
+        let schedule = config
+            .schedule
+            .map(|path| ScheduleRecorder::create(path, layout))
+            .transpose()?;
+
+        let debug_dumper = |file: Option<&Path>| {
+            file.map(|f| create_dumper(f, debug_config.clone()))
+                .transpose()
+        };
+
+        Ok(Simulator {
+            dumper: create_dumper(
+                &config.trace_output,
+                DumperConfig {
+                    baseline,
+                    records: config.records,
+                    ..debug_config.clone()
+                },
+            )?,
+            pam_dumper: debug_dumper(config.debug_pam.as_deref())?,
+            hwtlb_dumper: debug_dumper(config.debug_hardware_tlb.as_deref())?,
+            truth_dumper: debug_dumper(config.ground_truth_output.as_deref())?,
+            schedule,
+            backend,
+            pam: PAM::new(layout.pam_entries(), config.pws_size).with_eviction(config.pam_eviction),
+            attacker: config.attacker,
+            hw_tlb: HardwareTLB::from(config.hardware_tlb).with_prefetch(config.hw_prefetch),
+            flush_policy: config.flush_policy,
+            pte_observations: PageTableObservations::new(),
+            noise: config.noise,
+            aex_notify: config.aex_notify,
+            rng: StdRng::seed_from_u64(config.seed),
+            write_erip: config.write_erip,
+            prefetch: config.prefetch,
+            warmup: config.warmup,
+            hidden_pages,
+            prefetch_regions,
+            prefetcher: config.prefetcher,
+            target: config.target.map(TargetRange::new),
+            cache_line_pages,
+            last_erip: 0,
+            stats: SimulationStats::new(),
+            pam_coverage: config
+                .pam_coverage_log
+                .map(|path| (path, PamCoverage::new())),
+            working_sets: config.working_sets.map(|path| (path, WorkingSets::new())),
+            information_gain: config
+                .information_gain
+                .map(|(path, bucket_steps)| (path, InformationGain::new(bucket_steps))),
+            metrics: config.metrics,
+            first_run: true,
+        })
+    }
+}
+
+/// Writes what the simulator reads from the backend at every step,
+/// see [`SimulatorBuilder::record_schedule`]
+struct ScheduleRecorder {
+    path: PathBuf,
+    writer: TraceWriter<BufWriter<File>>,
+    step: u64,
+    /// PAM as of the last record, to only write the entries that changed
+    pam: Vec<u64>,
+    pam_counter: u64,
+}
+
+impl ScheduleRecorder {
+    fn create(path: PathBuf, layout: TlblurLayout) -> Result<Self, ProfilerError> {
+        let header = TraceHeader {
+            num_pages: layout.num_pages,
+            has_counts: false,
+            schedule: true,
+        };
+        match File::create(&path).and_then(|file| TraceWriter::new(BufWriter::new(file), header)) {
+            Ok(writer) => Ok(Self {
+                path,
+                writer,
+                step: 0,
+                pam: vec![0; layout.pam_entries()],
+                pam_counter: 0,
+            }),
+            Err(error) => Err(ProfilerError::Output { path, error }),
+        }
+    }
+
+    /// Write a record of the step, with the pages accessed in it if they were read
+    /// from `backend`, and `pam` as last read by the simulator.
+    fn record(
+        &mut self,
+        backend: &mut impl Backend,
+        accessed: bool,
+        pam: &[u64],
+    ) -> Result<(), ProfilerError> {
+        let counter = backend.read_pam_counter()?;
+        let mut entries = Vec::new();
+        for (entry, (&value, last)) in pam.iter().zip(&mut self.pam).enumerate() {
+            if value != *last {
+                *last = value;
+                entries.push((entry, value));
+            }
+        }
+        let pam = (counter != self.pam_counter || !entries.is_empty())
+            .then_some(PamRecord { counter, entries });
+        self.pam_counter = counter;
+
+        let pages = if accessed {
+            backend.accessed_pages()
+        } else {
+            &[]
+        };
+        let record = TraceRecord {
+            step: self.step,
+            // Relative, as the enclave may be based elsewhere when replaying
+            erip: Some(backend.erip().wrapping_sub(backend.base()) as u64),
+            pages: pages
+                .iter()
+                .map(|access| PageRecord {
+                    page: access.page.get(),
+                    perms: access.perms.bits(),
+                    count: 1,
+                })
+                .collect(),
+            stack_page: backend.stack_page().map(RelativePage::get),
+            pam,
+        };
+        self.writer
+            .write(&record)
+            .map_err(|error| ProfilerError::Output {
+                path: self.path.clone(),
+                error,
+            })?;
+        self.step += 1;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), ProfilerError> {
+        self.writer.flush().map_err(|error| ProfilerError::Output {
+            path: self.path.clone(),
+            error,
+        })
+    }
+}
+
+/// TLBlur simulator, created by a [`SimulatorBuilder`]
+pub struct Simulator<B, S = RSet> {
+    dumper: VCDDumper<S>,
+    pam_dumper: Option<VCDDumper<S>>,
+    hwtlb_dumper: Option<VCDDumper<S>>,
+    /// Dumper of all accessed pages, whether the attacker observes them or not
+    truth_dumper: Option<VCDDumper<S>>,
+    /// Recorder of the interrupt schedule, if requested
+    schedule: Option<ScheduleRecorder>,
+    backend: B,
+    pam: PAM,
+    attacker: Attacker,
+    hw_tlb: HardwareTLB,
+    flush_policy: FlushPolicy,
+    pte_observations: PageTableObservations,
+    noise: NoiseModel,
+    aex_notify: Option<AexNotify>,
+    rng: StdRng,
+    write_erip: bool,
+    prefetch: bool,
+    warmup: bool,
+    /// Pages left out of the trace of the attacker
+    hidden_pages: Vec<Range<usize>>,
+    /// Prefetch regions, with symbols resolved to ranges
+    prefetch_regions: Vec<PrefetchRegion>,
+    prefetcher: Box<dyn Prefetcher + Send + Sync>,
+    target: Option<TargetRange>,
+    /// Pages of which the accessed cache lines are recorded
+    cache_line_pages: Vec<Range<usize>>,
+    /// Address of the instruction executed in the current step,
+    /// only read if cache lines are recorded
+    last_erip: usize,
+    stats: SimulationStats,
+    /// Pages accessed while not in the PAM, and the file to write them to
+    pam_coverage: Option<(PathBuf, PamCoverage)>,
+    /// Distinct sets of observed pages, and the file to write them to
+    working_sets: Option<(PathBuf, WorkingSets)>,
+    /// Knowledge of the attacker over time, and the file to write it to
+    information_gain: Option<(PathBuf, InformationGain)>,
+    /// Live counters, if requested
+    metrics: Option<Arc<Metrics>>,
+    first_run: bool,
+}
+
+#[cfg(feature = "sgx")]
+impl<S: TracePageSet + Send + Sync + 'static> Simulator<HardwareBackend, S> {
+    /// Install the simulator as trap handler and run the profiler with the given arguments,
+    /// returning the summary of the run.
+    ///
+    /// Only one simulator can be run per process, as the trap handler can only be
+    /// registered once.
+    pub fn run(
+        self,
+        lib: ProfilerLibrary<'_>,
+        args: &[impl AsRef<str>],
+    ) -> Result<SimulationSummary, ProfilerError> {
+        let enclave = unsafe { EnclaveRef::from_raw(self.backend.enclave().id()) };
+        // The simulator and the first error of a step, which stops the simulation
+        let state = Arc::new(HandlerState::new((self, Ok(()))));
+        let handler_state = state.clone();
+        create_trap_handler(move || {
+            handler_state.with(|(simulator, result)| {
+                if result.is_err() {
+                    return;
+                }
+                *result = simulator.step();
+                if result.is_ok() && stop_requested() {
+                    // This was the last step, the enclave continues without single-stepping
+                    *result = simulator.flush();
+                }
+            });
+        })?;
+        run_profiler(lib, &enclave, args);
+        let (mut simulator, result) = state.take().ok_or(ProfilerError::HandlerBusy)?;
+        result?;
+        check_signal_handlers()?;
+        simulator.flush()?;
+        simulator.write_outputs()?;
+        Ok(simulator.summary())
+    }
+}
+
+impl<B: Backend, S: TracePageSet> Simulator<B, S> {
+    /// State of the simulated hardware TLB
+    pub fn hardware_tlb(&self) -> &HardwareTLB {
+        &self.hw_tlb
+    }
+
+    /// Totals of the steps simulated so far
+    pub fn summary(&self) -> SimulationSummary {
+        SimulationSummary {
+            pam_uncovered_pages: self
+                .pam_coverage
+                .as_ref()
+                .map(|(_, coverage)| coverage.uncovered_pages()),
+            information_gain: self.information_gain().map(InformationGain::total),
+            ..self.stats.summary(self.pam.updates())
+        }
+    }
+
+    /// Pages accessed so far while they were not in the PAM, if tracked
+    pub fn pam_coverage(&self) -> Option<&PamCoverage> {
+        self.pam_coverage.as_ref().map(|(_, coverage)| coverage)
+    }
+
+    /// Distinct sets of pages observed so far, if counted
+    pub fn working_sets(&self) -> Option<&WorkingSets> {
+        self.working_sets
+            .as_ref()
+            .map(|(_, working_sets)| working_sets)
+    }
+
+    /// Knowledge of the attacker so far, if tracked
+    pub fn information_gain(&self) -> Option<&InformationGain> {
+        self.information_gain.as_ref().map(|(_, gain)| gain)
+    }
+
+    /// Distinct pages the attacker observed so far
+    pub fn observed_pages(&self) -> &BTreeSet<usize> {
+        self.stats.observed_pages()
+    }
+
+    /// Page accesses the attacker can currently observe through the PTE A/D bits
+    pub fn observations(&self) -> &PageTableObservations {
+        &self.pte_observations
+    }
+
+    /// Write the statistics gathered so far to the files they were requested in,
+    /// e.g. with [`SimulatorBuilder::working_sets`]
+    pub fn write_outputs(&self) -> Result<(), ProfilerError> {
+        let output = |path: &Path, result: io::Result<()>| {
+            result.map_err(|error| ProfilerError::Output {
+                path: path.to_owned(),
+                error,
+            })
+        };
+        if let Some((path, coverage)) = &self.pam_coverage {
+            output(path, coverage.write_csv(path))?;
+        }
+        if let Some((path, working_sets)) = &self.working_sets {
+            output(path, working_sets.write(path))?;
+        }
+        if let Some((path, gain)) = &self.information_gain {
+            output(path, gain.write_csv(path))?;
+        }
+        Ok(())
+    }
+
+    /// Flush the steps simulated so far to the VCD outputs and the schedule.
+    ///
+    /// Returns `ProfilerError::Output` with the path of the output that failed.
+    pub fn flush(&mut self) -> Result<(), ProfilerError> {
+        for dumper in [
+            Some(&mut self.dumper),
+            self.pam_dumper.as_mut(),
+            self.hwtlb_dumper.as_mut(),
+            self.truth_dumper.as_mut(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            dumper.flush().map_err(|error| ProfilerError::Output {
+                path: dumper.path().to_owned(),
+                error,
+            })?;
+        }
+        if let Some(schedule) = &mut self.schedule {
+            schedule.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Simulate a single step of enclave execution.
+    ///
+    /// When running on hardware, this is called on every single-step interrupt.
+    /// The first step only primes the backend and records nothing.
+    ///
+    /// Returns `ProfilerError::PamRead` if the PAM can not be read from the enclave.
+    pub fn step(&mut self) -> Result<(), ProfilerError> {
+        self.backend.next_step();
+
+        // The executed instruction is the one the previous step was interrupted at
+        let erip = if self.cache_line_pages.is_empty() {
+            0
+        } else {
+            std::mem::replace(&mut self.last_erip, self.backend.erip())
+        };
+
+        // Update the local PAM to match the one in the instrumented enclave
+        self.pam.update_pam(&mut self.backend)?;
+
+        // Need to "prime" the page table on the first interrupt
+        // to get accurate measurements.
+        if self.first_run {
+            self.first_run = false;
+            if self.warmup {
+                self.warm_up()?;
+            }
+            self.record_schedule(false)?;
+            self.backend.clear_accesses();
+            return Ok(());
+        }
+
+        if let Some(target) = &mut self.target {
+            match target.step(self.backend.erip()) {
+                TargetStep::Record => {}
+                TargetStep::Enter => {
+                    debug!("entered target range at erip {:#x}", self.backend.erip());
+                    debug!("PAM at target entry: {:?}", self.pam.snapshot());
+                    self.hw_tlb.flush();
+                    self.pte_observations.clear();
+                    self.record_schedule(false)?;
+                    self.backend.clear_accesses();
+                    return Ok(());
+                }
+                TargetStep::Skip => {
+                    // The accesses are left to the step that enters the target range
+                    self.record_schedule(false)?;
+                    return Ok(());
+                }
+            }
+        }
+
+        let write_erip = self.write_erip.then(|| self.backend.erip());
+
+        if let Some(d) = self.pam_dumper.as_mut() {
+            d.next_step(|entry| {
+                if let Some(rip) = write_erip {
+                    entry.write_erip_at(rip);
+                }
+
+                entry.write_page_accesses(self.pam.get_pam());
+            })
+        }
+
+        if let Some(d) = self.hwtlb_dumper.as_mut() {
+            d.next_step(|entry| {
+                if let Some(rip) = write_erip {
+                    entry.write_erip_at(rip);
+                }
+
+                entry.write_page_accesses(self.hw_tlb.iter());
+            })
+        }
+
+        // Check which pages were accessed
+        self.backend.update_page_accesses();
+        self.record_schedule(true)?;
+        let accessed = self.backend.accessed_pages();
+        let accessed = if self.cache_line_pages.is_empty() {
+            Cow::Borrowed(accessed)
+        } else {
+            Cow::Owned(self.cache_line_accesses(accessed, erip))
+        };
+
+        if let Some(d) = self.truth_dumper.as_mut() {
+            d.next_step(|entry| {
+                if let Some(rip) = write_erip {
+                    entry.write_erip_at(rip);
+                }
+
+                entry.write_page_accesses(accessed.iter());
+            })
+        }
+
+        // This is the effect on the real page table, which we simulate,
+        // because the real page table is used to trace page accesses of each instruction
+        let hw_tlb = &self.hw_tlb;
+        self.pte_observations
+            .update(accessed.iter().filter(|p| !hw_tlb.test(p)));
+
+        let can_observe = self.attacker.can_observe();
+        let can_trigger_interrupt = self.attacker.can_trigger_interrupt(&accessed, &self.hw_tlb);
+        trace!(
+            "{} pages accessed, interrupt: {can_trigger_interrupt}",
+            accessed.len()
+        );
+        let tlb_hits = accessed.iter().filter(|p| hw_tlb.test(p)).count();
+        self.stats
+            .record_step(&accessed, tlb_hits, can_trigger_interrupt);
+        if let Some(metrics) = &self.metrics {
+            metrics.record_step(accessed.len(), Some(self.backend.erip()));
+            metrics.record_tlb(tlb_hits, can_trigger_interrupt);
+            let pam_pages = self.pam.get_pam().filter(|p| p.page.get() != 0).count();
+            metrics.record_pam(pam_pages, self.pam.updates());
+        }
+        if let Some((_, coverage)) = &mut self.pam_coverage {
+            coverage.record(accessed.iter(), self.pam.get_pam());
+        }
+
+        // Only write observations to the VCD trace if the attacker can observe
+        if can_observe == CanObserve::Always
+            || can_trigger_interrupt && can_observe == CanObserve::Interrupt
+        {
+            // Write to VCD trace
+            self.dumper.next_step(|entry| {
+                if let Some(rip) = write_erip {
+                    entry.write_erip_at(rip);
+                }
+
+                // An attacker can only observe accesses to pages not in the hardware TLB
+                let observed = self.attacker.observe(
+                    entry,
+                    &accessed,
+                    &self.hw_tlb,
+                    &mut self.pte_observations,
+                    &self.noise,
+                    &self.hidden_pages,
+                    &mut self.rng,
+                );
+                self.stats.record_observed_pages(&observed);
+                if let Some((_, working_sets)) = &mut self.working_sets {
+                    working_sets.record(observed.iter());
+                }
+                if let Some((_, gain)) = &mut self.information_gain {
+                    gain.record(observed.iter());
+                }
+            });
+        }
+
+        self.attacker.handle_step(&mut self.pte_observations);
+
+        if let Some(aex_notify) = &mut self.aex_notify {
+            aex_notify.record(&accessed);
+        }
+
+        // Simulate interrupt if attacker can trigger an interrupt now
+        if can_trigger_interrupt {
+            self.attacker
+                .handle_interrupt(&accessed, &mut self.pte_observations);
+
+            // Interrupt causes hardware TLB flush, depending on the policy
+            let flush = match self.flush_policy {
+                FlushPolicy::AlwaysOnInterrupt => true,
+                FlushPolicy::OnlyOnEresume => self.attacker.interrupts_with_exception(),
+                FlushPolicy::Never => false,
+            };
+            if flush {
+                self.hw_tlb.flush();
+            } else {
+                // The translations of this step survive the interrupt
+                self.hw_tlb.update(accessed.iter(), &mut self.rng);
+            }
+
+            // Resume to AEX handler
+            if self.prefetch {
+                self.prefetch_after_interrupt();
+            }
+            if let Some(aex_notify) = &self.aex_notify {
+                self.hw_tlb.update(aex_notify.restore(), &mut self.rng);
+                self.pte_observations.update(aex_notify.restore());
+            }
+        } else {
+            // We triggered a trap interrupt, but the attacker would not have interrupted...
+            // Now the real hardware TLB is flushed, nothing we can do about that now.
+            //
+            // Instead we simulate the hardware TLB.
+
+            // If the attacker doesn't interrupt, the hardware TLB would not be flushed,
+            // so we update it to take the accesses of the current instruction into account.
+            self.hw_tlb.update(accessed.iter(), &mut self.rng);
+        }
+
+        // Clear all A/D bits so we can accurately record page accesses
+        self.backend.clear_accesses();
+        Ok(())
+    }
+
+    /// Write the step to the schedule, if it is recorded, with the accessed pages if
+    /// they were read from the backend in this step.
+    fn record_schedule(&mut self, accessed: bool) -> Result<(), ProfilerError> {
+        match &mut self.schedule {
+            Some(schedule) => schedule.record(&mut self.backend, accessed, &self.pam.pam_buffer),
+            None => Ok(()),
+        }
+    }
+
+    /// Record the cache line of the instruction at `erip` in the accesses to
+    /// the pages of which cache lines are recorded, and drop the lines of others.
+    fn cache_line_accesses(&self, accessed: &[PageAccess], erip: usize) -> Vec<PageAccess> {
+        let code_page = self.backend.page_containing(erip);
+        accessed
+            .iter()
+            .map(|access| {
+                let page = access.page.get();
+                if !self.cache_line_pages.iter().any(|p| p.contains(&page)) {
+                    PageAccess {
+                        lines: 0,
+                        ..*access
+                    }
+                } else if Some(access.page) == code_page {
+                    access.with_line(erip)
+                } else {
+                    *access
+                }
+            })
+            .collect()
+    }
+
+    /// Simulate the pages that TLBlur prefetches when resuming from an interrupt
+    fn prefetch_after_interrupt(&mut self) {
+        // TLBlur prefetches pages from PAM, other prefetchers may choose differently
+        let pages = self
+            .prefetcher
+            .prefetch(&self.pam, self.backend.accessed_pages());
+        self.hw_tlb.update(pages.iter(), &mut self.rng);
+        self.pte_observations.update(pages.iter());
+
+        let pages = self.prefetch_region_pages();
+        self.hw_tlb.update(pages.iter(), &mut self.rng);
+        self.pte_observations.update(pages.iter());
+    }
+
+    /// Pages of the fixed prefetch regions at the current step
+    fn prefetch_region_pages(&self) -> Vec<PageAccess> {
+        let mut accesses = Vec::new();
+        for region in &self.prefetch_regions {
+            let pages = match &region.kind {
+                PrefetchKind::Stack { radius } => match self.backend.stack_page() {
+                    Some(page) => page.get().saturating_sub(*radius)..page.get() + radius + 1,
+                    None => continue,
+                },
+                PrefetchKind::Range(pages) => pages.clone(),
+                PrefetchKind::Symbol(_) => {
+                    unreachable!("symbols are resolved when building the simulator")
+                }
+            };
+            accesses.extend(pages.map(|page| region.access(RelativePage::new(page))));
+        }
+        accesses
+    }
+
+    /// Populate the hardware TLB and the PAM with the resident set expected at enclave entry,
+    /// so the first recorded step doesn't see every page as a miss.
+    ///
+    /// These pages are not observed by the attacker.
+    fn warm_up(&mut self) -> Result<(), ProfilerError> {
+        self.pam.warm_up(&mut self.backend)?;
+
+        let mut pages = self.prefetch_region_pages();
+        pages.extend(self.pam.get_pam().filter(|p| p.page.get() != 0));
+        if let Some(page) = self.backend.page_containing(self.backend.erip()) {
+            pages.push(PageAccess::new(page, Perms::READ | Perms::EXECUTE));
+        }
+        debug!("warming up hardware TLB with {} pages", pages.len());
+        self.hw_tlb.update(pages.iter(), &mut self.rng);
+        Ok(())
+    }
+}
+
+impl<S: TracePageSet> Simulator<MockBackend, S> {
+    /// Simulate the steps of the backend until its script is exhausted
+    pub fn replay(&mut self) -> Result<(), ProfilerError> {
+        while !self.backend.is_done() {
+            self.step()?;
+        }
+        Ok(())
+    }
+}
+
+/// Replay the steps of `backend` once with the simulator of every builder, and compare
+/// what their attackers observe of the same execution of the enclave.
+///
+/// Every builder should have an output of its own, as the simulators are run in turn.
+/// Their statistics are written to their outputs like those of a [`Simulator::run`].
+///
+/// ```
+/// use sgx_profiler::{
+///     attacker::Attacker,
+///     backend::{MockBackend, MockStep},
+///     simulator::{compare_attackers, TlblurLayout},
+///     sgx_step::page::RelativePage,
+///     PageAccess, Perms, SimulatorBuilder,
+/// };
+///
+/// let dir = tempfile::tempdir().unwrap();
+/// let access = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+/// let backend = MockBackend::new(
+///     64,
+///     [10, 10, 11, 10].map(|page| MockStep {
+///         accesses: vec![access(page)],
+///         ..Default::default()
+///     }),
+/// );
+/// let layout = TlblurLayout::mock(64);
+///
+/// let builders = ["single-step", "page-fault"].map(|name| {
+///     let trace = dir.path().join(format!("compare-{name}.vcd"));
+///     SimulatorBuilder::new(trace)
+///         .attacker(name.parse::<Attacker>().unwrap())
+///         .prefetch(false)
+/// });
+/// let comparison = compare_attackers(builders, &backend, layout).unwrap();
+/// let [single_step, page_fault] = &comparison.attackers[..] else {
+///     unreachable!()
+/// };
+/// assert_eq!(single_step.attacker, "single-step");
+/// assert_eq!(single_step.observations, 4);
+/// // Page 10 stays accessible after it faults, so its second access is not observed
+/// assert_eq!(page_fault.observations, 3);
+/// assert_eq!(comparison.intersection.iter().collect::<Vec<_>>(), [&10, &11]);
+/// ```
+pub fn compare_attackers<S: TracePageSet>(
+    builders: impl IntoIterator<Item = SimulatorBuilder<S>>,
+    backend: &MockBackend,
+    layout: TlblurLayout,
+) -> Result<AttackerComparison, ProfilerError> {
+    let mut comparison = AttackerComparison::new();
+    for builder in builders {
+        let mut simulator = builder.build_with_backend(backend.clone(), layout)?;
+        simulator.replay()?;
+        simulator.write_outputs()?;
+        comparison.add(
+            &simulator.attacker,
+            &simulator.summary(),
+            simulator.observed_pages(),
+        );
+    }
+    Ok(comparison)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        attacker::InterruptPattern, backend::MockStep, dump::TraceReader, golden::Trace,
+        tlb::ReplacementPolicy,
+    };
+    use clap::ValueEnum;
+    use rand::Rng;
+
+    /// Simulate the same random script with every source of randomness enabled, and
+    /// return the trace
+    fn simulate(seed: u64, trace: &Path) -> Vec<u8> {
+        let mut rng = StdRng::seed_from_u64(1298);
+        let script = (0..500)
+            .map(|_| MockStep {
+                accesses: (0..rng.gen_range(1..4))
+                    .map(|_| PageAccess::new(RelativePage::new(rng.gen_range(1..48)), Perms::READ))
+                    .collect(),
+                pam_updates: vec![RelativePage::new(rng.gen_range(1..48))],
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let mut simulator = SimulatorBuilder::new(trace)
+            .seed(seed)
+            .noise(NoiseModel {
+                drop_probability: 0.2,
+            })
+            .hardware_tlb(HardwareTLBConfig::SetAssociative {
+                num_sets: 4,
+                ways_per_set: 2,
+                replacement: ReplacementPolicy::Random,
+            })
+            .build_with_backend(MockBackend::new(64, script), TlblurLayout::mock(64))
+            .unwrap();
+        simulator.replay().unwrap();
+        drop(simulator);
+        std::fs::read(trace).unwrap()
+    }
+
+    #[test]
+    fn same_seed_gives_identical_trace() {
+        let dir = tempfile::tempdir().unwrap();
+        let trace = simulate(7, &dir.path().join("first.vcd"));
+        assert_eq!(trace, simulate(7, &dir.path().join("second.vcd")));
+        assert_ne!(trace, simulate(8, &dir.path().join("other-seed.vcd")));
+    }
+
+    #[test]
+    fn prefetch_regions_are_cached_after_an_interrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let access = |page, perms| PageAccess::new(RelativePage::new(page), perms);
+        let step = MockStep {
+            accesses: vec![access(10, Perms::READ)],
+            stack_page: Some(RelativePage::new(30)),
+            ..Default::default()
+        };
+        let backend = MockBackend::new(64, [step]).with_symbol("table", 50..52);
+        let regions = ["stack:1:rw", "range:40-42:r", "symbol:table:x"]
+            .map(|region| region.parse().unwrap())
+            .to_vec();
+        let mut simulator = SimulatorBuilder::new(dir.path().join("regions.vcd"))
+            .attacker(Attacker::SingleStep)
+            .prefetch_regions(regions)
+            .build_with_backend(backend, TlblurLayout::mock(64))
+            .unwrap();
+        simulator.step().unwrap();
+        simulator.step().unwrap();
+
+        let rw = Perms::READ | Perms::WRITE;
+        let cached = [
+            access(29, rw),
+            access(30, rw),
+            access(31, rw),
+            access(40, Perms::READ),
+            access(41, Perms::READ),
+            access(50, Perms::EXECUTE),
+            access(51, Perms::EXECUTE),
+        ];
+        for page in cached {
+            assert!(simulator.hardware_tlb().test(&page), "{page:?} not cached");
+            assert!(simulator.observations().iter().any(|p| p.covers(&page)));
+        }
+        for page in [28, 32, 39, 42, 49, 52] {
+            assert!(!simulator.hardware_tlb().test(&access(page, Perms::READ)));
+        }
+    }
+
+    #[test]
+    fn hw_prefetch_hides_sequential_accesses() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = (10..40).map(|page| MockStep {
+            accesses: vec![PageAccess::new(RelativePage::new(page), Perms::READ)],
+            ..Default::default()
+        });
+        let interrupts = |prefetch| {
+            let mut simulator = SimulatorBuilder::new(dir.path().join("hw-prefetch.vcd"))
+                .attacker(Attacker::SingleStep)
+                .flush_policy(FlushPolicy::Never)
+                .prefetch(false)
+                .hw_prefetch(prefetch)
+                .build_with_backend(MockBackend::new(64, script.clone()), TlblurLayout::mock(64))
+                .unwrap();
+            simulator.replay().unwrap();
+            simulator.summary().interrupts
+        };
+
+        // Every page misses, unless it was prefetched along with an earlier one
+        assert_eq!(interrupts(AdjacentPrefetch::default()), 30);
+        assert_eq!(
+            interrupts(AdjacentPrefetch {
+                window: 1,
+                stride: 1
+            }),
+            1
+        );
+        // Hits prefetch too, so pages 12 and 13 prefetch every other page after them
+        assert_eq!(
+            interrupts(AdjacentPrefetch {
+                window: 1,
+                stride: 2
+            }),
+            2
+        );
+    }
+
+    #[test]
+    fn hidden_instrumentation_pages_are_never_traced() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = TlblurLayout::mock(64);
+        let hidden = TLBLUR_SYMBOLS
+            .iter()
+            .flat_map(|name| layout.symbol_pages(name).unwrap())
+            .map(|page| format!("trace._{page}"))
+            .collect::<Vec<_>>();
+        assert_eq!(hidden, ["trace._60", "trace._61", "trace._62"]);
+
+        let mut rng = StdRng::seed_from_u64(1360);
+        let script = (0..300)
+            .map(|_| MockStep {
+                accesses: (0..rng.gen_range(1..4))
+                    .map(|_| PageAccess::new(RelativePage::new(rng.gen_range(1..64)), Perms::READ))
+                    .collect(),
+                pam_updates: vec![RelativePage::new(rng.gen_range(1..60))],
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        // Changes of the other page wires, and the number of accesses to hidden pages
+        let traced = |attacker: &Attacker, hide| {
+            let path = dir.path().join(format!("{attacker}-{hide}.vcd"));
+            let mut simulator = SimulatorBuilder::new(&path)
+                .attacker(attacker.clone())
+                .hide_instrumentation(hide)
+                .build_with_backend(MockBackend::new(64, script.clone()), layout)
+                .unwrap();
+            // The instrumentation is still prefetched into the TLB after every interrupt
+            let counter = PageAccess::new(RelativePage::new(61), Perms::READ | Perms::WRITE);
+            for _ in 0..=script.len() {
+                let interrupts = simulator.summary().interrupts;
+                simulator.step().unwrap();
+                if simulator.summary().interrupts > interrupts {
+                    assert!(simulator.hardware_tlb().test(&counter));
+                }
+            }
+            drop(simulator);
+
+            let mut hidden_accesses = 0;
+            let mut changes = Trace::read(&path).unwrap().changes;
+            for wires in changes.values_mut() {
+                wires.retain(|(wire, value)| {
+                    let is_hidden = hidden.contains(wire);
+                    hidden_accesses += usize::from(is_hidden && value == "1");
+                    wire.starts_with("trace._") && !is_hidden
+                });
+            }
+            changes.retain(|_, wires| !wires.is_empty());
+            (changes, hidden_accesses)
+        };
+
+        for attacker in [
+            Attacker::SingleStep,
+            Attacker::from(InterruptPattern::PageFault),
+            Attacker::Stealthy {
+                clear_interval: 3,
+                steps: 0,
+            },
+        ] {
+            let (visible, hidden_accesses) = traced(&attacker, false);
+            assert!(
+                hidden_accesses > 0,
+                "{attacker} did not observe the instrumentation"
+            );
+            let (hiding, hidden_accesses) = traced(&attacker, true);
+            assert_eq!(
+                hidden_accesses, 0,
+                "{attacker} observed the instrumentation"
+            );
+            // Other pages are traced as before
+            assert_eq!(hiding, visible, "{attacker}");
+        }
+    }
+
+    #[test]
+    fn baseline_symbols_start_the_trace_accessed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("baseline.vcd");
+        let step = MockStep {
+            accesses: vec![PageAccess::new(RelativePage::new(10), Perms::READ)],
+            ..Default::default()
+        };
+        let backend = MockBackend::new(64, [step]).with_symbol("table", 20..22);
+        {
+            let mut simulator = SimulatorBuilder::new(&path)
+                .attacker(Attacker::DebugSingleStep)
+                .baseline_symbols(vec!["table".into()])
+                .build_with_backend(backend, TlblurLayout::mock(64))
+                .unwrap();
+            simulator.replay().unwrap();
+        }
+
+        let trace = Trace::read(&path).unwrap();
+        let high =
+            |ts, page: usize| trace.changes[&ts].contains(&(format!("trace._{page}"), "1".into()));
+        assert!(high(0, 20) && high(0, 21) && !high(0, 10));
+        // The steps follow the baseline
+        assert!(trace.changes.keys().any(|&ts| ts > 0 && high(ts, 10)));
+
+        let result = SimulatorBuilder::new(dir.path().join("missing.vcd"))
+            .baseline_symbols(vec!["missing".into()])
+            .build_with_backend(MockBackend::new(64, []), TlblurLayout::mock(64));
+        assert!(matches!(
+            result,
+            Err(ProfilerError::SymbolNotFound(name)) if name == "missing"
+        ));
+    }
+
+    #[test]
+    fn unknown_prefetch_symbol_fails_the_build() {
+        let dir = tempfile::tempdir().unwrap();
+        let result = SimulatorBuilder::new(dir.path().join("missing.vcd"))
+            .prefetch_regions(vec!["symbol:missing:r".parse().unwrap()])
+            .build_with_backend(MockBackend::new(64, []), TlblurLayout::mock(64));
+        assert!(matches!(
+            result,
+            Err(ProfilerError::SymbolNotFound(name)) if name == "missing"
+        ));
+    }
+
+    #[test]
+    fn information_gain_is_written_for_the_observed_pages() {
+        let mut rng = StdRng::seed_from_u64(1380);
+        let script = (0..200)
+            .map(|_| MockStep {
+                accesses: (0..rng.gen_range(1..4))
+                    .map(|_| PageAccess::new(RelativePage::new(rng.gen_range(1..48)), Perms::READ))
+                    .collect(),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let dir = tempfile::tempdir().unwrap();
+        let csv = dir.path().join("infogain.csv");
+        let simulate = |bucket_steps| {
+            let mut simulator = SimulatorBuilder::new(dir.path().join("infogain.vcd"))
+                .attacker(Attacker::SingleStep)
+                .prefetch(false)
+                .information_gain(&csv, bucket_steps)
+                .build_with_backend(MockBackend::new(64, script.clone()), TlblurLayout::mock(64))
+                .unwrap();
+            simulator.replay().unwrap();
+            simulator.write_outputs().unwrap();
+            simulator
+        };
+
+        // With a single bucket, the gain is the number of distinct observed pages
+        let simulator = simulate(1000);
+        let observed_pages = simulator.observed_pages().len() as u64;
+        assert!(observed_pages > 1);
+        assert_eq!(simulator.summary().information_gain, Some(observed_pages));
+
+        let simulator = simulate(10);
+        let series = simulator.information_gain().unwrap().series();
+        assert_eq!(
+            simulator.summary().information_gain,
+            series.last().map(|p| p.total)
+        );
+        let rows = std::fs::read_to_string(&csv).unwrap();
+        let rows = rows.lines().collect::<Vec<_>>();
+        assert_eq!(rows[0], "step,new,total");
+        assert_eq!(rows.len(), series.len() + 1);
+        for (row, point) in rows[1..].iter().zip(&series) {
+            assert_eq!(
+                *row,
+                format!("{},{},{}", point.step, point.new, point.total)
+            );
+        }
+    }
+
+    #[test]
+    fn aex_notify_reduces_single_step_observations() {
+        let dir = tempfile::tempdir().unwrap();
+        // A loop over four pages
+        let script = (0..40)
+            .map(|i| MockStep {
+                accesses: vec![PageAccess::new(RelativePage::new(10 + i % 4), Perms::READ)],
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let observations = |aex_notify: Option<AexNotify>| {
+            let mut builder = SimulatorBuilder::new(dir.path().join("aex-notify.vcd"))
+                .attacker(Attacker::SingleStep)
+                .prefetch(false);
+            if let Some(aex_notify) = aex_notify {
+                builder = builder.aex_notify(aex_notify);
+            }
+            let mut simulator = builder
+                .build_with_backend(MockBackend::new(64, script.clone()), TlblurLayout::mock(64))
+                .unwrap();
+            simulator.replay().unwrap();
+            simulator.summary().observations
+        };
+
+        let unprotected = observations(None);
+        assert!(unprotected >= 38, "{unprotected} observations");
+        assert_eq!(observations(Some(AexNotify::new(0))), unprotected);
+        // The next page of the loop is always evicted from a smaller working set
+        assert_eq!(observations(Some(AexNotify::new(3))), unprotected);
+        // Once the whole loop is restored, only the first accesses miss
+        let full = observations(Some(AexNotify::new(4)));
+        assert!(full <= 4, "{full} observations");
+    }
+
+    #[test]
+    fn compared_attackers_match_their_own_runs() {
+        let mut rng = StdRng::seed_from_u64(1389);
+        let script = (0..300)
+            .map(|_| MockStep {
+                accesses: (0..rng.gen_range(1..4))
+                    .map(|_| PageAccess::new(RelativePage::new(rng.gen_range(1..48)), Perms::READ))
+                    .collect(),
+                pam_updates: vec![RelativePage::new(rng.gen_range(1..48))],
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let backend = MockBackend::new(64, script);
+        let dir = tempfile::tempdir().unwrap();
+        let patterns = InterruptPattern::value_variants();
+        let builder = |pattern: &InterruptPattern, name: &str| {
+            SimulatorBuilder::new(dir.path().join(format!("{name}-{pattern}.vcd")))
+                .attacker(pattern.to_string().parse::<Attacker>().unwrap())
+        };
+
+        let comparison = compare_attackers(
+            patterns.iter().map(|pattern| builder(pattern, "compare")),
+            &backend,
+            TlblurLayout::mock(64),
+        )
+        .unwrap();
+        assert_eq!(comparison.attackers.len(), patterns.len());
+        for (pattern, observed) in patterns.iter().zip(&comparison.attackers) {
+            let mut simulator = builder(pattern, "alone")
+                .build_with_backend(backend.clone(), TlblurLayout::mock(64))
+                .unwrap();
+            simulator.replay().unwrap();
+            assert_eq!(observed.attacker, pattern.to_string());
+            assert_eq!(observed.observations, simulator.summary().observations);
+            assert_eq!(&observed.pages, simulator.observed_pages());
+            assert!(observed.pages.is_subset(&comparison.union));
+            assert!(comparison.intersection.is_subset(&observed.pages));
+            drop(simulator);
+
+            // Every attacker writes the trace of its own run
+            let trace = |name: &str| {
+                std::fs::read(dir.path().join(format!("{name}-{pattern}.vcd"))).unwrap()
+            };
+            assert_eq!(trace("compare"), trace("alone"), "{pattern}");
+        }
+        assert!(!comparison.intersection.is_empty());
+    }
+
+    #[test]
+    fn coarse_page_faults_batch_the_accesses_of_a_period() {
+        let dir = tempfile::tempdir().unwrap();
+        // Every step accesses two pages that were not accessed before, so every step
+        // misses the hardware TLB
+        let steps = 24;
+        let pages = |step: usize| [1 + 2 * step, 2 + 2 * step];
+        let script = (0..steps)
+            .map(|step| MockStep {
+                accesses: pages(step)
+                    .map(|page| PageAccess::new(RelativePage::new(page), Perms::READ))
+                    .to_vec(),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+
+        for period in [1, 3, 5] {
+            let path = dir.path().join(format!("coarse-{period}.vcd"));
+            let summary = {
+                let mut simulator = SimulatorBuilder::new(&path)
+                    .attacker(Attacker::CoarsePageFault { period, steps: 0 })
+                    .prefetch(false)
+                    .build_with_backend(
+                        MockBackend::new(64, script.clone()),
+                        TlblurLayout::mock(64),
+                    )
+                    .unwrap();
+                simulator.replay().unwrap();
+                simulator.summary()
+            };
+            let faults = steps / period;
+            assert_eq!(summary.interrupts as usize, faults, "period {period}");
+            assert_eq!(summary.observations as usize, faults, "period {period}");
+
+            // Every observation holds the accesses of the steps since the last fault
+            let trace = Trace::read(&path).unwrap();
+            for fault in 0..faults {
+                let accessed = trace.changes[&(fault as u64)]
+                    .iter()
+                    .filter(|(name, value)| value == "1" && name.starts_with("trace._"))
+                    .map(|(name, _)| name["trace._".len()..].parse::<usize>().unwrap())
+                    .collect::<BTreeSet<_>>();
+                let batch = (fault * period..(fault + 1) * period)
+                    .flat_map(pages)
+                    .collect::<BTreeSet<_>>();
+                assert_eq!(accessed, batch, "fault {fault} with period {period}");
+            }
+        }
+    }
+
+    #[test]
+    fn replayed_schedules_reproduce_the_recorded_run() {
+        let mut rng = StdRng::seed_from_u64(1399);
+        let script = (0..400)
+            .map(|_| MockStep {
+                accesses: (0..rng.gen_range(0..4))
+                    .map(|_| PageAccess::new(RelativePage::new(rng.gen_range(1..48)), Perms::READ))
+                    .collect(),
+                pam_updates: (0..rng.gen_range(0..2))
+                    .map(|_| RelativePage::new(rng.gen_range(1..48)))
+                    .collect(),
+                stack_page: rng.gen_bool(0.9).then(|| RelativePage::new(50)),
+                erip: rng.gen_range(0x1000..0x3000),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let (recorded_base, replayed_base) = (0x40_0000, 0x7f00_0000);
+        let configs: [fn(SimulatorBuilder, usize) -> SimulatorBuilder; 3] = [
+            |builder, _| {
+                builder
+                    .seed(3)
+                    .noise(NoiseModel {
+                        drop_probability: 0.2,
+                    })
+                    .hardware_tlb(HardwareTLBConfig::SetAssociative {
+                        num_sets: 4,
+                        ways_per_set: 2,
+                        replacement: ReplacementPolicy::Random,
+                    })
+                    .pws_size(4)
+                    .warmup(true)
+            },
+            |builder, base| {
+                builder
+                    .attacker(InterruptPattern::PageFault.into())
+                    .target_range(Some(base + 0x1800..base + 0x2800))
+            },
+            |builder, _| {
+                builder
+                    .attacker(Attacker::Stealthy {
+                        clear_interval: 3,
+                        steps: 0,
+                    })
+                    .hide_instrumentation(true)
+                    .prefetch(false)
+            },
+        ];
+
+        let dir = tempfile::tempdir().unwrap();
+        for (i, config) in configs.iter().enumerate() {
+            let path = |name: &str| dir.path().join(format!("{name}-{i}"));
+            let schedule = path("schedule.trace");
+            let (recorded, recorded_pages) = {
+                let backend = MockBackend::new(64, script.clone()).with_base(recorded_base);
+                let mut simulator =
+                    config(SimulatorBuilder::new(path("recorded.vcd")), recorded_base)
+                        .record_schedule(&schedule)
+                        .build_with_backend(backend, TlblurLayout::mock(64))
+                        .unwrap();
+                simulator.replay().unwrap();
+                (simulator.summary(), simulator.observed_pages().clone())
+            };
+
+            let reader =
+                TraceReader::new(io::BufReader::new(File::open(&schedule).unwrap())).unwrap();
+            assert!(reader.header().schedule);
+            let (backend, num_pages) = MockBackend::from_records(reader).unwrap();
+            assert_eq!(num_pages, 64);
+            // The enclave is based elsewhere in the replay
+            let backend = backend.with_base(replayed_base);
+            let mut simulator = config(SimulatorBuilder::new(path("replayed.vcd")), replayed_base)
+                .build_with_backend(backend, TlblurLayout::mock(64))
+                .unwrap();
+            simulator.replay().unwrap();
+            assert_eq!(simulator.summary(), recorded, "config {i}");
+            assert_eq!(simulator.observed_pages(), &recorded_pages, "config {i}");
+            assert!(recorded.observations > 0, "config {i}");
+            drop(simulator);
+
+            assert_eq!(
+                Trace::read(path("replayed.vcd")).unwrap(),
+                Trace::read(path("recorded.vcd")).unwrap(),
+                "config {i}"
+            );
+        }
+    }
+}
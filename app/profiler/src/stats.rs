@@ -0,0 +1,984 @@
+//! Statistics gathered while profiling
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    error::Error,
+    fmt::{self, Display},
+    fs, io,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{bitmap::PageBitmap, PageAccess};
+
+/// Distribution of the number of distinct pages accessed per step
+#[derive(Debug, Clone, Default)]
+pub struct PageCountHistogram {
+    /// Number of steps, indexed by the number of pages accessed in the step
+    steps: Vec<u64>,
+}
+
+/// Summary of a [`PageCountHistogram`], as written to JSON
+#[derive(Debug, Clone, Serialize)]
+pub struct PageCountSummary {
+    pub steps: u64,
+    pub mean: f64,
+    pub median: usize,
+    pub max: usize,
+    /// Number of steps per page count, for every page count that occurred
+    pub histogram: BTreeMap<usize, u64>,
+}
+
+impl PageCountHistogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a step that accessed `pages` distinct pages
+    pub fn record(&mut self, pages: usize) {
+        if self.steps.len() <= pages {
+            self.steps.resize(pages + 1, 0);
+        }
+        self.steps[pages] += 1;
+    }
+
+    pub fn summary(&self) -> PageCountSummary {
+        let total = self.steps.iter().sum::<u64>();
+        let weighted = self
+            .steps
+            .iter()
+            .enumerate()
+            .map(|(pages, &n)| pages as u64 * n)
+            .sum::<u64>();
+
+        // The median is the first page count at which half of the steps are reached
+        let mut cumulative = 0;
+        let median = self
+            .steps
+            .iter()
+            .position(|&n| {
+                cumulative += n;
+                cumulative * 2 >= total
+            })
+            .unwrap_or(0);
+
+        PageCountSummary {
+            steps: total,
+            mean: if total == 0 {
+                0.
+            } else {
+                weighted as f64 / total as f64
+            },
+            median,
+            max: self.steps.iter().rposition(|&n| n > 0).unwrap_or(0),
+            histogram: self
+                .steps
+                .iter()
+                .enumerate()
+                .filter(|(_, &n)| n > 0)
+                .map(|(pages, &n)| (pages, n))
+                .collect(),
+        }
+    }
+
+    /// Write the distribution to `path`, as CSV if it has a `.csv` extension
+    /// and as JSON including the summary otherwise.
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        let path = path.as_ref();
+        let summary = self.summary();
+        let contents = if path.extension().is_some_and(|e| e == "csv") {
+            std::iter::once("pages,steps".to_owned())
+                .chain(
+                    summary
+                        .histogram
+                        .iter()
+                        .map(|(pages, n)| format!("{pages},{n}")),
+                )
+                .collect::<Vec<_>>()
+                .join("\n")
+                + "\n"
+        } else {
+            serde_json::to_string_pretty(&summary)?
+        };
+        fs::write(path, contents)?;
+        Ok(())
+    }
+}
+
+/// First and last step at which every page was accessed
+///
+/// Steps are numbered from 0, as the timestamps of a VCD trace with step timestamps.
+///
+/// ```
+/// use sgx_profiler::{sgx_step::page::RelativePage, stats::PageLiveness, PageAccess, Perms};
+///
+/// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+/// let mut liveness = PageLiveness::new();
+/// liveness.record([read(1), read(2)].iter());
+/// liveness.record([].iter());
+/// liveness.record([read(1)].iter());
+/// assert_eq!(liveness.get(1), Some((0, 2)));
+/// assert_eq!(liveness.get(2), Some((0, 0)));
+/// assert_eq!(liveness.get(3), None);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PageLiveness {
+    /// First and last step, by page
+    pages: BTreeMap<usize, (u64, u64)>,
+    /// Number of recorded steps
+    steps: u64,
+}
+
+impl PageLiveness {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the pages accessed in the next step
+    pub fn record<'a>(&mut self, pages: impl Iterator<Item = &'a PageAccess>) {
+        for access in pages {
+            self.pages
+                .entry(access.page.get())
+                .and_modify(|(_, last)| *last = self.steps)
+                .or_insert((self.steps, self.steps));
+        }
+        self.steps += 1;
+    }
+
+    /// First and last step at which `page` was accessed, if it was accessed
+    pub fn get(&self, page: usize) -> Option<(u64, u64)> {
+        self.pages.get(&page).copied()
+    }
+
+    /// Write a `page,first,last` CSV row for every accessed page to `path`
+    pub fn write_csv(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let rows = self
+            .pages
+            .iter()
+            .map(|(page, (first, last))| format!("{page},{first},{last}\n"));
+        fs::write(
+            path,
+            std::iter::once("page,first,last\n".to_owned())
+                .chain(rows)
+                .collect::<String>(),
+        )
+    }
+}
+
+/// Accesses to pages that were never accessed before in the run
+///
+/// A page-fault attacker that revokes every page once only observes these cold
+/// accesses, so they give its view of a single-step trace, without the repeated
+/// accesses that only single-stepping reveals.
+///
+/// ```
+/// use sgx_profiler::{sgx_step::page::RelativePage, stats::FirstAccesses, PageAccess, Perms};
+///
+/// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+/// let mut first = FirstAccesses::new();
+/// let steps = [vec![read(1), read(2)], vec![read(2)], vec![read(3), read(1)], vec![read(1)]];
+/// let pages = steps
+///     .iter()
+///     .map(|step| first.record(step.iter()).iter().map(|p| p.page.get()).collect::<Vec<_>>())
+///     .collect::<Vec<_>>();
+/// // Every page is a first access exactly once
+/// assert_eq!(pages, [vec![1, 2], vec![], vec![3], vec![]]);
+/// assert_eq!(first.accessed().len(), 3);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct FirstAccesses {
+    /// Pages accessed so far
+    seen: PageBitmap,
+    /// First accesses of the last recorded step
+    step: Vec<PageAccess>,
+}
+
+impl FirstAccesses {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the pages accessed in the next step, and return the ones that are
+    /// accessed for the first time
+    pub fn record<'a>(&mut self, pages: impl Iterator<Item = &'a PageAccess>) -> &[PageAccess] {
+        self.step.clear();
+        for access in pages {
+            if !self.seen.contains(access.page.get()) {
+                self.seen.insert(access.page.get());
+                self.step.push(*access);
+            }
+        }
+        &self.step
+    }
+
+    /// All pages accessed so far
+    pub fn accessed(&self) -> &PageBitmap {
+        &self.seen
+    }
+}
+
+/// Number of steps in which every distinct set of pages was accessed
+///
+/// This is a compact fingerprint of the behavior of the enclave, when the order of
+/// the steps does not matter. The pages of a step are sorted before they are
+/// counted, so the order in which they were found does not matter either.
+///
+/// ```
+/// use sgx_profiler::{sgx_step::page::RelativePage, stats::WorkingSets, PageAccess, Perms};
+///
+/// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+/// let mut working_sets = WorkingSets::new();
+/// working_sets.record([read(1), read(2)].iter());
+/// working_sets.record([read(2), read(1), read(2)].iter());
+/// working_sets.record([read(3)].iter());
+/// assert_eq!(working_sets.get(&[2, 1]), 2);
+/// assert_eq!(working_sets.get(&[3]), 1);
+/// assert_eq!(working_sets.get(&[1]), 0);
+///
+/// let summary = working_sets.summary();
+/// assert_eq!((summary.steps, summary.distinct), (3, 2));
+/// // The most frequent set comes first
+/// assert_eq!(summary.sets[0].pages, [1, 2]);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct WorkingSets {
+    /// Number of steps, by sorted pages
+    sets: HashMap<Vec<usize>, u64>,
+    steps: u64,
+}
+
+/// Set of pages accessed in one or more steps, as written to JSON
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkingSet {
+    pub pages: Vec<usize>,
+    pub steps: u64,
+}
+
+/// Summary of [`WorkingSets`], as written to JSON
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkingSetSummary {
+    pub steps: u64,
+    /// Number of distinct sets
+    pub distinct: usize,
+    /// All sets, from the most to the least frequent
+    pub sets: Vec<WorkingSet>,
+}
+
+impl WorkingSets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the pages accessed in the next step
+    pub fn record<'a>(&mut self, pages: impl Iterator<Item = &'a PageAccess>) {
+        let mut pages = pages.map(|p| p.page.get()).collect::<Vec<_>>();
+        pages.sort_unstable();
+        pages.dedup();
+        *self.sets.entry(pages).or_default() += 1;
+        self.steps += 1;
+    }
+
+    /// Number of steps in which exactly `pages` were accessed, in any order
+    pub fn get(&self, pages: &[usize]) -> u64 {
+        let mut pages = pages.to_vec();
+        pages.sort_unstable();
+        pages.dedup();
+        self.sets.get(&pages).copied().unwrap_or(0)
+    }
+
+    pub fn summary(&self) -> WorkingSetSummary {
+        let mut sets = self
+            .sets
+            .iter()
+            .map(|(pages, &steps)| WorkingSet {
+                pages: pages.clone(),
+                steps,
+            })
+            .collect::<Vec<_>>();
+        sets.sort_by(|a, b| b.steps.cmp(&a.steps).then_with(|| a.pages.cmp(&b.pages)));
+        WorkingSetSummary {
+            steps: self.steps,
+            distinct: sets.len(),
+            sets,
+        }
+    }
+
+    /// Write the summary to `path` as JSON.
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(&self.summary())?)
+    }
+}
+
+/// Growth of what the attacker knows about the page accesses of the enclave
+///
+/// The observation steps are divided into buckets of `bucket_steps` steps, and the
+/// attacker learns a new (page, bucket) pair the first time it observes a page in a
+/// bucket. The cumulative number of pairs grows quickly for an attacker that sees most
+/// accesses, and stays flat if the defense hides them. Steps are the observation steps,
+/// i.e. the steps of the attacker trace.
+///
+/// ```
+/// use sgx_profiler::{
+///     sgx_step::page::RelativePage,
+///     stats::{InformationGain, InformationGainPoint},
+///     PageAccess, Perms,
+/// };
+///
+/// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+/// let mut gain = InformationGain::new(2);
+/// for pages in [vec![1, 2], vec![2], vec![2, 3], vec![]] {
+///     gain.record(pages.into_iter().map(read).collect::<Vec<_>>().iter());
+/// }
+/// gain.record([read(1)].iter());
+/// assert_eq!(gain.total(), 5);
+/// assert_eq!(
+///     gain.series(),
+///     [
+///         InformationGainPoint { step: 2, new: 2, total: 2 },
+///         InformationGainPoint { step: 4, new: 2, total: 4 },
+///         // The last bucket is partial
+///         InformationGainPoint { step: 5, new: 1, total: 5 },
+///     ]
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct InformationGain {
+    bucket_steps: u64,
+    steps: u64,
+    /// Pages observed in the current bucket
+    bucket_pages: HashSet<usize>,
+    /// Points of the completed buckets
+    series: Vec<InformationGainPoint>,
+}
+
+/// Knowledge of the attacker at the end of a bucket of an [`InformationGain`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct InformationGainPoint {
+    /// Number of observation steps up to the end of the bucket
+    pub step: u64,
+    /// Distinct pages observed in the bucket
+    pub new: u64,
+    /// Distinct (page, bucket) pairs observed up to the end of the bucket
+    pub total: u64,
+}
+
+impl InformationGain {
+    /// Count distinct pages per bucket of `bucket_steps` steps, at least one
+    pub fn new(bucket_steps: u64) -> Self {
+        Self {
+            bucket_steps: bucket_steps.max(1),
+            steps: 0,
+            bucket_pages: HashSet::new(),
+            series: Vec::new(),
+        }
+    }
+
+    /// Record the pages observed in the next step
+    pub fn record<'a>(&mut self, pages: impl Iterator<Item = &'a PageAccess>) {
+        self.bucket_pages.extend(pages.map(|p| p.page.get()));
+        self.steps += 1;
+        if self.steps.is_multiple_of(self.bucket_steps) {
+            self.series.push(self.current());
+            self.bucket_pages.clear();
+        }
+    }
+
+    /// Point of the bucket that is being filled
+    fn current(&self) -> InformationGainPoint {
+        let new = self.bucket_pages.len() as u64;
+        InformationGainPoint {
+            step: self.steps,
+            new,
+            total: self.series.last().map_or(0, |p| p.total) + new,
+        }
+    }
+
+    /// Distinct (page, bucket) pairs observed so far
+    pub fn total(&self) -> u64 {
+        self.current().total
+    }
+
+    /// Points at the end of every bucket, including the last partial one
+    pub fn series(&self) -> Vec<InformationGainPoint> {
+        let mut series = self.series.clone();
+        if self.steps > series.last().map_or(0, |p| p.step) {
+            series.push(self.current());
+        }
+        series
+    }
+
+    /// Write the series to `path` as CSV, with the columns `step,new,total`.
+    pub fn write_csv(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut csv = String::from("step,new,total\n");
+        for point in self.series() {
+            csv.push_str(&format!("{},{},{}\n", point.step, point.new, point.total));
+        }
+        fs::write(path, csv)
+    }
+}
+
+/// Pages that were accessed while they were not in the PAM
+///
+/// TLBlur prefetches the pages in the PAM after an interrupt, so every accessed page
+/// should be in it, and an access to a page that is not may leak to the attacker.
+/// Pages of the fixed prefetch regions are not in the PAM either.
+///
+/// Steps are numbered from 0, as in [`PageLiveness`].
+///
+/// ```
+/// use sgx_profiler::{sgx_step::page::RelativePage, stats::PamCoverage, PageAccess, Perms};
+///
+/// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+/// let mut coverage = PamCoverage::new();
+/// coverage.record([read(1), read(2)].iter(), [read(1)].iter());
+/// coverage.record([read(2), read(3)].iter(), [read(2), read(3)].iter());
+/// coverage.record([read(2)].iter(), [read(3)].iter());
+/// assert_eq!(coverage.uncovered_pages(), 1);
+/// assert_eq!(coverage.get(2), Some((2, 0)));
+/// assert_eq!(coverage.get(1), None);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PamCoverage {
+    /// Number of steps in which the page was uncovered and the first of them, by page
+    pages: BTreeMap<usize, (u64, u64)>,
+    /// Number of recorded steps
+    steps: u64,
+}
+
+impl PamCoverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the pages accessed in the next step and the pages in the PAM at that step
+    pub fn record<'a>(
+        &mut self,
+        accessed: impl Iterator<Item = &'a PageAccess>,
+        pam: impl Iterator<Item = &'a PageAccess>,
+    ) {
+        let pam = pam.map(|p| p.page.get()).collect::<BTreeSet<_>>();
+        for access in accessed.filter(|p| !pam.contains(&p.page.get())) {
+            self.pages
+                .entry(access.page.get())
+                .and_modify(|(steps, _)| *steps += 1)
+                .or_insert((1, self.steps));
+        }
+        self.steps += 1;
+    }
+
+    /// Number of distinct pages that were accessed while not in the PAM
+    pub fn uncovered_pages(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Number of steps in which `page` was accessed while not in the PAM, and the first
+    /// of them, if there are any
+    pub fn get(&self, page: usize) -> Option<(u64, u64)> {
+        self.pages.get(&page).copied()
+    }
+
+    /// Write a `page,steps,first` CSV row for every uncovered page to `path`
+    pub fn write_csv(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let rows = self
+            .pages
+            .iter()
+            .map(|(page, (steps, first))| format!("{page},{steps},{first}\n"));
+        fs::write(
+            path,
+            std::iter::once("page,steps,first\n".to_owned())
+                .chain(rows)
+                .collect::<String>(),
+        )
+    }
+}
+
+/// Totals of a simulator run, as written to JSON
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SimulationSummary {
+    /// Simulated steps, excluding the priming step and steps outside the target range
+    pub steps: u64,
+    /// Steps at which the attacker could interrupt the enclave
+    pub interrupts: u64,
+    /// Number of distinct pages accessed in any step
+    pub pages_accessed: usize,
+    /// Largest number of distinct pages accessed between two interrupts
+    pub max_working_set: usize,
+    /// Fraction of the page accesses that hit the hardware TLB, `None` without accesses
+    pub tlb_hit_rate: Option<f64>,
+    /// Number of times the instrumentation updated the PAM
+    pub pam_updates: u64,
+    /// Steps at which the attacker observed the page table, i.e. steps written to the trace
+    pub observations: u64,
+    /// Total number of page accesses observed by the attacker
+    pub observed_pages: u64,
+    /// Number of distinct pages accessed while not in the PAM, if tracked,
+    /// see [`PamCoverage`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pam_uncovered_pages: Option<usize>,
+    /// Distinct (page, bucket) pairs observed by the attacker, if tracked,
+    /// see [`InformationGain`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub information_gain: Option<u64>,
+}
+
+impl SimulationSummary {
+    /// Write the summary to `path` as JSON.
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Accumulates the [`SimulationSummary`] of a simulator run
+///
+/// ```
+/// use sgx_profiler::{sgx_step::page::RelativePage, stats::SimulationStats, PageAccess, Perms};
+///
+/// // A run without steps has no hit rate
+/// let summary = SimulationStats::new().summary(0);
+/// assert_eq!((summary.steps, summary.pages_accessed), (0, 0));
+/// assert_eq!(summary.tlb_hit_rate, None);
+///
+/// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+/// let mut stats = SimulationStats::new();
+/// stats.record_step(&[read(1), read(2)], 0, false);
+/// stats.record_step(&[read(2), read(3)], 1, true);
+/// stats.record_observation(3);
+/// stats.record_step(&[read(1)], 1, false);
+/// let summary = stats.summary(2);
+/// assert_eq!((summary.steps, summary.interrupts), (3, 1));
+/// assert_eq!((summary.pages_accessed, summary.max_working_set), (3, 3));
+/// assert_eq!(summary.tlb_hit_rate, Some(0.4));
+/// assert_eq!((summary.observations, summary.observed_pages), (1, 3));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SimulationStats {
+    steps: u64,
+    interrupts: u64,
+    pages: BTreeSet<usize>,
+    /// Pages accessed since the last interrupt
+    working_set: BTreeSet<usize>,
+    max_working_set: usize,
+    tlb_lookups: u64,
+    tlb_hits: u64,
+    observations: u64,
+    observed_pages: u64,
+    /// Distinct pages observed so far
+    observed: BTreeSet<usize>,
+}
+
+impl SimulationStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a step that accessed `accessed`, of which `tlb_hits` hit the hardware TLB,
+    /// and whether the attacker interrupted the enclave after it.
+    pub fn record_step(&mut self, accessed: &[PageAccess], tlb_hits: usize, interrupt: bool) {
+        self.steps += 1;
+        self.tlb_lookups += accessed.len() as u64;
+        self.tlb_hits += tlb_hits as u64;
+        for access in accessed {
+            self.pages.insert(access.page.get());
+            self.working_set.insert(access.page.get());
+        }
+        self.max_working_set = self.max_working_set.max(self.working_set.len());
+        if interrupt {
+            self.interrupts += 1;
+            self.working_set.clear();
+        }
+    }
+
+    /// Record that the attacker observed `pages` page accesses in the current step.
+    pub fn record_observation(&mut self, pages: usize) {
+        self.observations += 1;
+        self.observed_pages += pages as u64;
+    }
+
+    /// Record the pages the attacker observed in the current step, like
+    /// [`SimulationStats::record_observation`], and remember which they were.
+    pub fn record_observed_pages(&mut self, pages: &[PageAccess]) {
+        self.record_observation(pages.len());
+        self.observed.extend(pages.iter().map(|p| p.page.get()));
+    }
+
+    /// Distinct pages recorded by [`SimulationStats::record_observed_pages`]
+    pub fn observed_pages(&self) -> &BTreeSet<usize> {
+        &self.observed
+    }
+
+    /// Summary of the recorded steps, with the number of PAM updates counted by the [`PAM`](crate::pam::PAM)
+    pub fn summary(&self, pam_updates: u64) -> SimulationSummary {
+        SimulationSummary {
+            steps: self.steps,
+            interrupts: self.interrupts,
+            pages_accessed: self.pages.len(),
+            max_working_set: self.max_working_set,
+            tlb_hit_rate: (self.tlb_lookups > 0)
+                .then(|| self.tlb_hits as f64 / self.tlb_lookups as f64),
+            pam_updates,
+            observations: self.observations,
+            observed_pages: self.observed_pages,
+            pam_uncovered_pages: None,
+            information_gain: None,
+        }
+    }
+}
+
+/// Observations of one of the attackers of an [`AttackerComparison`]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AttackerObservations {
+    /// Name of the attacker, e.g. `single-step`
+    pub attacker: String,
+    /// Steps at which the attacker observed the page table
+    pub observations: u64,
+    /// Total number of page accesses observed
+    pub observed_pages: u64,
+    /// Distinct pages observed in any step
+    pub pages: BTreeSet<usize>,
+    /// Pages that none of the other attackers observed
+    pub exclusive: BTreeSet<usize>,
+}
+
+/// What several attackers observe of the same execution of an enclave,
+/// see [`compare_attackers`](crate::simulator::compare_attackers)
+///
+/// ```
+/// use std::collections::BTreeSet;
+/// use sgx_profiler::stats::{AttackerComparison, SimulationSummary};
+///
+/// let summary = |observations| SimulationSummary { observations, ..Default::default() };
+/// let mut comparison = AttackerComparison::new();
+/// comparison.add("single-step", &summary(5), &BTreeSet::from([1, 2, 3]));
+/// comparison.add("page-fault", &summary(2), &BTreeSet::from([2, 3, 4]));
+///
+/// assert_eq!(comparison.union, BTreeSet::from([1, 2, 3, 4]));
+/// assert_eq!(comparison.intersection, BTreeSet::from([2, 3]));
+/// assert_eq!(comparison.attackers[0].exclusive, BTreeSet::from([1]));
+/// assert_eq!(comparison.attackers[1].exclusive, BTreeSet::from([4]));
+/// assert_eq!(comparison.attackers[1].observations, 2);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct AttackerComparison {
+    pub attackers: Vec<AttackerObservations>,
+    /// Pages observed by any of the attackers
+    pub union: BTreeSet<usize>,
+    /// Pages observed by all of the attackers
+    pub intersection: BTreeSet<usize>,
+}
+
+impl AttackerComparison {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an attacker with the `summary` of its run, which observed the distinct `pages`.
+    pub fn add(
+        &mut self,
+        attacker: impl Display,
+        summary: &SimulationSummary,
+        pages: &BTreeSet<usize>,
+    ) {
+        self.intersection = match self.attackers.is_empty() {
+            true => pages.clone(),
+            false => self.intersection.intersection(pages).copied().collect(),
+        };
+        self.union.extend(pages);
+        self.attackers.push(AttackerObservations {
+            attacker: attacker.to_string(),
+            observations: summary.observations,
+            observed_pages: summary.observed_pages,
+            pages: pages.clone(),
+            exclusive: BTreeSet::new(),
+        });
+
+        for i in 0..self.attackers.len() {
+            let others = self
+                .attackers
+                .iter()
+                .enumerate()
+                .filter(|&(j, _)| j != i)
+                .flat_map(|(_, other)| &other.pages)
+                .copied()
+                .collect::<BTreeSet<_>>();
+            self.attackers[i].exclusive = &self.attackers[i].pages - &others;
+        }
+    }
+
+    /// Write the comparison to `path` as JSON.
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+impl Display for AttackerComparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for attacker in &self.attackers {
+            writeln!(
+                f,
+                "{}: {} observations, {} page accesses, {} distinct pages, {} only seen by it",
+                attacker.attacker,
+                attacker.observations,
+                attacker.observed_pages,
+                attacker.pages.len(),
+                attacker.exclusive.len(),
+            )?;
+        }
+        write!(
+            f,
+            "{} pages observed by any attacker, {} by all",
+            self.union.len(),
+            self.intersection.len()
+        )
+    }
+}
+
+/// Periodic report of the number of steps processed and the throughput of a run
+///
+/// The clock is only read every [`ProgressReport::CHECK_STEPS`] steps, to keep
+/// the overhead per step low.
+///
+/// ```
+/// use std::time::Duration;
+/// use sgx_profiler::stats::ProgressReport;
+///
+/// let mut report = ProgressReport::new(Duration::ZERO);
+/// let reports = (0..2 * ProgressReport::CHECK_STEPS)
+///     .filter_map(|_| report.step())
+///     .collect::<Vec<_>>();
+/// assert_eq!(reports.len(), 2);
+/// assert_eq!(reports[1].steps, 2 * ProgressReport::CHECK_STEPS);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ProgressReport {
+    interval: Duration,
+    steps: u64,
+    last_report: Instant,
+    last_steps: u64,
+}
+
+/// Progress of a run at the time of a report
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    /// Steps processed since the start of the run
+    pub steps: u64,
+    /// Steps per second since the previous report
+    pub steps_per_sec: f64,
+}
+
+impl Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} steps, {:.0} steps/s", self.steps, self.steps_per_sec)
+    }
+}
+
+impl ProgressReport {
+    /// Number of steps between two reads of the clock
+    pub const CHECK_STEPS: u64 = 1024;
+
+    /// Report at most once every `interval`
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            steps: 0,
+            last_report: Instant::now(),
+            last_steps: 0,
+        }
+    }
+
+    /// Count a step, returning the progress if a report is due.
+    pub fn step(&mut self) -> Option<Progress> {
+        self.steps += 1;
+        if !self.steps.is_multiple_of(Self::CHECK_STEPS) {
+            return None;
+        }
+        let elapsed = self.last_report.elapsed();
+        if elapsed < self.interval {
+            return None;
+        }
+
+        let progress = Progress {
+            steps: self.steps,
+            steps_per_sec: (self.steps - self.last_steps) as f64 / elapsed.as_secs_f64(),
+        };
+        self.last_report = Instant::now();
+        self.last_steps = self.steps;
+        Some(progress)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Perms;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use sgx_step::page::RelativePage;
+
+    fn read(page: usize) -> PageAccess {
+        PageAccess::new(RelativePage::new(page), Perms::READ)
+    }
+
+    #[test]
+    fn information_gain_counts_distinct_pages_per_bucket() {
+        let mut rng = StdRng::seed_from_u64(1380);
+        for bucket_steps in [1, 3, 7, 100] {
+            let mut gain = InformationGain::new(bucket_steps);
+            let mut pairs = HashSet::new();
+            let steps = rng.gen_range(1..60);
+            for step in 0..steps {
+                let pages = (0..rng.gen_range(0..5))
+                    .map(|_| read(rng.gen_range(1..16)))
+                    .collect::<Vec<_>>();
+                gain.record(pages.iter());
+                pairs.extend(pages.iter().map(|p| (p.page.get(), step / bucket_steps)));
+                assert_eq!(gain.total(), pairs.len() as u64);
+            }
+
+            let series = gain.series();
+            assert_eq!(series.len() as u64, steps.div_ceil(bucket_steps));
+            assert_eq!(series.last().unwrap().step, steps);
+            let mut total = 0;
+            for (bucket, point) in series.iter().enumerate() {
+                let bucket = bucket as u64;
+                assert_eq!(point.step, ((bucket + 1) * bucket_steps).min(steps));
+                let new = pairs.iter().filter(|&&(_, b)| b == bucket).count() as u64;
+                total += new;
+                assert_eq!((point.new, point.total), (new, total));
+            }
+        }
+    }
+
+    #[test]
+    fn information_gain_buckets_hold_at_least_one_step() {
+        let mut gain = InformationGain::new(0);
+        gain.record([read(1)].iter());
+        gain.record([read(1)].iter());
+        assert_eq!(gain.total(), 2);
+        assert_eq!(gain.series().len(), 2);
+        assert!(InformationGain::new(4).series().is_empty());
+    }
+
+    #[test]
+    fn information_gain_csv_has_a_row_per_bucket() {
+        let mut gain = InformationGain::new(2);
+        for pages in [
+            vec![read(1), read(2)],
+            vec![read(2)],
+            vec![],
+            vec![read(3)],
+            vec![read(1)],
+        ] {
+            gain.record(pages.iter());
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("infogain.csv");
+        gain.write_csv(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "step,new,total\n2,2,2\n4,1,3\n5,1,4\n"
+        );
+    }
+
+    #[test]
+    fn attacker_comparison_matches_set_operations() {
+        let mut rng = StdRng::seed_from_u64(1389);
+        let attackers = (0..4)
+            .map(|_| {
+                (0..32)
+                    .filter(|_| rng.gen_bool(0.5))
+                    .collect::<BTreeSet<usize>>()
+            })
+            .collect::<Vec<_>>();
+        let mut comparison = AttackerComparison::new();
+        for (i, pages) in attackers.iter().enumerate() {
+            let summary = SimulationSummary {
+                observations: i as u64,
+                observed_pages: 2 * pages.len() as u64,
+                ..Default::default()
+            };
+            comparison.add(format!("attacker-{i}"), &summary, pages);
+
+            let added = &attackers[..=i];
+            let union = added.iter().flatten().copied().collect::<BTreeSet<_>>();
+            let intersection = union
+                .iter()
+                .filter(|page| added.iter().all(|pages| pages.contains(page)))
+                .copied()
+                .collect::<BTreeSet<_>>();
+            assert_eq!(comparison.union, union);
+            assert_eq!(comparison.intersection, intersection);
+            for (j, observed) in comparison.attackers.iter().enumerate() {
+                let exclusive = attackers[j]
+                    .iter()
+                    .filter(|page| (0..=i).all(|k| k == j || !attackers[k].contains(page)))
+                    .copied()
+                    .collect::<BTreeSet<_>>();
+                assert_eq!(observed.exclusive, exclusive, "attacker {j} of {}", i + 1);
+                assert_eq!(observed.attacker, format!("attacker-{j}"));
+                assert_eq!(observed.observations, j as u64);
+                assert_eq!(observed.pages, attackers[j]);
+            }
+        }
+    }
+
+    #[test]
+    fn attacker_comparison_is_written_as_json() {
+        let mut comparison = AttackerComparison::new();
+        let summary = SimulationSummary {
+            observations: 3,
+            observed_pages: 4,
+            ..Default::default()
+        };
+        comparison.add("single-step", &summary, &BTreeSet::from([1, 2]));
+        comparison.add(
+            "page-fault",
+            &SimulationSummary::default(),
+            &BTreeSet::from([2]),
+        );
+        assert_eq!(
+            comparison.to_string(),
+            "single-step: 3 observations, 4 page accesses, 2 distinct pages, 1 only seen by it\n\
+             page-fault: 0 observations, 0 page accesses, 1 distinct pages, 0 only seen by it\n\
+             2 pages observed by any attacker, 1 by all"
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("compare.json");
+        comparison.write(&path).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json["union"], serde_json::json!([1, 2]));
+        assert_eq!(json["intersection"], serde_json::json!([2]));
+        assert_eq!(json["attackers"][0]["exclusive"], serde_json::json!([1]));
+        assert_eq!(json["attackers"][1]["attacker"], "page-fault");
+    }
+
+    #[test]
+    fn first_accesses_are_recorded_once_per_page() {
+        let mut rng = StdRng::seed_from_u64(1396);
+        let mut first = FirstAccesses::new();
+        let mut seen = BTreeSet::new();
+        for _ in 0..200 {
+            let step = (0..rng.gen_range(0..6))
+                .map(|_| {
+                    let perms = [Perms::READ, Perms::WRITE, Perms::EXECUTE][rng.gen_range(0..3)];
+                    PageAccess::new(RelativePage::new(rng.gen_range(0..64)), perms)
+                })
+                .collect::<Vec<_>>();
+
+            // The first access to every new page, in the order of the step
+            let mut expected = Vec::new();
+            for access in &step {
+                if seen.insert(access.page.get()) {
+                    expected.push(*access);
+                }
+            }
+            assert_eq!(first.record(step.iter()), expected);
+            assert_eq!(first.accessed().len(), seen.len());
+        }
+        assert!(seen.len() > 32);
+    }
+}
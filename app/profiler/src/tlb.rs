@@ -0,0 +1,647 @@
+//! Simulated hardware TLB
+
+use std::collections::{HashMap, VecDeque};
+
+use clap::ValueEnum;
+use rand::{rngs::StdRng, Rng};
+use serde::{Deserialize, Serialize};
+
+use sgx_step::page::RelativePage;
+
+use crate::{simulator::DeterministicState, PageAccess};
+
+#[derive(Debug, Clone)]
+pub struct TLBEntry {
+    page: PageAccess,
+    valid: bool,
+}
+
+/// Replacement policy used when inserting into a full TLB set
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReplacementPolicy {
+    /// Evict the least recently used entry
+    #[default]
+    Lru,
+    /// Evict a uniformly random entry
+    Random,
+}
+
+/// When the simulated hardware TLB is flushed on an interrupt by the attacker
+///
+/// An enclave's address translations may not outlive an enclave exit, but the SGX
+/// architecture leaves some freedom in when they are dropped. The policies bracket
+/// the possible behaviors, the stricter ones being more favorable to the attacker.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FlushPolicy {
+    /// Flush on every interrupt, as every AEX flushes the TLB entries of the
+    /// enclave. This is the behavior TLBlur relies on.
+    #[default]
+    AlwaysOnInterrupt,
+    /// Only flush on interrupts raised by the enclave itself, i.e. page faults and
+    /// debug traps, after which ERESUME re-executes the interrupted instruction with
+    /// freshly walked translations. Asynchronous interrupts, such as the timer
+    /// interrupts of a single-stepping attacker, leave the TLB in place.
+    OnlyOnEresume,
+    /// Never flush, as if the translations of the enclave survived every interrupt
+    Never,
+}
+
+/// Set of a TLB, holding up to `capacity` entries
+///
+/// ```
+/// use rand::{rngs::StdRng, SeedableRng};
+/// use sgx_profiler::{
+///     sgx_step::page::RelativePage,
+///     tlb::{ReplacementPolicy, Set},
+///     PageAccess, Perms,
+/// };
+///
+/// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+/// let mut rng = StdRng::seed_from_u64(0);
+///
+/// let mut set = Set::new(2, ReplacementPolicy::Lru);
+/// assert!(set.is_empty());
+/// set.insert(read(1), &mut rng);
+/// set.insert(read(2), &mut rng);
+/// assert_eq!((set.len(), set.capacity()), (2, 2));
+///
+/// // Inserting into a full set evicts an entry
+/// set.insert(read(3), &mut rng);
+/// assert_eq!(set.len(), 2);
+/// let pages = set.valid_entries().map(|p| p.page.get()).collect::<Vec<_>>();
+/// assert_eq!(pages, [2, 3]);
+///
+/// // Invalidated entries keep their way until they are evicted
+/// set.invalidate(&read(2));
+/// assert_eq!(set.len(), 2);
+/// assert_eq!(set.valid_entries().count(), 1);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Set {
+    ways: VecDeque<TLBEntry>,
+    capacity: usize,
+    replacement: ReplacementPolicy,
+}
+
+impl Set {
+    pub fn new(capacity: usize, replacement: ReplacementPolicy) -> Self {
+        Set {
+            ways: VecDeque::with_capacity(capacity),
+            capacity,
+            replacement,
+        }
+    }
+
+    /// Number of occupied ways, including invalidated entries
+    pub fn len(&self) -> usize {
+        self.ways.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ways.is_empty()
+    }
+
+    /// Number of ways
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Entries that are valid, from least to most recently used
+    pub fn valid_entries(&self) -> impl Iterator<Item = &PageAccess> {
+        self.ways
+            .iter()
+            .filter(|entry| entry.valid)
+            .map(|entry| &entry.page)
+    }
+
+    pub fn lookup(&self, page: &PageAccess) -> bool {
+        for entry in &self.ways {
+            if entry.page.covers(page) && entry.valid {
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn insert(&mut self, page: PageAccess, rng: &mut StdRng) {
+        // Check if the page is already in the set
+        if let Some(pos) = self
+            .ways
+            .iter()
+            .position(|entry| entry.page.page == page.page && entry.valid)
+        {
+            // Move the found entry to the back (most recently used), refilling it
+            // with the permissions of this access if it lacks them
+            let mut entry = self.ways.remove(pos).unwrap();
+            entry.page = entry.page.union(&page);
+            self.ways.push_back(entry);
+        } else {
+            // Insert new entry, evicting an entry if necessary
+            if self.ways.len() == self.capacity {
+                match self.replacement {
+                    ReplacementPolicy::Lru => {
+                        self.ways.pop_front(); // Evict the least recently used (LRU) entry
+                    }
+                    ReplacementPolicy::Random => {
+                        let victim = rng.gen_range(0..self.ways.len());
+                        self.ways.remove(victim);
+                    }
+                }
+            }
+            self.ways.push_back(TLBEntry { page, valid: true });
+        }
+    }
+
+    pub fn invalidate(&mut self, page: &PageAccess) {
+        for entry in &mut self.ways {
+            if entry.page.covers(page) {
+                entry.valid = false;
+            }
+        }
+    }
+}
+
+/// Geometry of the simulated hardware TLB
+///
+/// An entry only covers the permissions it was filled with. A write to a page that
+/// is cached for reading misses, as the processor walks the page table again to set
+/// the dirty bit, after which the entry covers both.
+///
+/// ```
+/// use rand::{rngs::StdRng, SeedableRng};
+/// use sgx_profiler::{
+///     sgx_step::page::RelativePage,
+///     tlb::{HardwareTLB, HardwareTLBConfig, ReplacementPolicy},
+///     PageAccess, Perms,
+/// };
+///
+/// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+/// let mut rng = StdRng::seed_from_u64(0);
+///
+/// // Without a capacity, no page is ever evicted
+/// let mut tlb = HardwareTLB::from(HardwareTLBConfig::Perfect { capacity: None });
+/// tlb.update((0..1000).map(read).collect::<Vec<_>>().iter(), &mut rng);
+/// assert!((0..1000).all(|page| tlb.test(&read(page))));
+///
+/// // With a capacity, the least recently used page is evicted
+/// let mut tlb = HardwareTLB::from(HardwareTLBConfig::Perfect { capacity: Some(2) });
+/// tlb.update([read(1), read(2), read(1), read(3)].iter(), &mut rng);
+/// assert!(tlb.test(&read(1)) && tlb.test(&read(3)));
+/// assert!(!tlb.test(&read(2)));
+/// assert_eq!(tlb.iter().count(), 2);
+///
+/// // A write misses an entry filled by a read, and then refills it
+/// let write = PageAccess::new(RelativePage::new(1), Perms::READ | Perms::WRITE);
+/// for config in [
+///     HardwareTLBConfig::Perfect { capacity: None },
+///     HardwareTLBConfig::Perfect { capacity: Some(1) },
+/// ] {
+///     let mut tlb = HardwareTLB::from(config);
+///     tlb.update([read(1)].iter(), &mut rng);
+///     assert!(tlb.test(&read(1)) && !tlb.test(&write));
+///     tlb.update([write].iter(), &mut rng);
+///     assert!(tlb.test(&read(1)) && tlb.test(&write));
+///     assert_eq!(tlb.iter().count(), 1);
+/// }
+///
+/// // Configs are tagged with their kind, named like the policies on the command line
+/// let config = HardwareTLBConfig::SetAssociative {
+///     num_sets: 4,
+///     ways_per_set: 2,
+///     replacement: ReplacementPolicy::Random,
+/// };
+/// let json = serde_json::to_string(&config).unwrap();
+/// assert_eq!(
+///     json,
+///     r#"{"type":"set-associative","num_sets":4,"ways_per_set":2,"replacement":"random"}"#
+/// );
+/// assert_eq!(serde_json::from_str::<HardwareTLBConfig>(&json).unwrap(), config);
+/// let config = HardwareTLBConfig::Perfect { capacity: Some(64) };
+/// let toml = toml::to_string(&config).unwrap();
+/// assert_eq!(toml::from_str::<HardwareTLBConfig>(&toml).unwrap(), config);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum HardwareTLBConfig {
+    /// Fully-associative TLB, of unbounded size unless a `capacity` is given.
+    /// A bounded TLB evicts the least recently used entry when full.
+    Perfect { capacity: Option<usize> },
+    SetAssociative {
+        num_sets: usize,
+        ways_per_set: usize,
+        replacement: ReplacementPolicy,
+    },
+}
+
+/// Prefetching of the translations of the neighbors of an accessed page by the hardware
+///
+/// Some processors fill the TLB with the translations of adjacent pages along with the
+/// accessed one. This is independent of the prefetching of TLBlur, and keeps more pages
+/// resident. Prefetched entries get the permissions of the access that caused them.
+///
+/// ```
+/// use rand::{rngs::StdRng, SeedableRng};
+/// use sgx_profiler::{
+///     sgx_step::page::RelativePage,
+///     tlb::{AdjacentPrefetch, HardwareTLB, HardwareTLBConfig},
+///     PageAccess, Perms,
+/// };
+///
+/// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+/// let mut rng = StdRng::seed_from_u64(0);
+/// let config = HardwareTLBConfig::Perfect { capacity: None };
+///
+/// let mut tlb = HardwareTLB::from(config);
+/// tlb.update([read(10)].iter(), &mut rng);
+/// assert!(!tlb.test(&read(9)) && !tlb.test(&read(11)));
+///
+/// let mut tlb = HardwareTLB::from(config).with_prefetch(AdjacentPrefetch { window: 1, stride: 1 });
+/// tlb.update([read(10)].iter(), &mut rng);
+/// assert!(tlb.test(&read(9)) && tlb.test(&read(10)) && tlb.test(&read(11)));
+/// assert!(!tlb.test(&read(12)));
+///
+/// let prefetch = AdjacentPrefetch { window: 2, stride: 2 };
+/// assert_eq!(prefetch.neighbors(3).collect::<Vec<_>>(), [1, 5, 7]);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AdjacentPrefetch {
+    /// Number of neighbors prefetched on each side of a page, none if zero
+    pub window: usize,
+    /// Distance in pages between the prefetched neighbors
+    pub stride: usize,
+}
+
+impl AdjacentPrefetch {
+    /// Pages prefetched along with `page`, excluding `page` itself
+    pub fn neighbors(&self, page: usize) -> impl Iterator<Item = usize> {
+        let stride = self.stride;
+        let below = (1..=self.window)
+            .rev()
+            .filter_map(move |n| page.checked_sub(n * stride));
+        let above = (1..=self.window).map(move |n| page + n * stride);
+        below.chain(above).filter(move |&neighbor| neighbor != page)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HardwareTLB {
+    entries: TLBEntries,
+    prefetch: AdjacentPrefetch,
+}
+
+#[derive(Debug, Clone)]
+enum TLBEntries {
+    Perfect(HashMap<RelativePage, PageAccess, DeterministicState>),
+    /// Perfect TLB with a bounded capacity, which is a single set with LRU replacement
+    BoundedPerfect(Set),
+    SetAssociative {
+        sets: Vec<Set>,
+        num_sets: usize,
+    },
+}
+
+impl HardwareTLB {
+    /// Also insert the neighbors of every inserted page, see [`AdjacentPrefetch`]
+    pub fn with_prefetch(mut self, prefetch: AdjacentPrefetch) -> Self {
+        self.prefetch = prefetch;
+        self
+    }
+
+    pub fn flush(&mut self) {
+        match &mut self.entries {
+            TLBEntries::Perfect(ref mut pages) => pages.clear(),
+            TLBEntries::BoundedPerfect(set) => set.ways.clear(),
+            TLBEntries::SetAssociative { sets, .. } => {
+                for set in sets {
+                    set.ways.clear();
+                }
+            }
+        }
+    }
+
+    /// Insert the translations of `pages`, preceded by those of their neighbors if
+    /// prefetching is enabled, so the accessed page is the most recently used.
+    pub fn update<'a>(&mut self, pages: impl Iterator<Item = &'a PageAccess>, rng: &mut StdRng) {
+        for page in pages {
+            for neighbor in self.prefetch.neighbors(page.page.get()) {
+                self.insert(
+                    PageAccess {
+                        page: RelativePage::new(neighbor),
+                        ..*page
+                    },
+                    rng,
+                );
+            }
+            self.insert(page.to_owned(), rng);
+        }
+    }
+
+    fn insert(&mut self, page: PageAccess, rng: &mut StdRng) {
+        match &mut self.entries {
+            TLBEntries::Perfect(ref mut tlb) => {
+                // "perfect" fully-associative hardware TLB with infinite size
+                tlb.entry(page.page)
+                    .and_modify(|entry| *entry = entry.union(&page))
+                    .or_insert(page);
+            }
+            TLBEntries::BoundedPerfect(set) => set.insert(page, rng),
+            TLBEntries::SetAssociative { sets, num_sets } => {
+                let set_index = Self::get_set_index(&page, *num_sets);
+                sets[set_index].insert(page, rng);
+            }
+        }
+    }
+
+    pub fn test(&self, page: &PageAccess) -> bool {
+        match &self.entries {
+            TLBEntries::Perfect(pages) => pages.get(&page.page).is_some_and(|p| p.covers(page)),
+            TLBEntries::BoundedPerfect(set) => set.lookup(page),
+            TLBEntries::SetAssociative { sets, num_sets } => {
+                let set_index = Self::get_set_index(page, *num_sets);
+                sets[set_index].lookup(page)
+            }
+        }
+    }
+
+    /// Use for debugging purposes only
+    pub fn iter(&self) -> Box<dyn Iterator<Item = &PageAccess> + '_> {
+        match &self.entries {
+            TLBEntries::Perfect(pages) => Box::new(pages.values()),
+            TLBEntries::BoundedPerfect(set) => Box::new(set.valid_entries()),
+            TLBEntries::SetAssociative { sets, .. } => {
+                Box::new(sets.iter().flat_map(|set| set.valid_entries()))
+            }
+        }
+    }
+
+    /// Number of valid entries across all sets
+    ///
+    /// ```
+    /// use rand::{rngs::StdRng, SeedableRng};
+    /// use sgx_profiler::{
+    ///     sgx_step::page::RelativePage,
+    ///     tlb::{HardwareTLB, HardwareTLBConfig, ReplacementPolicy},
+    ///     PageAccess, Perms,
+    /// };
+    ///
+    /// let read = |page| PageAccess::new(RelativePage::new(page), Perms::READ);
+    /// let mut tlb = HardwareTLB::from(HardwareTLBConfig::SetAssociative {
+    ///     num_sets: 2,
+    ///     ways_per_set: 2,
+    ///     replacement: ReplacementPolicy::Lru,
+    /// });
+    /// // Pages 0, 2 and 4 map to the same set, which only holds two of them
+    /// let pages = [0, 1, 2, 4].map(read);
+    /// tlb.update(pages.iter(), &mut StdRng::seed_from_u64(0));
+    /// assert_eq!(tlb.entry_count(), 3);
+    /// assert!(!tlb.test(&read(0)));
+    /// ```
+    pub fn entry_count(&self) -> usize {
+        match &self.entries {
+            TLBEntries::Perfect(pages) => pages.len(),
+            TLBEntries::BoundedPerfect(set) => set.valid_entries().count(),
+            TLBEntries::SetAssociative { sets, .. } => {
+                sets.iter().map(|set| set.valid_entries().count()).sum()
+            }
+        }
+    }
+
+    fn get_set_index(page: &PageAccess, num_sets: usize) -> usize {
+        page.page.get() % num_sets
+    }
+}
+
+impl From<HardwareTLBConfig> for HardwareTLB {
+    fn from(value: HardwareTLBConfig) -> Self {
+        let entries = match value {
+            HardwareTLBConfig::Perfect { capacity: None } => {
+                TLBEntries::Perfect(HashMap::default())
+            }
+            HardwareTLBConfig::Perfect {
+                capacity: Some(capacity),
+            } => TLBEntries::BoundedPerfect(Set::new(capacity, ReplacementPolicy::Lru)),
+            HardwareTLBConfig::SetAssociative {
+                num_sets,
+                ways_per_set,
+                replacement,
+            } => TLBEntries::SetAssociative {
+                sets: (0..num_sets)
+                    .map(|_| Set::new(ways_per_set, replacement))
+                    .collect(),
+                num_sets,
+            },
+        };
+        Self {
+            entries,
+            prefetch: AdjacentPrefetch::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::Perms;
+
+    fn read(page: usize) -> PageAccess {
+        PageAccess::new(RelativePage::new(page), Perms::READ)
+    }
+
+    #[test]
+    fn bounded_perfect_tlb_keeps_the_most_recently_used_pages() {
+        let mut rng = StdRng::seed_from_u64(1340);
+        for capacity in [1, 4, 16] {
+            let mut tlb = HardwareTLB::from(HardwareTLBConfig::Perfect {
+                capacity: Some(capacity),
+            });
+            // Pages from least to most recently used
+            let mut lru = Vec::<usize>::new();
+            for _ in 0..2000 {
+                let page = rng.gen_range(0..32);
+                tlb.update([read(page)].iter(), &mut rng);
+                lru.retain(|&p| p != page);
+                lru.push(page);
+                if lru.len() > capacity {
+                    lru.remove(0);
+                }
+
+                assert_eq!(tlb.entry_count(), lru.len());
+                for p in 0..32 {
+                    assert_eq!(tlb.test(&read(p)), lru.contains(&p), "page {p}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn set_entries_are_ordered_by_use() {
+        let mut rng = StdRng::seed_from_u64(1352);
+        let mut set = Set::new(3, ReplacementPolicy::Lru);
+        let pages = |set: &Set| {
+            set.valid_entries()
+                .map(|p| p.page.get())
+                .collect::<Vec<_>>()
+        };
+        for page in [1, 2, 3, 1] {
+            set.insert(read(page), &mut rng);
+        }
+        assert_eq!(pages(&set), [2, 3, 1]);
+        set.insert(read(4), &mut rng);
+        assert_eq!(pages(&set), [3, 1, 4]);
+
+        // An invalidated entry takes up a way until it is evicted
+        set.invalidate(&read(1));
+        assert_eq!((set.len(), set.capacity()), (3, 3));
+        assert_eq!(pages(&set), [3, 4]);
+        // Re-inserting the page fills a new way instead of the invalidated one
+        set.insert(read(1), &mut rng);
+        assert_eq!(set.len(), 3);
+        assert_eq!(pages(&set), [4, 1]);
+        set.insert(read(5), &mut rng);
+        assert_eq!(pages(&set), [4, 1, 5]);
+    }
+
+    #[test]
+    fn random_replacement_stays_within_the_capacity() {
+        let mut rng = StdRng::seed_from_u64(1352);
+        let mut set = Set::new(4, ReplacementPolicy::Random);
+        assert!(set.is_empty());
+        for page in 0..100 {
+            set.insert(read(page), &mut rng);
+            assert_eq!(set.len(), (page + 1).min(4));
+            assert_eq!(set.valid_entries().count(), set.len());
+            // The inserted page is never the victim
+            assert!(set.lookup(&read(page)));
+        }
+    }
+
+    #[test]
+    fn entry_count_sums_the_valid_entries_of_all_sets() {
+        let mut rng = StdRng::seed_from_u64(1352);
+        let (num_sets, ways_per_set) = (4, 2);
+        let mut tlb = HardwareTLB::from(HardwareTLBConfig::SetAssociative {
+            num_sets,
+            ways_per_set,
+            replacement: ReplacementPolicy::Lru,
+        });
+        // Distinct pages per set, from least to most recently used
+        let mut sets = vec![Vec::new(); num_sets];
+        for _ in 0..500 {
+            let page = rng.gen_range(0..64);
+            tlb.update([read(page)].iter(), &mut rng);
+            let set: &mut Vec<usize> = &mut sets[page % num_sets];
+            set.retain(|&p| p != page);
+            set.push(page);
+            if set.len() > ways_per_set {
+                set.remove(0);
+            }
+
+            assert_eq!(tlb.entry_count(), sets.iter().map(Vec::len).sum::<usize>());
+            assert_eq!(tlb.iter().count(), tlb.entry_count());
+        }
+        tlb.flush();
+        assert_eq!(tlb.entry_count(), 0);
+    }
+
+    #[test]
+    fn adjacent_prefetch_neighbors() {
+        let neighbors = |window, stride, page| {
+            AdjacentPrefetch { window, stride }
+                .neighbors(page)
+                .collect::<Vec<_>>()
+        };
+        assert!(neighbors(0, 1, 10).is_empty());
+        assert_eq!(neighbors(1, 1, 10), [9, 11]);
+        assert_eq!(neighbors(3, 4, 20), [8, 12, 16, 24, 28, 32]);
+        // Neighbors below the first page are dropped, and a page is not its own neighbor
+        assert_eq!(neighbors(2, 1, 1), [0, 2, 3]);
+        assert!(neighbors(2, 0, 5).is_empty());
+    }
+
+    #[test]
+    fn prefetched_neighbors_hit_with_the_permissions_of_the_access() {
+        let mut rng = StdRng::seed_from_u64(1369);
+        let rw = |page| PageAccess::new(RelativePage::new(page), Perms::READ | Perms::WRITE);
+        for config in [
+            HardwareTLBConfig::Perfect { capacity: None },
+            HardwareTLBConfig::Perfect { capacity: Some(8) },
+            HardwareTLBConfig::SetAssociative {
+                num_sets: 4,
+                ways_per_set: 2,
+                replacement: ReplacementPolicy::Lru,
+            },
+        ] {
+            let mut tlb = HardwareTLB::from(config).with_prefetch(AdjacentPrefetch {
+                window: 1,
+                stride: 2,
+            });
+            tlb.update([rw(10)].iter(), &mut rng);
+            for page in [8, 10, 12] {
+                assert!(tlb.test(&rw(page)), "{config:?}: {page} missed");
+            }
+            for page in [9, 11, 6, 14] {
+                assert!(!tlb.test(&read(page)), "{config:?}: {page} hit");
+            }
+            assert_eq!(tlb.entry_count(), 3);
+        }
+    }
+
+    #[test]
+    fn accessed_page_outlives_its_prefetched_neighbors() {
+        let mut rng = StdRng::seed_from_u64(1369);
+        let mut tlb = HardwareTLB::from(HardwareTLBConfig::Perfect { capacity: Some(2) })
+            .with_prefetch(AdjacentPrefetch {
+                window: 1,
+                stride: 1,
+            });
+        tlb.update([read(10)].iter(), &mut rng);
+        // Page 9 was inserted first, and evicted by the accessed page
+        assert!(tlb.test(&read(10)) && tlb.test(&read(11)));
+        assert!(!tlb.test(&read(9)));
+    }
+
+    #[test]
+    fn unbounded_perfect_tlb_never_evicts() {
+        let mut rng = StdRng::seed_from_u64(1340);
+        let mut unbounded = HardwareTLB::from(HardwareTLBConfig::Perfect { capacity: None });
+        // A capacity that is never reached behaves the same
+        let mut large = HardwareTLB::from(HardwareTLBConfig::Perfect {
+            capacity: Some(4096),
+        });
+        let mut seen = Vec::new();
+        for _ in 0..2000 {
+            let page = rng.gen_range(0..4096);
+            for tlb in [&mut unbounded, &mut large] {
+                tlb.update([read(page)].iter(), &mut rng);
+            }
+            if !seen.contains(&page) {
+                seen.push(page);
+            }
+        }
+
+        for tlb in [&unbounded, &large] {
+            assert_eq!(tlb.entry_count(), seen.len());
+            assert!(seen.iter().all(|&page| tlb.test(&read(page))));
+        }
+    }
+
+    #[test]
+    fn flushed_perfect_tlbs_are_empty() {
+        let mut rng = StdRng::seed_from_u64(1340);
+        for capacity in [None, Some(2)] {
+            let mut tlb = HardwareTLB::from(HardwareTLBConfig::Perfect { capacity });
+            tlb.update([read(1), read(2)].iter(), &mut rng);
+            tlb.flush();
+            assert_eq!(tlb.entry_count(), 0);
+            assert!(!tlb.test(&read(1)) && !tlb.test(&read(2)));
+
+            // Flushed entries no longer take up the capacity
+            tlb.update([read(3), read(4)].iter(), &mut rng);
+            assert_eq!(tlb.entry_count(), 2);
+        }
+    }
+}
@@ -1,717 +1,1027 @@
+//! See [`sgx_profiler::simulator`] for a description of the simulator.
+
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
     error::Error,
-    ffi::c_void,
-    fmt::Display,
-    io::Read,
+    ffi::OsString,
+    fs::{self, File},
+    io::BufReader,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use clap::{Parser, ValueEnum};
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use log::info;
+use serde::{Deserialize, Serialize};
 use sgx_profiler::{
-    create_dumper, create_enclave, create_trap_handler,
-    dump::{RSet, VCDDumper, VCDEntry},
-    run_profiler,
-    sgx_step::memory::EnclaveMemory,
-    PageAccess, PageTable, ProfilerLibrary,
+    attacker::{AexNotify, Attacker, InterruptPattern, NoiseModel},
+    backend::MockBackend,
+    check, create_enclave_with_token, create_stop_handler,
+    dump::{
+        CountSet, LineSet, PageFilter, PageList, RWXSet, TimestampSource, TracePageSet, TraceReader,
+    },
+    init_logger, isolate_thread,
+    metrics::Metrics,
+    pam::EvictionPolicy,
+    prefetch::{MarkovPrefetcher, PamPrefetcher, PrefetchRegion},
+    resolve_app_path, resolve_symbol_range,
+    sgx_step::{page::RelativePage, EnclaveRef},
+    simulator::{compare_attackers, TlblurLayout, TLBLUR_SYMBOLS},
+    stats::SimulationSummary,
+    stop_requested,
+    tlb::{AdjacentPrefetch, FlushPolicy, HardwareTLBConfig, ReplacementPolicy},
+    ProfilerError, ProfilerLibrary, ScanCacheConfig, SimulatorBuilder,
 };
-use sgx_step::{sgx_step_sys::PAGE_SIZE_4KiB, EnclaveRef};
 
-pub struct PageTableObservations {
-    state: HashMap<usize, PageAccess>,
+#[derive(Debug, Clone, Copy, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HardwareTLBType {
+    Perfect,
+    SetAssociative,
 }
 
-impl PageTableObservations {
-    pub fn new() -> Self {
-        Self {
-            state: HashMap::new(),
-        }
-    }
+#[derive(Debug, Clone, Copy, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PrefetcherType {
+    /// Prefetch the pages in the PAM, as TLBlur does
+    Pam,
+    /// Prefetch the pages that followed the accessed pages in `--markov-trace`
+    Markov,
+}
 
-    pub fn clear(&mut self) {
-        self.state.clear()
-    }
+#[derive(Debug, Clone, Copy, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TraceSetType {
+    /// One wire per page, set on any access
+    Rset,
+    /// Separate read, write and execute wires per page
+    Rwxset,
+    /// Number of accesses per page in each step
+    Countset,
+    /// Cache lines accessed per page in each step, see `--cache-line-symbol`
+    Lineset,
+}
 
-    pub fn update<'a>(&mut self, pages: impl Iterator<Item = &'a PageAccess>) {
-        for page in pages {
-            self.state
-                .entry(page.page)
-                .and_modify(|e| *e = e.union(page))
-                .or_insert(page.to_owned());
-        }
-    }
+/// SGX tlblur simulator
+///
+/// Serialized with the fields named after the long arguments, like the keys of
+/// `--config` files.
+#[derive(Parser, Debug, Serialize, Deserialize)]
+#[command(author, version, about, long_about = None)]
+#[serde(rename_all = "kebab-case")]
+struct Args {
+    /// TOML file with default values for the other arguments, keyed by their long name.
+    /// Arguments given on the command line take precedence.
+    #[arg(long)]
+    #[serde(skip)]
+    config: Option<PathBuf>,
 
-    fn iter<'a>(&'a self) -> impl Iterator<Item = &'a PageAccess> {
-        self.state.values()
-    }
-}
+    /// A shared object that provides the profiler_setup and profiler_run functions
+    #[arg(long, required_unless_present = "replay")]
+    so: Option<String>,
 
-pub struct PAM {
-    pam_enclave_mem: EnclaveMemory,
-    pam_counter_enclave_mem: EnclaveMemory,
-    pam_buffer: Vec<u64>,
-    pam_active: Vec<PageAccess>,
-    pam_counter: u64,
-}
+    /// An SGX binary that will be created by the profiler
+    #[arg(short, long)]
+    enclave: String,
 
-impl PAM {
-    pub fn new(
-        pam_address: *const c_void,
-        pam_counter_address: *const c_void,
-        pam_size: usize,
-        pws_size: usize,
-    ) -> Self {
-        Self {
-            pam_enclave_mem: EnclaveMemory::new(pam_address as usize),
-            pam_counter_enclave_mem: EnclaveMemory::new(pam_counter_address as usize),
-            pam_buffer: vec![0; pam_size],
-            pam_active: vec![PageAccess::default(); pws_size],
-            pam_counter: 0,
-        }
-    }
+    /// Directory that relative `--enclave` and `--so` paths are resolved against,
+    /// `SGX_APP_DIR` by default, or else the working directory
+    #[arg(long)]
+    base_dir: Option<PathBuf>,
 
-    fn get_pam(&self) -> impl Iterator<Item = &PageAccess> {
-        self.pam_active.iter()
-    }
-
-    pub fn update_pam(&mut self) {
-        let old_counter = self.pam_counter;
-
-        // Read the new PAM counter from enclave memory
-        let mut buf: [u8; 8] = [0; 8];
-        self.pam_counter_enclave_mem.read(&mut buf).unwrap();
-        let new_counter = u64::from_le_bytes(buf);
-
-        // If the counter changed compared to previous step of execution,
-        // then our local view of the PAM must be updated to match the one in enclave memory.
-        //
-        // In contrast to the representation of the PAM in enclave memory,
-        // PAM stored by the profiler more closely aligns with a real TLB, as it
-        // only contains the N most recent pages.
-        //
-        // It should match the behavior of the PAM, but it should not try to mimic the real TLB.
-        //
-        // NOTE: an assumption is made that at the time the counter is incremented, the PAM is
-        // already updated as well. We use the PAM global counter as a way to signal the
-        // profiler of a PAM update, to avoid having to walk through the entire PAM each step.
-        // This requires the instrumentation to be written in a specific way.
-        if old_counter != new_counter {
-            // println!("counter: {}", new_counter);
-            // Read the PAM from enclave memory
-            self.pam_enclave_mem
-                .read(unsafe { std::mem::transmute(self.pam_buffer.as_mut_slice()) })
-                .unwrap();
-
-            let mut found = false;
-            for (page, &value) in self.pam_buffer.iter().enumerate() {
-                // Only update this entry in profiler PAM if it was recently updated.
-                if value >= new_counter - 1 && value > 0 {
-                    self.pam_counter = new_counter;
-                    // Only update if not already in profiler PAM
-                    found = true;
-                    if self
-                        .pam_active
-                        .iter()
-                        .find(|p| p.page == (page as usize))
-                        .is_none()
-                    {
-                        // println!("new entry in PAM: {}", page);
-                        // Find the least recently used entry to evict according
-                        // to the state of the PAM
-                        if let Some((index, _)) =
-                            self.pam_active.iter().enumerate().min_by_key(|&(_, &p)| {
-                                if p.page == 0 {
-                                    0
-                                } else {
-                                    self.pam_buffer[p.page]
-                                }
-                            })
-                        {
-                            // println!("replaced an entry");
-                            // Replace the entry
-                            self.pam_active[index].page = page;
-
-                            // The real prefetcher can't do this,
-                            // but we can in the profiler because we don't care about
-                            // the permissions of pages.
-                            //
-                            // The real prefetcher would instead use the maximum
-                            // allowed permissions, we should be equivalent.
-                            self.pam_active[index].read = true;
-                            self.pam_active[index].write = true;
-                            self.pam_active[index].execute = true;
-                        }
-                    } else {
-                        // println!("already in PAM");
-                    }
-                }
-            }
-            if !found && new_counter - old_counter > 1 {
-                // println!("Warning: PAM counter incremented, but new entry not found!");
-            }
-        }
-    }
-}
+    /// Load the launch token from this file if it exists, and save it there
+    /// if it was created or updated
+    #[arg(long)]
+    token_file: Option<PathBuf>,
+
+    /// Output VCD file
+    #[arg(short = 'o', long = "output")]
+    #[serde(rename = "output")]
+    trace_output: String,
 
-unsafe impl Sync for PAM {}
-unsafe impl Send for PAM {}
+    /// Also write the trace to this file in the record format, which holds the erip
+    /// (with `--erip`), the accessed pages and their permissions of every step
+    #[arg(long)]
+    records: Option<PathBuf>,
 
-#[derive(Debug, Clone)]
-pub struct TLBEntry {
-    page: PageAccess,
-    valid: bool,
-}
+    /// Record the interrupt schedule to this file: what the simulator read from the
+    /// enclave at every interrupt, which `--replay` simulates exactly like this run
+    #[arg(long, conflicts_with = "replay")]
+    record_schedule: Option<PathBuf>,
 
-#[derive(Debug, Clone)]
-pub struct Set {
-    ways: VecDeque<TLBEntry>,
-    capacity: usize,
-}
+    #[arg(long)]
+    debug_pam: Option<String>,
 
-impl Set {
-    pub fn new(capacity: usize) -> Self {
-        Set {
-            ways: VecDeque::with_capacity(capacity),
-            capacity,
-        }
-    }
+    #[arg(long)]
+    debug_sim_hwtlb: Option<String>,
 
-    pub fn lookup(&self, page: &PageAccess) -> bool {
-        for entry in &self.ways {
-            if entry.page.covers(page) && entry.valid {
-                return true;
-            }
-        }
-        false
-    }
+    /// Also write all pages accessed in every step to this VCD file, including those
+    /// the attacker does not observe, with a timestamp for every simulated step like
+    /// `--debug-pam` and `--debug-sim-hwtlb`
+    #[arg(long)]
+    ground_truth_output: Option<PathBuf>,
 
-    pub fn insert(&mut self, page: PageAccess) {
-        // Check if the page is already in the set
-        if let Some(pos) = self
-            .ways
-            .iter()
-            .position(|entry| entry.page.covers(&page) && entry.valid)
-        {
-            // Move the found entry to the back (most recently used)
-            let entry = self.ways.remove(pos).unwrap();
-            self.ways.push_back(entry);
-        } else {
-            // Insert new entry, evicting the least recently used if necessary
-            if self.ways.len() == self.capacity {
-                self.ways.pop_front(); // Evict the least recently used (LRU) entry
-            }
-            self.ways.push_back(TLBEntry { page, valid: true });
-        }
-    }
+    /// Write the totals of the run, such as the number of interrupts and the TLB
+    /// hit rate, to this JSON file
+    #[arg(long, conflicts_with = "replay")]
+    summary_json: Option<PathBuf>,
 
-    pub fn invalidate(&mut self, page: &PageAccess) {
-        for entry in &mut self.ways {
-            if entry.page.covers(page) {
-                entry.valid = false;
-            }
-        }
-    }
-}
+    /// Simulate the steps of this record trace of the enclave, e.g. written by
+    /// `sgx_tracer --records`, instead of running it. The enclave is only created to
+    /// locate the TLBlur instrumentation. The PAM is not recorded, so it stays empty,
+    /// unless the trace is a schedule written by `--record-schedule`.
+    #[arg(long)]
+    replay: Option<PathBuf>,
+
+    /// Simulate each of these attackers on the steps of `--replay`, rather than the one
+    /// of `--irq-pat`, and compare what they observe. The trace of each attacker is
+    /// written to the output with the attacker appended to its name.
+    #[arg(
+        long,
+        requires = "replay",
+        num_args = 1..,
+        value_delimiter = ',',
+        conflicts_with_all = [
+            "records", "debug_pam", "debug_sim_hwtlb", "ground_truth_output",
+            "pam_coverage_log", "working_sets", "infogain_csv",
+        ],
+    )]
+    compare: Vec<InterruptPattern>,
+
+    /// Write the observations of every attacker of `--compare`, and the pages observed
+    /// by any and by all of them, to this JSON file
+    #[arg(long, requires = "replay")]
+    compare_json: Option<PathBuf>,
+
+    /// Write the pages that were accessed while not in the PAM, which TLBlur may fail
+    /// to hide, to this CSV file with the number of steps and the first step they were
+    /// uncovered in
+    #[arg(long)]
+    pam_coverage_log: Option<PathBuf>,
 
-#[derive(Debug, Clone, Copy, ValueEnum)]
-pub enum HardwareTLBType {
-    Perfect,
-    SetAssociative,
-}
+    /// Write the distinct sets of pages the attacker observed in a step, with the
+    /// number of steps they were observed in, to this JSON file
+    #[arg(long)]
+    working_sets: Option<PathBuf>,
 
-#[derive(Debug, Clone, Copy)]
-pub enum HardwareTLBConfig {
-    Perfect,
-    SetAssociative {
-        num_sets: usize,
-        ways_per_set: usize,
-    },
-}
+    /// Write the number of distinct (page, bucket) pairs the attacker has observed
+    /// after every bucket of steps to this CSV file, to compare how fast attackers
+    /// learn the accesses of the enclave
+    #[arg(long)]
+    infogain_csv: Option<PathBuf>,
 
-#[derive(Debug, Clone)]
-pub enum HardwareTLB {
-    Perfect(HashSet<PageAccess>),
-    SetAssociative {
-        sets: Vec<Set>,
-        num_sets: usize,
-        ways_per_set: usize,
-    },
+    /// Number of observation steps per bucket of --infogain-csv
+    #[arg(long, default_value_t = 1000, requires = "infogain_csv")]
+    infogain_bucket: u64,
+
+    /// Arguments to pass to the profiler_run function
+    #[arg(long, value_parser, num_args = 1.., value_delimiter = ' ')]
+    args: Vec<String>,
+
+    /// Write erip to VCD output
+    #[arg(long = "erip")]
+    #[serde(rename = "erip")]
+    write_erip: bool,
+
+    /// Size of the software TLB to simulate
+    #[arg(long, default_value_t = 10)]
+    pws_size: usize,
+
+    #[arg(long = "irq-pat", short = 'p', default_value_t = InterruptPattern::SingleStep)]
+    #[serde(rename = "irq-pat")]
+    interrupt_pattern: InterruptPattern,
+
+    #[arg(long = "observe-ptes", default_value_t = true)]
+    observe_ptes: bool,
+
+    /// Number of steps the stealthy attacker waits before reading and clearing PTE bits
+    #[arg(long, default_value_t = 1)]
+    stealthy_interval: usize,
+
+    /// Number of steps the coarse page-fault attacker waits after a fault before it
+    /// unmaps the pages again
+    #[arg(long, default_value_t = 1)]
+    fault_period: usize,
+
+    #[arg(long = "hw-tlb")]
+    #[serde(rename = "hw-tlb")]
+    hardware_tlb: HardwareTLBType,
+
+    /// Number of entries of the perfect hardware TLB, which evicts the least recently
+    /// used entry when full. Unbounded if not given.
+    #[arg(long)]
+    tlb_capacity: Option<usize>,
+
+    #[arg(long = "sets", default_value_t = 4)]
+    #[serde(rename = "sets")]
+    num_sets: usize,
+
+    #[arg(long = "ways", default_value_t = 2)]
+    #[serde(rename = "ways")]
+    ways_per_set: usize,
+
+    /// Replacement policy of the set-associative hardware TLB
+    #[arg(long, default_value = "lru")]
+    replacement: ReplacementPolicy,
+
+    /// Model a hardware TLB that also fills the translations of the neighbors of an
+    /// accessed page, independently of the prefetching of TLBlur
+    #[arg(long)]
+    hw_prefetch: bool,
+
+    /// Number of neighbors on each side of a page prefetched with `--hw-prefetch`
+    #[arg(long, default_value_t = 1, requires = "hw_prefetch")]
+    hw_prefetch_window: usize,
+
+    /// Distance in pages between the neighbors prefetched with `--hw-prefetch`
+    #[arg(long, default_value_t = 1, requires = "hw_prefetch")]
+    hw_prefetch_stride: usize,
+
+    /// When the hardware TLB is flushed on an interrupt by the attacker
+    #[arg(long, default_value = "always-on-interrupt")]
+    flush_policy: FlushPolicy,
+
+    /// Replacement policy of the PAM active set, which should match the
+    /// TLBlur variant the enclave was instrumented with
+    #[arg(long, default_value = "lru")]
+    pam_eviction: EvictionPolicy,
+
+    #[arg(long)]
+    no_prefetch: bool,
+
+    /// Leave the PAM, its counter and the PAM update code out of the trace of the attacker,
+    /// while still simulating them in the hardware TLB
+    #[arg(long)]
+    hide_instrumentation: bool,
+
+    /// Fill the hardware TLB and the PAM with the expected resident set at enclave entry,
+    /// so the first recorded step doesn't see every page as a miss
+    #[arg(long)]
+    warmup: bool,
+
+    /// Regions prefetched after every interrupt, in addition to the PAM entries.
+    /// Each region is `stack:RADIUS:PERMS`, `symbol:NAME:PERMS` or `range:START-END:PERMS`.
+    /// Defaults to the regions prefetched by TLBlur.
+    #[arg(long = "prefetch-region", num_args = 1.., value_delimiter = ' ')]
+    #[serde(rename = "prefetch-region")]
+    prefetch_regions: Vec<PrefetchRegion>,
+
+    /// Symbols whose pages are shown as accessed at the start of the trace,
+    /// e.g. to tell always-resident pages apart from pages that are never accessed
+    #[arg(long = "baseline-symbol", num_args = 1.., value_delimiter = ' ')]
+    #[serde(rename = "baseline-symbol")]
+    baseline_symbols: Vec<String>,
+
+    /// Symbols of which the accessed cache lines are recorded, rather than only the pages.
+    /// Only the lines of executed instructions are known, data lines are not.
+    #[arg(long = "cache-line-symbol", num_args = 1.., value_delimiter = ' ')]
+    #[serde(rename = "cache-line-symbol")]
+    cache_line_symbols: Vec<String>,
+
+    #[arg(long, default_value = "pam")]
+    prefetcher: PrefetcherType,
+
+    /// VCD trace of a previous run, from which the Markov prefetcher learns transitions
+    #[arg(long, required_if_eq("prefetcher", "markov"))]
+    markov_trace: Option<PathBuf>,
+
+    /// Number of successors the Markov prefetcher prefetches per accessed page
+    #[arg(long, default_value_t = 1)]
+    markov_degree: usize,
+
+    /// Scan the page table using multiple threads
+    #[arg(long)]
+    parallel_scan: bool,
+
+    /// Pin the profiler thread, which also runs the enclave, to this CPU and raise it
+    /// to the real-time scheduling policy, to reduce scheduling noise. Only warns if
+    /// the policy can not be changed without `CAP_SYS_NICE`.
+    #[arg(long, conflicts_with = "parallel_scan")]
+    cpu: Option<usize>,
+
+    /// Only scan PTEs near recently accessed pages, with a full scan every N steps.
+    /// Other pages are only rescanned in the 2 MiB regions that were accessed.
+    #[arg(long)]
+    scan_cache_interval: Option<usize>,
+
+    /// Number of neighboring pages to scan around recently accessed pages
+    #[arg(long, default_value_t = 1)]
+    scan_cache_neighborhood: usize,
+
+    /// Only lock and scan the PTEs of the enclave segments, skipping the gaps between
+    /// them, which is faster for sparse enclaves
+    #[arg(long)]
+    segments_only: bool,
+
+    /// Seed for all randomness in the simulator
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Probability that the attacker misses an observed page access
+    #[arg(long, default_value_t = 0.)]
+    observation_noise: f64,
+
+    /// Simulate an enclave that uses AEX-Notify to restore its most recently accessed
+    /// pages after every interrupt, to compare TLBlur with and against AEX-Notify
+    #[arg(long)]
+    aex_notify: bool,
+
+    /// Number of most recently accessed pages restored with --aex-notify
+    #[arg(long, default_value_t = 2, requires = "aex_notify")]
+    aex_notify_working_set: usize,
+
+    /// Only simulate instructions within this function or object.
+    /// Its size is taken from the ELF symbol table.
+    #[arg(long)]
+    target_symbol: Option<String>,
+
+    /// Timestamps of the VCD output, either the step count or TSC cycles
+    #[arg(long, default_value = "steps")]
+    timestamp: TimestampSource,
+
+    /// Only record these pages in the VCD output, as a comma-separated list of
+    /// pages and START-END ranges, e.g. `100-200,300`. END is exclusive.
+    #[arg(long, conflicts_with = "exclude_pages")]
+    pages: Option<PageList>,
+
+    /// Don't record these pages in the VCD output, in the format of `--pages`
+    #[arg(long)]
+    exclude_pages: Option<PageList>,
+
+    /// Page set of the VCD outputs, `rwxset` keeps writes apart from reads
+    #[arg(long, default_value = "rset")]
+    trace_set: TraceSetType,
+
+    /// Only check that the enclave and profiler library can be used, without running them
+    #[arg(long)]
+    check: bool,
+
+    /// Serve the steps, interrupts, TLB hit rate, PAM size and erip of the run at
+    /// `http://ADDR/metrics` in the Prometheus text format, e.g. `127.0.0.1:9100`
+    #[arg(long, value_name = "ADDR", conflicts_with = "replay")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Only log warnings and errors
+    #[arg(short, long)]
+    quiet: bool,
 }
 
-impl HardwareTLB {
-    pub fn flush(&mut self) {
-        match self {
-            Self::Perfect(ref mut pages) => pages.clear(),
-            Self::SetAssociative { sets, .. } => {
-                for set in sets {
-                    set.ways.clear();
+/// Convert the entries of a config file to command line arguments.
+///
+/// Keys must be the long name of an argument, unknown keys are rejected.
+fn config_to_args(config: &toml::Table) -> Result<Vec<OsString>, Box<dyn Error>> {
+    let command = Args::command();
+    let mut args = Vec::new();
+    for (key, value) in config {
+        let arg = command
+            .get_arguments()
+            .find(|a| a.get_long() == Some(key) && a.get_id() != "config")
+            .ok_or_else(|| format!("unknown key `{key}` in config file"))?;
+
+        let value = match value {
+            toml::Value::String(s) => s.clone(),
+            toml::Value::Integer(i) => i.to_string(),
+            toml::Value::Float(f) => f.to_string(),
+            toml::Value::Boolean(b) if matches!(arg.get_action(), ArgAction::SetTrue) => {
+                if *b {
+                    args.push(format!("--{key}").into());
                 }
+                continue;
             }
-        }
+            toml::Value::Boolean(b) => b.to_string(),
+            toml::Value::Array(values) if values.is_empty() => continue,
+            toml::Value::Array(values) => values
+                .iter()
+                .map(|v| match v {
+                    toml::Value::String(s) => s.clone(),
+                    v => v.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(" "),
+            _ => return Err(format!("unsupported value for `{key}` in config file").into()),
+        };
+        args.push(format!("--{key}={value}").into());
     }
+    Ok(args)
+}
+
+/// Parse the command line, filling in missing arguments from `--config` if given.
+fn parse_args() -> Result<Args, Box<dyn Error>> {
+    parse_args_from(std::env::args_os().collect())
+}
+
+fn parse_args_from(cli: Vec<OsString>) -> Result<Args, Box<dyn Error>> {
+    let config = cli.iter().enumerate().find_map(|(i, a)| {
+        let a = a.to_str()?;
+        match a.strip_prefix("--config") {
+            Some("") => cli.get(i + 1).cloned(),
+            Some(path) => path.strip_prefix('=').map(OsString::from),
+            None => None,
+        }
+    });
+    let Some(config) = config else {
+        return Ok(Args::parse_from(cli));
+    };
+
+    let config: toml::Table = toml::from_str(&fs::read_to_string(&config).map_err(|e| {
+        format!(
+            "failed to read config file {}: {e}",
+            config.to_string_lossy()
+        )
+    })?)?;
+
+    // Values from the config file come first, so that explicit arguments override them
+    let mut args = vec![cli[0].clone()];
+    args.extend(config_to_args(&config)?);
+    args.extend(cli.into_iter().skip(1));
+
+    let matches = Args::command()
+        .args_override_self(true)
+        .get_matches_from(args);
+    Ok(Args::from_arg_matches(&matches)?)
+}
 
-    pub fn update<'a>(&mut self, pages: impl Iterator<Item = &'a PageAccess>) {
-        match self {
-            Self::Perfect(ref mut tlb) => {
-                // "perfect" fully-associative hardware TLB with infinite size
-                for page in pages {
-                    tlb.insert(page.to_owned());
+/// Check that the enclave is instrumented with TLBlur and that the profiler library
+/// exports the required functions, without running the workload.
+fn check(args: &Args) -> Result<(), Box<dyn Error>> {
+    let mut problems = Vec::new();
+
+    match create_enclave_with_token(&args.enclave, true, args.token_file.as_deref()) {
+        Ok(enclave) => {
+            for (name, address) in TLBLUR_SYMBOLS
+                .iter()
+                .zip(enclave.symbol_addresses(&TLBLUR_SYMBOLS)?)
+            {
+                match address {
+                    None => problems.push(format!(
+                        "enclave is not instrumented with TLBlur, missing symbol `{name}`"
+                    )),
+                    Some(address)
+                        if RelativePage::containing(address as usize, &enclave).is_none() =>
+                    {
+                        problems.push(format!("symbol `{name}` lies outside of the enclave"))
+                    }
+                    Some(_) => {}
                 }
             }
-            Self::SetAssociative { sets, num_sets, .. } => {
-                for page in pages {
-                    let set_index = Self::get_set_index(page, *num_sets);
-                    sets[set_index].insert(page.to_owned());
+            if let Some(name) = &args.target_symbol {
+                if let Err(e) = resolve_symbol_range(&enclave, name) {
+                    problems.push(e.to_string());
                 }
             }
         }
+        Err(e) => problems.push(e.to_string()),
     }
 
-    pub fn test(&self, page: &PageAccess) -> bool {
-        match self {
-            Self::Perfect(pages) => pages.iter().any(|p| p.covers(page)),
-            Self::SetAssociative { sets, num_sets, .. } => {
-                let set_index = Self::get_set_index(page, *num_sets);
-                sets[set_index].lookup(page)
+    if let Some(so) = &args.so {
+        match unsafe { libloading::Library::new(so) } {
+            Ok(library) => {
+                if let Err(e) = ProfilerLibrary::new(&library) {
+                    problems.push(e.to_string());
+                }
             }
+            Err(e) => problems.push(format!("failed to load {so}: {e}")),
         }
     }
 
-    /// Use for debugging purposes only
-    pub fn iter(&self) -> impl Iterator<Item = &PageAccess> {
-        match self {
-            Self::Perfect(pages) => pages.iter(),
-            Self::SetAssociative { .. } => todo!(),
-        }
-    }
+    Ok(check::report(&problems)?)
+}
 
-    fn get_set_index(page: &PageAccess, num_sets: usize) -> usize {
-        (page.page as usize) % num_sets
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = parse_args()?;
+    init_logger(args.quiet);
+    let base_dir = args.base_dir.as_deref();
+    args.enclave = resolve_app_path(&args.enclave, base_dir)?
+        .to_string_lossy()
+        .into_owned();
+    if let Some(so) = &args.so {
+        args.so = Some(
+            resolve_app_path(so, base_dir)?
+                .to_string_lossy()
+                .into_owned(),
+        );
     }
-}
 
-impl From<HardwareTLBConfig> for HardwareTLB {
-    fn from(value: HardwareTLBConfig) -> Self {
-        match value {
-            HardwareTLBConfig::Perfect => Self::Perfect(HashSet::new()),
-            HardwareTLBConfig::SetAssociative {
-                num_sets,
-                ways_per_set,
-            } => Self::SetAssociative {
-                sets: (0..num_sets).map(|_| Set::new(ways_per_set)).collect(),
-                num_sets,
-                ways_per_set,
-            },
-        }
+    if args.check {
+        return check(&args);
     }
-}
 
-#[derive(Debug, Clone, Copy, ValueEnum)]
-enum InterruptPattern {
-    DebugSingleStep,
-    SingleStep,
-    PageFault,
-    Stealthy,
-}
+    let enclave = create_enclave_with_token(&args.enclave, true, args.token_file.as_deref())?;
+    if let Some(path) = &args.replay {
+        return replay(&args, &enclave, path);
+    }
+    create_stop_handler()?;
+
+    let library = unsafe { libloading::Library::new(args.so.as_ref().unwrap())? };
+
+    let mut builder = simulator_builder(
+        &args,
+        &enclave,
+        &args.trace_output,
+        attacker(args.interrupt_pattern, &args),
+    )?;
+    if let Some(addr) = args.metrics_addr {
+        let metrics = Arc::new(Metrics::new());
+        let addr = metrics.clone().serve(addr)?;
+        info!("serving metrics at http://{addr}/metrics");
+        builder = builder.metrics(metrics);
+    }
 
-impl Display for InterruptPattern {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(match self {
-            Self::DebugSingleStep => "debug-single-step",
-            Self::SingleStep => "single-step",
-            Self::PageFault => "page-fault",
-            Self::Stealthy => "stealthy",
-        })
+    let lib = ProfilerLibrary::new(&library)?;
+    if let Some(cpu) = args.cpu {
+        isolate_thread(cpu)?;
     }
+    let summary = match args.trace_set {
+        TraceSetType::Rset => run(builder, &enclave, lib, &args.args),
+        TraceSetType::Rwxset => run(builder.trace_set::<RWXSet>(), &enclave, lib, &args.args),
+        TraceSetType::Countset => run(builder.trace_set::<CountSet>(), &enclave, lib, &args.args),
+        TraceSetType::Lineset => run(builder.trace_set::<LineSet>(), &enclave, lib, &args.args),
+    }?;
+    if stop_requested() {
+        info!(
+            "interrupted, partial trace written to {}",
+            args.trace_output
+        );
+    } else {
+        info!("simulated trace written to {}", args.trace_output);
+    }
+    info!(
+        "{} steps, {} interrupts, {} pages accessed",
+        summary.steps, summary.interrupts, summary.pages_accessed
+    );
+    if let (Some(uncovered), Some(path)) = (summary.pam_uncovered_pages, &args.pam_coverage_log) {
+        info!(
+            "{uncovered} PAM-uncovered pages, written to {}",
+            path.display()
+        );
+    }
+    if let (Some(total), Some(path)) = (summary.information_gain, &args.infogain_csv) {
+        info!(
+            "attacker observed {total} distinct (page, bucket) pairs, written to {}",
+            path.display()
+        );
+    }
+    if let Some(path) = &args.summary_json {
+        summary.write(path)?;
+    }
+
+    Ok(())
 }
 
-#[derive(Debug, Clone)]
-enum Attacker {
-    DebugSingleStep,
-    SingleStep,
-    PageFault {
-        live_pages: Vec<usize>,
-        observe_ptes: bool,
-    },
-    Stealthy,
+/// The attacker of an interrupt pattern, configured by the arguments
+fn attacker(pattern: InterruptPattern, args: &Args) -> Attacker {
+    let mut attacker: Attacker = pattern.into();
+    match attacker {
+        Attacker::PageFault {
+            ref mut observe_ptes,
+            ..
+        } => *observe_ptes = args.observe_ptes,
+        Attacker::Stealthy {
+            ref mut clear_interval,
+            ..
+        } => *clear_interval = args.stealthy_interval,
+        Attacker::CoarsePageFault { ref mut period, .. } => *period = args.fault_period,
+        _ => {}
+    }
+    attacker
 }
 
-impl From<InterruptPattern> for Attacker {
-    fn from(value: InterruptPattern) -> Self {
-        match value {
-            InterruptPattern::DebugSingleStep => Attacker::DebugSingleStep,
-            InterruptPattern::SingleStep => Attacker::SingleStep,
-            InterruptPattern::PageFault => Attacker::PageFault {
-                live_pages: Vec::new(),
-                observe_ptes: true,
-            },
-            InterruptPattern::Stealthy => Attacker::Stealthy,
+/// Prefetching of the hardware TLB configured by the arguments, none without `--hw-prefetch`
+fn hw_prefetch(args: &Args) -> AdjacentPrefetch {
+    if args.hw_prefetch {
+        AdjacentPrefetch {
+            window: args.hw_prefetch_window,
+            stride: args.hw_prefetch_stride,
         }
+    } else {
+        AdjacentPrefetch::default()
     }
 }
 
-impl Display for Attacker {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(match self {
-            Self::DebugSingleStep => "debug-single-step",
-            Self::SingleStep => "single-step",
-            Self::PageFault { .. } => "page-fault",
-            Self::Stealthy => "stealthy",
+/// Set up a simulator of `attacker` with the arguments, writing its trace to `trace_output`
+fn simulator_builder(
+    args: &Args,
+    enclave: &EnclaveRef,
+    trace_output: &str,
+    attacker: Attacker,
+) -> Result<SimulatorBuilder, Box<dyn Error>> {
+    let mut builder = SimulatorBuilder::new(trace_output)
+        .pws_size(args.pws_size)
+        .hardware_tlb(match args.hardware_tlb {
+            HardwareTLBType::Perfect => HardwareTLBConfig::Perfect {
+                capacity: args.tlb_capacity,
+            },
+            HardwareTLBType::SetAssociative => HardwareTLBConfig::SetAssociative {
+                num_sets: args.num_sets,
+                ways_per_set: args.ways_per_set,
+                replacement: args.replacement,
+            },
         })
+        .hw_prefetch(hw_prefetch(args))
+        .flush_policy(args.flush_policy)
+        .pam_eviction(args.pam_eviction)
+        .attacker(attacker)
+        .noise(NoiseModel {
+            drop_probability: args.observation_noise,
+        })
+        .seed(args.seed)
+        .write_erip(args.write_erip)
+        .timestamp(args.timestamp)
+        .page_filter(PageFilter::from_lists(
+            args.pages.clone(),
+            args.exclude_pages.clone(),
+        ))
+        .prefetch(!args.no_prefetch)
+        .warmup(args.warmup)
+        .hide_instrumentation(args.hide_instrumentation)
+        .prefetch_regions(if args.prefetch_regions.is_empty() {
+            PrefetchRegion::tlblur_defaults()
+        } else {
+            args.prefetch_regions.clone()
+        })
+        .baseline_symbols(args.baseline_symbols.clone())
+        .cache_line_symbols(args.cache_line_symbols.clone())
+        .parallel_scan(args.parallel_scan)
+        .segments_only(args.segments_only)
+        .scan_cache(
+            args.scan_cache_interval
+                .map(|full_scan_interval| ScanCacheConfig {
+                    neighborhood: args.scan_cache_neighborhood,
+                    full_scan_interval,
+                }),
+        );
+    builder = builder.prefetcher(match args.prefetcher {
+        PrefetcherType::Pam => Box::new(PamPrefetcher),
+        PrefetcherType::Markov => Box::new(MarkovPrefetcher::from_vcd(
+            args.markov_trace.as_ref().unwrap(),
+            args.markov_degree,
+        )?),
+    });
+    if let Some(name) = &args.target_symbol {
+        builder = builder.target_range(Some(resolve_symbol_range(enclave, name)?));
     }
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum CanObserve {
-    Always,
-    Interrupt,
-}
-
-impl Attacker {
-    /// Given the behaviour of the attacker and the state of the HW TLB and page table,
-    /// would the attacker be able to interrupt at this point.
-    fn can_trigger_interrupt(&self, page_table: &PageTable, hw_tlb: &HardwareTLB) -> bool {
-        match self {
-            Attacker::DebugSingleStep => {
-                // A single-stepping adversary can always interrupt, regardless of the state of
-                // the hardware TLB.
-                //
-                // This is an unrealistic attacker model, as our defense prohibits such behavior
-                //
-                // An attack like this would require an enclave in debug mode with trap interrupts
-                true
-            }
-            Attacker::SingleStep => {
-                // We assume that this attacker can interrupt if there is some page accessed
-                // (change in PTE A bit) that was not in the hardware TLB.
-                //
-                // This is essentially the SGX-Step attacker
-                page_table.get_accessed_pages(|p| !hw_tlb.test(p)).count() > 0
-            }
-            Attacker::PageFault { live_pages, .. } => {
-                // The page fault attacker is like the single-stepping attacker, but has a
-                // set of live pages that are mapped. An interrupt can only be triggered
-                // by this attacker if there is a page that is not in the hardware TLB
-                // *and* not in the set of pages that the attacker made accessible.
-
-                page_table
-                    .get_accessed_pages(|p| !hw_tlb.test(p))
-                    .any(|p| !live_pages.contains(&p.page))
-            }
-            Attacker::Stealthy => {
-                // The stealthy attacker only observes changes to PTE bits, but never interrupts
-                false
-            }
-        }
+    if let Some(path) = &args.records {
+        builder = builder.records(path);
     }
-
-    fn observe<'d>(
-        &self,
-        entry: &mut VCDEntry<'d, RSet>,
-        page_table: &PageTable,
-        hw_tlb: &HardwareTLB,
-        observations: &mut PageTableObservations,
-    ) {
-        match self {
-            Attacker::PageFault {
-                ref live_pages,
-                observe_ptes: false,
-            } => entry.write_page_accesses(
-                page_table
-                    .get_accessed_pages(|p| !hw_tlb.test(p))
-                    .filter(|p| !live_pages.contains(&p.page)),
-            ),
-            _ => entry.write_page_accesses(observations.iter()),
-        };
+    if let Some(path) = &args.record_schedule {
+        builder = builder.record_schedule(path);
     }
-
-    fn can_observe(&self) -> CanObserve {
-        match self {
-            // Stealthy attacker sees everything without interrupts
-            Attacker::Stealthy => CanObserve::Always,
-            // Other attackers only observe on interrupt
-            _ => CanObserve::Interrupt,
-        }
+    if let Some(path) = &args.debug_pam {
+        builder = builder.debug_pam(path);
     }
-
-    fn handle_step(&mut self, observations: &mut PageTableObservations) {
-        match self {
-            Attacker::Stealthy => observations.clear(),
-            _ => {}
-        }
+    if let Some(path) = &args.debug_sim_hwtlb {
+        builder = builder.debug_hardware_tlb(path);
+    }
+    if let Some(path) = &args.ground_truth_output {
+        builder = builder.ground_truth_output(path);
+    }
+    if let Some(path) = &args.pam_coverage_log {
+        builder = builder.pam_coverage_log(path);
     }
+    if let Some(path) = &args.working_sets {
+        builder = builder.working_sets(path);
+    }
+    if args.aex_notify {
+        builder = builder.aex_notify(AexNotify::new(args.aex_notify_working_set));
+    }
+    if let Some(path) = &args.infogain_csv {
+        builder = builder.information_gain(path, args.infogain_bucket);
+    }
+    Ok(builder)
+}
 
-    fn handle_interrupt(
-        &mut self,
-        page_table: &PageTable,
-        observations: &mut PageTableObservations,
-    ) {
-        match self {
-            Attacker::PageFault {
-                ref mut live_pages, ..
-            } => {
-                // This attacker maps the pages that are necessary for the current instruction
-                // to execute. It can then not trigger page faults on those pages, so
-                // we record it in the live pages set to remember the current capabilities of
-                // the attacker.
-                live_pages.clear();
-                for page in page_table.get_all_accessed_pages() {
-                    live_pages.push(page.page);
-                }
-                observations.clear();
-            }
-            Attacker::Stealthy => {}
-            _ => {
-                // All other attackers clear PTE bits as often as possible
-                observations.clear();
-            }
+/// Explain a missing TLBlur symbol, which means the enclave is not instrumented
+fn instrumentation_error(e: ProfilerError) -> Box<dyn Error> {
+    match e {
+        ProfilerError::SymbolNotFound(name) => {
+            format!("enclave is not instrumented with TLBlur, missing symbol `{name}`").into()
         }
+        e => e.into(),
     }
 }
 
-/// SGX tlblur simulator
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    /// A shared object that provides the profiler_setup and profiler_run functions
-    #[arg(long)]
-    so: String,
-
-    /// An SGX binary that will be created by the profiler
-    #[arg(short, long)]
-    enclave: String,
-
-    /// Output VCD file
-    #[arg(short = 'o', long = "output")]
-    trace_output: String,
-
-    #[arg(long)]
-    debug_pam: Option<String>,
-
-    #[arg(long)]
-    debug_sim_hwtlb: Option<String>,
-
-    /// Arguments to pass to the profiler_run function
-    #[arg(long, value_parser, num_args = 1.., value_delimiter = ' ')]
-    args: Vec<String>,
-
-    /// Write erip to VCD output
-    #[arg(long = "erip")]
-    write_erip: bool,
-
-    /// Size of the software TLB to simulate
-    #[arg(long, default_value_t = 10)]
-    pws_size: usize,
-
-    #[arg(long = "irq-pat", short = 'p', default_value_t = InterruptPattern::SingleStep)]
-    interrupt_pattern: InterruptPattern,
+fn run<S: TracePageSet + Send + Sync + 'static>(
+    builder: SimulatorBuilder<S>,
+    enclave: &EnclaveRef,
+    lib: ProfilerLibrary<'_>,
+    args: &[String],
+) -> Result<SimulationSummary, Box<dyn Error>> {
+    let simulator = builder.build(enclave).map_err(instrumentation_error)?;
 
-    #[arg(long = "observe-ptes", default_value_t = true)]
-    observe_ptes: bool,
+    Ok(simulator.run(lib, args)?)
+}
 
-    #[arg(long = "hw-tlb")]
-    hardware_tlb: HardwareTLBType,
+/// Output of the attacker with the interrupt `pattern` of `--compare`, e.g.
+/// `trace-single-step.vcd` for `trace.vcd`
+fn attacker_output(output: &str, pattern: InterruptPattern) -> String {
+    let path = Path::new(output);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{stem}-{pattern}.{}", extension.to_string_lossy()),
+        None => format!("{stem}-{pattern}"),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
 
-    #[arg(long = "sets", default_value_t = 4)]
-    num_sets: usize,
+/// Simulate the attackers of `--compare`, or the one of `--irq-pat`, on the steps of
+/// the record trace at `path`
+fn replay(args: &Args, enclave: &EnclaveRef, path: &Path) -> Result<(), Box<dyn Error>> {
+    let reader = TraceReader::new(BufReader::new(File::open(path)?))?;
+    let schedule = reader.header().schedule;
+    let (mut backend, num_pages) = MockBackend::from_records(reader)?;
+    if schedule {
+        // The erips of a schedule are relative to the enclave base
+        backend = backend.with_base(enclave.base() as usize);
+    }
+    let layout = TlblurLayout::of_enclave(enclave).map_err(instrumentation_error)?;
+    if num_pages != layout.num_pages {
+        return Err(format!(
+            "{} is a trace of an enclave of {num_pages} pages, not of {} pages",
+            path.display(),
+            layout.num_pages
+        )
+        .into());
+    }
 
-    #[arg(long = "ways", default_value_t = 2)]
-    ways_per_set: usize,
+    let builders = match args.compare.as_slice() {
+        [] => vec![simulator_builder(
+            args,
+            enclave,
+            &args.trace_output,
+            attacker(args.interrupt_pattern, args),
+        )?],
+        patterns => patterns
+            .iter()
+            .map(|&pattern| {
+                let output = attacker_output(&args.trace_output, pattern);
+                simulator_builder(args, enclave, &output, attacker(pattern, args))
+            })
+            .collect::<Result<_, _>>()?,
+    };
+    let builders = builders.into_iter();
+    let comparison = match args.trace_set {
+        TraceSetType::Rset => compare_attackers(builders, &backend, layout),
+        TraceSetType::Rwxset => {
+            compare_attackers(builders.map(|b| b.trace_set::<RWXSet>()), &backend, layout)
+        }
+        TraceSetType::Countset => compare_attackers(
+            builders.map(|b| b.trace_set::<CountSet>()),
+            &backend,
+            layout,
+        ),
+        TraceSetType::Lineset => {
+            compare_attackers(builders.map(|b| b.trace_set::<LineSet>()), &backend, layout)
+        }
+    }
+    .map_err(instrumentation_error)?;
 
-    #[arg(long)]
-    no_prefetch: bool,
+    println!("{comparison}");
+    if let Some(path) = &args.compare_json {
+        comparison.write(path)?;
+    }
+    Ok(())
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let args = Args::parse();
-
-    let enclave = create_enclave(&args.enclave)?;
-
-    let library = unsafe { libloading::Library::new(&args.so)? };
-
-    let pam_address = enclave.symbol_address("__tlblur_pam")? as u64;
-    let pam_counter_address = enclave.symbol_address("__tlblur_counter")? as u64;
-    let pam_update_code_address = enclave.symbol_address("tlblur_pam_update")? as u64;
-
-    let mut dumper: VCDDumper<RSet> = create_dumper(&enclave, &args.trace_output);
-    let mut pam_dumper: Option<VCDDumper<RSet>> =
-        args.debug_pam.map(|f| create_dumper(&enclave, f));
-    let mut hwtlb_dumper: Option<VCDDumper<RSet>> =
-        args.debug_sim_hwtlb.map(|f| create_dumper(&enclave, f));
-    let mut page_table = PageTable::new(&enclave);
-    let num_pages = page_table.page_table_map.len();
-    let mut pam = PAM::new(
-        pam_address as *mut c_void,
-        pam_counter_address as *mut c_void,
-        num_pages * 8,
-        args.pws_size,
-    );
-    let write_erip = args.write_erip;
-    let no_prefetch = args.no_prefetch;
-    let mut attacker: Attacker = args.interrupt_pattern.into();
-    if let Attacker::PageFault {
-        ref mut observe_ptes,
-        ..
-    } = attacker
-    {
-        *observe_ptes = args.observe_ptes;
-    }
-    let mut hw_tlb = HardwareTLB::from(match args.hardware_tlb {
-        HardwareTLBType::Perfect => HardwareTLBConfig::Perfect,
-        HardwareTLBType::SetAssociative => HardwareTLBConfig::SetAssociative {
-            num_sets: args.num_sets,
-            ways_per_set: args.ways_per_set,
-        },
-    });
-    let mut pte_observations = PageTableObservations::new();
-
-    // Don't do this, this is a hacky way to get around Rust's aliasing rules
-    let enclave_ref = unsafe { EnclaveRef::from_raw(enclave.id()) };
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse `cli` after the program name, with the config file `config`
+    fn parse_with_config(config: &str, cli: &[&str]) -> Result<Args, Box<dyn Error>> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("experiment.toml");
+        fs::write(&path, config).unwrap();
+        let mut args = vec![
+            OsString::from("sgx_tlblur_sim"),
+            "--config".into(),
+            path.into(),
+        ];
+        args.extend(cli.iter().map(OsString::from));
+        parse_args_from(args)
+    }
 
-    let mut first_run = true;
+    #[test]
+    fn config_file_fills_in_arguments() {
+        let config = r#"
+            enclave = "enclave.signed.so"
+            so = "libprofiler.so"
+            output = "trace.vcd"
+            pws-size = 32
+            hw-tlb = "set-associative"
+            sets = 16
+            ways = 4
+            no-prefetch = true
+            args = ["--size", "64"]
+        "#;
+        let args = parse_with_config(config, &["--ways", "8"]).unwrap();
+
+        assert_eq!(args.enclave, "enclave.signed.so");
+        assert_eq!(args.so.as_deref(), Some("libprofiler.so"));
+        assert_eq!(args.trace_output, "trace.vcd");
+        assert_eq!(args.pws_size, 32);
+        assert!(matches!(args.hardware_tlb, HardwareTLBType::SetAssociative));
+        assert_eq!(args.num_sets, 16);
+        // Explicit arguments override the config file
+        assert_eq!(args.ways_per_set, 8);
+        assert!(args.no_prefetch);
+        assert_eq!(args.args, ["--size", "64"]);
+        // Arguments not in the config file keep their defaults
+        assert_eq!(args.fault_period, 1);
+    }
 
-    create_trap_handler(move || {
-        // Update the local PAM to match the one in the instrumented enclave
-        pam.update_pam();
+    /// Parse `cli` after the program name and the required arguments
+    fn try_parse(cli: &[&str]) -> Result<Args, clap::Error> {
+        let required = [
+            "sgx_tlblur_sim",
+            "--enclave",
+            "encl.so",
+            "--so",
+            "lib.so",
+            "-o",
+            "trace.vcd",
+            "--hw-tlb",
+            "perfect",
+        ];
+        Args::try_parse_from(required.iter().chain(cli))
+    }
 
-        // Need to "prime" the page table on the first interrupt
-        // to get accurate measurements.
-        if first_run {
-            first_run = false;
-            page_table.clear_all_ad_bits();
-            return;
+    #[test]
+    fn hw_prefetch_is_off_unless_requested() {
+        assert_eq!(
+            hw_prefetch(&try_parse(&[]).unwrap()),
+            AdjacentPrefetch::default()
+        );
+        let args = try_parse(&["--hw-prefetch"]).unwrap();
+        assert_eq!(
+            hw_prefetch(&args),
+            AdjacentPrefetch {
+                window: 1,
+                stride: 1
+            }
+        );
+        let args = try_parse(&[
+            "--hw-prefetch",
+            "--hw-prefetch-window",
+            "2",
+            "--hw-prefetch-stride",
+            "3",
+        ])
+        .unwrap();
+        assert_eq!(
+            hw_prefetch(&args),
+            AdjacentPrefetch {
+                window: 2,
+                stride: 3
+            }
+        );
+
+        // The window and stride only apply to `--hw-prefetch`
+        for option in ["--hw-prefetch-window", "--hw-prefetch-stride"] {
+            let error = try_parse(&[option, "2"]).unwrap_err();
+            assert_eq!(
+                error.kind(),
+                clap::error::ErrorKind::MissingRequiredArgument
+            );
         }
+    }
 
-        pam_dumper.as_mut().map(|d| {
-            d.next_step(|entry| {
-                if write_erip {
-                    entry.write_erip();
-                }
-
-                entry.write_page_accesses(pam.get_pam());
-            })
-        });
-
-        hwtlb_dumper.as_mut().map(|d| {
-            d.next_step(|entry| {
-                if write_erip {
-                    entry.write_erip();
-                }
+    #[test]
+    fn compare_replays_a_record_trace_without_the_profiler_library() {
+        let replay = |cli: &[&str]| {
+            let required = [
+                "sgx_tlblur_sim",
+                "--enclave",
+                "encl.so",
+                "-o",
+                "trace.vcd",
+                "--hw-tlb",
+                "perfect",
+            ];
+            Args::try_parse_from(required.iter().chain(cli))
+        };
+        let args = replay(&[
+            "--replay",
+            "records.bin",
+            "--compare",
+            "single-step,page-fault",
+        ])
+        .unwrap();
+        assert_eq!(args.so, None);
+        assert_eq!(
+            args.compare,
+            [InterruptPattern::SingleStep, InterruptPattern::PageFault]
+        );
+
+        let missing = |result: Result<Args, clap::Error>| {
+            assert_eq!(
+                result.unwrap_err().kind(),
+                clap::error::ErrorKind::MissingRequiredArgument
+            )
+        };
+        // Without --replay, the enclave is run by the library
+        missing(replay(&[]));
+        missing(try_parse(&["--compare", "single-step"]));
+        let conflict = try_parse(&[
+            "--replay",
+            "records.bin",
+            "--compare",
+            "single-step",
+            "--working-sets",
+            "sets.json",
+        ]);
+        assert_eq!(
+            conflict.unwrap_err().kind(),
+            clap::error::ErrorKind::ArgumentConflict
+        );
+    }
 
-                entry.write_page_accesses(hw_tlb.iter());
-            })
-        });
-
-        // Check which pages were accessed
-        page_table.update_page_accesses();
-
-        // This is the effect on the real page table, which we simulate,
-        // because the real page table is used to trace page accesses of each instruction
-        pte_observations.update(page_table.get_accessed_pages(|p| !hw_tlb.test(p)));
-
-        let can_observe = attacker.can_observe();
-        let can_trigger_interrupt = attacker.can_trigger_interrupt(&page_table, &hw_tlb);
-
-        // Only write observations to the VCD trace if the attacker can observe
-        if can_observe == CanObserve::Always
-            || can_trigger_interrupt && can_observe == CanObserve::Interrupt
-        {
-            // Write to VCD trace
-            dumper.next_step(|entry| {
-                if write_erip {
-                    entry.write_erip();
-                }
+    #[test]
+    fn schedules_are_not_recorded_while_replaying() {
+        let args = try_parse(&["--record-schedule", "schedule.trace"]).unwrap();
+        assert_eq!(args.record_schedule, Some(PathBuf::from("schedule.trace")));
+        let error = try_parse(&[
+            "--record-schedule",
+            "schedule.trace",
+            "--replay",
+            "records.trace",
+        ])
+        .unwrap_err();
+        assert_eq!(error.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
 
-                // An attacker can only observe accesses to pages not in the hardware TLB
-                // entry.write_page_accesses(page_table.get_accessed_pages(|p| !hw_tlb.test(p)));
+    #[test]
+    fn compared_attackers_write_their_own_trace() {
+        assert_eq!(
+            attacker_output("out/trace.vcd", InterruptPattern::PageFault),
+            "out/trace-page-fault.vcd"
+        );
+        assert_eq!(
+            attacker_output("trace", InterruptPattern::SingleStep),
+            "trace-single-step"
+        );
+    }
 
-                attacker.observe(entry, &page_table, &hw_tlb, &mut pte_observations);
-            });
-        }
+    #[test]
+    fn attacker_options_configure_their_attacker() {
+        let args = try_parse(&[
+            "--irq-pat",
+            "coarse-page-fault",
+            "--fault-period",
+            "7",
+            "--stealthy-interval",
+            "3",
+        ])
+        .unwrap();
+        assert!(matches!(
+            attacker(args.interrupt_pattern, &args),
+            Attacker::CoarsePageFault {
+                period: 7,
+                steps: 0
+            }
+        ));
+        assert!(matches!(
+            attacker(InterruptPattern::Stealthy, &args),
+            Attacker::Stealthy {
+                clear_interval: 3,
+                steps: 0
+            }
+        ));
+        let args = try_parse(&["--irq-pat", "coarse-page-fault"]).unwrap();
+        assert!(matches!(
+            attacker(args.interrupt_pattern, &args),
+            Attacker::CoarsePageFault { period: 1, .. }
+        ));
+    }
 
-        attacker.handle_step(&mut pte_observations);
-
-        // Simulate interrupt if attacker can trigger an interrupt now
-        if can_trigger_interrupt {
-            attacker.handle_interrupt(&page_table, &mut pte_observations);
-
-            // Interrupt causes hardware TLB flush
-            hw_tlb.flush();
-
-            // Resume to AEX handler
-            if !no_prefetch {
-                // TLBlur prefetches pages from PAM
-                hw_tlb.update(pam.get_pam());
-                pte_observations.update(pam.get_pam());
-
-                // Prefetch stack pages
-                let stack_ptr = unsafe { enclave_ref.gprsgx_region().fields.rsp };
-                if stack_ptr >= enclave_ref.base() as u64 && stack_ptr <= enclave_ref.limit() as u64
-                {
-                    let stack_page = (stack_ptr - enclave_ref.base() as u64) >> 12;
-                    let stack_pages = (stack_page - 1..=stack_page + 1)
-                        .map(|page| PageAccess {
-                            read: true,
-                            execute: true,
-                            write: false,
-                            page: page as usize,
-                        })
-                        .collect::<Vec<_>>();
-                    hw_tlb.update(stack_pages.iter());
-                    pte_observations.update(stack_pages.iter());
-                }
+    #[test]
+    fn aex_notify_working_set_requires_aex_notify() {
+        assert!(!try_parse(&[]).unwrap().aex_notify);
+        let args = try_parse(&["--aex-notify"]).unwrap();
+        assert_eq!((args.aex_notify, args.aex_notify_working_set), (true, 2));
+        let args = try_parse(&["--aex-notify", "--aex-notify-working-set", "8"]).unwrap();
+        assert_eq!(args.aex_notify_working_set, 8);
+
+        let error = try_parse(&["--aex-notify-working-set", "8"]).unwrap_err();
+        assert_eq!(
+            error.kind(),
+            clap::error::ErrorKind::MissingRequiredArgument
+        );
+    }
 
-                // Prefetch the PAM update code
-                let tlblur_tlb_update_page =
-                    (pam_update_code_address - enclave_ref.base() as u64) >> 12;
-                let page_access = PageAccess {
-                    read: true,
-                    execute: true,
-                    write: false,
-                    page: tlblur_tlb_update_page as usize,
-                };
-                hw_tlb.update(std::iter::once(&page_access));
-                pte_observations.update(std::iter::once(&page_access));
-
-                let counter_page = (pam_counter_address as u64 - enclave_ref.base() as u64) >> 12;
-                let page_access = PageAccess {
-                    read: true,
-                    execute: false,
-                    write: true,
-                    page: counter_page as usize,
-                };
-                hw_tlb.update(std::iter::once(&page_access));
-                pte_observations.update(std::iter::once(&page_access));
-
-                let pam_page = (pam_address - enclave_ref.base() as u64) >> 12;
-                let pam_pages = (pam_page
-                    ..=pam_page + (pam.pam_buffer.len() as u64 * 8) / PAGE_SIZE_4KiB as u64)
-                    .map(|page| PageAccess {
-                        read: true,
-                        execute: false,
-                        write: true,
-                        page: page as usize,
-                    })
-                    .collect::<Vec<_>>();
-                hw_tlb.update(pam_pages.iter());
-                pte_observations.update(pam_pages.iter());
-            }
-        } else {
-            // We triggered a trap interrupt, but the attacker would not have interrupted...
-            // Now the real hardware TLB is flushed, nothing we can do about that now.
-            //
-            // Instead we simulate the hardware TLB.
-
-            // If the attacker doesn't interrupt, the hardware TLB would not be flushed,
-            // so we update it to take the accesses of the current instruction into account.
-            hw_tlb.update(page_table.get_all_accessed_pages());
-        }
+    #[test]
+    fn malformed_config_file_is_rejected() {
+        let base = "enclave = \"enclave.so\"\nso = \"lib.so\"\noutput = \"trace.vcd\"\nhw-tlb = \"perfect\"\n";
 
-        // Clear all A/D bits so we can accurately record page accesses
-        page_table.clear_all_ad_bits();
-    })?;
+        let unknown = parse_with_config(&format!("{base}pws_size = 32\n"), &[]).unwrap_err();
+        assert_eq!(unknown.to_string(), "unknown key `pws_size` in config file");
 
-    let lib = ProfilerLibrary::new(&library)?;
-    run_profiler(lib, &enclave, &args.args);
+        let unsupported =
+            parse_with_config(&format!("{base}sets = {{ n = 4 }}\n"), &[]).unwrap_err();
+        assert_eq!(
+            unsupported.to_string(),
+            "unsupported value for `sets` in config file"
+        );
 
-    Ok(())
+        assert!(parse_with_config(&format!("{base}pws-size = \n"), &[]).is_err());
+    }
 }
@@ -1,15 +1,49 @@
-use std::error::Error;
+use std::{error::Error, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
-use clap::Parser;
+use clap::{error::ErrorKind, CommandFactory, Parser, ValueEnum};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
 use sgx_profiler::{
-    create_dumper, create_enclave, create_trap_handler,
-    dump::{RSet, VCDDumper},
-    run_profiler, PageTable, ProfilerLibrary,
+    check_signal_handlers, create_dumper, create_enclave_with_token, create_fault_handler,
+    create_sample_handler, create_stop_handler, create_thread_trap_handler,
+    create_trap_event_handler, create_trap_handler,
+    dump::{DumperConfig, PageFilter, PageList, RSet, TimestampSource, VCDDumper},
+    init_logger, isolate_thread,
+    metrics::Metrics,
+    resolve_app_path, resolve_symbol_pages, resolve_symbol_range, run_profiler, segment_ranges,
+    sgx_step::page::RelativePage,
+    sgx_step::sgx_step_sys::edbgrd_erip,
+    sgx_step::Segment,
+    stats::{FirstAccesses, PageCountHistogram, PageLiveness, ProgressReport, WorkingSets},
+    stop_requested, FaultTracker, HandlerState, PageAccess, PageTable, Perms, ProfilerError,
+    ProfilerLibrary, ScanCacheConfig, TargetRange, TargetStep,
 };
 
+/// Number of most recently faulting pages that stay accessible in page-fault mode.
+/// An instruction can access its code page and up to two data pages, and would
+/// never make progress if one of those was revoked while it faults on another.
+const FAULT_WINDOW: usize = 3;
+
+/// Number of pages that can fault in a single step in production mode
+const FAULT_QUEUE_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum TraceMode {
+    /// Single-step the enclave and record the pages accessed by every instruction
+    SingleStep,
+    /// Record every page fault as a step, without single-stepping.
+    /// This gives a coarser trace, as observed by a page-fault attacker.
+    /// `profiler_run` should not enable single-stepping in this mode.
+    PageFault,
+}
+
 /// SGX page access profiler
-#[derive(Parser, Debug)]
+///
+/// Serialized with the fields named after the long arguments.
+#[derive(Parser, Debug, Serialize, Deserialize)]
 #[command(author, version, about, long_about = None)]
+#[serde(rename_all = "kebab-case")]
 struct Args {
     /// A shared object that provides the profiler_setup and profiler_run functions
     #[arg(long)]
@@ -19,48 +53,700 @@ struct Args {
     #[arg(short, long)]
     enclave: String,
 
+    /// Directory that relative `--enclave` and `--so` paths are resolved against,
+    /// `SGX_APP_DIR` by default, or else the working directory
+    #[arg(long)]
+    base_dir: Option<PathBuf>,
+
+    /// Load the launch token from this file if it exists, and save it there
+    /// if it was created or updated
+    #[arg(long)]
+    token_file: Option<PathBuf>,
+
     /// Output VCD file
     #[arg(short = 'o', long = "output")]
+    #[serde(rename = "output")]
     trace_output: String,
 
+    /// Also write the trace to this file in the record format, which holds the erip
+    /// (with `--erip`), the accessed pages and their permissions of every step
+    #[arg(long)]
+    records: Option<PathBuf>,
+
     /// Arguments to pass to the profiler_run function
     #[arg(long, value_parser, num_args = 1.., value_delimiter = ' ')]
     args: Vec<String>,
 
     /// Write erip to VCD output
     #[arg(long = "erip")]
+    #[serde(rename = "erip")]
     write_erip: bool,
+
+    /// Scan the page table using multiple threads
+    #[arg(long)]
+    parallel_scan: bool,
+
+    /// Only scan PTEs near recently accessed pages, with a full scan every N steps.
+    /// Other pages are only rescanned in the 2 MiB regions that were accessed.
+    #[arg(long)]
+    scan_cache_interval: Option<usize>,
+
+    /// Number of neighboring pages to scan around recently accessed pages
+    #[arg(long, default_value_t = 1)]
+    scan_cache_neighborhood: usize,
+
+    /// Only lock and scan the PTEs of the enclave segments, skipping the gaps between
+    /// them, which is faster for sparse enclaves
+    #[arg(long)]
+    segments_only: bool,
+
+    /// Only record page accesses of instructions within this function or object.
+    /// Its size is taken from the ELF symbol table.
+    #[arg(long)]
+    target_symbol: Option<String>,
+
+    /// Symbols whose pages are shown as accessed at the start of the trace,
+    /// e.g. to tell always-resident pages apart from pages that are never accessed
+    #[arg(long = "baseline-symbol", num_args = 1.., value_delimiter = ' ')]
+    #[serde(rename = "baseline-symbol")]
+    baseline_symbols: Vec<String>,
+
+    /// Timestamps of the VCD output, either the step count or TSC cycles
+    #[arg(long, default_value = "steps")]
+    timestamp: TimestampSource,
+
+    /// Only record these pages in the VCD output, as a comma-separated list of
+    /// pages and START-END ranges, e.g. `100-200,300`. END is exclusive.
+    #[arg(long, conflicts_with = "exclude_pages")]
+    pages: Option<PageList>,
+
+    /// Don't record these pages in the VCD output, in the format of `--pages`
+    #[arg(long)]
+    exclude_pages: Option<PageList>,
+
+    /// Write the distribution of the number of pages accessed per step to this file,
+    /// as CSV if it ends in `.csv` and as JSON otherwise
+    #[arg(long)]
+    page_count_hist: Option<PathBuf>,
+
+    /// Write the first and last step at which every accessed page was accessed
+    /// to this CSV file
+    #[arg(long)]
+    liveness_csv: Option<PathBuf>,
+
+    /// Write the distinct sets of pages accessed in a step, with the number of steps
+    /// they were accessed in, to this JSON file
+    #[arg(long)]
+    working_sets: Option<PathBuf>,
+
+    /// Create the enclave in production (non-debug) mode. The erip and enclave memory
+    /// can not be read, so page accesses are tracked with page faults instead of A/D bits.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "write_erip",
+            "target_symbol",
+            "parallel_scan",
+            "scan_cache_interval",
+            "segments_only"
+        ]
+    )]
+    production: bool,
+
+    /// How the enclave is interrupted to record page accesses
+    #[arg(long, default_value = "single-step")]
+    mode: TraceMode,
+
+    /// Sample the accessed pages every N microseconds instead of single-stepping.
+    /// Every step of the trace holds all pages accessed since the previous sample,
+    /// so accesses can no longer be attributed to single instructions.
+    /// `profiler_run` should not enable single-stepping in this mode.
+    #[arg(long, value_name = "MICROSECONDS", conflicts_with_all = ["production", "target_symbol"])]
+    sample_interval: Option<u64>,
+
+    /// Give each of up to N enclave threads its own scope in the VCD output, with the
+    /// TCS address of every step in the `tcs` wire. Threads are told apart by their TCS,
+    /// and should be pinned to a single CPU, as the accesses of threads that run
+    /// concurrently are attributed to the thread that traps. The erip is only read
+    /// from the first TCS, so `--erip` is unavailable.
+    #[arg(
+        long,
+        default_value_t = 1,
+        conflicts_with_all = ["write_erip", "target_symbol", "production", "sample_interval"]
+    )]
+    threads: usize,
+
+    /// Add a `cause` wire to the VCD output with the reason of every trap:
+    /// 0 for a signal sent by another process, 1 for a single-step and 2 for any
+    /// other trap, to diagnose unreliable single-stepping
+    #[arg(long, conflicts_with_all = ["production", "sample_interval", "threads"])]
+    trap_cause: bool,
+
+    /// Pin the profiler thread, which also runs the enclave, to this CPU and raise it
+    /// to the real-time scheduling policy, to reduce scheduling noise. Only warns if
+    /// the policy can not be changed without `CAP_SYS_NICE`.
+    #[arg(long, conflicts_with = "parallel_scan")]
+    cpu: Option<usize>,
+
+    /// Print the number of steps, the steps per second and the current erip to stderr
+    /// at most every SECONDS
+    #[arg(long, value_name = "SECONDS")]
+    progress_interval: Option<u64>,
+
+    /// Only record the pages written by every step, and only clear the dirty bits of
+    /// the PTEs. The accessed bits stay set, so pages that are only read cost no PTE
+    /// writes. Written pages are recorded like in a full scan.
+    #[arg(long, conflicts_with = "production")]
+    writes_only: bool,
+
+    /// Also write a VCD trace with the same steps that only has the first access to
+    /// every page in the run, the accesses a page-fault attacker observes
+    #[arg(long, value_name = "PATH")]
+    first_access_set: Option<PathBuf>,
+
+    /// Serve the steps, steps per second, accessed pages and erip of the run at
+    /// `http://ADDR/metrics` in the Prometheus text format, e.g. `127.0.0.1:9100`.
+    /// The erip is read on every step for this, unless it can't be read.
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Only log warnings and errors
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+/// Statistics of the recorded steps, if requested
+#[derive(Default)]
+struct StepStats {
+    histogram: Option<PageCountHistogram>,
+    liveness: Option<PageLiveness>,
+    working_sets: Option<WorkingSets>,
+    /// Trace of `--first-access-set`
+    first_accesses: Option<(FirstAccesses, VCDDumper<RSet>)>,
+}
+
+impl StepStats {
+    /// Record the pages accessed in a step.
+    fn record<'a>(&mut self, pages: impl Iterator<Item = &'a PageAccess>) {
+        if self.histogram.is_none()
+            && self.liveness.is_none()
+            && self.working_sets.is_none()
+            && self.first_accesses.is_none()
+        {
+            return;
+        }
+        let pages = pages.collect::<Vec<_>>();
+        if let Some((first, dumper)) = &mut self.first_accesses {
+            let first = first.record(pages.iter().copied());
+            dumper.next_step(|entry| entry.write_page_accesses(first.iter()));
+        }
+        if let Some(liveness) = &mut self.liveness {
+            liveness.record(pages.iter().copied());
+        }
+        if let Some(histogram) = &mut self.histogram {
+            histogram.record(pages.len());
+        }
+        if let Some(working_sets) = &mut self.working_sets {
+            working_sets.record(pages.iter().copied());
+        }
+    }
+}
+
+/// State of the handler that records the trace, taken back after the run
+struct Trace {
+    dumper: VCDDumper<RSet>,
+    stats: StepStats,
+    /// Set once a stop was requested or a step failed
+    stopped: bool,
+    /// Error of the step that stopped the trace
+    result: Result<(), ProfilerError>,
+}
+
+impl Trace {
+    /// Record a step with `step`, unless the trace stopped.
+    ///
+    /// An error stops the trace, and is returned after the run.
+    fn step(
+        &mut self,
+        step: impl FnOnce(&mut VCDDumper<RSet>, &mut StepStats) -> Result<(), ProfilerError>,
+    ) {
+        if self.stopped {
+            return;
+        }
+        let result = step(&mut self.dumper, &mut self.stats);
+        self.keep_error(result);
+        self.stopped = self.result.is_err() || stop_requested();
+    }
+
+    /// Log the error of `result`, and keep it unless an earlier step failed
+    fn keep_error(&mut self, result: Result<(), ProfilerError>) {
+        if let Err(e) = result {
+            error!("{e}, stopping the trace");
+            if self.result.is_ok() {
+                self.result = Err(e);
+            }
+        }
+    }
+}
+
+/// Count a step that accessed `pages` pages in the metrics served with
+/// `--metrics-addr`, if any.
+///
+/// `erip` is only called for the metrics, and returns `None` if the erip can't be read.
+fn record_metrics(
+    metrics: &Option<Arc<Metrics>>,
+    pages: usize,
+    erip: impl FnOnce() -> Option<usize>,
+) {
+    if let Some(metrics) = metrics {
+        metrics.record_step(pages, erip());
+    }
+}
+
+/// Progress of the run, printed to stderr if requested
+struct ProgressLog {
+    report: ProgressReport,
+    /// Segments of the enclave, to name the segment of the current erip
+    segments: Vec<Segment>,
+}
+
+impl ProgressLog {
+    /// Count a step and print the progress if a report is due.
+    ///
+    /// `erip` is only called when printing, and returns `None` if the erip can't be read.
+    fn step(&mut self, erip: impl FnOnce() -> Option<usize>) {
+        let Some(progress) = self.report.step() else {
+            return;
+        };
+        match erip() {
+            Some(erip) => {
+                let segment = self
+                    .segments
+                    .iter()
+                    .find(|s| s.range().contains(&erip))
+                    .map_or("?", |s| s.name.as_str());
+                eprintln!("{progress}, erip {erip:#x} in {segment}");
+            }
+            None => eprintln!("{progress}"),
+        }
+    }
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let args = Args::parse();
+    let mut args = Args::parse();
+    init_logger(args.quiet);
+    let base_dir = args.base_dir.as_deref();
+    args.enclave = resolve_app_path(&args.enclave, base_dir)?
+        .to_string_lossy()
+        .into_owned();
+    args.so = resolve_app_path(&args.so, base_dir)?
+        .to_string_lossy()
+        .into_owned();
+    if args.mode == TraceMode::PageFault
+        && (args.sample_interval.is_some()
+            || args.target_symbol.is_some()
+            || args.parallel_scan
+            || args.scan_cache_interval.is_some()
+            || args.threads > 1
+            || args.trap_cause
+            || args.writes_only)
+    {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--sample-interval, --target-symbol, --parallel-scan, --scan-cache-interval, \
+                 --threads, --trap-cause and --writes-only require `--mode single-step`",
+            )
+            .exit();
+    }
+
+    let enclave =
+        create_enclave_with_token(&args.enclave, !args.production, args.token_file.as_deref())?;
+    create_stop_handler()?;
 
-    let enclave = create_enclave(&args.enclave)?;
+    let mut baseline = Vec::new();
+    for name in &args.baseline_symbols {
+        baseline.extend(
+            resolve_symbol_pages(&enclave, name)?
+                .map(|page| PageAccess::new(RelativePage::new(page), Perms::READ)),
+        );
+    }
+    let config = DumperConfig {
+        timestamp: args.timestamp,
+        baseline,
+        filter: PageFilter::from_lists(args.pages.clone(), args.exclude_pages.clone()),
+        records: args.records.clone(),
+        // A single-threaded enclave keeps the trace without thread scopes
+        threads: (args.threads > 1).then_some(args.threads),
+        cause: args.trap_cause,
+        ..Default::default()
+    };
+    let first_accesses = args
+        .first_access_set
+        .as_ref()
+        .map(|path| {
+            let config = DumperConfig {
+                timestamp: args.timestamp,
+                filter: config.filter.clone(),
+                ..Default::default()
+            };
+            create_dumper(&enclave, path, config).map(|dumper| (FirstAccesses::new(), dumper))
+        })
+        .transpose()?;
+    let dumper: VCDDumper<RSet> = create_dumper(&enclave, &args.trace_output, config)?;
+    let metrics = match args.metrics_addr {
+        Some(addr) => {
+            let metrics = Arc::new(Metrics::new());
+            let addr = metrics.clone().serve(addr)?;
+            info!("serving metrics at http://{addr}/metrics");
+            Some(metrics)
+        }
+        None => None,
+    };
+    let trace = Arc::new(HandlerState::new(Trace {
+        dumper,
+        stats: StepStats {
+            histogram: args
+                .page_count_hist
+                .as_ref()
+                .map(|_| PageCountHistogram::new()),
+            liveness: args.liveness_csv.as_ref().map(|_| PageLiveness::new()),
+            working_sets: args.working_sets.as_ref().map(|_| WorkingSets::new()),
+            first_accesses,
+        },
+        stopped: false,
+        result: Ok(()),
+    }));
+    let state = trace.clone();
+    let mut sample_timer = None;
+    let mut progress = args.progress_interval.map(|interval| ProgressLog {
+        report: ProgressReport::new(Duration::from_secs(interval)),
+        segments: enclave.segments(),
+    });
+    let read_erip = || Some(unsafe { edbgrd_erip() } as usize);
+    let segments = args.segments_only.then(|| segment_ranges(&enclave));
 
-    let mut dumper: VCDDumper<RSet> = create_dumper(&enclave, &args.trace_output);
-    let mut page_table = PageTable::new(&enclave);
-    let write_erip = args.write_erip;
+    if args.mode == TraceMode::PageFault {
+        let write_erip = args.write_erip;
+        let mut tracker = FaultTracker::new(&enclave)?;
+        create_fault_handler(&enclave, move |page| {
+            let stopped = state.with(|trace| {
+                trace.step(|dumper, stats| {
+                    tracker.record(page)?;
+                    dumper.next_step(|entry| {
+                        if write_erip {
+                            entry.write_erip();
+                        }
+                        entry.write_page_accesses(tracker.accessed_pages().last().into_iter());
+                    });
+                    stats.record(tracker.accessed_pages().last().into_iter());
+                    record_metrics(&metrics, 1, read_erip);
 
-    // let (signal_handle, handler_thread) = create_trap_handler(move || {
-    create_trap_handler(move || {
-        // Write to VCD trace
-        dumper.next_step(|entry| {
-            if write_erip {
-                entry.write_erip();
+                    if let Some(progress) = &mut progress {
+                        progress.step(read_erip);
+                    }
+
+                    // Revoke the pages that faulted before the last few, so they fault again
+                    tracker.retain_last(FAULT_WINDOW)
+                });
+                trace.stopped
+            });
+            if stopped == Some(false) {
+                return Ok(());
             }
+            // Let the enclave run to completion without faulting
+            tracker.restore_all()
+        })?;
+    } else if args.production {
+        info!(
+            "production mode: erip, target symbols and A/D bit scanning are unavailable, \
+             tracking page accesses with page faults"
+        );
+
+        let mut tracker = FaultTracker::new(&enclave)?;
+        let queue = Arc::new(tracker.queue(FAULT_QUEUE_CAPACITY));
+        let faults = queue.clone();
+        create_fault_handler(&enclave, move |page| faults.push(page))?;
 
-            // Check which pages were accessed and write to VCD
-            page_table.update_page_accesses();
-            entry.write_page_accesses(page_table.get_all_accessed_pages());
-        });
+        create_trap_handler(move || {
+            state.with(|trace| {
+                if trace.stopped {
+                    return;
+                }
+                trace.step(|dumper, stats| {
+                    tracker.record_queued(&queue)?;
+                    dumper.next_step(|entry| {
+                        entry.write_page_accesses(tracker.accessed_pages().iter());
+                    });
+                    stats.record(tracker.accessed_pages().iter());
+                    record_metrics(&metrics, tracker.accessed_pages().len(), || None);
+                    if let Some(progress) = &mut progress {
+                        // The erip can't be read from a production enclave
+                        progress.step(|| None);
+                    }
 
-        // Clear all A/D bits in enclave page table
-        page_table.clear_all_ad_bits();
-    })?;
+                    // Revoke access to the pages accessed in this step, so they fault again
+                    tracker.clear()
+                });
+                if trace.stopped {
+                    // This was the last step, the enclave continues without faulting
+                    trace.keep_error(tracker.restore_all());
+                }
+            });
+        })?;
+    } else if let Some(interval) = args.sample_interval {
+        let mut page_table = PageTable::new(&enclave, segments.as_deref())?;
+        page_table.set_parallel_scan(args.parallel_scan);
+        page_table.set_writes_only(args.writes_only);
+        let write_erip = args.write_erip;
+
+        sample_timer = Some(create_sample_handler(
+            Duration::from_micros(interval),
+            move || {
+                state.with(|trace| {
+                    trace.step(|dumper, stats| {
+                        // One step holds all pages accessed since the previous sample
+                        dumper.next_step(|entry| {
+                            if write_erip {
+                                entry.write_erip();
+                            }
+                            page_table.update_page_accesses();
+                            entry.write_page_accesses(page_table.get_all_accessed_pages());
+                        });
+                        stats.record(page_table.get_all_accessed_pages());
+                        record_metrics(
+                            &metrics,
+                            page_table.get_all_accessed_pages().count(),
+                            read_erip,
+                        );
+                        page_table.clear_ad_bits_accessed_only();
+                        if let Some(progress) = &mut progress {
+                            progress.step(read_erip);
+                        }
+                        Ok(())
+                    })
+                });
+            },
+        )?);
+    } else if args.threads > 1 {
+        let mut page_table = PageTable::new(&enclave, segments.as_deref())?;
+        page_table.set_parallel_scan(args.parallel_scan);
+        page_table.set_writes_only(args.writes_only);
+        page_table.set_scan_cache(args.scan_cache_interval.map(|full_scan_interval| {
+            ScanCacheConfig {
+                neighborhood: args.scan_cache_neighborhood,
+                full_scan_interval,
+            }
+        }));
+        let mut seen_threads = 0;
+
+        create_thread_trap_handler(move |tcs| {
+            state.with(|trace| {
+                trace.step(|dumper, stats| {
+                    let mut thread = Ok(0);
+                    dumper.next_step(|entry| {
+                        thread = entry.write_thread(tcs);
+                        page_table.update_page_accesses();
+                        if thread.is_ok() {
+                            entry.write_page_accesses(page_table.get_all_accessed_pages());
+                        }
+                    });
+                    if thread? == seen_threads {
+                        info!("tracing enclave thread {seen_threads} with TCS {tcs:#x}");
+                        seen_threads += 1;
+                    }
+
+                    stats.record(page_table.get_all_accessed_pages());
+                    record_metrics(
+                        &metrics,
+                        page_table.get_all_accessed_pages().count(),
+                        || None,
+                    );
+                    page_table.clear_ad_bits_accessed_only();
+                    if let Some(progress) = &mut progress {
+                        progress.step(|| None);
+                    }
+                    Ok(())
+                })
+            });
+        })?;
+    } else {
+        let mut page_table = PageTable::new(&enclave, segments.as_deref())?;
+        page_table.set_parallel_scan(args.parallel_scan);
+        page_table.set_writes_only(args.writes_only);
+        page_table.set_scan_cache(args.scan_cache_interval.map(|full_scan_interval| {
+            ScanCacheConfig {
+                neighborhood: args.scan_cache_neighborhood,
+                full_scan_interval,
+            }
+        }));
+        let write_erip = args.write_erip;
+        let mut target = args
+            .target_symbol
+            .as_ref()
+            .map(|name| resolve_symbol_range(&enclave, name).map(TargetRange::new))
+            .transpose()?;
+
+        // let (signal_handle, handler_thread) = create_trap_handler(move || {
+        create_trap_event_handler(move |event| {
+            if let Some(target) = &mut target {
+                match target.step(unsafe { edbgrd_erip() } as usize) {
+                    TargetStep::Record => {}
+                    TargetStep::Enter => {
+                        debug!("entered target symbol");
+                        // Discard the accesses made outside of the target
+                        page_table.clear_all_ad_bits();
+                        return;
+                    }
+                    TargetStep::Skip => return,
+                }
+            }
+
+            state.with(|trace| {
+                trace.step(|dumper, stats| {
+                    // Write to VCD trace
+                    dumper.next_step(|entry| {
+                        if write_erip {
+                            entry.write_erip();
+                        }
+                        entry.write_cause(event.cause());
+
+                        // Check which pages were accessed and write to VCD
+                        page_table.update_page_accesses();
+                        entry.write_page_accesses(page_table.get_all_accessed_pages());
+                    });
+                    stats.record(page_table.get_all_accessed_pages());
+                    record_metrics(
+                        &metrics,
+                        page_table.get_all_accessed_pages().count(),
+                        read_erip,
+                    );
+
+                    // Clear the A/D bits of the pages accessed in this step, all other
+                    // PTEs in the enclave page table are already clear. With `--writes-only`
+                    // these are the written pages, and only their dirty bits are cleared.
+                    page_table.clear_ad_bits_accessed_only();
+                    if let Some(progress) = &mut progress {
+                        progress.step(read_erip);
+                    }
+                    Ok(())
+                })
+            });
+        })?;
+    }
 
     let library = unsafe { libloading::Library::new(&args.so)? };
     let lib = ProfilerLibrary::new(&library)?;
+    if let Some(cpu) = args.cpu {
+        isolate_thread(cpu)?;
+    }
     run_profiler(lib, &enclave, &args.args);
+    if let Some(timer) = sample_timer {
+        timer.finish()?;
+    }
+    let Trace {
+        mut dumper,
+        mut stats,
+        result,
+        ..
+    } = trace.take().ok_or(ProfilerError::HandlerBusy)?;
+    let flushed = dumper.flush().map_err(|error| ProfilerError::Output {
+        path: dumper.path().to_owned(),
+        error,
+    });
+    result?;
+    flushed?;
+    check_signal_handlers()?;
+    if stop_requested() {
+        info!(
+            "interrupted, partial trace written to {}",
+            args.trace_output
+        );
+    } else {
+        info!("trace written to {}", args.trace_output);
+    }
+
+    if let Some((first, _)) = stats.first_accesses.take() {
+        // The trace is finished when its dumper is dropped
+        info!(
+            "{} pages accessed, first accesses written to {}",
+            first.accessed().len(),
+            args.first_access_set.as_ref().unwrap().display()
+        );
+    }
+    if let (Some(histogram), Some(path)) = (&stats.histogram, &args.page_count_hist) {
+        let summary = histogram.summary();
+        info!(
+            "pages per step: mean {:.2}, median {}, max {} over {} steps",
+            summary.mean, summary.median, summary.max, summary.steps
+        );
+        histogram.write(path)?;
+    }
+    if let (Some(liveness), Some(path)) = (&stats.liveness, &args.liveness_csv) {
+        liveness.write_csv(path)?;
+    }
+    if let (Some(working_sets), Some(path)) = (&stats.working_sets, &args.working_sets) {
+        let summary = working_sets.summary();
+        info!(
+            "{} distinct working sets over {} steps",
+            summary.distinct, summary.steps
+        );
+        working_sets.write(path)?;
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use sgx_profiler::golden;
+
+    use super::*;
+
+    #[test]
+    fn first_access_set_marks_every_page_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("first.vcd");
+        let mut rng = StdRng::seed_from_u64(1396);
+        let steps = (0..100)
+            .map(|_| {
+                (0..rng.gen_range(0..4))
+                    .map(|_| PageAccess::new(RelativePage::new(rng.gen_range(0..32)), Perms::READ))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        {
+            let mut stats = StepStats {
+                first_accesses: Some((FirstAccesses::new(), VCDDumper::new(&path, 32))),
+                ..Default::default()
+            };
+            for step in &steps {
+                stats.record(step.iter());
+            }
+        }
+
+        let mut first_steps = BTreeMap::new();
+        for (step, pages) in steps.iter().enumerate() {
+            for page in pages {
+                first_steps.entry(page.page.get()).or_insert(step as u64);
+            }
+        }
+        let trace = golden::Trace::read(&path).unwrap();
+        let mut marked = BTreeMap::new();
+        for (&ts, changes) in &trace.changes {
+            for (name, value) in changes {
+                if value == "1" {
+                    let page = name["trace._".len()..].parse::<usize>().unwrap();
+                    assert!(
+                        marked.insert(page, ts).is_none(),
+                        "page {page} marked again at {ts}"
+                    );
+                }
+            }
+        }
+        assert_eq!(marked, first_steps);
+    }
+}
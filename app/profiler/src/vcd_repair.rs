@@ -0,0 +1,43 @@
+//! Recover the complete steps of a VCD trace of a run that crashed, see
+//! [`sgx_profiler::repair`]
+
+use std::{error::Error, path::PathBuf};
+
+use clap::Parser;
+use log::{info, warn};
+use sgx_profiler::{init_logger, repair::repair_vcd};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// VCD trace written by `sgx_tracer` or `sgx_tlblur_sim`, possibly cut off
+    input: PathBuf,
+
+    /// Repaired VCD trace
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Only log warnings and errors
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+    init_logger(args.quiet);
+    if args.input == args.output {
+        return Err("the repaired trace must not overwrite the input".into());
+    }
+
+    let report = repair_vcd(&args.input, &args.output)?;
+    if report.header_repaired {
+        warn!("the trace ends within its definitions, closed the open scopes");
+    }
+    info!(
+        "recovered {} steps, discarded {}, written to {}",
+        report.recovered,
+        report.discarded,
+        args.output.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,20 @@
+#include <stdint.h>
+
+#define NUM_TEST_PAGES 4
+#define TEST_PAGE_SIZE 4096
+
+/*
+ * Pages with a known access pattern: `ecall_touch_pages` writes to each of them
+ * exactly once, in order. The `test_enclave` integration test of the profiler
+ * looks up this symbol to find the expected pages in the trace.
+ */
+uint8_t test_pages[NUM_TEST_PAGES][TEST_PAGE_SIZE]
+    __attribute__((aligned(TEST_PAGE_SIZE)));
+
+void ecall_touch_pages(void)
+{
+    for (int i = 0; i < NUM_TEST_PAGES; i++)
+    {
+        *(volatile uint8_t *) &test_pages[i][0] = i + 1;
+    }
+}
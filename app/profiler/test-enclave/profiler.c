@@ -0,0 +1,16 @@
+#include "Enclave/encl_u.h"
+#include "libsgxstep/debug.h"
+#include "libsgxstep/simstep.h"
+#include <sgx_urts.h>
+#include <stdint.h>
+
+void profiler_setup(int eid, int e_size, void *e_start, uint64_t argc,
+                    char **argv) {}
+
+void profiler_run(int eid) {
+  start_single_stepping();
+  SGX_ASSERT(ecall_touch_pages(eid));
+  stop_single_stepping();
+}
+
+void profiler_destroy(int eid) {}
@@ -0,0 +1,108 @@
+//! Simulator output against the golden traces in `tests/golden`
+//!
+//! Run with the `UPDATE_GOLDEN` environment variable set to overwrite the golden
+//! traces with the produced ones, after a change that is meant to alter them.
+
+use std::path::{Path, PathBuf};
+
+use sgx_profiler::{
+    attacker::Attacker,
+    backend::{MockBackend, MockStep},
+    golden::Trace,
+    sgx_step::page::RelativePage,
+    simulator::TlblurLayout,
+    PageAccess, Perms, SimulatorBuilder,
+};
+
+/// Environment variable that makes [`assert_trace_eq`] overwrite the golden
+/// trace with the produced one instead of comparing them
+const UPDATE_GOLDEN_VAR: &str = "UPDATE_GOLDEN";
+
+/// Assert that the `produced` VCD trace is semantically equal to the `golden` one.
+///
+/// Traces are equal if they declare the same wires and set them to the same
+/// values at the same timestamps, regardless of id codes and of the order of the
+/// changes within a timestamp.
+#[track_caller]
+fn assert_trace_eq(golden: &Path, produced: &Path) {
+    if std::env::var_os(UPDATE_GOLDEN_VAR).is_some() {
+        std::fs::copy(produced, golden)
+            .unwrap_or_else(|e| panic!("failed to update golden trace {}: {e}", golden.display()));
+        return;
+    }
+
+    if let Some(difference) = read(golden).first_difference(&read(produced)) {
+        panic!(
+            "trace {} does not match golden trace {}: {difference}",
+            produced.display(),
+            golden.display()
+        );
+    }
+}
+
+fn read(path: &Path) -> Trace {
+    Trace::read(path).unwrap_or_else(|e| panic!("failed to read trace {}: {e}", path.display()))
+}
+
+fn golden(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(name)
+}
+
+/// Simulate a short script of reads and writes, with a PAM of `pws_size` pages,
+/// and write the attacker observations to `output`
+fn simulate_script(output: &Path, pws_size: usize) {
+    let access = |page, write| {
+        let perms = if write {
+            Perms::READ | Perms::WRITE
+        } else {
+            Perms::READ
+        };
+        PageAccess::new(RelativePage::new(page), perms)
+    };
+    let script = [
+        (vec![access(10, false)], vec![10]),
+        (vec![access(11, true)], vec![11]),
+        (vec![access(10, false), access(12, false)], vec![10, 12]),
+        (vec![access(13, false)], vec![]),
+        (vec![access(11, false)], vec![11]),
+    ];
+    let backend = MockBackend::new(
+        64,
+        script.into_iter().map(|(accesses, pages)| MockStep {
+            accesses,
+            pam_updates: pages.into_iter().map(RelativePage::new).collect(),
+            ..Default::default()
+        }),
+    );
+
+    let mut simulator = SimulatorBuilder::new(output)
+        .attacker(Attacker::SingleStep)
+        .pws_size(pws_size)
+        .build_with_backend(backend, TlblurLayout::mock(64))
+        .unwrap();
+    // The first step only primes the simulator
+    for _ in 0..6 {
+        simulator.step().unwrap();
+    }
+}
+
+#[test]
+fn single_step_matches_golden() {
+    let dir = tempfile::tempdir().unwrap();
+    let produced = dir.path().join("single-step.vcd");
+    simulate_script(&produced, 2);
+    assert_trace_eq(&golden("single-step.vcd"), &produced);
+}
+
+#[test]
+fn changed_simulation_differs_from_golden() {
+    let dir = tempfile::tempdir().unwrap();
+    let produced = dir.path().join("single-step.vcd");
+    // A PAM of more pages changes what the attacker observes
+    simulate_script(&produced, 8);
+    assert!(read(&golden("single-step.vcd"))
+        .first_difference(&read(&produced))
+        .is_some());
+}
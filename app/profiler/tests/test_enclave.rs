@@ -0,0 +1,103 @@
+//! Profile the minimal enclave in `test-enclave` by single-stepping it, and check
+//! that the pages of its `test_pages` are accessed one after the other.
+//!
+//! The test is skipped when no SGX driver is available, or when it does not run as
+//! root, which is needed to map the page tables of the enclave.
+#![cfg(feature = "sgx")]
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Arc,
+};
+
+use sgx_profiler::{
+    create_enclave, create_trap_handler,
+    dump::{RSet, VCDDumper},
+    golden::Trace,
+    resolve_symbol_pages, run_profiler, HandlerState, PageTable, ProfilerLibrary,
+};
+
+/// Number of pages of `test_pages` in `test-enclave/Enclave/encl.c`
+const NUM_TEST_PAGES: usize = 4;
+
+fn test_enclave(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("test-enclave")
+        .join(name)
+}
+
+/// Why the test can not run on this machine, if it can't
+fn skip_reason() -> Option<&'static str> {
+    if !Path::new("/dev/sgx_enclave").exists() && !Path::new("/dev/isgx").exists() {
+        return Some("no SGX driver found");
+    }
+    if unsafe { nix::libc::geteuid() } != 0 {
+        return Some("not running as root");
+    }
+    None
+}
+
+#[test]
+fn test_pages_are_accessed_in_order() {
+    if let Some(reason) = skip_reason() {
+        eprintln!("skipping the test enclave: {reason}");
+        return;
+    }
+
+    let status = Command::new("make")
+        .arg("-C")
+        .arg(test_enclave(""))
+        .arg("all")
+        .status()
+        .unwrap();
+    assert!(status.success(), "failed to build the test enclave");
+
+    let enclave = create_enclave(test_enclave("Enclave/encl.so").to_str().unwrap(), true).unwrap();
+    let test_pages = resolve_symbol_pages(&enclave, "test_pages").unwrap();
+    assert_eq!(test_pages.len(), NUM_TEST_PAGES);
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("trace.vcd");
+    let dumper = Arc::new(HandlerState::new(VCDDumper::<RSet>::new(
+        &path,
+        enclave.page_count(),
+    )));
+    let state = dumper.clone();
+    let mut page_table = PageTable::new(&enclave, None).unwrap();
+    create_trap_handler(move || {
+        state.with(|dumper| {
+            dumper.next_step(|entry| {
+                page_table.update_page_accesses();
+                entry.write_page_accesses(page_table.get_all_accessed_pages());
+            });
+            page_table.clear_ad_bits_accessed_only();
+        });
+    })
+    .unwrap();
+
+    let library = unsafe { libloading::Library::new(test_enclave("profiler-test.so")) }.unwrap();
+    run_profiler(
+        ProfilerLibrary::new(&library).unwrap(),
+        &enclave,
+        &[] as &[&str],
+    );
+    dumper.take().unwrap().flush().unwrap();
+
+    // Timestamp of the first access to every test page
+    let trace = Trace::read(&path).unwrap();
+    let first_access = |page: usize| {
+        let wire = (format!("trace._{page}"), "1".to_string());
+        trace
+            .changes
+            .iter()
+            .find(|(_, changes)| changes.contains(&wire))
+            .map(|(&ts, _)| ts)
+            .unwrap_or_else(|| panic!("test page {page} was never accessed"))
+    };
+    let first_accesses = test_pages.clone().map(first_access).collect::<Vec<_>>();
+    assert!(
+        first_accesses.is_sorted_by(|a, b| a < b),
+        "test pages {test_pages:?} not accessed in order: {first_accesses:?}"
+    );
+}
@@ -1,19 +1,28 @@
+#[cfg(feature = "sys")]
 pub mod memory;
+pub mod page;
+#[cfg(feature = "sys")]
 pub mod page_table;
 
+#[cfg(feature = "sys")]
 use std::{
     borrow::Borrow,
-    error::Error,
     ffi::{c_void, CStr, CString, NulError},
     ops::{Deref, DerefMut},
     ptr::null_mut,
 };
+use std::{error::Error, ops::Range};
+#[cfg(feature = "sgx")]
+use std::{fs, io, path::Path};
 
+#[cfg(feature = "sys")]
 pub use sgx_step_sys;
+#[cfg(feature = "sys")]
 use sgx_step_sys::{
     edbgrd, get_enclave_base, get_enclave_limit, get_enclave_size, get_enclave_ssa_gprsgx_adrs,
-    get_symbol_offset, gprsgx_region_t, print_enclave_info, register_enclave_info, set_debug_optin,
-    PAGE_SIZE_4KiB,
+    get_section, get_section_count, get_symbol_offset, get_symbol_offsets, get_symbol_size,
+    gprsgx_region, gprsgx_region_t, print_enclave_info, register_enclave_info, section,
+    set_debug_optin, PAGE_SIZE_4KiB,
 };
 
 #[cfg(feature = "sgx")]
@@ -28,6 +37,12 @@ use sgx_urts_sys::{
 #[derive(Debug, Clone, Copy)]
 pub enum EnclaveId {
     SGX(u64),
+    /// Enclave mapped at `base..base + size` in the address space of process `pid`
+    External {
+        pid: u32,
+        base: usize,
+        size: usize,
+    },
 }
 
 #[allow(unreachable_patterns)]
@@ -38,12 +53,139 @@ impl EnclaveId {
             _ => None,
         }
     }
+
+    /// Process that maps the enclave, or `None` for an enclave of the calling process
+    pub fn pid(&self) -> Option<u32> {
+        match self {
+            &EnclaveId::External { pid, .. } => Some(pid),
+            _ => None,
+        }
+    }
+}
+
+// ELF section flags (see `elf.h`)
+#[cfg(feature = "sys")]
+const SHF_WRITE: u64 = 0x1;
+#[cfg(feature = "sys")]
+const SHF_EXECINSTR: u64 = 0x4;
+
+/// Permissions of an enclave segment
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SegmentPerms {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+/// Contiguous region of enclave memory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub name: String,
+    /// Absolute start address of the segment
+    pub start: usize,
+    /// Absolute end address of the segment (exclusive)
+    pub end: usize,
+    pub perms: SegmentPerms,
+}
+
+impl Segment {
+    /// Returns the range of absolute addresses of this segment
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+}
+
+/// General purpose registers of an interrupted enclave thread, as saved in the
+/// GPRSGX region of its SSA frame
+///
+/// The SSA is only written on an asynchronous enclave exit (AEX), so the values are
+/// only meaningful while the thread is interrupted, e.g. inside a fault or trap handler.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GprSgx {
+    pub rax: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rbx: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rflags: u64,
+    pub rip: u64,
+    /// Untrusted stack pointer at the time of the last enclave entry
+    pub ursp: u64,
+    /// Untrusted frame pointer at the time of the last enclave entry
+    pub urbp: u64,
+    pub exitinfo: u32,
+    pub fsbase: u64,
+    pub gsbase: u64,
+}
+
+#[cfg(feature = "sys")]
+impl From<gprsgx_region> for GprSgx {
+    fn from(r: gprsgx_region) -> Self {
+        Self {
+            rax: r.rax,
+            rcx: r.rcx,
+            rdx: r.rdx,
+            rbx: r.rbx,
+            rsp: r.rsp,
+            rbp: r.rbp,
+            rsi: r.rsi,
+            rdi: r.rdi,
+            r8: r.r8,
+            r9: r.r9,
+            r10: r.r10,
+            r11: r.r11,
+            r12: r.r12,
+            r13: r.r13,
+            r14: r.r14,
+            r15: r.r15,
+            rflags: r.rflags,
+            rip: r.rip,
+            ursp: r.ursp,
+            urbp: r.urbp,
+            exitinfo: r.exitinfo,
+            fsbase: r.fsbase,
+            gsbase: r.gsbase,
+        }
+    }
+}
+
+/// Reasons the SSA of an enclave can not be read, see [`EnclaveRef::registers`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SsaError {
+    /// The enclave belongs to another process, see [`EnclaveRef::from_external`]
+    External,
+    /// The enclave is a production enclave, whose memory can not be read
+    Production,
+}
+
+impl std::fmt::Display for SsaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::External => "the SSA of an enclave of another process can not be read",
+            Self::Production => "the SSA of a production enclave can not be read",
+        })
+    }
 }
 
+impl Error for SsaError {}
+
 /// Handle to an SGX enclave
+#[cfg(feature = "sys")]
 #[derive(Debug)]
 pub struct EnclaveRef(EnclaveId);
 
+#[cfg(feature = "sys")]
 impl EnclaveRef {
     /// Create an `EnclaveRef` from the given `EnclaveId`.
     ///
@@ -62,27 +204,94 @@ impl EnclaveRef {
         Self(eid)
     }
 
+    /// Create an `EnclaveRef` for an enclave launched by another process.
+    ///
+    /// The enclave is mapped at `base..base + size` in the address space of process
+    /// `pid`. Its base, size and page tables are available, but libsgxstep does not
+    /// know its ELF file or SSA frames, so the symbol, SSA and register accessors
+    /// must not be used. As the TCS and SSA pages can not be told apart, `end`
+    /// is the last page of the enclave.
+    pub fn from_external(pid: u32, base: usize, size: usize) -> Self {
+        Self(EnclaveId::External { pid, base, size })
+    }
+
     pub fn id(&self) -> EnclaveId {
         self.0
     }
 
+    /// Process that maps the enclave, or `None` if it is mapped by the calling process
+    pub fn pid(&self) -> Option<u32> {
+        self.0.pid()
+    }
+
     pub fn base(&self) -> *mut c_void {
-        unsafe { get_enclave_base() as *mut c_void }
+        match self.0 {
+            EnclaveId::External { base, .. } => base as *mut c_void,
+            _ => unsafe { get_enclave_base() as *mut c_void },
+        }
     }
 
     pub fn size(&self) -> *mut c_void {
-        unsafe { get_enclave_size() as *mut c_void }
+        match self.0 {
+            EnclaveId::External { size, .. } => size as *mut c_void,
+            _ => unsafe { get_enclave_size() as *mut c_void },
+        }
+    }
+
+    /// Number of 4 KiB pages of the enclave, including the TCS and SSA pages.
+    /// A partial last page counts as a page.
+    ///
+    /// ```
+    /// use sgx_step::EnclaveRef;
+    ///
+    /// let page_count = |size| EnclaveRef::from_external(1, 0x7000_0000, size).page_count();
+    /// assert_eq!(page_count(0), 0);
+    /// assert_eq!(page_count(4096), 1);
+    /// assert_eq!(page_count(3 * 4096), 3);
+    /// assert_eq!(page_count(3 * 4096 + 1), 4);
+    /// assert_eq!(page_count(4 * 4096 - 1), 4);
+    /// ```
+    pub fn page_count(&self) -> usize {
+        (self.size() as usize).div_ceil(PAGE_SIZE_4KiB as usize)
     }
 
     pub fn limit(&self) -> *mut c_void {
-        unsafe { get_enclave_limit() as *mut c_void }
+        match self.0 {
+            EnclaveId::External { base, size, .. } => (base + size) as *mut c_void,
+            _ => unsafe { get_enclave_limit() as *mut c_void },
+        }
     }
 
     pub fn ssa_gprsgx(&self) -> *mut c_void {
         unsafe { get_enclave_ssa_gprsgx_adrs() as *mut c_void }
     }
 
+    /// Address of the GPRSGX region of the current SSA frame of the enclave thread.
+    ///
+    /// The address is read from the TCS, which requires a debug enclave of the
+    /// calling process, unlike [`EnclaveRef::ssa_gprsgx`], which returns an address
+    /// below the enclave base otherwise.
+    pub fn ssa_gprsgx_address(&self) -> Result<usize, SsaError> {
+        if let EnclaveId::External { .. } = self.0 {
+            return Err(SsaError::External);
+        }
+        // Debug reads of production enclaves fail, see `edbgrdwr`
+        let mut probe = 0u64;
+        let read = unsafe { edbgrd(self.base(), &mut probe as *mut _ as *mut c_void, 8) };
+        if read < 0 {
+            return Err(SsaError::Production);
+        }
+        Ok(self.ssa_gprsgx() as usize)
+    }
+
+    /// Address of the last page of the enclave before its TCS and SSA pages.
+    ///
+    /// For an enclave of another process, this is the last page of the enclave, or
+    /// its base if it is empty.
     pub fn end(&self) -> *mut c_void {
+        if let EnclaveId::External { base, size, .. } = self.0 {
+            return (base + size.saturating_sub(PAGE_SIZE_4KiB as usize)) as *mut c_void;
+        }
         ((self.ssa_gprsgx() as usize & !0xfff) - PAGE_SIZE_4KiB as usize) as *mut c_void
     }
 
@@ -98,6 +307,46 @@ impl EnclaveRef {
         }
     }
 
+    /// Read the registers saved in the SSA of the interrupted enclave thread.
+    ///
+    /// Reading the SSA requires a debug enclave of the calling process, otherwise an
+    /// [`SsaError`] is returned. The values are only valid after an AEX, see [`GprSgx`].
+    ///
+    /// ```no_run
+    /// # use sgx_step::{sgx_step_sys::edbgrd_erip, EnclaveRef};
+    /// # fn trap_handler(enclave: &EnclaveRef) {
+    /// let registers = enclave.registers().unwrap();
+    /// assert_eq!(registers.rip, unsafe { edbgrd_erip() });
+    /// // The enclave thread runs on a stack within the enclave
+    /// let enclave_range = enclave.base() as u64..enclave.limit() as u64;
+    /// assert!(enclave_range.contains(&registers.rsp));
+    /// # }
+    /// ```
+    ///
+    /// ```
+    /// use sgx_step::{EnclaveRef, SsaError};
+    ///
+    /// let external = EnclaveRef::from_external(1, 0x7000_0000, 0x10_0000);
+    /// assert_eq!(external.registers(), Err(SsaError::External));
+    /// ```
+    pub fn registers(&self) -> Result<GprSgx, SsaError> {
+        let address = self.ssa_gprsgx_address()?;
+        let mut region = std::mem::MaybeUninit::<gprsgx_region_t>::zeroed();
+        let size = std::mem::size_of::<gprsgx_region_t>();
+        let read = unsafe {
+            edbgrd(
+                address as *mut c_void,
+                region.as_mut_ptr() as *mut c_void,
+                size,
+            )
+        };
+        if read != size as i32 {
+            return Err(SsaError::Production);
+        }
+        // Every bit pattern is a valid `gprsgx_region`
+        Ok(unsafe { region.assume_init().fields }.into())
+    }
+
     pub fn dump(&self) {
         unsafe { print_enclave_info() }
     }
@@ -107,7 +356,7 @@ impl EnclaveRef {
     }
 
     pub fn symbol_offset(&self, name: &str) -> Result<*mut c_void, NulError> {
-        let c_str = CString::new(name).unwrap();
+        let c_str = CString::new(name)?;
         Ok(unsafe { get_symbol_offset(c_str.as_ptr()) as *mut c_void })
     }
 
@@ -115,22 +364,151 @@ impl EnclaveRef {
         self.symbol_offset(name)
             .map(|o| ((self.base() as usize) + (o as usize)) as *mut c_void)
     }
+
+    /// Returns the range of absolute addresses covered by the given symbol.
+    ///
+    /// The size of a symbol is taken from the `st_size` field of the ELF symbol table,
+    /// which the compiler sets for functions and objects. Returns `None` if the symbol
+    /// does not exist or has no size, as is the case for e.g. assembly labels.
+    pub fn symbol_range(&self, name: &str) -> Result<Option<Range<usize>>, NulError> {
+        let c_str = CString::new(name)?;
+        let (offset, size) = unsafe {
+            (
+                get_symbol_offset(c_str.as_ptr()),
+                get_symbol_size(c_str.as_ptr()),
+            )
+        };
+        if offset == 0 || size == 0 {
+            return Ok(None);
+        }
+        let start = self.base() as usize + offset;
+        Ok(Some(start..start + size))
+    }
+
+    /// Returns the layout of the enclave, sorted by start address.
+    ///
+    /// Code and data segments are derived from the allocated sections of the enclave ELF
+    /// file, which are registered when the enclave is created. Their permissions are
+    /// the section flags, which the SGX loader uses as the initial SECINFO permissions.
+    ///
+    /// Heap, stack, TCS and SSA pages are added by the loader and are not described by
+    /// the ELF file. They are reported as a single read-write `runtime` segment spanning
+    /// from the end of the last section to the end of the enclave.
+    pub fn segments(&self) -> Vec<Segment> {
+        let base = self.base() as usize;
+        let mut segments = (0..unsafe { get_section_count() })
+            .filter_map(|i| {
+                let mut section: section = unsafe { std::mem::zeroed() };
+                if unsafe { get_section(i, &mut section) } != 0 {
+                    return None;
+                }
+                Some(Segment {
+                    name: unsafe { CStr::from_ptr(section.name) }
+                        .to_string_lossy()
+                        .into_owned(),
+                    start: base + section.address,
+                    end: base + section.address + section.size,
+                    perms: SegmentPerms {
+                        read: true,
+                        write: section.flags & SHF_WRITE != 0,
+                        execute: section.flags & SHF_EXECINSTR != 0,
+                    },
+                })
+            })
+            .collect::<Vec<_>>();
+        segments.sort_by_key(|s| s.start);
+
+        let runtime_start = segments.last().map(|s| s.end).unwrap_or(base);
+        let end = self.end() as usize;
+        if runtime_start < end {
+            segments.push(Segment {
+                name: "runtime".into(),
+                start: runtime_start,
+                end,
+                perms: SegmentPerms {
+                    read: true,
+                    write: true,
+                    execute: false,
+                },
+            });
+        }
+
+        segments
+    }
+
+    /// Resolve the addresses of multiple symbols in a single pass over the symbol table.
+    ///
+    /// Symbols that are not found are returned as `None`.
+    pub fn symbol_addresses(&self, names: &[&str]) -> Result<Vec<Option<u64>>, NulError> {
+        let c_names = names
+            .iter()
+            .map(|&name| CString::new(name))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut c_name_ptrs = c_names.iter().map(|n| n.as_ptr()).collect::<Vec<_>>();
+        let mut offsets = vec![0; names.len()];
+        unsafe { get_symbol_offsets(c_name_ptrs.as_mut_ptr(), names.len(), offsets.as_mut_ptr()) };
+
+        let base = self.base() as u64;
+        Ok(offsets
+            .into_iter()
+            .map(|o| if o == 0 { None } else { Some(base + o as u64) })
+            .collect())
+    }
 }
 
 /// Owned SGX enclave
+#[cfg(feature = "sys")]
 #[derive(Debug)]
 pub struct Enclave(EnclaveRef);
 
+#[cfg(feature = "sys")]
 impl Enclave {
     /// Create a new SGX enclave and register the enclave in SGX-Step
     #[cfg(feature = "sgx")]
-    pub fn new_sgx(enclave_so: &str, debug: bool) -> Result<Self, Box<dyn Error>> {
+    pub fn new_sgx(enclave_so: &str, debug: bool) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Self::create_sgx(enclave_so, debug, None)
+    }
+
+    /// Create a new SGX enclave like [`Enclave::new_sgx`], reusing the launch token
+    /// stored in `token_file`.
+    ///
+    /// The token file is created if it does not exist, and overwritten if the token
+    /// was updated when creating the enclave. A file that does not hold a token is
+    /// ignored, so a fresh token is created.
+    #[cfg(feature = "sgx")]
+    pub fn new_sgx_with_token(
+        enclave_so: &str,
+        debug: bool,
+        token_file: impl AsRef<Path>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Self::create_sgx(enclave_so, debug, Some(token_file.as_ref()))
+    }
+
+    #[cfg(feature = "sgx")]
+    fn create_sgx(
+        enclave_so: &str,
+        debug: bool,
+        token_file: Option<&Path>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
         use sgx_step_sys::register_symbols;
 
         let mut token: sgx_launch_token_t = [0; 1024];
         let mut updated = 0;
         let mut eid: sgx_enclave_id_t = 0;
 
+        let mut token_loaded = false;
+        if let Some(path) = token_file {
+            match fs::read(path) {
+                Ok(saved) if saved.len() == token.len() => {
+                    token.copy_from_slice(&saved);
+                    token_loaded = true;
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
         let enclave_so = CString::new(enclave_so)?;
 
         let result = unsafe {
@@ -145,6 +523,12 @@ impl Enclave {
         };
 
         if result == _status_t_SGX_SUCCESS {
+            if let Some(path) = token_file {
+                if updated != 0 || !token_loaded {
+                    fs::write(path, token)?;
+                }
+            }
+
             if debug {
                 unsafe { set_debug_optin() };
             }
@@ -167,12 +551,14 @@ impl Enclave {
     }
 }
 
+#[cfg(feature = "sys")]
 impl AsRef<EnclaveRef> for Enclave {
     fn as_ref(&self) -> &EnclaveRef {
         &self.0
     }
 }
 
+#[cfg(feature = "sys")]
 impl Deref for Enclave {
     type Target = EnclaveRef;
 
@@ -181,12 +567,14 @@ impl Deref for Enclave {
     }
 }
 
+#[cfg(feature = "sys")]
 impl DerefMut for Enclave {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.0
     }
 }
 
+#[cfg(feature = "sys")]
 impl Drop for Enclave {
     fn drop(&mut self) {
         match self.id() {
@@ -198,12 +586,139 @@ impl Drop for Enclave {
                     "Owned SGX enclave dropped, but not compiled with SGX support! This is a bug!"
                 );
             },
+            EnclaveId::External { .. } => {
+                unreachable!("Owned enclave of another process dropped! This is a bug!")
+            }
         }
     }
 }
 
+#[cfg(feature = "sys")]
 impl Borrow<EnclaveRef> for Enclave {
     fn borrow(&self) -> &EnclaveRef {
         &self.0
     }
 }
+
+#[cfg(all(test, feature = "sys"))]
+mod tests {
+    use super::*;
+    use std::sync::Once;
+
+    /// Symbol with a known address in the test binary, which is registered as the
+    /// ELF file of the enclave
+    #[no_mangle]
+    extern "C" fn sgx_step_test_symbol() {}
+
+    /// Register the symbols of the test binary, and return an enclave at its load
+    /// base, so the address of a symbol is the address of the item in this process
+    fn test_binary_enclave() -> EnclaveRef {
+        static REGISTER: Once = Once::new();
+        let exe = std::env::current_exe().unwrap();
+        REGISTER.call_once(|| {
+            let path = CString::new(exe.to_str().unwrap()).unwrap();
+            unsafe { sgx_step_sys::register_symbols(path.as_ptr()) };
+        });
+
+        // The first mapping of the binary is at offset 0 of the file
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        let line = maps
+            .lines()
+            .find(|line| line.ends_with(exe.to_str().unwrap()))
+            .unwrap();
+        let (range, _) = line.split_once(' ').unwrap();
+        let (base, end) = range.split_once('-').unwrap();
+        let base = usize::from_str_radix(base, 16).unwrap();
+        let end = usize::from_str_radix(end, 16).unwrap();
+        EnclaveRef::from_external(std::process::id(), base, end - base)
+    }
+
+    #[test]
+    fn symbol_addresses_resolves_known_symbols() {
+        let enclave = test_binary_enclave();
+        let addresses = enclave
+            .symbol_addresses(&["sgx_step_test_symbol", "no_such_symbol"])
+            .unwrap();
+        assert_eq!(
+            addresses,
+            [Some(sgx_step_test_symbol as *const () as u64), None]
+        );
+        assert_eq!(
+            enclave.symbol_address("sgx_step_test_symbol").unwrap() as u64,
+            addresses[0].unwrap()
+        );
+        assert!(enclave.symbol_addresses(&["sgx_step\0test"]).is_err());
+    }
+
+    #[test]
+    fn code_segment_is_executable() {
+        let enclave = test_binary_enclave();
+        let symbol = sgx_step_test_symbol as *const () as usize;
+        let segments = enclave.segments();
+        let code = segments
+            .iter()
+            .find(|segment| segment.range().contains(&symbol))
+            .unwrap();
+        assert_eq!(code.name, ".text");
+        assert!(code.perms.execute && !code.perms.write, "{code:?}");
+        assert!(segments.is_sorted_by_key(|segment| segment.start));
+    }
+
+    #[test]
+    fn registers_are_decoded_from_the_ssa_layout() {
+        // GPRSGX region as written by an AEX: the 20 registers up to urbp, exitinfo and a
+        // reserved field, then fsbase and gsbase
+        let mut bytes = [0u8; 184];
+        for (i, register) in bytes[..160].chunks_mut(8).enumerate() {
+            register.copy_from_slice(&(0x1000 + i as u64).to_le_bytes());
+        }
+        bytes[160..164].copy_from_slice(&0x8000_0306u32.to_le_bytes());
+        bytes[164..168].copy_from_slice(&u32::MAX.to_le_bytes());
+        bytes[168..176].copy_from_slice(&0x7f00_0000u64.to_le_bytes());
+        bytes[176..184].copy_from_slice(&0x7f10_0000u64.to_le_bytes());
+
+        let region = gprsgx_region_t { bytes };
+        let registers = GprSgx::from(unsafe { region.fields });
+        assert_eq!(
+            registers,
+            GprSgx {
+                rax: 0x1000,
+                rcx: 0x1001,
+                rdx: 0x1002,
+                rbx: 0x1003,
+                rsp: 0x1004,
+                rbp: 0x1005,
+                rsi: 0x1006,
+                rdi: 0x1007,
+                r8: 0x1008,
+                r9: 0x1009,
+                r10: 0x100a,
+                r11: 0x100b,
+                r12: 0x100c,
+                r13: 0x100d,
+                r14: 0x100e,
+                r15: 0x100f,
+                rflags: 0x1010,
+                rip: 0x1011,
+                ursp: 0x1012,
+                urbp: 0x1013,
+                exitinfo: 0x8000_0306,
+                fsbase: 0x7f00_0000,
+                gsbase: 0x7f10_0000,
+            }
+        );
+        // The offset of rip read by `edbgrd_erip`
+        assert_eq!(bytes[136..144], 0x1011u64.to_le_bytes());
+    }
+
+    #[test]
+    fn end_of_empty_external_enclave_is_its_base() {
+        let base = 0x7000_0000;
+        let page = PAGE_SIZE_4KiB as usize;
+        assert_eq!(EnclaveRef::from_external(1, base, 0).end() as usize, base);
+        assert_eq!(
+            EnclaveRef::from_external(1, base, 3 * page).end() as usize,
+            base + 2 * page
+        );
+    }
+}
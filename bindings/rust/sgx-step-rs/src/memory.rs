@@ -1,27 +1,126 @@
 use std::{
     ffi::c_void,
     io::{self, Read, Write},
+    ops::Range,
 };
 
+use bytemuck::Pod;
 use sgx_step_sys::{edbgrd, edbgwr};
 
+use crate::EnclaveRef;
+
 /// Wrapper around `edbgwr` and `edbgrd` that implements `std::io::Read` and `std::io::Write`
 /// to read or write a given number of bytes from a fixed address.
 #[derive(Debug)]
-pub struct EnclaveMemory(usize);
+pub struct EnclaveMemory {
+    address: usize,
+    /// Addresses that reads and writes must stay within, if they are checked
+    bounds: Option<Range<usize>>,
+}
 
 impl EnclaveMemory {
     /// Create a new `EnclaveMemory` handle to read or write from the given address.
     pub fn new(address: usize) -> Self {
-        Self(address)
+        Self {
+            address,
+            bounds: None,
+        }
+    }
+
+    /// Create a handle to read or write from the given address, checking that
+    /// reads and writes stay within the enclave.
+    pub fn in_enclave(enclave: &EnclaveRef, address: usize) -> Self {
+        Self::with_bounds(address, enclave.base() as usize..enclave.limit() as usize)
+    }
+
+    /// Create a handle to read or write from the given address, checking that
+    /// reads and writes stay within the `bounds` addresses.
+    pub fn with_bounds(address: usize, bounds: Range<usize>) -> Self {
+        Self {
+            address,
+            bounds: Some(bounds),
+        }
+    }
+
+    /// Read `buf.len()` values from the address, reinterpreting the bytes as `T`.
+    ///
+    /// Returns an `InvalidInput` error without reading if the read would leave
+    /// the bounds of this handle.
+    ///
+    /// ```
+    /// use sgx_step::memory::EnclaveMemory;
+    ///
+    /// let data: [u64; 4] = [1, 2, 3, u64::MAX];
+    /// let start = data.as_ptr() as usize;
+    /// let memory = EnclaveMemory::with_bounds(start, start..start + std::mem::size_of_val(&data));
+    ///
+    /// let mut words = [0u64; 4];
+    /// memory.read_into(&mut words).unwrap();
+    /// assert_eq!(words, data);
+    ///
+    /// let mut bytes = [0u8; 32];
+    /// memory.read_into(&mut bytes).unwrap();
+    /// assert_eq!(bytes[..8], 1u64.to_ne_bytes());
+    /// assert_eq!(bytes[24..], [0xff; 8]);
+    ///
+    /// let mut too_many = [0u64; 5];
+    /// assert!(memory.read_into(&mut too_many).is_err());
+    /// assert_eq!(too_many, [0; 5]);
+    /// ```
+    pub fn read_into<T: Pod>(&self, buf: &mut [T]) -> io::Result<()> {
+        let buf = bytemuck::cast_slice_mut::<T, u8>(buf);
+        let read = self.read_at(buf)?;
+        if read != buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("read {read} of {} bytes of enclave memory", buf.len()),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check that `len` bytes from the address lie within the bounds, if any
+    fn check_bounds(&self, len: usize) -> io::Result<()> {
+        let Some(bounds) = &self.bounds else {
+            return Ok(());
+        };
+        match self.address.checked_add(len) {
+            Some(end) if bounds.start <= self.address && end <= bounds.end => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "access of {len} bytes at {:#x} exceeds enclave memory {bounds:x?}",
+                    self.address
+                ),
+            )),
+        }
+    }
+
+    fn read_at(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check_bounds(buf.len())?;
+        let rv = unsafe {
+            edbgrd(
+                self.address as *const c_void,
+                buf.as_ptr() as *mut c_void,
+                buf.len(),
+            )
+        };
+        if rv == -1 {
+            Err(io::Error::other(format!(
+                "failed to read from enclave memory: {rv}"
+            )))
+        } else {
+            Ok(rv as usize)
+        }
     }
 }
 
 impl Write for EnclaveMemory {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_bounds(buf.len())?;
         let rv = unsafe {
             edbgwr(
-                self.0 as *const c_void,
+                self.address as *const c_void,
                 buf.as_ptr() as *mut c_void,
                 buf.len(),
             )
@@ -42,19 +141,6 @@ impl Write for EnclaveMemory {
 
 impl Read for EnclaveMemory {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let rv = unsafe {
-            edbgrd(
-                self.0 as *const c_void,
-                buf.as_ptr() as *mut c_void,
-                buf.len(),
-            )
-        };
-        if rv == -1 {
-            Err(io::Error::other(format!(
-                "failed to read from enclave memory: {rv}"
-            )))
-        } else {
-            Ok(buf.len())
-        }
+        self.read_at(buf).map(|_| buf.len())
     }
 }
@@ -0,0 +1,133 @@
+//! Page numbers
+//!
+//! Pages are identified either relative to the enclave base, which is what the
+//! `*_pages` functions of libsgxstep and the profiler traces use, or by their absolute
+//! virtual address. `PageNum` keeps both apart, and converting between them requires
+//! the enclave.
+//!
+//! The page numbers that cross the FFI boundary to libsgxstep, i.e. the page passed to
+//! the handler of `register_fault_handler` and the pages of `revoke_pages` and
+//! `restore_pages`, are relative. libsgxstep computes them from addresses without
+//! checking that these lie within the enclave, so incoming page numbers should be
+//! converted with [`RelativePage::checked`].
+
+use std::{fmt::Display, marker::PhantomData};
+
+#[cfg(feature = "sys")]
+use crate::EnclaveRef;
+
+/// Size of a page in bytes
+pub const PAGE_SIZE: usize = 4096;
+
+/// Page number relative to the enclave base
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Relative;
+
+/// Page number in the virtual address space
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Absolute;
+
+/// Number of a 4 KiB page, either `Relative` to the enclave base or `Absolute`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PageNum<K>(usize, PhantomData<K>);
+
+pub type RelativePage = PageNum<Relative>;
+pub type AbsolutePage = PageNum<Absolute>;
+
+impl<K> PageNum<K> {
+    pub const fn new(page: usize) -> Self {
+        Self(page, PhantomData)
+    }
+
+    pub const fn get(self) -> usize {
+        self.0
+    }
+
+    /// The page `count` pages after this one
+    pub const fn offset(self, count: usize) -> Self {
+        Self::new(self.0 + count)
+    }
+}
+
+impl<K> Display for PageNum<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// With the `serde` feature, pages are serialized as their plain page number
+#[cfg(feature = "serde")]
+impl<K> serde::Serialize for PageNum<K> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, K> serde::Deserialize<'de> for PageNum<K> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        usize::deserialize(deserializer).map(Self::new)
+    }
+}
+
+#[cfg(feature = "sys")]
+impl RelativePage {
+    /// Page number received from libsgxstep, checked to be one of the
+    /// [`EnclaveRef::page_count`] pages of the enclave.
+    ///
+    /// Returns `None` for pages outside of the enclave, e.g. of a fault on an address
+    /// outside of it, or if libsgxstep passed an absolute page number instead.
+    ///
+    /// ```
+    /// use sgx_step::{page::RelativePage, EnclaveRef};
+    ///
+    /// let base = 0x7000_0000;
+    /// let enclave = EnclaveRef::from_external(1, base, 4 * 4096);
+    /// assert_eq!(RelativePage::checked(3, &enclave), Some(RelativePage::new(3)));
+    /// assert_eq!(RelativePage::checked(4, &enclave), None);
+    /// // An absolute page number is rejected rather than taken as a page of the enclave
+    /// assert_eq!(RelativePage::checked(base / 4096 + 1, &enclave), None);
+    /// // libsgxstep wraps around for addresses below the enclave base
+    /// assert_eq!(RelativePage::checked(0usize.wrapping_sub(1), &enclave), None);
+    /// ```
+    pub fn checked(page: usize, enclave: &EnclaveRef) -> Option<Self> {
+        (page < enclave.page_count()).then(|| Self::new(page))
+    }
+
+    /// Returns the page of the enclave containing the given absolute address,
+    /// or `None` if the address lies outside of the enclave.
+    pub fn containing(address: usize, enclave: &EnclaveRef) -> Option<Self> {
+        AbsolutePage::containing(address).to_relative(enclave)
+    }
+
+    pub fn to_absolute(self, enclave: &EnclaveRef) -> AbsolutePage {
+        AbsolutePage::containing(enclave.base() as usize).offset(self.0)
+    }
+
+    /// Absolute address of the start of the page
+    pub fn address(self, enclave: &EnclaveRef) -> usize {
+        self.to_absolute(enclave).address()
+    }
+}
+
+impl AbsolutePage {
+    pub fn containing(address: usize) -> Self {
+        Self::new(address / PAGE_SIZE)
+    }
+
+    /// Returns the page relative to the enclave base,
+    /// or `None` if the page lies outside of the enclave.
+    #[cfg(feature = "sys")]
+    pub fn to_relative(self, enclave: &EnclaveRef) -> Option<RelativePage> {
+        let base = Self::containing(enclave.base() as usize);
+        let limit = Self::containing(enclave.limit() as usize - 1);
+        (base.0..=limit.0)
+            .contains(&self.0)
+            .then(|| RelativePage::new(self.0 - base.0))
+    }
+
+    /// Address of the start of the page
+    pub fn address(self) -> usize {
+        self.0 * PAGE_SIZE
+    }
+}
@@ -3,10 +3,11 @@ use sgx_step_sys::{
     mark_dirty, mark_executable, mark_execute_disable, mark_non_executable, mark_non_writable,
     mark_not_accessed, mark_not_execute_disable, mark_not_present, mark_present, mark_rsvd,
     mark_supervisor, mark_user, mark_writable, present, pt_level_t_PAGE, pt_level_t_PGD,
-    pt_level_t_PMD, pt_level_t_PTE, pt_level_t_PUD, remap_page_table_level, rsvd, user, writable,
+    pt_level_t_PMD, pt_level_t_PTE, pt_level_t_PUD, remap_page_table_level,
+    remap_pid_page_table_level, rsvd, user, writable,
 };
 
-use std::ffi::c_void;
+use std::{ffi::c_void, fmt::Display};
 
 /// Level in the page table
 #[derive(Debug, Clone, Copy)]
@@ -30,9 +31,10 @@ impl Into<u32> for PageTableLevel {
     }
 }
 
-/// Page table entry for the page at a given address
+/// Page table entry for the page at a given address, and whether it was remapped
+/// by libsgxstep and has to be unmapped on drop
 #[derive(Debug)]
-pub struct PageTableEntry(*mut u64);
+pub struct PageTableEntry(*mut u64, bool);
 
 unsafe impl Sync for PageTableEntry {}
 unsafe impl Send for PageTableEntry {}
@@ -40,15 +42,56 @@ unsafe impl Send for PageTableEntry {}
 impl PageTableEntry {
     /// Create a new `PageTableEntry` for the page at the given address
     pub fn new(address: usize) -> Option<Self> {
+        Self::at_level(address, PageTableLevel::PTE)
+    }
+
+    /// Create a new `PageTableEntry` for the entry at `level` of the page table walk
+    /// of the given address, e.g. the PMD entry that maps the page table of the
+    /// 2 MiB region around it. Like for a PTE, the processor sets its accessed bit
+    /// when the entry is used in a page walk.
+    pub fn at_level(address: usize, level: PageTableLevel) -> Option<Self> {
+        let ptr =
+            unsafe { remap_page_table_level(address as *mut c_void, level.into()) } as *mut u64;
+        if ptr.is_null() {
+            None
+        } else {
+            Some(Self(ptr, true))
+        }
+    }
+
+    /// Create a new `PageTableEntry` for the page at the given address in the
+    /// address space of process `pid`.
+    ///
+    /// The PTE is mapped through `/dev/mem`, so its A/D bits can be read and
+    /// cleared as for the calling process. Returns `None` if the calling process
+    /// is not allowed to ptrace `pid`.
+    pub fn new_in(pid: u32, address: usize) -> Option<Self> {
+        Self::at_level_in(pid, address, PageTableLevel::PTE)
+    }
+
+    /// Like [`PageTableEntry::at_level`], in the address space of process `pid`
+    pub fn at_level_in(pid: u32, address: usize, level: PageTableLevel) -> Option<Self> {
         let ptr =
-            unsafe { remap_page_table_level(address as *mut c_void, pt_level_t_PTE) } as *mut u64;
+            unsafe { remap_pid_page_table_level(pid as _, address as *mut c_void, level.into()) }
+                as *mut u64;
         if ptr.is_null() {
             None
         } else {
-            Some(Self(ptr))
+            Some(Self(ptr, true))
         }
     }
 
+    /// Create a `PageTableEntry` that reads and writes the entry at `ptr`, e.g. a copy
+    /// of a PTE or one that was mapped elsewhere.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for reads and writes for as long as the entry is used.
+    /// It is not unmapped when the entry is dropped.
+    pub unsafe fn from_raw(ptr: *mut u64) -> Self {
+        Self(ptr, false)
+    }
+
     pub fn accessed(&self) -> bool {
         accessed(unsafe { *self.0 }) > 0
     }
@@ -73,6 +116,11 @@ impl PageTableEntry {
         unsafe { *self.0 = mark_clean(*self.0) }
     }
 
+    /// Clear both the accessed and dirty bit with a single write to the PTE
+    pub fn mark_not_accessed_clean(&mut self) {
+        unsafe { *self.0 = mark_clean(mark_not_accessed(*self.0)) }
+    }
+
     pub fn writable(&self) -> bool {
         writable(unsafe { *self.0 }) > 0
     }
@@ -144,10 +192,138 @@ impl PageTableEntry {
     pub fn clear_rsvd(&mut self) {
         unsafe { *self.0 = clear_rsvd(*self.0) }
     }
+
+    /// Raw value of the entry
+    pub fn raw(&self) -> u64 {
+        unsafe { *self.0 }
+    }
+
+    /// Snapshot of the flags of the entry, e.g. to check that clearing the A/D bits stuck
+    pub fn flags(&self) -> PteFlags {
+        PteFlags::from_raw(self.raw())
+    }
+}
+
+/// Flags of a page table entry at one point in time, see [`PageTableEntry::flags`]
+///
+/// ```
+/// use sgx_step::page_table::{PteFlagChange, PteFlags};
+///
+/// let pte = PteFlags::PRESENT | PteFlags::WRITABLE | PteFlags::ACCESSED | PteFlags::DIRTY;
+/// let before = PteFlags::from_raw(0x1234_5000 | pte);
+/// assert!(before.present && before.writable && before.accessed && before.dirty);
+/// assert!(!before.user && !before.page_size && !before.execute_disable);
+/// assert_eq!(before.to_string(), "P RW A D");
+///
+/// // Clear the A/D bits like `PageTableEntry::mark_not_accessed_clean`
+/// let after = PteFlags::from_raw(0x1234_5000 | pte & !(PteFlags::ACCESSED | PteFlags::DIRTY));
+/// assert_eq!(
+///     before.diff(&after),
+///     [
+///         PteFlagChange { flag: "A", before: true, after: false },
+///         PteFlagChange { flag: "D", before: true, after: false },
+///     ]
+/// );
+/// assert_eq!(before.diff(&after)[0].to_string(), "A 1 -> 0");
+/// assert!(after.diff(&after).is_empty());
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PteFlags {
+    pub present: bool,
+    pub writable: bool,
+    pub user: bool,
+    pub accessed: bool,
+    pub dirty: bool,
+    /// The entry maps a large page instead of a page table
+    pub page_size: bool,
+    pub execute_disable: bool,
+}
+
+impl PteFlags {
+    pub const PRESENT: u64 = 1 << 0;
+    pub const WRITABLE: u64 = 1 << 1;
+    pub const USER: u64 = 1 << 2;
+    pub const ACCESSED: u64 = 1 << 5;
+    pub const DIRTY: u64 = 1 << 6;
+    pub const PAGE_SIZE: u64 = 1 << 7;
+    pub const EXECUTE_DISABLE: u64 = 1 << 63;
+
+    /// Decode the flags of the raw entry `pte`
+    pub fn from_raw(pte: u64) -> Self {
+        Self {
+            present: pte & Self::PRESENT != 0,
+            writable: pte & Self::WRITABLE != 0,
+            user: pte & Self::USER != 0,
+            accessed: pte & Self::ACCESSED != 0,
+            dirty: pte & Self::DIRTY != 0,
+            page_size: pte & Self::PAGE_SIZE != 0,
+            execute_disable: pte & Self::EXECUTE_DISABLE != 0,
+        }
+    }
+
+    /// Short names of the flags and their values
+    fn named(&self) -> [(&'static str, bool); 7] {
+        [
+            ("P", self.present),
+            ("RW", self.writable),
+            ("US", self.user),
+            ("A", self.accessed),
+            ("D", self.dirty),
+            ("PS", self.page_size),
+            ("NX", self.execute_disable),
+        ]
+    }
+
+    /// Flags that changed from this snapshot to the later snapshot `after`
+    pub fn diff(&self, after: &Self) -> Vec<PteFlagChange> {
+        self.named()
+            .into_iter()
+            .zip(after.named())
+            .filter(|((_, before), (_, after))| before != after)
+            .map(|((flag, before), (_, after))| PteFlagChange {
+                flag,
+                before,
+                after,
+            })
+            .collect()
+    }
+}
+
+/// Lists the short names of the set flags, e.g. `P RW A`
+impl Display for PteFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let set = self
+            .named()
+            .into_iter()
+            .filter_map(|(flag, set)| set.then_some(flag))
+            .collect::<Vec<_>>();
+        f.write_str(&set.join(" "))
+    }
+}
+
+/// Change of a flag between two [`PteFlags`] snapshots
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PteFlagChange {
+    /// Short name of the flag, as in the `Display` output of [`PteFlags`]
+    pub flag: &'static str,
+    pub before: bool,
+    pub after: bool,
+}
+
+impl Display for PteFlagChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} -> {}",
+            self.flag, self.before as u8, self.after as u8
+        )
+    }
 }
 
 impl Drop for PageTableEntry {
     fn drop(&mut self) {
-        unsafe { free_map(self.0 as *mut c_void) };
+        if self.1 {
+            unsafe { free_map(self.0 as *mut c_void) };
+        }
     }
 }
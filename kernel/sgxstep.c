@@ -25,6 +25,10 @@
 #include <asm/page.h>
 #include <linux/mm.h>
 #include <linux/sched.h>
+#include <linux/sched/mm.h>
+#include <linux/sched/task.h>
+#include <linux/ptrace.h>
+#include <linux/pid.h>
 #include <asm/irq.h>
 #include <asm/apic.h>
 
@@ -222,9 +226,8 @@ long sgx_step_ioctl_invpg(struct file *filep, unsigned int cmd, unsigned long ar
     return 0;
 }
 
-long sgx_step_get_pt_mapping(struct file *filep, unsigned int cmd, unsigned long arg)
+static long get_pt_mapping(struct mm_struct *mm, address_mapping_t *map)
 {
-    address_mapping_t *map = (address_mapping_t*) arg;
 	pgd_t *pgd = NULL;
 	pud_t *pud = NULL;
 	pmd_t *pmd = NULL;
@@ -240,8 +243,8 @@ long sgx_step_get_pt_mapping(struct file *filep, unsigned int cmd, unsigned long
 	memset( map, 0x00, sizeof( address_mapping_t ) );
 	map->virt = virt;
 	
-	map->pgd_phys_address = __pa( current->mm->pgd );
-	pgd = pgd_offset( current->mm, virt );
+	map->pgd_phys_address = __pa( mm->pgd );
+	pgd = pgd_offset( mm, virt );
 	map->pgd = *((uint64_t *) pgd);
 	
 	if ( !pgd_present( *pgd ) )
@@ -282,6 +285,53 @@ long sgx_step_get_pt_mapping(struct file *filep, unsigned int cmd, unsigned long
     return 0;
 }
 
+long sgx_step_get_pt_mapping(struct file *filep, unsigned int cmd, unsigned long arg)
+{
+    return get_pt_mapping(current->mm, (address_mapping_t*) arg);
+}
+
+/*
+ * Walk the page tables of another process, e.g. to profile an enclave that was
+ * launched by a separate application. The caller must be allowed to ptrace the
+ * target process.
+ */
+long sgx_step_get_pid_pt_mapping(struct file *filep, unsigned int cmd, unsigned long arg)
+{
+    pid_address_mapping_t *data = (pid_address_mapping_t*) arg;
+    struct pid *pid;
+    struct task_struct *task;
+    struct mm_struct *mm = NULL;
+    long ret;
+    RET_ASSERT(data);
+
+    pid = find_get_pid(data->pid);
+    task = get_pid_task(pid, PIDTYPE_PID);
+    put_pid(pid);
+    if (!task)
+        return -ESRCH;
+
+    if (ptrace_may_access(task, PTRACE_MODE_ATTACH_REALCREDS))
+        mm = get_task_mm(task);
+    else
+        log("not allowed to access page tables of pid %lld", data->pid);
+    put_task_struct(task);
+    if (!mm)
+        return -EPERM;
+
+    #if (LINUX_VERSION_CODE >= KERNEL_VERSION(5,8,0))
+        mmap_read_lock(mm);
+        ret = get_pt_mapping(mm, &data->map);
+        mmap_read_unlock(mm);
+    #else
+        down_read(&mm->mmap_sem);
+        ret = get_pt_mapping(mm, &data->map);
+        up_read(&mm->mmap_sem);
+    #endif
+    mmput(mm);
+
+    return ret;
+}
+
 long sgx_step_ioctl_setup_isr_map(struct file *filep, unsigned int cmd, unsigned long arg)
 {
     uint64_t nr_pinned_pages;
@@ -339,6 +389,9 @@ long step_ioctl(struct file *filep, unsigned int cmd, unsigned long arg)
         case SGX_STEP_IOCTL_SETUP_ISR_MAP:
             handler = sgx_step_ioctl_setup_isr_map;
             break;
+        case SGX_STEP_IOCTL_GET_PID_PT_MAPPING:
+            handler = sgx_step_get_pid_pt_mapping;
+            break;
         default:
             return -EINVAL;
     }
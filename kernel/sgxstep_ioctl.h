@@ -27,6 +27,7 @@
 #define SGX_STEP_IOCTL_GET_PT_MAPPING   _IOWR(SGX_STEP_IOCTL_MAGIC, 0, address_mapping_t)
 #define SGX_STEP_IOCTL_INVPG            _IOWR(SGX_STEP_IOCTL_MAGIC, 1, invpg_t)
 #define SGX_STEP_IOCTL_SETUP_ISR_MAP    _IOWR(SGX_STEP_IOCTL_MAGIC, 2, setup_isr_map_t)
+#define SGX_STEP_IOCTL_GET_PID_PT_MAPPING _IOWR(SGX_STEP_IOCTL_MAGIC, 3, pid_address_mapping_t)
 
 typedef struct {
 	uint64_t virt;
@@ -38,6 +39,11 @@ typedef struct {
 	uint64_t pte;
 } address_mapping_t;
 
+typedef struct {
+    uint64_t pid;             // in
+    address_mapping_t map;    // in/out
+} pid_address_mapping_t;
+
 typedef struct {
     uint64_t adrs;
 } invpg_t;
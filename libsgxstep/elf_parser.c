@@ -13,6 +13,9 @@
 struct symbol symbols[MAX_SYMBOLS] = {0};
 int symbol_count = 0;
 
+struct section sections[MAX_SECTIONS] = {0};
+size_t section_count = 0;
+
 uintptr_t get_symbol_offset(const char *name) {
     if (!name) {
         return 0;
@@ -27,18 +30,76 @@ uintptr_t get_symbol_offset(const char *name) {
     return 0;
 }
 
-void add_symbol(const char *name, uintptr_t address) {
+void get_symbol_offsets(const char **names, size_t count, uintptr_t *offsets) {
+    for (size_t j = 0; j < count; j++) {
+        offsets[j] = 0;
+    }
+
+    for (size_t i = 0; i < symbol_count; i++) {
+        if (symbols[i].name == NULL) {
+            continue;
+        }
+
+        for (size_t j = 0; j < count; j++) {
+            if (offsets[j] == 0 && names[j] && strcmp(symbols[i].name, names[j]) == 0) {
+                offsets[j] = symbols[i].address;
+            }
+        }
+    }
+}
+
+size_t get_symbol_size(const char *name) {
+    if (!name) {
+        return 0;
+    }
+
+    for (size_t i = 0; i < symbol_count; i++) {
+        if (symbols[i].name != NULL && strcmp(symbols[i].name, name) == 0) {
+            return symbols[i].size;
+        }
+    }
+
+    return 0;
+}
+
+void add_symbol(const char *name, uintptr_t address, size_t size) {
     ASSERT(symbol_count < MAX_SYMBOLS);
     ASSERT(name);
     symbols[symbol_count].name = strdup(name);
     symbols[symbol_count].address = address;
+    symbols[symbol_count].size = size;
     symbol_count++;
 }
 
+static void add_section(const char *name, uintptr_t address, size_t size, uint64_t flags) {
+    ASSERT(section_count < MAX_SECTIONS);
+    sections[section_count].name = strdup(name ? name : "");
+    sections[section_count].address = address;
+    sections[section_count].size = size;
+    sections[section_count].flags = flags;
+    section_count++;
+}
+
+size_t get_section_count(void) {
+    return section_count;
+}
+
+int get_section(size_t index, struct section *section) {
+    if (index >= section_count || !section) {
+        return -1;
+    }
+
+    *section = sections[index];
+    return 0;
+}
+
 void free_symbols() {
     for (size_t i = 0; i < symbol_count; i++) {
         free(symbols[i].name);
     }
+    for (size_t i = 0; i < section_count; i++) {
+        free(sections[i].name);
+    }
 }
 
 void register_symbols(const char *filename) {
@@ -79,6 +140,11 @@ void register_symbols(const char *filename) {
             goto err_elf_end;
         }
 
+        if ((shdr.sh_flags & SHF_ALLOC) && shdr.sh_size > 0) {
+            add_section(elf_strptr(elf, shstrndx, shdr.sh_name), shdr.sh_addr,
+                        shdr.sh_size, shdr.sh_flags);
+        }
+
         if (shdr.sh_type == SHT_SYMTAB || shdr.sh_type == SHT_DYNSYM) {
             Elf_Data *data = NULL;
             size_t n_symbols = shdr.sh_size / shdr.sh_entsize;
@@ -96,7 +162,7 @@ void register_symbols(const char *filename) {
                         continue;
                     }
 
-                    add_symbol(name, sym.st_value);
+                    add_symbol(name, sym.st_value, sym.st_size);
                 }
             }
         }
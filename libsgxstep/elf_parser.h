@@ -4,14 +4,30 @@
 #include <stddef.h>
 #include <stdint.h>
 #define MAX_SYMBOLS 100000
+#define MAX_SECTIONS 256
 
 struct symbol {
     char *name;
     uintptr_t address;
+    size_t size;
+};
+
+/* Allocated section of the ELF file, used to describe the enclave layout */
+struct section {
+    char *name;
+    uintptr_t address;
+    size_t size;
+    uint64_t flags;
 };
 
 uintptr_t get_symbol_offset(const char *name);
-static void add_symbol(const char *name, uintptr_t address);
+/* Look up the offsets of multiple symbols in a single pass (0 if not found) */
+void get_symbol_offsets(const char **names, size_t count, uintptr_t *offsets);
+/* Size of the symbol as recorded in the ELF symbol table (0 if not found or unknown) */
+size_t get_symbol_size(const char *name);
+static void add_symbol(const char *name, uintptr_t address, size_t size);
 void register_symbols(const char *filename);
+size_t get_section_count(void);
+int get_section(size_t index, struct section *section);
 
 #endif
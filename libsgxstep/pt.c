@@ -105,6 +105,20 @@ void *remap_page_table_level( void *address, pt_level_t level )
 	return addr_remapped;
 }
 
+void *remap_pid_page_table_level( pid_t pid, void *address, pt_level_t level )
+{
+	address_mapping_t *map = get_pid_mappings( pid, address );
+	void *addr_remapped = NULL;
+	
+	if ( map )
+	{
+		addr_remapped = remap( phys_address( map, level ) );
+		free( map );
+	}
+	
+	return addr_remapped;
+}
+
 address_mapping_t *get_mappings( void *address )
 {
 	address_mapping_t *mapping;
@@ -118,6 +132,28 @@ address_mapping_t *get_mappings( void *address )
     return mapping;
 }
 
+/*
+ * Page table mappings of `address` in the address space of process `pid`.
+ * Returns NULL if the calling process is not allowed to ptrace `pid`, or if
+ * `pid` does not exist.
+ */
+address_mapping_t *get_pid_mappings( pid_t pid, void *address )
+{
+	pid_address_mapping_t param;
+	address_mapping_t *mapping;
+	memset( &param, 0x00, sizeof( pid_address_mapping_t ) );
+	param.pid = pid;
+	param.map.virt = (uintptr_t) address;
+
+    step_open();
+    if ( ioctl( fd_step, SGX_STEP_IOCTL_GET_PID_PT_MAPPING, &param ) < 0 )
+        return NULL;
+
+	ASSERT( (mapping = (address_mapping_t *) malloc(sizeof(address_mapping_t))) );
+	*mapping = param.map;
+    return mapping;
+}
+
 uint64_t phys_address( address_mapping_t *map, pt_level_t level )
 {
 	uint64_t base = phys_base_address(map, level);
@@ -23,6 +23,7 @@
 
 #include <stdint.h>
 #include <inttypes.h>
+#include <sys/types.h>
 #include "../kernel/sgxstep_ioctl.h"
 
 // Returns a mask of the form:
@@ -163,9 +164,11 @@ typedef enum {PGD, PUD, PMD, PTE, PAGE} pt_level_t;
 void *remap(uint64_t phys);
 void free_map(void *p);
 void *remap_page_table_level( void *address, pt_level_t level );
+void *remap_pid_page_table_level( pid_t pid, void *address, pt_level_t level );
 void flush_tlb(void *adrs);
 
 address_mapping_t *get_mappings( void *address );
+address_mapping_t *get_pid_mappings( pid_t pid, void *address );
 uint64_t phys_address( address_mapping_t *map, pt_level_t level );
 uint64_t phys_base_address( address_mapping_t *map, pt_level_t level );
 uint64_t virt_index( address_mapping_t *map, pt_level_t level );